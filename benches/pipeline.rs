@@ -0,0 +1,66 @@
+//! Timings for each stage of the front end and code generation, over inputs
+//! of increasing size. Run with `cargo bench`, optionally with a filter such
+//! as `cargo bench -- tokenize`.
+
+use std::fs;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rcc::{generate, parse, tokenize, Options};
+
+/// A program returning a sum of `operands` terms, one per line. Chains can't
+/// nest more than 1024 deep, so this is the largest kind of valid program.
+fn long_expression(operands: usize) -> String {
+    let terms: Vec<String> = (0..operands)
+        .map(|i| format!("    {} * ({} - {})", i % 7, i % 5, i % 3))
+        .collect();
+    format!("int main() {{\n    return\n{};\n}}\n", terms.join(" +\n"))
+}
+
+/// 100k lines of tokens, which lex but don't parse, since no valid program
+/// is this large yet.
+fn large_token_stream() -> String {
+    "    return x;\n".repeat(100_000)
+}
+
+fn inputs() -> Vec<(&'static str, String)> {
+    let small = fs::read_to_string("tests/testfiles/valid/precedence.c").unwrap();
+    vec![("small", small), ("medium", long_expression(300))]
+}
+
+fn bench_tokenize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tokenize");
+    let large = large_token_stream();
+    for (size, source) in inputs().iter().chain(&[("large", large)]) {
+        group.bench_with_input(BenchmarkId::from_parameter(size), source, |b, source| {
+            b.iter(|| tokenize(black_box(source)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (size, source) in &inputs() {
+        let tokens = tokenize(source).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(size), &tokens, |b, tokens| {
+            b.iter(|| parse(black_box(tokens)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_generate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate");
+    let options = Options::default();
+    for (size, source) in &inputs() {
+        let ast = parse(&tokenize(source).unwrap()).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(size), &ast, |b, ast| {
+            b.iter(|| generate(black_box(ast), &options))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_tokenize, bench_parse, bench_generate);
+criterion_main!(benches);
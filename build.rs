@@ -0,0 +1,45 @@
+//! Identifies the source rcc is built from, so that object files cached by
+//! one build aren't reused by another that generates different code under
+//! the same version number.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The 64-bit FNV-1a hash, continued from `hash`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Hash the names and contents of the files under `dir`, in a fixed order.
+fn hash_dir(hash: u64, root: &Path, dir: &Path) -> u64 {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths.iter().fold(hash, |hash, path| {
+        if path.is_dir() {
+            hash_dir(hash, root, path)
+        } else {
+            let name = path.strip_prefix(root).unwrap().to_string_lossy();
+            let hash = fnv1a(hash, name.as_bytes());
+            fnv1a(hash, &fs::read(path).unwrap())
+        }
+    })
+}
+
+fn main() {
+    let root = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    hash = hash_dir(hash, &root, &root.join("src"));
+    // Dependencies such as the regex crate affect the output too
+    if let Ok(lock) = fs::read(root.join("Cargo.lock")) {
+        hash = fnv1a(hash, &lock);
+    }
+    println!("cargo:rustc-env=RCC_BUILD_ID={:016x}", hash);
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
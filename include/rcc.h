@@ -0,0 +1,23 @@
+/* The C interface to rcc, built with `cargo build --release --features ffi`
+ * as librcc.so. Each function compiles NUL-terminated source text and fills
+ * in a result whose JSON is the output, or {"errors": [...]} on failure.
+ * Free every result with rcc_result_free. */
+
+#ifndef RCC_H
+#define RCC_H
+
+typedef struct rcc_result {
+    /* 0 on success, or the exit status rcc would give for the error */
+    int status;
+    char *json;
+} rcc_result;
+
+/* {"tokens": [{"token": "IntKw", "line": 1, "column": 1}, ...]} */
+int rcc_tokenize(const char *source, rcc_result *result);
+/* {"ast": "..."}, the syntax tree as rcc --emit ast writes it */
+int rcc_parse(const char *source, rcc_result *result);
+/* {"assembly": "..."} for the host */
+int rcc_compile(const char *source, rcc_result *result);
+void rcc_result_free(rcc_result *result);
+
+#endif
@@ -1,90 +1,771 @@
-use std::error::Error;
+use std::fmt;
 use std::fs;
-use std::io::ErrorKind;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use config::{Emit, Stage};
+use error::{CompilerError, IoContext, SemanticError, SyntaxError};
+use observer::Observer;
+
+#[cfg(feature = "driver")]
+mod assembler;
 mod assembly;
-mod ast;
+pub mod ast;
+mod backend;
+#[cfg(feature = "driver")]
+mod cache;
 pub mod config;
+pub mod diagnostic;
+#[cfg(feature = "driver")]
+mod driver;
 pub mod error;
-mod lexer;
-mod parser;
+pub mod format;
+mod interp;
+pub mod lexer;
+#[cfg(feature = "driver")]
+mod llvm;
+#[cfg(feature = "driver")]
+mod object;
+pub mod observer;
+pub mod parser;
+pub mod preprocessor;
+mod qbe;
+#[cfg(feature = "driver")]
+pub mod repl;
+mod riscv;
+mod semantic;
+pub mod source_map;
+pub mod stats;
+pub mod target;
+#[cfg(feature = "driver")]
+pub mod test_runner;
+#[cfg(feature = "driver")]
+mod toolchain;
+
+#[cfg(feature = "driver")]
+pub use driver::{compile, interpret, run, watch};
 
-fn replace_ext(input: &PathBuf, new_ext: &str) -> PathBuf {
-    let mut new_path = input.clone();
+fn replace_ext(input: &Path, new_ext: &str) -> PathBuf {
+    let mut new_path = input.to_path_buf();
     new_path.set_extension(new_ext);
     new_path
 }
 
-fn get_temp_assembly_file(input_file: &PathBuf) -> PathBuf {
-    replace_ext(input_file, "s")
+fn get_exe_file(input_file: &Path) -> PathBuf {
+    replace_ext(input_file, "")
 }
 
-fn get_exe_file(input_file: &PathBuf) -> PathBuf {
-    replace_ext(input_file, "")
+/// Move a path derived from a source's name into the output directory, if
+/// one is configured.
+fn in_out_dir(path: PathBuf, config: &config::Config) -> PathBuf {
+    match (&config.out_dir, path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path,
+    }
 }
 
-pub fn compile(config: &config::Config) -> Result<(), Box<dyn Error>> {
-    println!("Starting compilation...");
+/// What a compilation produced.
+#[derive(Debug, Default)]
+pub struct CompileOutput {
+    /// The linked executable, unless compilation stopped before linking
+    pub executable: Option<PathBuf>,
+    /// The assembly files written with `--emit asm`, one per source
+    pub assembly: Vec<PathBuf>,
+    /// The warnings reported, which didn't stop compilation
+    pub diagnostics: Vec<diagnostic::Diagnostic>,
+    pub timings: StageTimings,
+}
 
-    let contents = fs::read_to_string(&config.filename)?;
-    let tokens = lexer::tokenize(&contents)?;
-    let ast = parser::parse(&tokens)?;
-    let code = assembly::generate(&ast)?;
+/// What each stage of a compilation cost, in total over every source.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageTimings {
+    pub preprocess: StageCost,
+    pub lex: StageCost,
+    pub parse: StageCost,
+    pub codegen: StageCost,
+    pub assemble: StageCost,
+    pub link: StageCost,
+}
 
-    // Output assembly to a temporary file
-    let output_file = get_temp_assembly_file(&config.filename);
-    fs::write(&output_file, code)?;
+impl StageTimings {
+    fn stages(&self) -> [(&'static str, StageCost); 6] {
+        [
+            ("preprocess", self.preprocess),
+            ("lex", self.lex),
+            ("parse", self.parse),
+            ("codegen", self.codegen),
+            ("assemble", self.assemble),
+            ("link", self.link),
+        ]
+    }
+}
 
-    let exe_path = get_exe_file(&config.filename);
-    let exe_file = match exe_path.to_str() {
-        Some(p) => p,
-        None => return Err("Failed to parse path".into()),
-    };
+/// The table `--time-passes` prints, with the time each stage took and the
+/// most memory it allocated at once.
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<12} {:>12} {:>12}", "stage", "time", "peak memory")?;
+        let mut total = StageCost::default();
+        for (name, cost) in self.stages().iter() {
+            writeln!(f, "{:<12} {}", name, cost)?;
+            total.add(cost);
+        }
+        writeln!(f, "{:<12} {}", "total", total)
+    }
+}
 
-    // Execute gcc to compile the assembly to machine code and link
-    let output = Command::new("gcc")
-        .arg(&output_file)
-        .args(&["-o", &exe_file])
-        .output()?;
+/// How long a stage took, and the most it allocated at once beyond what was
+/// allocated before it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StageCost {
+    pub time: Duration,
+    /// In bytes, or `None` if allocations aren't counted, which needs the
+    /// [`stats::Allocator`]
+    pub peak_memory: Option<usize>,
+}
+
+impl StageCost {
+    /// Add another run of the stage, whose peak is only the highest of the two.
+    fn add(&mut self, other: &StageCost) {
+        self.time += other.time;
+        self.peak_memory = match (self.peak_memory, other.peak_memory) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+impl fmt::Display for StageCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = format!("{:.2?}", self.time);
+        match self.peak_memory {
+            Some(bytes) => {
+                let kib = format!("{:.1} KiB", bytes as f64 / 1024.0);
+                write!(f, "{:>12} {:>12}", time, kib)
+            }
+            None => write!(f, "{:>12} {:>12}", time, "-"),
+        }
+    }
+}
+
+/// Run one step of compilation in a tracing span, adding its cost to `cost`
+/// and reporting how long it took in verbose mode.
+fn step<T>(config: &config::Config, cost: &mut StageCost, name: &str, f: impl FnOnce() -> T) -> T {
+    let span = tracing::info_span!("stage", name).entered();
+    let base = stats::start();
+    let start = Instant::now();
+    let result = f();
+    cost.add(&StageCost {
+        time: start.elapsed(),
+        peak_memory: base.map(stats::peak_since),
+    });
+    tracing::info!(elapsed = ?start.elapsed(), "done");
+    drop(span);
+    if config.verbosity == config::Verbosity::Verbose {
+        eprintln!("rcc: {} ({:.2?})", name, start.elapsed());
+    }
+    result
+}
+
+/// Stop if the compilation has been cancelled, which is checked before each
+/// stage of each source. The lexer, parser and code generators also check as
+/// they go, so that a long stage stops part of the way through.
+fn check_cancelled(config: &config::Config) -> Result<(), CompilerError> {
+    if config.cancellation.is_cancelled() {
+        Err(CompilerError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
 
-    // Remove the temporary file
-    match fs::remove_file(&output_file) {
-        Ok(()) => (),
-        // Ignore file not found error
-        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
-        // Return other errors to the caller
-        Err(e) => return Err(e.into()),
+/// The error for a backend failing to generate code, saying for which file
+/// if it isn't compiled in memory.
+fn codegen_error(error: &'static str, filename: Option<&Path>) -> CompilerError {
+    match (error, filename) {
+        (backend::CANCELLED, _) => CompilerError::Cancelled,
+        (error, Some(filename)) => {
+            CompilerError::Codegen(format!("{}: {}", filename.display(), error))
+        }
+        (error, None) => CompilerError::Codegen(error.to_string()),
     }
+}
 
-    if !output.stderr.is_empty() {
-        Err(String::from_utf8(output.stderr).unwrap().into())
+/// Compile the syntax tree of `filename` to assembly, with which of its
+/// lines came from which statements if that's wanted, or return `None` if
+/// the configuration stops after code generation. The output depends only on
+/// the source and the configuration, so compiling the same input twice gives
+/// byte-identical assembly.
+fn generate_assembly(
+    ast: &ast::Node,
+    filename: &Path,
+    config: &config::Config,
+    timings: &mut StageTimings,
+) -> Result<Option<(String, Option<source_map::SourceMap>)>, CompilerError> {
+    check_cancelled(config)?;
+    let name = format!("codegen {}", filename.display());
+    let code = step(config, &mut timings.codegen, &name, || {
+        backend::generate(&*backend::for_config(config, filename), ast)
+    })
+    .map_err(|e| codegen_error(e, Some(filename)))?;
+    let (code, map) = if config.emits(Emit::AsmMap) {
+        let (code, map) = source_map::extract(&code);
+        (code, Some(map))
     } else {
+        (code, None)
+    };
+    config.observers.assembly(filename, &code);
+    if config.stop_after == Some(Stage::Codegen) {
+        return Ok(None);
+    }
+    Ok(Some((code, map)))
+}
+
+/// Lex and parse the source text of `filename`, writing the tokens and AST
+/// if they're requested, or return `None` if the configuration stops before
+/// there's an AST to use.
+fn parse_source(
+    source: &preprocessor::Preprocessed,
+    filename: &Path,
+    config: &config::Config,
+    timings: &mut StageTimings,
+) -> Result<Option<ast::Node>, CompilerError> {
+    let contents = &source.text[..];
+    check_cancelled(config)?;
+    let name = filename.display();
+    let tokens = step(config, &mut timings.lex, &format!("lex {}", name), || {
+        lexer::tokenize_cancellable(contents, &config.cancellation)
+    })
+    .ok_or(CompilerError::Cancelled)?
+    .map_err(|errors| {
+        let errors = errors
+            .into_iter()
+            .map(|(e, span)| {
+                let location = locate(&e, span, source, filename);
+                CompilerError::Lex(e, location)
+            })
+            .collect();
+        CompilerError::report(errors, 0)
+    })?;
+    diagnostic::warn(config, &token_warnings(&tokens, source, filename))
+        .map_err(CompilerError::Semantic)?;
+    config.observers.tokens(filename, &tokens);
+    if config.emits(Emit::Tokens) {
+        let text: String = tokens
+            .iter()
+            .map(|(token, span)| format!("{}:{}: {:?}\n", span.line, span.column, token))
+            .collect();
+        write_output(filename, Emit::Tokens, text.as_bytes(), config)?;
+    }
+    if config.stop_after == Some(Stage::Lex) {
+        return Ok(None);
+    }
+    check_cancelled(config)?;
+    let ast = step(
+        config,
+        &mut timings.parse,
+        &format!("parse {}", name),
+        || parser::parse_cancellable(&tokens, &config.cancellation),
+    )
+    .ok_or(CompilerError::Cancelled)?
+    .map_err(|(e, index)| {
+        // Running out of tokens is reported just after the last of them
+        let span = match tokens.get(index) {
+            Some((_, span)) => *span,
+            None => lexer::span_at(contents, contents.trim_end().len()),
+        };
+        let location = locate(&e, span, source, filename);
+        CompilerError::Parse(e, location)
+    })?;
+    diagnostic::warn(config, &overflow_warnings(&ast, source, filename))
+        .map_err(CompilerError::Semantic)?;
+    config.observers.ast(filename, &ast);
+    if config.emits(Emit::Ast) {
+        write_output(
+            filename,
+            Emit::Ast,
+            format!("{:#?}\n", ast).as_bytes(),
+            config,
+        )?;
+    }
+    if config.stop_after == Some(Stage::Parse) {
+        return Ok(None);
+    }
+    validate(&ast, |e, span| match span {
+        Some(span) => diagnostic::render(&e.to_string(), &locate_span(span, source, filename)),
+        None => format!("{}: {}", filename.display(), e),
+    })?;
+    if config.stop_after == Some(Stage::Validate) {
+        return Ok(None);
+    }
+    Ok(Some(ast))
+}
+
+/// Check that `ast` keeps the constraints that parsing doesn't, or return
+/// every one it breaks, each described by `describe` with where it is.
+fn validate(
+    ast: &ast::Node,
+    describe: impl Fn(&SemanticError, Option<lexer::Span>) -> String,
+) -> Result<(), CompilerError> {
+    let errors: Vec<_> = semantic::check(ast)
+        .into_iter()
+        .map(|(e, span)| CompilerError::Semantic(describe(&e, span)))
+        .collect();
+    if errors.is_empty() {
         Ok(())
+    } else {
+        Err(CompilerError::report(errors, 0))
+    }
+}
+
+/// Describe a semantic error in a tree compiled in memory, which has no
+/// source lines to show.
+fn describe_in_memory(error: &SemanticError, span: Option<lexer::Span>) -> String {
+    match span {
+        Some(span) => format!("{}:{}: {}", span.line, span.column, error),
+        None => error.to_string(),
     }
 }
 
+/// The warnings about the tokens of `filename`, which are all about
+/// character constants holding more than one character, as GCC gives them.
+fn token_warnings(
+    tokens: &[(lexer::Token, lexer::Span)],
+    source: &preprocessor::Preprocessed,
+    filename: &Path,
+) -> Vec<diagnostic::Diagnostic> {
+    tokens
+        .iter()
+        .filter_map(|(token, span)| match token {
+            lexer::Token::CharLiteral { chars, .. } if *chars > 1 => {
+                let problem = if *chars > 4 {
+                    "character constant too long for its type"
+                } else {
+                    "multi-character character constant"
+                };
+                let (file, line) = source.place(filename, span.line);
+                Some(diagnostic::Diagnostic {
+                    warning: diagnostic::Warning::Multichar,
+                    message: format!("{}:{}:{}: {}", file.display(), line, span.column, problem),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Warnings for the constant expressions in `filename` whose arithmetic
+/// overflows an `int` and wraps around, which as in GCC is reported with the
+/// value it wraps to.
+fn overflow_warnings(
+    ast: &ast::Node,
+    source: &preprocessor::Preprocessed,
+    filename: &Path,
+) -> Vec<diagnostic::Diagnostic> {
+    interp::overflows(ast)
+        .into_iter()
+        .map(|overflow| {
+            let at = match overflow.span {
+                Some(span) => {
+                    let (file, line) = source.place(filename, span.line);
+                    format!("{}:{}:{}", file.display(), line, span.column)
+                }
+                None => filename.display().to_string(),
+            };
+            diagnostic::Diagnostic {
+                warning: diagnostic::Warning::Overflow,
+                message: format!(
+                    "{}: integer overflow in expression `{}` results in {}",
+                    at,
+                    format::expr_to_c(overflow.expr),
+                    overflow.value
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Where a syntax error is in `filename`, underlining the invalid text or
+/// the token it was found at.
+fn locate(
+    error: &SyntaxError,
+    span: lexer::Span,
+    source: &preprocessor::Preprocessed,
+    filename: &Path,
+) -> diagnostic::Location {
+    let mut location = locate_span(span, source, filename);
+    match error {
+        SyntaxError::InvalidIdentifier(text)
+        | SyntaxError::InvalidIntLiteral(text)
+        | SyntaxError::InvalidEscape(text)
+        | SyntaxError::EscapeOutOfRange(text) => location.len = text.chars().count(),
+        _ => (),
+    }
+    location
+}
+
+/// Where the token at `span` is in `filename`, underlining it. The file and
+/// line are the ones the text came from before preprocessing, and the line
+/// shown is as preprocessed, so that the column matches it.
+fn locate_span(
+    span: lexer::Span,
+    source: &preprocessor::Preprocessed,
+    filename: &Path,
+) -> diagnostic::Location {
+    let mut location = diagnostic::Location::new(filename, &source.text, span.line, span.column, 1);
+    let (file, line) = source.place(filename, span.line);
+    location.file = file.to_path_buf();
+    location.line = line;
+    location.len = diagnostic::token_len(&location.text, span.column);
+    location
+}
+
+/// The name errors give for source text compiled in memory
+const IN_MEMORY_SOURCE: &str = "<input>";
+
+/// The configuration for compiling in memory, which never prints anything.
+fn in_memory_config(options: &config::Options) -> config::Config {
+    config::Config {
+        target: options.target,
+        debug_info: options.debug_info,
+        stack_protector: options.stack_protector,
+        verbosity: config::Verbosity::Quiet,
+        cancellation: options.cancellation.clone(),
+        ..Default::default()
+    }
+}
+
+/// Generate assembly for the options' target from a syntax tree, such as
+/// one from [`parser::parse`].
+pub fn generate(ast: &ast::Node, options: &config::Options) -> Result<String, CompilerError> {
+    validate(ast, describe_in_memory)?;
+    let config = in_memory_config(options);
+    let backend = backend::for_config(&config, Path::new(IN_MEMORY_SOURCE));
+    backend::generate(&*backend, ast).map_err(|e| codegen_error(e, None))
+}
+
+/// Generate assembly as [`generate`] does, along with which of its lines
+/// came from each statement of the tree, which the parser records when the
+/// tokens have positions, as those from [`lexer::tokenize`] do.
+pub fn generate_with_map(
+    ast: &ast::Node,
+    options: &config::Options,
+) -> Result<(String, source_map::SourceMap), CompilerError> {
+    validate(ast, describe_in_memory)?;
+    let config = config::Config {
+        emit: vec![Emit::Assembly, Emit::AsmMap],
+        ..in_memory_config(options)
+    };
+    let backend = backend::for_config(&config, Path::new(IN_MEMORY_SOURCE));
+    let code = backend::generate(&*backend, ast).map_err(|e| codegen_error(e, None))?;
+    Ok(source_map::extract(&code))
+}
+
+/// Compile C source text to assembly for the options' target, without
+/// reading or writing any files or running other programs.
+pub fn compile_to_assembly(
+    source: &str,
+    options: &config::Options,
+) -> Result<String, CompilerError> {
+    let filename = Path::new(IN_MEMORY_SOURCE);
+    let config = in_memory_config(options);
+    let preprocessed = if options.preprocess {
+        let output = preprocessor::preprocess(source, filename, &[])
+            .map_err(|e| CompilerError::Preprocess(e.to_string()))?;
+        diagnostic::warn(&config, &output.warnings).map_err(CompilerError::Preprocess)?;
+        output
+    } else {
+        preprocessor::Preprocessed::unmapped(source)
+    };
+    // Nothing is configured to stop compilation before there's assembly
+    let timings = &mut StageTimings::default();
+    let ast =
+        parse_source(&preprocessed, filename, &config, timings)?.expect("no stage to stop after");
+    let (code, _) =
+        generate_assembly(&ast, filename, &config, timings)?.expect("no stage to stop after");
+    Ok(code)
+}
+
+/// Where to write the output `emit` for `source`: the -o path, next to the
+/// source or in the output directory, or `None` for standard output.
+fn output_path(source: &Path, emit: Emit, config: &config::Config) -> Option<PathBuf> {
+    match &config.output {
+        Some(path) if config::is_stdin(path) => None,
+        Some(path) => Some(path.clone()),
+        None => Some(in_out_dir(
+            match emit.extension(config.backend) {
+                Some(ext) => replace_ext(source, ext),
+                None => get_exe_file(source),
+            },
+            config,
+        )),
+    }
+}
+
+/// Write one of the text outputs for `source`.
+fn write_output(
+    source: &Path,
+    emit: Emit,
+    contents: &[u8],
+    config: &config::Config,
+) -> Result<(), CompilerError> {
+    match output_path(source, emit, config) {
+        Some(path) => fs::write(&path, contents).context("write output to", &path)?,
+        None => io::stdout().write_all(contents)?,
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn assembly_file_names() {
-        let cases = [
-            ("test.c", "test.s"),
-            ("mydir/src.c", "mydir/src.s"),
-            ("src.c.c", "src.c.s"),
-            ("/my/abs/path/to/file.c", "/my/abs/path/to/file.s"),
-        ];
-        for (input, output) in &cases {
-            assert_eq!(
-                get_temp_assembly_file(&PathBuf::from(input)),
-                PathBuf::from(output)
-            );
+    fn reproducible_assembly() {
+        for entry in fs::read_dir("tests/testfiles/valid").unwrap() {
+            let path = entry.unwrap().path();
+            let contents = fs::read_to_string(&path).unwrap();
+            for &debug_info in &[false, true] {
+                let config = config::Config {
+                    filenames: vec![path.clone()],
+                    debug_info,
+                    ..Default::default()
+                };
+                let timings = &mut StageTimings::default();
+                // Not every test file is supported by the compiler yet
+                let ast = match parse_source(
+                    &preprocessor::Preprocessed::unmapped(contents.clone()),
+                    &path,
+                    &config,
+                    timings,
+                ) {
+                    Ok(Some(ast)) => ast,
+                    _ => continue,
+                };
+                if let Ok(Some(first)) = generate_assembly(&ast, &path, &config, timings) {
+                    let second = generate_assembly(&ast, &path, &config, timings)
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(first.0, second.0, "{}", path.display());
+                }
+            }
         }
     }
 
+    #[test]
+    fn assembly_source_map() {
+        let options = config::Options::default();
+        let tokens = lexer::tokenize("int main() {\n    asm(\"nop\");\n    return ~1;\n}").unwrap();
+        let ast = parser::parse(&tokens).unwrap();
+        let (code, map) = generate_with_map(&ast, &options).unwrap();
+        // The assembly is the same as without the map
+        assert_eq!(code, generate(&ast, &options).unwrap());
+        let lines: Vec<&str> = code.lines().collect();
+        let mapped = |i: usize| {
+            let mapping = map.mappings[i];
+            (
+                mapping.span.line,
+                lines[mapping.start - 1..mapping.end].to_vec(),
+            )
+        };
+        assert_eq!(map.mappings.len(), 2);
+        assert_eq!(mapped(0), (2, vec!["  nop"]));
+        let (line, ret) = mapped(1);
+        assert_eq!(line, 3);
+        assert_eq!(ret.first(), Some(&"  movl\t$1, %eax"));
+        assert_eq!(ret.last(), Some(&"  ret"));
+    }
+
+    #[test]
+    fn cancelled_compilation() {
+        let options = config::Options::default();
+        let source = "int main() { return 1; }";
+        assert!(compile_to_assembly(source, &options).is_ok());
+        options.cancellation.clone().cancel();
+        assert!(matches!(
+            compile_to_assembly(source, &options),
+            Err(CompilerError::Cancelled)
+        ));
+
+        let config = config::Config::default();
+        let timings = &mut StageTimings::default();
+        let ast = parse_source(
+            &preprocessor::Preprocessed::unmapped(source),
+            Path::new("test.c"),
+            &config,
+            timings,
+        )
+        .unwrap()
+        .unwrap();
+        config.cancellation.cancel();
+        assert!(matches!(
+            generate_assembly(&ast, Path::new("test.c"), &config, timings),
+            Err(CompilerError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn multichar_warnings() {
+        let tokens = lexer::tokenize("'a' 'ab'\n'abcde'").unwrap();
+        let warnings = token_warnings(&tokens, &Default::default(), Path::new("test.c"));
+        let messages: Vec<&str> = warnings.iter().map(|w| w.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "test.c:1:5: multi-character character constant",
+                "test.c:2:1: character constant too long for its type"
+            ]
+        );
+
+        let config = config::Config {
+            warnings_as_errors: config::WarningsAsErrors::All,
+            verbosity: config::Verbosity::Quiet,
+            ..Default::default()
+        };
+        let timings = &mut StageTimings::default();
+        assert!(parse_source(
+            &preprocessor::Preprocessed::unmapped("int main() { return 'ab'; }"),
+            Path::new("test.c"),
+            &config,
+            timings
+        )
+        .is_err());
+        assert!(parse_source(
+            &preprocessor::Preprocessed::unmapped("int main() { return 'a'; }"),
+            Path::new("test.c"),
+            &config,
+            timings
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn overflow_warning_messages() {
+        let config = config::Config::default();
+        let timings = &mut StageTimings::default();
+        let source = "int main() {\n  return -(0x7fffffff * 2);\n}";
+        let ast = parse_source(
+            &preprocessor::Preprocessed::unmapped(source),
+            Path::new("test.c"),
+            &config,
+            timings,
+        )
+        .unwrap()
+        .unwrap();
+        let warnings = overflow_warnings(&ast, &Default::default(), Path::new("test.c"));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "test.c:2:3: integer overflow in expression `-(2147483647 * 2)` results in 2"
+        );
+    }
+
+    #[test]
+    fn stop_after_stage() {
+        // Lexing succeeds but parsing fails
+        let contents = &preprocessor::Preprocessed::unmapped("int main() { return 1 +; }");
+        let path = Path::new("test.c");
+        let config = |stop_after| config::Config {
+            stop_after,
+            ..Default::default()
+        };
+        let timings = &mut StageTimings::default();
+        assert!(
+            parse_source(contents, path, &config(Some(Stage::Lex)), timings)
+                .unwrap()
+                .is_none()
+        );
+        assert!(parse_source(contents, path, &config(Some(Stage::Parse)), timings).is_err());
+        assert!(parse_source(contents, path, &config(None), timings).is_err());
+
+        // Parsing succeeds but validation fails
+        let contents = &preprocessor::Preprocessed::unmapped("int main() { break; }");
+        assert!(
+            parse_source(contents, path, &config(Some(Stage::Parse)), timings)
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            parse_source(contents, path, &config(Some(Stage::Validate)), timings),
+            Err(CompilerError::Semantic(_))
+        ));
+        let ast = parser::parse(&lexer::tokenize(&contents.text).unwrap()).unwrap();
+        assert!(matches!(
+            generate(&ast, &config::Options::default()),
+            Err(CompilerError::Semantic(message)) if message.starts_with("1:14: Semantic Error [E0200]")
+        ));
+
+        let contents = &preprocessor::Preprocessed::unmapped("int main() { return 1; }");
+        assert!(
+            parse_source(contents, path, &config(Some(Stage::Validate)), timings)
+                .unwrap()
+                .is_none()
+        );
+        let ast = parse_source(contents, path, &config(None), timings)
+            .unwrap()
+            .unwrap();
+        assert!(
+            generate_assembly(&ast, path, &config(Some(Stage::Codegen)), timings)
+                .unwrap()
+                .is_none()
+        );
+        assert!(generate_assembly(&ast, path, &config(None), timings)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn errors_located_before_preprocessing() {
+        let dir = tempfile::tempdir().unwrap();
+        // Directives aren't in the preprocessed text, so its lines shift
+        let header = "// h.h\n\n#define ONE 1\n\n// five lines\n";
+        fs::write(dir.path().join("h.h"), header).unwrap();
+        let path = dir.path().join("a.c");
+        let source = "#include \"h.h\"\nint main() {\n    return ONE +;\n}\n";
+        let preprocessed = preprocessor::preprocess(source, &path, &[]).unwrap();
+        let config = config::Config {
+            verbosity: config::Verbosity::Quiet,
+            ..Default::default()
+        };
+        let timings = &mut StageTimings::default();
+        match parse_source(&preprocessed, &path, &config, timings) {
+            Err(CompilerError::Parse(_, location)) => {
+                assert_eq!((location.file, location.line), (path, 3));
+                assert_eq!(location.text, "    return 1 +;");
+                assert_eq!(location.column, 15);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn outputs_in_out_dir() {
+        let mut config = config::Config {
+            emit: vec![Emit::Assembly],
+            ..Default::default()
+        };
+        let source = Path::new("src/foo.c");
+        assert_eq!(
+            output_path(source, Emit::Assembly, &config),
+            Some(PathBuf::from("src/foo.s"))
+        );
+        config.out_dir = Some(PathBuf::from("build"));
+        assert_eq!(
+            output_path(source, Emit::Assembly, &config),
+            Some(PathBuf::from("build/foo.s"))
+        );
+        assert_eq!(
+            output_path(source, Emit::Executable, &config),
+            Some(PathBuf::from("build/foo"))
+        );
+        // -o is used as given
+        config.output = Some(PathBuf::from("out.s"));
+        assert_eq!(
+            output_path(source, Emit::Assembly, &config),
+            Some(PathBuf::from("out.s"))
+        );
+    }
+
     #[test]
     fn exe_file_names() {
         let cases = [
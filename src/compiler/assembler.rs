@@ -0,0 +1,635 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use super::object::{Object, Relocation, RelocationKind, Symbol};
+use super::target::Os;
+
+/// Assembles the subset of AT&T syntax x86-64 assembly that rcc generates
+/// for `os` into an object, so that no external assembler is needed.
+///
+/// Debug information directives aren't supported, so code compiled with
+/// `-g` still needs the system assembler.
+pub fn assemble(code: &str, os: Os) -> Result<Object, Box<dyn Error>> {
+    let mut assembler = match os {
+        Os::Linux => Assembler {
+            local_prefix: ".L",
+            ..Default::default()
+        },
+        // Mach-O code starts in the text section without saying so
+        Os::MacOs => Assembler {
+            local_prefix: "L",
+            in_text: true,
+            ..Default::default()
+        },
+    };
+    for line in code.lines() {
+        let line = line.trim();
+        if !line.is_empty() {
+            assembler
+                .line(line)
+                .map_err(|e| format!("{}: {}", e, line))?;
+        }
+    }
+    assembler.finish()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Size {
+    Byte,
+    Dword,
+    Qword,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Register {
+    number: u8,
+    size: Size,
+}
+
+impl Register {
+    fn parse(name: &str) -> Option<Self> {
+        const QWORD: [&str; 16] = [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ];
+        const DWORD: [&str; 16] = [
+            "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+            "r12d", "r13d", "r14d", "r15d",
+        ];
+        const BYTE: [&str; 16] = [
+            "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b",
+            "r12b", "r13b", "r14b", "r15b",
+        ];
+        [
+            (QWORD, Size::Qword),
+            (DWORD, Size::Dword),
+            (BYTE, Size::Byte),
+        ]
+        .iter()
+        .find_map(|(names, size)| {
+            names
+                .iter()
+                .position(|n| *n == name)
+                .map(|number| Register {
+                    number: number as u8,
+                    size: *size,
+                })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Memory {
+        /// Whether the address is relative to the `%fs` segment
+        fs: bool,
+        base: Option<Register>,
+        displacement: i32,
+    },
+    Label(String),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        if let Some(n) = text.strip_prefix('$') {
+            return n
+                .parse()
+                .map(Operand::Immediate)
+                .map_err(|_| "Invalid immediate");
+        }
+        if let Some(offset) = text.strip_prefix("%fs:") {
+            return Ok(Operand::Memory {
+                fs: true,
+                base: None,
+                displacement: offset.parse().map_err(|_| "Invalid displacement")?,
+            });
+        }
+        if let Some(name) = text.strip_prefix('%') {
+            return Register::parse(name)
+                .map(Operand::Register)
+                .ok_or("Unknown register");
+        }
+        if let (Some(open), true) = (text.find('('), text.ends_with(')')) {
+            let base = text[open + 1..text.len() - 1]
+                .strip_prefix('%')
+                .and_then(Register::parse)
+                .filter(|r| r.size == Size::Qword)
+                .ok_or("Unsupported addressing mode")?;
+            let displacement = match &text[..open] {
+                "" => 0,
+                d => d.parse().map_err(|_| "Unsupported addressing mode")?,
+            };
+            return Ok(Operand::Memory {
+                fs: false,
+                base: Some(base),
+                displacement,
+            });
+        }
+        if text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        {
+            Ok(Operand::Label(String::from(text)))
+        } else {
+            Err("Unsupported operand")
+        }
+    }
+
+    fn register(&self) -> Option<Register> {
+        match self {
+            Operand::Register(r) => Some(*r),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Assembler {
+    object: Object,
+    /// Indices into `object.symbols` by name
+    symbols: HashMap<String, usize>,
+    /// Offsets of local labels, which don't appear in the symbol table
+    labels: HashMap<String, u64>,
+    /// Branches to local labels: the offset of the rel32 field and the label
+    fixups: Vec<(usize, String)>,
+    /// Whether code is being placed in the text section
+    in_text: bool,
+    /// The prefix of local label names for the object format
+    local_prefix: &'static str,
+}
+
+impl Assembler {
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&index) = self.symbols.get(name) {
+            return index;
+        }
+        self.object.symbols.push(Symbol {
+            name: String::from(name),
+            value: None,
+            size: 0,
+            global: false,
+            function: false,
+        });
+        self.symbols
+            .insert(String::from(name), self.object.symbols.len() - 1);
+        self.object.symbols.len() - 1
+    }
+
+    fn offset(&self) -> u64 {
+        self.object.text.len() as u64
+    }
+
+    fn line(&mut self, line: &str) -> Result<(), &'static str> {
+        if let Some(label) = line.strip_suffix(':') {
+            let offset = self.offset();
+            if label.starts_with(self.local_prefix) {
+                self.labels.insert(String::from(label), offset);
+            } else {
+                let index = self.symbol(label);
+                self.object.symbols[index].value = Some(offset);
+            }
+            return Ok(());
+        }
+
+        let (name, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        if name.starts_with('.') {
+            return self.directive(name, rest);
+        }
+        if !self.in_text {
+            return Err("Instruction outside the text section");
+        }
+        let operands = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',')
+                .map(|op| Operand::parse(op.trim()))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        self.instruction(name, &operands)
+    }
+
+    fn directive(&mut self, name: &str, args: &str) -> Result<(), &'static str> {
+        match name {
+            ".file" if args.starts_with('"') => {
+                let file = args.trim_matches('"');
+                self.object.source_file = Some(String::from(file));
+            }
+            ".text" => self.in_text = true,
+            ".p2align" => {
+                let power: u32 = args
+                    .parse()
+                    .ok()
+                    .filter(|&power| power < 16)
+                    .ok_or("Invalid .p2align directive")?;
+                let align = 1 << power;
+                // Padding is never run, but fill it with nops as assemblers do
+                let padding = self.offset().next_multiple_of(align) - self.offset();
+                self.emit(&vec![0x90; padding as usize]);
+                self.object.alignment = self.object.alignment.max(align);
+            }
+            ".section" => self.in_text = args == ".text",
+            ".globl" => {
+                let index = self.symbol(args);
+                self.object.symbols[index].global = true;
+            }
+            ".type" => match args.split_once(',') {
+                Some((symbol, "@function")) | Some((symbol, " @function")) => {
+                    let index = self.symbol(symbol.trim());
+                    self.object.symbols[index].function = true;
+                }
+                _ => return Err("Unsupported symbol type"),
+            },
+            ".size" => {
+                let (symbol, size) = args.split_once(',').ok_or("Invalid .size directive")?;
+                let index = self.symbol(symbol.trim());
+                match (
+                    size.trim().strip_prefix(".-"),
+                    self.object.symbols[index].value,
+                ) {
+                    (Some(s), Some(start)) if s == symbol.trim() => {
+                        self.object.symbols[index].size = self.offset() - start;
+                    }
+                    _ => return Err("Unsupported .size expression"),
+                }
+            }
+            _ if name == ".loc" || name == ".file" || name.starts_with(".cfi") => {
+                return Err("The integrated assembler doesn't support debug information")
+            }
+            _ => return Err("Unsupported directive"),
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.object.text.extend_from_slice(bytes);
+    }
+
+    /// Emit an instruction with a ModRM byte addressing `rm`, prefixed with
+    /// any segment override and REX prefix it needs.
+    fn emit_modrm(
+        &mut self,
+        opcode: &[u8],
+        reg: u8,
+        rm: &Operand,
+        size: Size,
+    ) -> Result<(), &'static str> {
+        let (rex_b, fs, modrm) = match rm {
+            Operand::Register(r) => (
+                r.number >> 3,
+                false,
+                vec![0xc0 | (reg & 7) << 3 | r.number & 7],
+            ),
+            Operand::Memory {
+                fs,
+                base: None,
+                displacement,
+            } => {
+                // An absolute address needs a SIB byte with no base or index
+                let mut bytes = vec![(reg & 7) << 3 | 0b100, 0x25];
+                bytes.extend_from_slice(&displacement.to_le_bytes());
+                (0, *fs, bytes)
+            }
+            Operand::Memory {
+                fs,
+                base: Some(base),
+                displacement,
+            } => {
+                let rm_bits = base.number & 7;
+                let (mode, disp) = if *displacement == 0 && rm_bits != 0b101 {
+                    (0b00, Vec::new())
+                } else if let Ok(d) = i8::try_from(*displacement) {
+                    (0b01, vec![d as u8])
+                } else {
+                    (0b10, displacement.to_le_bytes().to_vec())
+                };
+                let mut bytes = vec![mode << 6 | (reg & 7) << 3 | rm_bits];
+                // %rsp and %r12 as a base need a SIB byte
+                if rm_bits == 0b100 {
+                    bytes.push(0x24);
+                }
+                bytes.extend(disp);
+                (base.number >> 3, *fs, bytes)
+            }
+            _ => return Err("Unsupported operand"),
+        };
+        if fs {
+            self.emit(&[0x64]);
+        }
+        let w = if size == Size::Qword { 0b1000 } else { 0 };
+        let rex = 0x40 | w | (reg >> 3) << 2 | rex_b;
+        // %spl, %bpl, %sil and %dil can only be encoded with a REX prefix
+        let byte_register = |op: &Operand| match op {
+            Operand::Register(r) => r.size == Size::Byte && (4..8).contains(&r.number),
+            _ => false,
+        };
+        if rex != 0x40 || (size == Size::Byte && (byte_register(rm) || (4..8).contains(&reg))) {
+            self.emit(&[rex]);
+        }
+        self.emit(opcode);
+        self.emit(&modrm);
+        Ok(())
+    }
+
+    fn emit_immediate(&mut self, n: i64, short: bool) -> Result<(), &'static str> {
+        if short {
+            self.emit(&[n as i8 as u8]);
+        } else {
+            let n = i32::try_from(n).map_err(|_| "Immediate out of range")?;
+            self.emit(&n.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, name: &str, operands: &[Operand]) -> Result<(), &'static str> {
+        // The size comes from the register operands, which must all agree
+        let mut registers = operands.iter().filter_map(Operand::register);
+        let size = registers.next().map(|r| r.size);
+        if registers.any(|r| Some(r.size) != size) {
+            return Err("Mismatched operand sizes");
+        }
+        let suffix = match size {
+            Some(Size::Byte) => "b",
+            Some(Size::Dword) => "l",
+            Some(Size::Qword) => "q",
+            None => "",
+        };
+        let name = match name.strip_suffix(suffix) {
+            Some(base) if !suffix.is_empty() && name != "imul" && name != "sete" => base,
+            _ => name,
+        };
+        let size = size.unwrap_or(Size::Dword);
+
+        match (name, operands) {
+            ("mov", [Operand::Immediate(n), Operand::Register(r)]) if size == Size::Dword => {
+                if r.number >= 8 {
+                    self.emit(&[0x41]);
+                }
+                self.emit(&[0xb8 + (r.number & 7)]);
+                self.emit_immediate(*n, false)?;
+            }
+            ("mov", [Operand::Register(src), dst]) => {
+                self.emit_modrm(&[0x89], src.number, dst, size)?
+            }
+            ("mov", [src, Operand::Register(dst)]) => {
+                self.emit_modrm(&[0x8b], dst.number, src, size)?
+            }
+            ("add", _) | ("sub", _) | ("cmp", _) => {
+                // The ModRM extension for the immediate form and the opcode
+                // for the register form
+                let (extension, opcode) = match name {
+                    "add" => (0, 0x01),
+                    "sub" => (5, 0x29),
+                    _ => (7, 0x39),
+                };
+                match operands {
+                    [Operand::Immediate(n), dst] => {
+                        let short = i8::try_from(*n).is_ok();
+                        let opcode = if short { 0x83 } else { 0x81 };
+                        self.emit_modrm(&[opcode], extension, dst, size)?;
+                        self.emit_immediate(*n, short)?;
+                    }
+                    [Operand::Register(src), dst] => {
+                        self.emit_modrm(&[opcode], src.number, dst, size)?
+                    }
+                    [src, Operand::Register(dst)] => {
+                        self.emit_modrm(&[opcode + 2], dst.number, src, size)?
+                    }
+                    _ => return Err("Unsupported operands"),
+                }
+            }
+            ("imul", [Operand::Immediate(n), Operand::Register(dst)]) => {
+                let short = i8::try_from(*n).is_ok();
+                let opcode = if short { 0x6b } else { 0x69 };
+                self.emit_modrm(&[opcode], dst.number, &operands[1], size)?;
+                self.emit_immediate(*n, short)?;
+            }
+            ("imul", [src, Operand::Register(dst)]) => {
+                self.emit_modrm(&[0x0f, 0xaf], dst.number, src, size)?
+            }
+            ("neg", [rm]) => self.emit_modrm(&[0xf7], 3, rm, size)?,
+            ("not", [rm]) => self.emit_modrm(&[0xf7], 2, rm, size)?,
+            ("idiv", [rm]) => self.emit_modrm(&[0xf7], 7, rm, size)?,
+            ("sete", [rm]) if size == Size::Byte => self.emit_modrm(&[0x0f, 0x94], 0, rm, size)?,
+            ("xchg", [Operand::Register(r), Operand::Register(eax)])
+            | ("xchg", [Operand::Register(eax), Operand::Register(r)])
+                if eax.number == 0 && size == Size::Dword =>
+            {
+                if r.number >= 8 {
+                    self.emit(&[0x41]);
+                }
+                self.emit(&[0x90 + (r.number & 7)]);
+            }
+            ("xchg", [Operand::Register(src), dst]) => {
+                self.emit_modrm(&[0x87], src.number, dst, size)?
+            }
+            ("push", [Operand::Register(r)]) | ("pop", [Operand::Register(r)])
+                if r.size == Size::Qword =>
+            {
+                if r.number >= 8 {
+                    self.emit(&[0x41]);
+                }
+                let opcode = if name == "push" { 0x50 } else { 0x58 };
+                self.emit(&[opcode + (r.number & 7)]);
+            }
+            ("cdq", []) | ("cltd", []) => self.emit(&[0x99]),
+            ("ret", []) => self.emit(&[0xc3]),
+            ("nop", []) => self.emit(&[0x90]),
+            ("call", [Operand::Label(symbol)]) => {
+                self.emit(&[0xe8]);
+                let symbol = self.symbol(symbol);
+                self.object.relocations.push(Relocation {
+                    offset: self.offset(),
+                    symbol,
+                    kind: RelocationKind::Call,
+                    // Relative to the end of the 4 byte field
+                    addend: -4,
+                });
+                self.emit(&[0; 4]);
+            }
+            ("je", [Operand::Label(label)])
+            | ("jne", [Operand::Label(label)])
+            | ("jmp", [Operand::Label(label)])
+                if label.starts_with(self.local_prefix) =>
+            {
+                let opcode: &[u8] = match name {
+                    "je" => &[0x0f, 0x84],
+                    "jne" => &[0x0f, 0x85],
+                    _ => &[0xe9],
+                };
+                self.emit(opcode);
+                self.fixups.push((self.object.text.len(), label.clone()));
+                self.emit(&[0; 4]);
+            }
+            _ => return Err("Unsupported instruction"),
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Object, Box<dyn Error>> {
+        for (position, label) in &self.fixups {
+            let target = *self
+                .labels
+                .get(label)
+                .ok_or_else(|| format!("Undefined label: {}", label))?;
+            let relative = target as i64 - (*position as i64 + 4);
+            let relative = i32::try_from(relative).map_err(|_| "Branch out of range")?;
+            self.object.text[*position..*position + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+        // Anything referenced but never defined must come from another object
+        for symbol in &mut self.object.symbols {
+            if symbol.value.is_none() {
+                symbol.global = true;
+            }
+        }
+        Ok(self.object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(instruction: &str) -> Vec<u8> {
+        assemble(&format!(".text\n{}", instruction), Os::Linux)
+            .unwrap()
+            .text
+    }
+
+    #[test]
+    fn instruction_encodings() {
+        // Expected bytes from GNU as
+        let cases: &[(&str, &[u8])] = &[
+            ("push\t%rbp", &[0x55]),
+            ("push\t%r11", &[0x41, 0x53]),
+            ("pop\t%r11", &[0x41, 0x5b]),
+            ("movq\t%rsp, %rbp", &[0x48, 0x89, 0xe5]),
+            (
+                "movq\t%fs:40, %rdx",
+                &[0x64, 0x48, 0x8b, 0x14, 0x25, 0x28, 0, 0, 0],
+            ),
+            ("movq\t%rax, -8(%rbp)", &[0x48, 0x89, 0x45, 0xf8]),
+            ("movq\t-8(%rbp), %rcx", &[0x48, 0x8b, 0x4d, 0xf8]),
+            ("cmpq\t%rdx, %rcx", &[0x48, 0x39, 0xd1]),
+            ("subq\t$16, %rsp", &[0x48, 0x83, 0xec, 0x10]),
+            ("subq\t$1024, %rsp", &[0x48, 0x81, 0xec, 0, 4, 0, 0]),
+            ("movl\t$-7, %r10d", &[0x41, 0xba, 0xf9, 0xff, 0xff, 0xff]),
+            ("movl\t%r11d, %r8d", &[0x45, 0x89, 0xd8]),
+            ("subl\t$300, %esi", &[0x81, 0xee, 0x2c, 0x01, 0, 0]),
+            ("imul\t$3, %ecx", &[0x6b, 0xc9, 0x03]),
+            ("imul\t$1000, %edi", &[0x69, 0xff, 0xe8, 0x03, 0, 0]),
+            ("subl\t%r11d, %eax", &[0x44, 0x29, 0xd8]),
+            ("imul\t%r9d, %r10d", &[0x45, 0x0f, 0xaf, 0xd1]),
+            ("not\t%r8d", &[0x41, 0xf7, 0xd0]),
+            ("cmpl\t$0, %edi", &[0x83, 0xff, 0x00]),
+            ("sete\t%al", &[0x0f, 0x94, 0xc0]),
+            ("sete\t%sil", &[0x40, 0x0f, 0x94, 0xc6]),
+            ("sete\t%r10b", &[0x41, 0x0f, 0x94, 0xc2]),
+            ("cdq", &[0x99]),
+            ("idivl\t%r11d", &[0x41, 0xf7, 0xfb]),
+            ("xchgl\t%r9d, %eax", &[0x41, 0x91]),
+            ("ret", &[0xc3]),
+            ("nop", &[0x90]),
+        ];
+        for (instruction, bytes) in cases {
+            assert_eq!(encode(instruction), *bytes, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn branches_to_local_labels() {
+        let code = ".text\n  je\t.L1\n  ret\n.L1:\n  je\t.L1";
+        assert_eq!(
+            assemble(code, Os::Linux).unwrap().text,
+            [0x0f, 0x84, 1, 0, 0, 0, 0xc3, 0x0f, 0x84, 0xfa, 0xff, 0xff, 0xff]
+        );
+        assert!(assemble(".text\n  je\t.L2", Os::Linux).is_err());
+        assert_eq!(
+            assemble(".text\n  jmp\t.L1\n  ret\n.L1:", Os::Linux)
+                .unwrap()
+                .text,
+            [0xe9, 1, 0, 0, 0, 0xc3]
+        );
+        assert_eq!(
+            assemble(".text\n.L1:\n  jne\t.L1", Os::Linux).unwrap().text,
+            [0x0f, 0x85, 0xfa, 0xff, 0xff, 0xff]
+        );
+        // Mach-O local labels have no dot, and code starts in the text section
+        assert_eq!(
+            assemble("  je\tL1\nL1:\n  ret", Os::MacOs).unwrap().text,
+            [0x0f, 0x84, 0, 0, 0, 0, 0xc3]
+        );
+    }
+
+    #[test]
+    fn symbols() {
+        let code = "  .file \"foo.c\"
+  .section .text
+.globl main
+  .type main, @function
+main:
+  call\t__stack_chk_fail
+  ret
+  .size main, .-main";
+        let object = assemble(code, Os::Linux).unwrap();
+        assert_eq!(object.source_file.as_deref(), Some("foo.c"));
+        assert_eq!(
+            object.symbols,
+            [
+                Symbol {
+                    name: String::from("main"),
+                    value: Some(0),
+                    size: 6,
+                    global: true,
+                    function: true,
+                },
+                Symbol {
+                    name: String::from("__stack_chk_fail"),
+                    value: None,
+                    size: 0,
+                    global: true,
+                    function: false,
+                },
+            ]
+        );
+        assert_eq!(
+            object.relocations,
+            [Relocation {
+                offset: 1,
+                symbol: 1,
+                kind: RelocationKind::Call,
+                addend: -4,
+            }]
+        );
+    }
+
+    #[test]
+    fn function_alignment() {
+        let object = assemble(".text\n  ret\n  .p2align 4\nfoo:\n  ret", Os::Linux).unwrap();
+        let mut text = vec![0xc3];
+        text.extend([0x90; 15]);
+        text.push(0xc3);
+        assert_eq!(object.text, text);
+        assert_eq!(object.alignment, 16);
+        assert!(assemble(".text\n  .p2align 40", Os::Linux).is_err());
+    }
+
+    #[test]
+    fn unsupported_input() {
+        let error = |code: &str| assemble(code, Os::Linux).unwrap_err().to_string();
+        assert_eq!(
+            error(".text\n  .loc 1 1 1"),
+            "The integrated assembler doesn't support debug information: .loc 1 1 1"
+        );
+        assert_eq!(
+            error(".text\n  lea\t8(%rsp), %rax"),
+            "Unsupported instruction: lea\t8(%rsp), %rax"
+        );
+        assert_eq!(error("  ret"), "Instruction outside the text section: ret");
+    }
+}
@@ -1,125 +1,868 @@
 use super::ast;
+use super::backend::{self, Backend, Labels};
+use super::config::{Cancellation, StackProtector};
+use super::source_map;
+use super::target::{Os, Target};
+
+/// x86-64 code generator using AT&T syntax.
+pub struct X86_64 {
+    pub target: Target,
+    /// The name of the source file being compiled, if known
+    pub source_file: Option<String>,
+    /// Emit debug line information for the source file
+    pub debug_info: bool,
+    pub stack_protector: StackProtector,
+    /// Align the start of each function to this many bytes
+    pub align_functions: Option<u32>,
+    /// Abort with a message instead of trapping on a bad division
+    pub check_div: bool,
+    /// Mark the code generated for each located node, for a source map
+    pub source_map: bool,
+    pub cancellation: Cancellation,
+}
+
+impl Backend for X86_64 {
+    fn generate(&self, ast: &ast::Node) -> Result<String, &'static str> {
+        let mut ctx = Context {
+            debug_info: self.debug_info && self.source_file.is_some(),
+            stack_protector: self.stack_protector,
+            align_functions: self.align_functions,
+            check_div: self.check_div,
+            source_map: self.source_map,
+            cancellation: self.cancellation.clone(),
+            ..Context::new(self.target)
+        };
+        let mut code = Vec::new();
+        if let Some(file) = &self.source_file {
+            let file = file.escape_default();
+            if self.target.is_elf() {
+                code.push(format!("  .file \"{}\"", file));
+            }
+            if ctx.debug_info {
+                code.push(format!(".file 1 \"{}\"", file));
+            }
+        }
+        code.push(ast.generate_assembly(&mut ctx)?);
+        // Assemblers expect the file to end with a newline
+        Ok(code.join("\n") + "\n")
+    }
+}
+
+/// The System V ABI requires %rsp to be 16-byte aligned at every call.
+const STACK_ALIGNMENT: usize = 16;
+
+fn align_to(size: usize, alignment: usize) -> usize {
+    size.div_ceil(alignment) * alignment
+}
+
+/// Round a function's local storage up so %rsp stays aligned after the
+/// prologue, which leaves it aligned by pushing %rbp over the return address.
+fn frame_size(locals: usize) -> usize {
+    align_to(locals, STACK_ALIGNMENT)
+}
+
+struct Context {
+    target: Target,
+    debug_info: bool,
+    stack_protector: StackProtector,
+    align_functions: Option<u32>,
+    check_div: bool,
+    source_map: bool,
+    cancellation: Cancellation,
+    /// The labels of the routines reporting a zero divisor and an overflowing
+    /// quotient, once a checked division needs them
+    div_errors: Option<(String, String)>,
+    /// Bytes pushed onto the stack below the current function's frame
+    stack_depth: usize,
+    /// Whether the current function has a stack canary
+    canary: bool,
+    labels: Labels,
+}
+
+impl Context {
+    fn new(target: Target) -> Self {
+        Context {
+            target,
+            debug_info: false,
+            stack_protector: StackProtector::Off,
+            align_functions: None,
+            check_div: false,
+            source_map: false,
+            cancellation: Cancellation::default(),
+            div_errors: None,
+            stack_depth: 0,
+            canary: false,
+            labels: Labels::new(match target.os {
+                Os::Linux => ".L",
+                Os::MacOs => "L",
+            }),
+        }
+    }
+
+    /// Generate a new label local to the object file.
+    fn label(&mut self) -> String {
+        self.labels.label()
+    }
+
+    /// The labels of the division error routines, which are emitted at the
+    /// end of the program.
+    fn div_errors(&mut self) -> (String, String) {
+        if self.div_errors.is_none() {
+            self.div_errors = Some((self.label(), self.label()));
+        }
+        self.div_errors.clone().unwrap()
+    }
+}
+
+/// The canary is stored in the first slot of the frame.
+const CANARY_OFFSET: &str = "-8(%rbp)";
+
+/// Whether a function's frame contains arrays that could be overflowed.
+fn has_arrays(_function: &ast::Node) -> bool {
+    // There are no local variables, let alone arrays, yet
+    false
+}
+
+fn needs_canary(function: &ast::Node, ctx: &Context) -> bool {
+    match ctx.stack_protector {
+        StackProtector::Off => false,
+        StackProtector::Arrays => has_arrays(function),
+        StackProtector::All => true,
+    }
+}
+
+/// Load the stack guard value into a register.
+fn load_stack_guard(register: &str, ctx: &Context) -> Vec<String> {
+    match ctx.target.os {
+        // glibc keeps the guard in the thread control block
+        Os::Linux => vec![format!("  movq\t%fs:40, %{}", register)],
+        Os::MacOs => vec![
+            format!(
+                "  movq\t{}@GOTPCREL(%rip), %{}",
+                ctx.target.symbol("__stack_chk_guard"),
+                register
+            ),
+            format!("  movq\t(%{}), %{}", register, register),
+        ],
+    }
+}
+
+/// Check the canary is intact before returning, preserving %eax.
+fn generate_canary_check(ctx: &mut Context) -> String {
+    let ok = ctx.label();
+    let mut code = vec![format!("  movq\t{}, %rcx", CANARY_OFFSET)];
+    code.extend(load_stack_guard("rdx", ctx));
+    code.push(String::from("  cmpq\t%rdx, %rcx"));
+    code.push(format!("  je\t{}", ok));
+    code.push(generate_call("__stack_chk_fail", ctx));
+    code.push(format!("{}:", ok));
+    code.join("\n")
+}
+
+fn push(register: &str, ctx: &mut Context) -> String {
+    ctx.stack_depth += 8;
+    format!("  push\t%{}", register)
+}
+
+fn pop(register: &str, ctx: &mut Context) -> String {
+    ctx.stack_depth -= 8;
+    format!("  pop\t%{}", register)
+}
+
+/// Call a function, padding the stack so %rsp is 16-byte aligned at the call.
+fn generate_call(symbol: &str, ctx: &mut Context) -> String {
+    let padding = align_to(ctx.stack_depth, STACK_ALIGNMENT) - ctx.stack_depth;
+    let call = format!("  call\t{}", ctx.target.symbol(symbol));
+    if padding == 0 {
+        call
+    } else {
+        [
+            format!("  subq\t${}, %rsp", padding),
+            call,
+            format!("  addq\t${}, %rsp", padding),
+        ]
+        .join("\n")
+    }
+}
 
 trait Assembly {
-    fn generate_assembly(&self) -> Result<String, &'static str>;
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str>;
+}
+
+/// The directive aligning a function to `bytes`, a power of two, if it
+/// needs one. `.p2align` means the same for every target, unlike `.align`.
+pub(super) fn align_function(bytes: Option<u32>) -> Option<String> {
+    match bytes {
+        Some(bytes) if bytes > 1 => Some(format!("  .p2align {}", bytes.trailing_zeros())),
+        _ => None,
+    }
+}
+
+/// The lines of an `asm` statement's text, indented like generated code.
+pub(super) fn inline_asm(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| format!("  {}", line))
+        .collect()
 }
 
 impl Assembly for ast::Node {
-    fn generate_assembly(&self) -> Result<String, &'static str> {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str> {
         let mut code = Vec::new();
         match self {
-            ast::Node::Program(node) => code.push(node.generate_assembly()?),
-            ast::Node::Function(id, node) => {
-                code.push(format!(".globl _{}", id));
-                code.push(format!("_{}:", id));
-                code.push(node.generate_assembly()?);
+            ast::Node::Program(node) => {
+                if ctx.target.is_elf() {
+                    code.push(String::from("  .section .text"));
+                }
+                code.push(node.generate_assembly(ctx)?);
+                if let Some((zero, overflow)) = ctx.div_errors.clone() {
+                    code.push(generate_div_errors(&zero, &overflow, ctx));
+                }
+                if ctx.target.is_elf() {
+                    // Mark the stack as non-executable for the GNU linker
+                    code.push(String::from("  .section .note.GNU-stack,\"\",@progbits"));
+                }
+            }
+            ast::Node::Function(id, _, node) => {
+                let _span = tracing::debug_span!("function", name = &**id).entered();
+                let symbol = ctx.target.symbol(id);
+                code.extend(align_function(ctx.align_functions));
+                code.push(format!(".globl {}", symbol));
+                if ctx.target.is_elf() {
+                    code.push(format!("  .type {}, @function", symbol));
+                }
+                code.push(format!("{}:", symbol));
+                if ctx.debug_info {
+                    code.push(String::from("  .cfi_startproc"));
+                }
+                // Set up the frame pointer, which realigns %rsp
+                code.push(String::from("  push\t%rbp"));
+                if ctx.debug_info {
+                    code.push(String::from("  .cfi_def_cfa_offset 16"));
+                    code.push(String::from("  .cfi_offset %rbp, -16"));
+                }
+                code.push(String::from("  movq\t%rsp, %rbp"));
+                if ctx.debug_info {
+                    code.push(String::from("  .cfi_def_cfa_register %rbp"));
+                }
+                ctx.canary = needs_canary(node, ctx);
+                let frame = frame_size(if ctx.canary { 8 } else { 0 });
+                if frame > 0 {
+                    code.push(format!("  subq\t${}, %rsp", frame));
+                }
+                if ctx.canary {
+                    code.extend(load_stack_guard("rax", ctx));
+                    code.push(format!("  movq\t%rax, {}", CANARY_OFFSET));
+                }
+                ctx.stack_depth = 0;
+                code.push(node.generate_assembly(ctx)?);
+                if !node.always_returns() {
+                    code.push(ast::Node::implicit_return().generate_assembly(ctx)?);
+                }
+                if ctx.debug_info {
+                    code.push(String::from("  .cfi_endproc"));
+                }
+                if ctx.target.is_elf() {
+                    code.push(format!("  .size {}, .-{}", symbol, symbol));
+                }
             }
             ast::Node::Statement(st, expr) => match st {
                 ast::Statement::Return => {
-                    code.push(expr.generate_assembly()?);
+                    code.push(expr.generate_into(&REGISTERS, ctx)?);
+                    if ctx.canary {
+                        code.push(generate_canary_check(ctx));
+                    }
+                    if ctx.debug_info {
+                        code.push(String::from("  .cfi_remember_state"));
+                    }
+                    // Tear down the frame
+                    code.push(String::from("  movq\t%rbp, %rsp"));
+                    code.push(String::from("  pop\t%rbp"));
+                    if ctx.debug_info {
+                        code.push(String::from("  .cfi_def_cfa %rsp, 8"));
+                    }
                     code.push(String::from("  ret"));
+                    if ctx.debug_info {
+                        // Code following the return still has the frame set up
+                        code.push(String::from("  .cfi_restore_state"));
+                    }
+                }
+                ast::Statement::If(then, otherwise) => {
+                    // Jump past the first branch when the condition is 0
+                    let other = ctx.label();
+                    code.push(expr.generate_into(&REGISTERS, ctx)?);
+                    code.push(format!("  cmpl\t$0, %{}", REGISTERS[0].dword));
+                    code.push(format!("  je\t{}", other));
+                    code.push(then.generate_assembly(ctx)?);
+                    if let Some(otherwise) = otherwise {
+                        // Skip the else branch, unless the first one returned
+                        let end = (!then.always_returns()).then(|| ctx.label());
+                        if let Some(end) = &end {
+                            code.push(format!("  jmp\t{}", end));
+                        }
+                        code.push(format!("{}:", other));
+                        code.push(otherwise.generate_assembly(ctx)?);
+                        code.extend(end.map(|end| format!("{}:", end)));
+                    } else {
+                        code.push(format!("{}:", other));
+                    }
+                }
+                ast::Statement::DoWhile(body) => {
+                    // The body comes first, so only the loop back is a jump
+                    let start = ctx.label();
+                    code.push(format!("{}:", start));
+                    ctx.labels.enter_loop();
+                    let body = body.generate_assembly(ctx);
+                    let labels = ctx.labels.exit_loop();
+                    code.push(body?);
+                    code.extend(labels.next.map(|next| format!("{}:", next)));
+                    code.push(expr.generate_into(&REGISTERS, ctx)?);
+                    code.push(format!("  cmpl\t$0, %{}", REGISTERS[0].dword));
+                    code.push(format!("  jne\t{}", start));
+                    code.extend(labels.end.map(|end| format!("{}:", end)));
                 }
             },
+            ast::Node::Block(statements) => {
+                for statement in statements {
+                    backend::check_cancelled(&ctx.cancellation)?;
+                    code.push(statement.generate_assembly(ctx)?);
+                }
+            }
+            ast::Node::Jump(jump) => code.push(format!("  jmp\t{}", ctx.labels.jump(*jump))),
+            ast::Node::InlineAsm { text, .. } => code.extend(inline_asm(text)),
+            ast::Node::Located(span, node) => {
+                if ctx.debug_info {
+                    code.push(format!("  .loc 1 {} {}", span.line, span.column));
+                }
+                if ctx.source_map {
+                    code.push(source_map::begin(*span));
+                }
+                code.push(node.generate_assembly(ctx)?);
+                if ctx.source_map {
+                    code.push(source_map::end());
+                }
+            }
+            ast::Node::Error => return Err("Can't generate code for a program with syntax errors"),
         };
         Ok(code.join("\n"))
     }
 }
 
-impl Assembly for ast::Expr {
-    fn generate_assembly(&self) -> Result<String, &'static str> {
+/// A 32-bit general purpose register, along with its 64-bit and low byte names.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Register {
+    dword: &'static str,
+    qword: &'static str,
+    byte: &'static str,
+}
+
+const fn register(dword: &'static str, qword: &'static str, byte: &'static str) -> Register {
+    Register { dword, qword, byte }
+}
+
+const EAX: Register = register("eax", "rax", "al");
+
+/// Caller-saved registers available for evaluating expressions, in order of
+/// preference. %edx is left out since division clobbers it.
+const REGISTERS: [Register; 7] = [
+    EAX,
+    register("ecx", "rcx", "cl"),
+    register("esi", "rsi", "sil"),
+    register("edi", "rdi", "dil"),
+    register("r8d", "r8", "r8b"),
+    register("r9d", "r9", "r9b"),
+    register("r10d", "r10", "r10b"),
+];
+
+/// Holds an operand popped off the stack when an expression needs more
+/// registers than are available.
+const SPILL: Register = register("r11d", "r11", "r11b");
+
+/// Expressions are evaluated Sethi–Ullman style: each subexpression is
+/// computed into the first of a list of free registers, evaluating the
+/// operand that needs more registers first so that the stack is only used
+/// once every register is in use.
+trait Expression {
+    /// The number of registers needed to evaluate the expression without
+    /// spilling to the stack.
+    fn registers_needed(&self) -> usize;
+
+    fn as_literal(&self) -> Option<i32>;
+
+    /// Evaluate the expression into `regs[0]`, using the rest of `regs` as
+    /// scratch registers.
+    fn generate_into(&self, regs: &[Register], ctx: &mut Context) -> Result<String, &'static str>;
+}
+
+impl Expression for ast::Expr {
+    fn registers_needed(&self) -> usize {
+        match self {
+            ast::Expr::Term(t) => t.registers_needed(),
+        }
+    }
+
+    fn as_literal(&self) -> Option<i32> {
         match self {
-            ast::Expr::Term(t) => t.generate_assembly(),
+            ast::Expr::Term(t) => t.as_literal(),
+        }
+    }
+
+    fn generate_into(&self, regs: &[Register], ctx: &mut Context) -> Result<String, &'static str> {
+        match self {
+            ast::Expr::Term(t) => t.generate_into(regs, ctx),
         }
     }
 }
 
-impl Assembly for ast::Term {
-    fn generate_assembly(&self) -> Result<String, &'static str> {
+impl Expression for ast::Term {
+    fn registers_needed(&self) -> usize {
+        match self {
+            ast::Term::Factor(f) => f.registers_needed(),
+            ast::Term::BinOp(op, t1, t2) => binary_registers_needed(op, &**t1, &**t2),
+        }
+    }
+
+    fn as_literal(&self) -> Option<i32> {
+        match self {
+            ast::Term::Factor(f) => f.as_literal(),
+            ast::Term::BinOp(..) => None,
+        }
+    }
+
+    fn generate_into(&self, regs: &[Register], ctx: &mut Context) -> Result<String, &'static str> {
         match self {
-            ast::Term::Factor(f) => f.generate_assembly(),
-            ast::Term::BinOp(op, t1, t2) => generate_binary_op(op, &**t1, &**t2),
+            ast::Term::Factor(f) => f.generate_into(regs, ctx),
+            ast::Term::BinOp(op, t1, t2) => generate_binary_op(op, &**t1, &**t2, regs, ctx),
         }
     }
 }
 
-impl Assembly for ast::Factor {
-    fn generate_assembly(&self) -> Result<String, &'static str> {
+impl Expression for ast::Factor {
+    fn registers_needed(&self) -> usize {
+        match self {
+            ast::Factor::IntLiteral(_) => 1,
+            ast::Factor::UnOp(_, f) => f.registers_needed(),
+            ast::Factor::BinOp(op, f1, f2) => binary_registers_needed(op, &**f1, &**f2),
+            ast::Factor::Expr(e) => e.registers_needed(),
+        }
+    }
+
+    fn as_literal(&self) -> Option<i32> {
+        match self {
+            ast::Factor::IntLiteral(n) => Some(*n),
+            ast::Factor::Expr(e) => e.as_literal(),
+            _ => None,
+        }
+    }
+
+    fn generate_into(&self, regs: &[Register], ctx: &mut Context) -> Result<String, &'static str> {
         match self {
-            // Move the integer into %eax
-            ast::Factor::IntLiteral(n) => Ok(format!("  movl\t${}, %eax", n)),
-            ast::Factor::UnOp(op, f) => generate_unary_op(op, f),
-            ast::Factor::BinOp(op, f1, f2) => generate_binary_op(op, &**f1, &**f2),
-            ast::Factor::Expr(e) => e.generate_assembly(),
+            // Move the integer into the destination register
+            ast::Factor::IntLiteral(n) => Ok(format!("  movl\t${}, %{}", n, regs[0].dword)),
+            ast::Factor::UnOp(op, f) => generate_unary_op(op, f, regs, ctx),
+            ast::Factor::BinOp(op, f1, f2) => generate_binary_op(op, &**f1, &**f2, regs, ctx),
+            ast::Factor::Expr(e) => e.generate_into(regs, ctx),
         }
     }
 }
 
-fn generate_unary_op(op: &ast::UnOp, factor: &ast::Factor) -> Result<String, &'static str> {
-    let mut code = Vec::new();
-    code.push(factor.generate_assembly()?);
+fn generate_unary_op(
+    op: &ast::UnOp,
+    factor: &ast::Factor,
+    regs: &[Register],
+    ctx: &mut Context,
+) -> Result<String, &'static str> {
+    let dest = regs[0];
+    let mut code = vec![factor.generate_into(regs, ctx)?];
     match op {
-        ast::UnOp::Negate => code.push(String::from("  neg\t%eax")),
-        ast::UnOp::Complement => code.push(String::from("  not\t%eax")),
+        ast::UnOp::Negate => code.push(format!("  neg\t%{}", dest.dword)),
+        ast::UnOp::Complement => code.push(format!("  not\t%{}", dest.dword)),
         ast::UnOp::LogicalNegate => {
-            code.push(String::from("  cmpl\t$0, %eax"));
-            // Zero out the eax register
-            code.push(String::from("  movl\t$0, %eax"));
-            code.push(String::from("  sete\t%al"));
+            code.push(format!("  cmpl\t$0, %{}", dest.dword));
+            // Zero out the destination register
+            code.push(format!("  movl\t$0, %{}", dest.dword));
+            code.push(format!("  sete\t%{}", dest.byte));
         }
     }
     Ok(code.join("\n"))
 }
 
+/// Whether the operation can take b directly as an immediate operand.
+fn takes_immediate(op: &ast::BinOp, b: &impl Expression) -> bool {
+    *op != ast::BinOp::Divide && b.as_literal().is_some()
+}
+
+fn binary_registers_needed(op: &ast::BinOp, a: &impl Expression, b: &impl Expression) -> usize {
+    if takes_immediate(op, b) {
+        return a.registers_needed();
+    }
+    let (a_needed, b_needed) = (a.registers_needed(), b.registers_needed());
+    if a_needed == b_needed {
+        a_needed + 1
+    } else {
+        a_needed.max(b_needed)
+    }
+}
+
 fn generate_binary_op(
     op: &ast::BinOp,
-    a: &impl Assembly,
-    b: &impl Assembly,
+    a: &impl Expression,
+    b: &impl Expression,
+    regs: &[Register],
+    ctx: &mut Context,
 ) -> Result<String, &'static str> {
+    let dest = regs[0];
     let mut code = Vec::new();
-    // Evaluate a
-    code.push(a.generate_assembly()?);
-    // Push the value in %eax on to the stack
-    code.push(String::from("  push\t%rax"));
-    // Evaluate b
-    code.push(b.generate_assembly()?);
-    // Pop a's result from the stack to the %ecx register
-    code.push(String::from("  pop\t%rcx"));
+
+    if takes_immediate(op, b) {
+        code.push(a.generate_into(regs, ctx)?);
+        let n = b.as_literal().unwrap();
+        match op {
+            ast::BinOp::Add => code.push(format!("  addl\t${}, %{}", n, dest.dword)),
+            ast::BinOp::Subtract => code.push(format!("  subl\t${}, %{}", n, dest.dword)),
+            ast::BinOp::Multiply => code.push(format!("  imul\t${}, %{}", n, dest.dword)),
+            ast::BinOp::Divide => unreachable!(),
+        }
+        return Ok(code.join("\n"));
+    }
+
+    // Registers holding the values of a and b, one of which is dest
+    let (ra, rb);
+    let (a_needed, b_needed) = (a.registers_needed(), b.registers_needed());
+    if a_needed.min(b_needed) >= regs.len() {
+        // Both operands need every register, so keep b on the stack while
+        // evaluating a
+        code.push(b.generate_into(regs, ctx)?);
+        code.push(push(dest.qword, ctx));
+        code.push(a.generate_into(regs, ctx)?);
+        code.push(pop(SPILL.qword, ctx));
+        ra = dest;
+        rb = SPILL;
+    } else if a_needed >= b_needed {
+        code.push(a.generate_into(regs, ctx)?);
+        code.push(b.generate_into(&regs[1..], ctx)?);
+        ra = dest;
+        rb = regs[1];
+    } else {
+        code.push(b.generate_into(regs, ctx)?);
+        code.push(a.generate_into(&regs[1..], ctx)?);
+        ra = regs[1];
+        rb = dest;
+    }
+
+    // The operand that isn't in dest
+    let other = if ra == dest { rb } else { ra };
     match op {
-        // Add %ecx to %eax and save the result in %eax
-        ast::BinOp::Add => code.push(String::from("  addl\t%ecx, %eax")),
+        ast::BinOp::Add => code.push(format!("  addl\t%{}, %{}", other.dword, dest.dword)),
         ast::BinOp::Subtract => {
-            code.push(String::from("  subl\t%eax, %ecx"));
-            code.push(String::from("  movl\t%ecx, %eax"));
-        }
-        ast::BinOp::Multiply => code.push(String::from("  imul\t%ecx, %eax")),
-        ast::BinOp::Divide => {
-            // Move b's value to %ebx
-            code.push(String::from("  movl\t%eax, %ebx"));
-            // Move a's value to %eax
-            code.push(String::from("  movl\t%ecx, %eax"));
-            // Sign extend the value in %eax
-            code.push(String::from("  cdq"));
-            // Divide %edx:%eax by %ebx
-            code.push(String::from("  idivl\t%ebx"));
+            code.push(format!("  subl\t%{}, %{}", rb.dword, ra.dword));
+            if ra != dest {
+                code.push(format!("  movl\t%{}, %{}", ra.dword, dest.dword));
+            }
         }
+        ast::BinOp::Multiply => code.push(format!("  imul\t%{}, %{}", other.dword, dest.dword)),
+        ast::BinOp::Divide => code.push(generate_division(ra, rb, dest, regs, ctx)),
     };
     Ok(code.join("\n"))
 }
 
-pub fn generate(ast: &ast::Node) -> Result<String, &'static str> {
-    ast.generate_assembly()
+/// Divide ra by rb into dest. idivl divides %edx:%eax, so %eax is preserved
+/// around the division if it holds the value of an enclosing expression.
+fn generate_division(
+    ra: Register,
+    rb: Register,
+    dest: Register,
+    regs: &[Register],
+    ctx: &mut Context,
+) -> String {
+    let mut code = Vec::new();
+    // %eax is only ever the first register of the list, so it is in use
+    // whenever it has been dropped from the free registers
+    let save_eax = !regs.contains(&EAX);
+    if save_eax {
+        code.push(push(EAX.qword, ctx));
+    }
+    let divisor = if ra == EAX {
+        rb
+    } else if rb == EAX {
+        // Swap the operands so the dividend is in %eax
+        code.push(format!("  xchgl\t%{}, %eax", ra.dword));
+        ra
+    } else {
+        code.push(format!("  movl\t%{}, %eax", ra.dword));
+        rb
+    };
+    if ctx.check_div {
+        code.push(generate_div_check(divisor, ctx));
+    }
+    // Sign extend %eax into %edx
+    code.push(String::from("  cdq"));
+    code.push(format!("  idivl\t%{}", divisor.dword));
+    if dest != EAX {
+        code.push(format!("  movl\t%eax, %{}", dest.dword));
+    }
+    if save_eax {
+        code.push(pop(EAX.qword, ctx));
+    }
+    code.join("\n")
+}
+
+/// Jump to an error routine if dividing %eax by `divisor` would trap, either
+/// because it's zero or because the quotient of INT_MIN / -1 doesn't fit.
+fn generate_div_check(divisor: Register, ctx: &mut Context) -> String {
+    let (zero, overflow) = ctx.div_errors();
+    let ok = ctx.label();
+    [
+        format!("  cmpl\t$0, %{}", divisor.dword),
+        format!("  je\t{}", zero),
+        format!("  cmpl\t$-1, %{}", divisor.dword),
+        format!("  jne\t{}", ok),
+        String::from("  cmpl\t$-2147483648, %eax"),
+        format!("  je\t{}", overflow),
+        format!("{}:", ok),
+    ]
+    .join("\n")
+}
+
+/// The routines checked divisions jump to, which write a message to stderr
+/// and abort. They never return, so they realign the stack themselves rather
+/// than track what was pushed where they were jumped from.
+fn generate_div_errors(zero: &str, overflow: &str, ctx: &mut Context) -> String {
+    const ZERO_MESSAGE: &str = "rcc: division by zero\n";
+    const OVERFLOW_MESSAGE: &str = "rcc: division overflow\n";
+    let (zero_message, overflow_message, report) = (ctx.label(), ctx.label(), ctx.label());
+    let mut code = vec![
+        format!("{}:", zero),
+        format!("  leaq\t{}(%rip), %rsi", zero_message),
+        format!("  movl\t${}, %edx", ZERO_MESSAGE.len()),
+        format!("  jmp\t{}", report),
+        format!("{}:", overflow),
+        format!("  leaq\t{}(%rip), %rsi", overflow_message),
+        format!("  movl\t${}, %edx", OVERFLOW_MESSAGE.len()),
+        format!("{}:", report),
+        // Write to stderr
+        String::from("  movl\t$2, %edi"),
+        String::from("  andq\t$-16, %rsp"),
+        format!("  call\t{}", ctx.target.symbol("write")),
+        format!("  call\t{}", ctx.target.symbol("abort")),
+    ];
+    code.push(String::from(match ctx.target.os {
+        Os::Linux => "  .section .rodata",
+        Os::MacOs => "  .section __TEXT,__const",
+    }));
+    for (label, message) in [
+        (zero_message, ZERO_MESSAGE),
+        (overflow_message, OVERFLOW_MESSAGE),
+    ] {
+        code.push(format!("{}:", label));
+        code.push(format!("  .ascii \"{}\"", message.escape_default()));
+    }
+    code.join("\n")
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::lexer::Span;
     use super::*;
     use ast::*;
 
+    fn generate(ast: &Node, triple: &str) -> Result<String, &'static str> {
+        X86_64 {
+            target: Target::parse(triple).unwrap(),
+            source_file: None,
+            debug_info: false,
+            stack_protector: StackProtector::Off,
+            align_functions: None,
+            check_div: false,
+            source_map: false,
+            cancellation: Cancellation::default(),
+        }
+        .generate(ast)
+    }
+
+    #[test]
+    fn inline_asm() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Block(vec![
+                    Node::InlineAsm {
+                        text: String::from("nop\n\t  pause\n"),
+                        volatile: false,
+                    },
+                    Node::Statement(
+                        Statement::Return,
+                        Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                    ),
+                ])
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
+            ".globl _foo
+_foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
+  nop
+  pause
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
+        );
+    }
+
+    #[test]
+    fn do_while_loop() {
+        // do asm("nop"); while (1 - 1);
+        let condition = Expr::Term(
+            Term::BinOp(
+                BinOp::Subtract,
+                Term::Factor(int(1)).into(),
+                Term::Factor(int(1)).into(),
+            )
+            .into(),
+        );
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::DoWhile(
+                        Node::InlineAsm {
+                            text: String::from("nop"),
+                            volatile: false,
+                        }
+                        .into(),
+                    ),
+                    condition.into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
+            ".globl _main
+_main:
+  push\t%rbp
+  movq\t%rsp, %rbp
+L1:
+  nop
+  movl\t$1, %eax
+  subl\t$1, %eax
+  cmpl\t$0, %eax
+  jne\tL1
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let program = |body: Node| {
+            Node::Program(Node::Function("main".into(), Attributes::default(), body.into()).into())
+        };
+        let literal = |n| Expr::Term(Term::Factor(int(n)).into());
+        // do { do continue; while (1); break; } while (2);
+        let inner = Node::Statement(
+            Statement::DoWhile(Node::Jump(Jump::Continue).into()),
+            literal(1).into(),
+        );
+        let ast = program(Node::Statement(
+            Statement::DoWhile(Node::Block(vec![inner, Node::Jump(Jump::Break)]).into()),
+            literal(2).into(),
+        ));
+        // Each jumps to the labels of its own loop
+        assert_eq!(
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
+            ".globl _main
+_main:
+  push\t%rbp
+  movq\t%rsp, %rbp
+L1:
+L2:
+  jmp\tL3
+L3:
+  movl\t$1, %eax
+  cmpl\t$0, %eax
+  jne\tL2
+  jmp\tL4
+  movl\t$2, %eax
+  cmpl\t$0, %eax
+  jne\tL1
+L4:
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
+        );
+    }
+
+    #[test]
+    fn if_statement() {
+        let literal = |n| Expr::Term(Term::Factor(Factor::IntLiteral(n).into()).into());
+        // if (1) asm("nop"); else return 2;
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::If(
+                        Node::InlineAsm {
+                            text: String::from("nop"),
+                            volatile: false,
+                        }
+                        .into(),
+                        Some(Node::Statement(Statement::Return, literal(2).into()).into()),
+                    ),
+                    literal(1).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        // Falling off the end returns 0
+        assert_eq!(
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
+            ".globl _main
+_main:
+  push\t%rbp
+  movq\t%rsp, %rbp
+  movl\t$1, %eax
+  cmpl\t$0, %eax
+  je\tL1
+  nop
+  jmp\tL2
+L1:
+  movl\t$2, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+L2:
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
+        );
+    }
+
     #[test]
     fn basic_function() {
         let ast = Node::Program(
             Node::Function(
-                String::from("foo"),
+                "foo".into(),
+                Attributes::default(),
                 Node::Statement(
                     Statement::Return,
                     Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
@@ -129,19 +872,372 @@ mod tests {
             .into(),
         );
         assert_eq!(
-            generate(&ast).unwrap(),
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
             ".globl _foo
 _foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
+        );
+    }
+
+    #[test]
+    fn basic_function_linux() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast, "x86_64-linux-gnu").unwrap(),
+            "  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
+  movl\t$0, %eax
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn elf_file_directive() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        for (triple, has_file) in &[("x86_64-linux-gnu", true), ("x86_64-apple-darwin", false)] {
+            let backend = X86_64 {
+                target: Target::parse(triple).unwrap(),
+                source_file: Some(String::from("dir/foo.c")),
+                debug_info: false,
+                stack_protector: StackProtector::Off,
+                align_functions: None,
+                check_div: false,
+                source_map: false,
+                cancellation: Cancellation::default(),
+            };
+            let code = backend.generate(&ast).unwrap();
+            assert_eq!(code.starts_with("  .file \"dir/foo.c\"\n"), *has_file);
+        }
+    }
+
+    #[test]
+    fn stack_protector() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        let mut backend = X86_64 {
+            target: Target::parse("x86_64-linux-gnu").unwrap(),
+            source_file: None,
+            debug_info: false,
+            stack_protector: StackProtector::All,
+            align_functions: None,
+            check_div: false,
+            source_map: false,
+            cancellation: Cancellation::default(),
+        };
+        assert_eq!(
+            backend.generate(&ast).unwrap(),
+            "  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
+  subq\t$16, %rsp
+  movq\t%fs:40, %rax
+  movq\t%rax, -8(%rbp)
+  movl\t$0, %eax
+  movq\t-8(%rbp), %rcx
+  movq\t%fs:40, %rdx
+  cmpq\t%rdx, %rcx
+  je\t.L1
+  call\t__stack_chk_fail
+.L1:
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+
+        // Functions without arrays aren't protected by default
+        backend.stack_protector = StackProtector::Arrays;
+        assert!(!backend.generate(&ast).unwrap().contains("__stack_chk_fail"));
+    }
+
+    #[test]
+    fn function_alignment() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        let mut backend = X86_64 {
+            target: Target::parse("x86_64-linux-gnu").unwrap(),
+            source_file: None,
+            debug_info: false,
+            stack_protector: StackProtector::Off,
+            align_functions: Some(32),
+            check_div: false,
+            source_map: false,
+            cancellation: Cancellation::default(),
+        };
+        let code = backend.generate(&ast).unwrap();
+        assert!(code.contains("  .p2align 5\n.globl foo\n"));
+
+        // Byte alignment is the same as none at all
+        backend.align_functions = Some(1);
+        assert!(!backend.generate(&ast).unwrap().contains(".p2align"));
+    }
+
+    #[test]
+    fn debug_info() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Located(
+                    Span { line: 2, column: 5 },
+                    Node::Statement(
+                        Statement::Return,
+                        Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        let backend = X86_64 {
+            target: Target::parse("x86_64-linux-gnu").unwrap(),
+            source_file: Some(String::from("foo.c")),
+            debug_info: true,
+            stack_protector: StackProtector::Off,
+            align_functions: None,
+            check_div: false,
+            source_map: false,
+            cancellation: Cancellation::default(),
+        };
+        assert_eq!(
+            backend.generate(&ast).unwrap(),
+            "  .file \"foo.c\"
+.file 1 \"foo.c\"
+  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  .cfi_startproc
+  push\t%rbp
+  .cfi_def_cfa_offset 16
+  .cfi_offset %rbp, -16
+  movq\t%rsp, %rbp
+  .cfi_def_cfa_register %rbp
+  .loc 1 2 5
   movl\t$0, %eax
-  ret"
+  .cfi_remember_state
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  .cfi_def_cfa %rsp, 8
+  ret
+  .cfi_restore_state
+  .cfi_endproc
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn frame_sizes_are_aligned() {
+        assert_eq!(frame_size(0), 0);
+        assert_eq!(frame_size(4), 16);
+        assert_eq!(frame_size(16), 16);
+        assert_eq!(frame_size(20), 32);
+    }
+
+    #[test]
+    fn calls_are_aligned() {
+        let mut ctx = Context::new(Target::parse("x86_64-linux-gnu").unwrap());
+        assert_eq!(generate_call("foo", &mut ctx), "  call\tfoo");
+        push("rax", &mut ctx);
+        assert_eq!(
+            generate_call("foo", &mut ctx),
+            "  subq\t$8, %rsp\n  call\tfoo\n  addq\t$8, %rsp"
+        );
+        push("rax", &mut ctx);
+        assert_eq!(generate_call("foo", &mut ctx), "  call\tfoo");
+    }
+
+    fn int(n: i32) -> Box<Factor> {
+        Factor::IntLiteral(n).into()
+    }
+
+    fn registers(code: Result<String, &'static str>) -> Vec<String> {
+        code.unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn binary_ops_use_registers() {
+        // 1 + 2 * 3
+        let expr = Term::BinOp(
+            BinOp::Add,
+            Term::Factor(int(1)).into(),
+            Term::Factor(Factor::BinOp(BinOp::Multiply, int(2), int(3)).into()).into(),
+        );
+        let mut ctx = Context::new(Target::default());
+        assert_eq!(expr.registers_needed(), 2);
+        assert_eq!(
+            registers(expr.generate_into(&REGISTERS, &mut ctx)),
+            vec![
+                "  movl\t$1, %eax",
+                "  movl\t$2, %ecx",
+                "  imul\t$3, %ecx",
+                "  addl\t%ecx, %eax",
+            ]
+        );
+    }
+
+    #[test]
+    fn checked_division() {
+        let expr = Factor::BinOp(BinOp::Divide, int(3), int(4));
+        let mut ctx = Context {
+            check_div: true,
+            ..Context::new(Target::parse("x86_64-linux-gnu").unwrap())
+        };
+        assert_eq!(
+            registers(expr.generate_into(&REGISTERS, &mut ctx)),
+            vec![
+                "  movl\t$3, %eax",
+                "  movl\t$4, %ecx",
+                "  cmpl\t$0, %ecx",
+                "  je\t.L1",
+                "  cmpl\t$-1, %ecx",
+                "  jne\t.L3",
+                "  cmpl\t$-2147483648, %eax",
+                "  je\t.L2",
+                ".L3:",
+                "  cdq",
+                "  idivl\t%ecx",
+            ]
+        );
+        // Later divisions share the error routines
+        expr.generate_into(&REGISTERS, &mut ctx).unwrap();
+        assert_eq!(
+            ctx.div_errors,
+            Some((String::from(".L1"), String::from(".L2")))
+        );
+    }
+
+    #[test]
+    fn heavier_operand_evaluated_first() {
+        // 1 - (2 - 3 / 4)
+        let expr = Factor::BinOp(
+            BinOp::Subtract,
+            int(1),
+            Factor::Expr(
+                Expr::Term(
+                    Term::BinOp(
+                        BinOp::Subtract,
+                        Term::Factor(int(2)).into(),
+                        Term::Factor(Factor::BinOp(BinOp::Divide, int(3), int(4)).into()).into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        let mut ctx = Context::new(Target::default());
+        assert_eq!(
+            registers(expr.generate_into(&REGISTERS, &mut ctx)),
+            vec![
+                "  movl\t$3, %eax",
+                "  movl\t$4, %ecx",
+                "  cdq",
+                "  idivl\t%ecx",
+                "  movl\t$2, %ecx",
+                "  subl\t%eax, %ecx",
+                "  movl\t%ecx, %eax",
+                "  movl\t$1, %ecx",
+                "  subl\t%eax, %ecx",
+                "  movl\t%ecx, %eax",
+            ]
+        );
+    }
+
+    #[test]
+    fn spill_when_out_of_registers() {
+        // 1 - (2 - 3)
+        let expr = Factor::BinOp(
+            BinOp::Subtract,
+            int(1),
+            Factor::BinOp(BinOp::Subtract, int(2), int(3)).into(),
         );
+        let mut ctx = Context::new(Target::default());
+        assert_eq!(
+            registers(expr.generate_into(&REGISTERS[..1], &mut ctx)),
+            vec![
+                "  movl\t$2, %eax",
+                "  subl\t$3, %eax",
+                "  push\t%rax",
+                "  movl\t$1, %eax",
+                "  pop\t%r11",
+                "  subl\t%r11d, %eax",
+            ]
+        );
+        assert_eq!(ctx.stack_depth, 0);
     }
 
     #[test]
     fn function_return_negate_1() {
         let ast = Node::Program(
             Node::Function(
-                String::from("foo"),
+                "foo".into(),
+                Attributes::default(),
                 Node::Statement(
                     Statement::Return,
                     Expr::Term(
@@ -155,15 +1251,19 @@ _foo:
                 .into(),
             )
             .into(),
-        )
-        .into();
+        );
         assert_eq!(
-            generate(&ast).unwrap(),
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
             ".globl _foo
 _foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
   movl\t$1, %eax
   neg\t%eax
-  ret"
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
         );
     }
 
@@ -171,7 +1271,8 @@ _foo:
     fn function_return_complement_1() {
         let ast = Node::Program(
             Node::Function(
-                String::from("foo"),
+                "foo".into(),
+                Attributes::default(),
                 Node::Statement(
                     Statement::Return,
                     Expr::Term(
@@ -187,12 +1288,17 @@ _foo:
             .into(),
         );
         assert_eq!(
-            generate(&ast).unwrap(),
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
             ".globl _foo
 _foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
   movl\t$1, %eax
   not\t%eax
-  ret"
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
         );
     }
 
@@ -200,7 +1306,8 @@ _foo:
     fn function_return_logical_negate_1() {
         let ast = Node::Program(
             Node::Function(
-                String::from("foo"),
+                "foo".into(),
+                Attributes::default(),
                 Node::Statement(
                     Statement::Return,
                     Expr::Term(
@@ -216,14 +1323,19 @@ _foo:
             .into(),
         );
         assert_eq!(
-            generate(&ast).unwrap(),
+            generate(&ast, "x86_64-apple-darwin").unwrap(),
             ".globl _foo
 _foo:
+  push\t%rbp
+  movq\t%rsp, %rbp
   movl\t$1, %eax
   cmpl\t$0, %eax
   movl\t$0, %eax
   sete\t%al
-  ret"
+  movq\t%rbp, %rsp
+  pop\t%rbp
+  ret
+"
         );
     }
 }
@@ -1,42 +1,219 @@
+//! The syntax tree the parser produces and the backends generate code from.
+//! Expressions are nested by precedence, so that a [`Term`] is a sum and a
+//! [`Factor`] a product.
+
 use std::fmt;
 
+use super::lexer::{Name, Span};
+
+/// How a function is declared, besides its name and return type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// Declared `inline`, so that its definition is only for inlining and
+    /// provides no external symbol, as in C99
+    pub inline: bool,
+    /// Declared with `(void)`, as a prototype taking no arguments, rather
+    /// than with `()`, which leaves its parameters unspecified
+    pub prototype: bool,
+    /// Declared `__attribute__((noreturn))`, as never returning
+    pub noreturn: bool,
+    /// Declared `__attribute__((unused))`, as possibly never used
+    pub unused: bool,
+}
+
+/// A part of a program that isn't an expression.
 #[derive(Debug, PartialEq)]
 pub enum Node {
     Program(Box<Node>),
-    Function(String, Box<Node>),
+    Function(Name, Attributes, Box<Node>),
     Statement(Statement, Box<Expr>),
+    /// The statements of a function body or compound statement, in order
+    Block(Vec<Node>),
+    /// A `break` or `continue` statement
+    Jump(Jump),
+    /// GNU-style basic `asm("...")`, whose text is copied into the output.
+    /// Basic asm is always volatile, so `volatile` only records whether it
+    /// was written that way.
+    InlineAsm {
+        text: String,
+        volatile: bool,
+    },
+    // A node annotated with its position in the source
+    Located(Span, Box<Node>),
+    /// In place of a part of the program that couldn't be parsed, in a tree
+    /// from [`parse_with_recovery`](super::parser::parse_with_recovery)
+    Error,
+}
+
+impl Node {
+    /// The return statement a function body that doesn't end in one is
+    /// completed with, returning 0 as falling off the end of `main` does.
+    pub fn implicit_return() -> Self {
+        let zero = Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into());
+        Self::Statement(Statement::Return, zero.into())
+    }
+
+    /// Whether running this always ends in a return statement, so that
+    /// control never reaches whatever follows it.
+    pub fn always_returns(&self) -> bool {
+        match self {
+            Self::Statement(Statement::Return, _) => true,
+            Self::Statement(Statement::If(then, Some(otherwise)), _) => {
+                then.always_returns() && otherwise.always_returns()
+            }
+            Self::Statement(Statement::If(_, None), _) => false,
+            Self::Statement(Statement::DoWhile(body), _) => body.always_returns(),
+            // Unless a statement before the return leaves the loop
+            Self::Block(statements) => statements
+                .iter()
+                .take_while(|statement| !statement.jumps())
+                .any(Node::always_returns),
+            Self::Program(node) | Self::Function(_, _, node) | Self::Located(_, node) => {
+                node.always_returns()
+            }
+            Self::Jump(_) | Self::InlineAsm { .. } | Self::Error => false,
+        }
+    }
+
+    /// Whether this can break out of or continue the loop it's in.
+    fn jumps(&self) -> bool {
+        match self {
+            Self::Jump(_) => true,
+            Self::Statement(Statement::If(then, otherwise), _) => {
+                then.jumps()
+                    || otherwise
+                        .as_ref()
+                        .is_some_and(|otherwise| otherwise.jumps())
+            }
+            Self::Block(statements) => statements.iter().any(Node::jumps),
+            Self::Located(_, node) => node.jumps(),
+            // The jumps in a loop are its own
+            Self::Statement(..)
+            | Self::Program(_)
+            | Self::Function(..)
+            | Self::InlineAsm { .. }
+            | Self::Error => false,
+        }
+    }
+
+    /// This node without any locations around it.
+    pub fn unlocated(&self) -> &Self {
+        match self {
+            Self::Located(_, node) => node.unlocated(),
+            node => node,
+        }
+    }
+
+    /// The names of the functions this node defines for other sources to use
+    pub fn function_names(&self) -> Vec<&str> {
+        match self {
+            Self::Program(node) | Self::Located(_, node) => node.function_names(),
+            Self::Function(_, attributes, _) if attributes.inline => vec![],
+            Self::Function(name, ..) => vec![&name[..]],
+            Self::Statement(..)
+            | Self::Block(_)
+            | Self::Jump(_)
+            | Self::InlineAsm { .. }
+            | Self::Error => vec![],
+        }
+    }
 }
 
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Program(ast) => write!(f, "{}", *ast),
-            Self::Function(name, node) => write!(
+            Self::Function(name, attributes, node) => write!(
                 f,
-                "FUN INT {}:
-    params: ()
+                "FUN {}INT {}:
+    params: ({})
     body:
         {}",
-                name, node
+                if attributes.inline { "INLINE " } else { "" },
+                name,
+                if attributes.prototype { "void" } else { "" },
+                node
             ),
+            Self::Statement(Statement::If(then, otherwise), condition) => {
+                write!(f, "IF {} THEN {}", condition, then)?;
+                match otherwise {
+                    Some(otherwise) => write!(f, " ELSE {}", otherwise),
+                    None => Ok(()),
+                }
+            }
+            Self::Statement(Statement::DoWhile(body), condition) => {
+                write!(f, "DO {} WHILE {}", body, condition)
+            }
             Self::Statement(s, node) => write!(f, "{} {}", s, node),
+            Self::Block(statements) => {
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n        ")?;
+                    }
+                    write!(f, "{}", statement)?;
+                }
+                Ok(())
+            }
+            Self::InlineAsm { text, volatile } => {
+                write!(
+                    f,
+                    "ASM {}{:?}",
+                    if *volatile { "VOLATILE " } else { "" },
+                    text
+                )
+            }
+            Self::Jump(jump) => write!(f, "{}", jump),
+            Self::Located(_, node) => write!(f, "{}", node),
+            Self::Error => write!(f, "ERROR"),
+        }
+    }
+}
+
+/// Where a `break` or `continue` statement goes in the innermost loop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jump {
+    /// To the code after the loop
+    Break,
+    /// To the check of whether to run the loop again
+    Continue,
+}
+
+/// The panic message for a [`Jump`] outside a loop, which validation
+/// rejects, so later stages don't check again.
+pub const JUMP_OUTSIDE_LOOP: &str = "break or continue outside a loop, which validation rejects";
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Break => write!(f, "BREAK"),
+            Self::Continue => write!(f, "CONTINUE"),
         }
     }
 }
 
+/// The kind of a statement, which applies to its expression.
 #[derive(Debug, PartialEq)]
 pub enum Statement {
     Return,
+    /// Run the first statement if the expression is nonzero, and otherwise
+    /// the `else` statement, if there is one
+    If(Box<Node>, Option<Box<Node>>),
+    /// Run the statement, then again for as long as the expression is
+    /// nonzero
+    DoWhile(Box<Node>),
 }
 
 impl fmt::Display for Statement {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Return => write!(f, "RETURN"),
+            Self::If(..) => write!(f, "IF"),
+            Self::DoWhile(_) => write!(f, "DO"),
         }
     }
 }
 
+/// A complete expression, as in a `return` statement.
 #[derive(Debug, PartialEq)]
 pub enum Expr {
     Term(Box<Term>),
@@ -50,6 +227,7 @@ impl fmt::Display for Expr {
     }
 }
 
+/// An expression of additions and subtractions.
 #[derive(Debug, PartialEq)]
 pub enum Term {
     Factor(Box<Factor>),
@@ -66,6 +244,7 @@ impl fmt::Display for Term {
     }
 }
 
+/// An operand of a [`Term`]: a product, a unary operation or a literal.
 #[derive(Debug, PartialEq)]
 pub enum Factor {
     Expr(Box<Expr>),
@@ -86,6 +265,7 @@ impl fmt::Display for Factor {
     }
 }
 
+/// A prefix operator.
 #[derive(Debug, PartialEq)]
 pub enum UnOp {
     Negate,
@@ -103,6 +283,7 @@ impl fmt::Display for UnOp {
     }
 }
 
+/// An infix arithmetic operator.
 #[derive(Debug, PartialEq)]
 pub enum BinOp {
     Add,
@@ -253,7 +434,8 @@ mod tests {
             format!(
                 "{}",
                 Node::Function(
-                    String::from("foo"),
+                    "foo".into(),
+                    Attributes::default(),
                     Node::Statement(
                         Statement::Return,
                         Expr::Term(Term::Factor(Factor::IntLiteral(10).into()).into()).into()
@@ -275,7 +457,8 @@ mod tests {
                 "{}",
                 Node::Program(
                     Node::Function(
-                        String::from("foo"),
+                        "foo".into(),
+                        Attributes::default(),
                         Node::Statement(
                             Statement::Return,
                             Expr::Term(Term::Factor(Factor::IntLiteral(10).into()).into()).into()
@@ -0,0 +1,250 @@
+use std::path::Path;
+
+use super::assembly;
+use super::ast;
+use super::config::{BackendKind, Cancellation, Config, Emit};
+#[cfg(feature = "driver")]
+use super::llvm;
+use super::qbe;
+use super::riscv;
+use super::target::Arch;
+
+/// A code generator lowering the AST to textual assembly or intermediate language.
+/// It's given programs through [`generate`], which leaves out inline
+/// definitions.
+pub trait Backend {
+    fn generate(&self, ast: &ast::Node) -> Result<String, &'static str>;
+}
+
+/// The error a backend stops with when the compilation is cancelled.
+pub const CANCELLED: &str = "Compilation cancelled";
+
+/// Stop generating code if `cancellation` has been used, which backends
+/// check before each statement.
+pub fn check_cancelled(cancellation: &Cancellation) -> Result<(), &'static str> {
+    if cancellation.is_cancelled() {
+        Err(CANCELLED)
+    } else {
+        Ok(())
+    }
+}
+
+/// The local labels of a native backend's program, and the labels the
+/// `continue` and `break` statements in each loop being generated jump to.
+pub struct Labels {
+    prefix: &'static str,
+    /// The number of labels generated so far
+    count: usize,
+    /// The loops being generated, innermost last
+    loops: Vec<Loop>,
+}
+
+/// The labels the `continue` and `break` statements in a loop jump to, made
+/// when the first of each is generated.
+#[derive(Default)]
+pub struct Loop {
+    pub next: Option<String>,
+    pub end: Option<String>,
+}
+
+impl Labels {
+    /// Labels named `prefix` followed by a number, which the assembler keeps
+    /// local to the object file.
+    pub fn new(prefix: &'static str) -> Self {
+        Labels {
+            prefix,
+            count: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    /// Generate a new label.
+    pub fn label(&mut self) -> String {
+        self.count += 1;
+        format!("{}{}", self.prefix, self.count)
+    }
+
+    /// Start generating a loop, which jumps are then in.
+    pub fn enter_loop(&mut self) {
+        self.loops.push(Loop::default());
+    }
+
+    /// Finish generating the innermost loop, giving the labels its jumps
+    /// used, which are where the loop continues and ends.
+    pub fn exit_loop(&mut self) -> Loop {
+        self.loops.pop().expect("Exited a loop that wasn't entered")
+    }
+
+    /// The label a `break` or `continue` in the innermost loop jumps to.
+    pub fn jump(&mut self, jump: ast::Jump) -> String {
+        let innermost = self.loops.last().expect(ast::JUMP_OUTSIDE_LOOP);
+        let target = match jump {
+            ast::Jump::Break => &innermost.end,
+            ast::Jump::Continue => &innermost.next,
+        };
+        if let Some(label) = target {
+            return label.clone();
+        }
+        let label = self.label();
+        let innermost = self.loops.last_mut().unwrap();
+        match jump {
+            ast::Jump::Break => innermost.end = Some(label.clone()),
+            ast::Jump::Continue => innermost.next = Some(label.clone()),
+        }
+        label
+    }
+}
+
+/// Generate code for a program with `backend`. Nothing can call an inline
+/// definition yet, so it needs no out-of-line copy, and the program is
+/// generated without it.
+pub fn generate(backend: &dyn Backend, ast: &ast::Node) -> Result<String, &'static str> {
+    if let ast::Node::Program(function) = ast {
+        if let ast::Node::Function(_, attributes, _) = function.unlocated() {
+            if attributes.inline {
+                let empty = ast::Node::Program(ast::Node::Block(Vec::new()).into());
+                return backend.generate(&empty);
+            }
+        }
+    }
+    backend.generate(ast)
+}
+
+/// The backend for compiling `filename` to assembly with the given
+/// configuration.
+pub fn for_config(config: &Config, filename: &Path) -> Box<dyn Backend> {
+    if config.backend == BackendKind::Qbe {
+        return Box::new(qbe::Qbe {
+            cancellation: config.cancellation.clone(),
+        });
+    }
+    let source_file = filename.to_string_lossy().into_owned();
+    match config.target.arch {
+        Arch::X86_64 => Box::new(assembly::X86_64 {
+            target: config.target,
+            source_file: Some(source_file),
+            debug_info: config.debug_info,
+            stack_protector: config.stack_protector,
+            align_functions: config.align_functions,
+            check_div: config.check_div,
+            source_map: config.emits(Emit::AsmMap),
+            cancellation: config.cancellation.clone(),
+        }),
+        Arch::RiscV64 => Box::new(riscv::RiscV64 {
+            target: config.target,
+            source_file: Some(source_file),
+            align_functions: config.align_functions,
+            source_map: config.emits(Emit::AsmMap),
+            cancellation: config.cancellation.clone(),
+        }),
+    }
+}
+
+/// The backend for writing LLVM IR, which LLVM compiles instead of rcc.
+#[cfg(feature = "driver")]
+pub fn llvm_ir(config: &Config) -> Box<dyn Backend> {
+    Box::new(llvm::Llvm {
+        target: config.target,
+        align_functions: config.align_functions,
+        cancellation: config.cancellation.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ast::*;
+    use crate::compiler::config::StackProtector;
+    use crate::compiler::target::Target;
+
+    /// Every backend, checking `cancellation`.
+    fn backends(cancellation: &Cancellation) -> Vec<Box<dyn Backend>> {
+        let target = Target::parse("x86_64-unknown-linux-gnu").unwrap();
+        let backends: Vec<Box<dyn Backend>> = vec![
+            Box::new(assembly::X86_64 {
+                target,
+                source_file: None,
+                debug_info: false,
+                stack_protector: StackProtector::Off,
+                align_functions: None,
+                check_div: false,
+                source_map: false,
+                cancellation: cancellation.clone(),
+            }),
+            Box::new(riscv::RiscV64 {
+                target: Target::parse("riscv64-unknown-linux-gnu").unwrap(),
+                source_file: None,
+                align_functions: None,
+                source_map: false,
+                cancellation: cancellation.clone(),
+            }),
+            Box::new(qbe::Qbe {
+                cancellation: cancellation.clone(),
+            }),
+        ];
+        // LLVM IR is only written by the driver
+        #[cfg(feature = "driver")]
+        let backends = {
+            let mut backends = backends;
+            backends.push(Box::new(llvm::Llvm {
+                target,
+                align_functions: None,
+                cancellation: cancellation.clone(),
+            }));
+            backends
+        };
+        backends
+    }
+
+    fn function(attributes: Attributes) -> Node {
+        Node::Program(
+            Node::Function(
+                "foo".into(),
+                attributes,
+                Node::Block(vec![Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )])
+                .into(),
+            )
+            .into(),
+        )
+    }
+
+    #[test]
+    fn inline_function_has_no_symbol() {
+        let ast = function(Attributes {
+            inline: true,
+            ..Default::default()
+        });
+        for backend in &backends(&Cancellation::default()) {
+            let code = generate(&**backend, &ast).unwrap();
+            assert!(!code.contains("foo"), "{}", code);
+        }
+    }
+
+    #[test]
+    fn cancelled_generation() {
+        let ast = function(Attributes::default());
+        let cancellation = Cancellation::default();
+        cancellation.cancel();
+        for backend in &backends(&cancellation) {
+            assert_eq!(generate(&**backend, &ast), Err(CANCELLED));
+        }
+    }
+
+    #[test]
+    fn loop_labels() {
+        let mut labels = Labels::new(".L");
+        assert_eq!(labels.label(), ".L1");
+        labels.enter_loop();
+        // Jumps share their loop's labels, which are made on first use
+        assert_eq!(labels.jump(Jump::Break), ".L2");
+        labels.enter_loop();
+        assert_eq!(labels.jump(Jump::Continue), ".L3");
+        let inner = labels.exit_loop();
+        assert_eq!((inner.next.as_deref(), inner.end), (Some(".L3"), None));
+        assert_eq!(labels.jump(Jump::Break), ".L2");
+        assert_eq!(labels.exit_loop().end.as_deref(), Some(".L2"));
+    }
+}
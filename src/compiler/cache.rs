@@ -0,0 +1,187 @@
+//! A cache of object files between builds, so that sources which haven't
+//! changed aren't compiled again.
+//!
+//! Entries are keyed by a hash of the preprocessed source, every option that
+//! affects the object file and the build of rcc that compiled it. Each entry
+//! is an object file and a list of the symbols it defines, which the driver
+//! needs to check for duplicates.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use super::config::{self, Config};
+
+/// A hash of the source rcc was built from, set by the build script, since
+/// the code it generates can change without the version changing
+const BUILD_ID: &str = env!("RCC_BUILD_ID");
+
+/// A hash identifying the object file for a source and configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key(u128);
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl Key {
+    pub fn new(config: &Config, source: &Path, contents: &str) -> Self {
+        Self::for_build(BUILD_ID, config, source, contents)
+    }
+
+    fn for_build(build: &str, config: &Config, source: &Path, contents: &str) -> Self {
+        // The source name goes into the object's symbol table
+        let options = format!(
+            "{}\0{}\0{}\0{:?}\0{}\0{:?}\0{:?}\0{}\0{:?}\0{}\0{:?}\0",
+            config::VERSION,
+            build,
+            source.display(),
+            config.target,
+            config.debug_info,
+            config.stack_protector,
+            config.align_functions,
+            config.check_div,
+            config.backend,
+            config.integrated_as,
+            config.assembler,
+        );
+        Key(fnv1a(options.bytes().chain(contents.bytes())))
+    }
+}
+
+/// The 128-bit FNV-1a hash, which unlike the standard library's hasher is
+/// the same in every build of rcc.
+fn fnv1a(bytes: impl Iterator<Item = u8>) -> u128 {
+    const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    bytes.fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u128).wrapping_mul(PRIME)
+    })
+}
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The cache selected by the configuration, if any. The user's cache
+    /// directory is `$XDG_CACHE_HOME/rcc`, or `~/.cache/rcc`.
+    pub fn for_config(config: &Config) -> Option<Self> {
+        let dir = match &config.cache {
+            config::Cache::Off => return None,
+            config::Cache::Dir(dir) => dir.clone(),
+            config::Cache::User => match env::var_os("XDG_CACHE_HOME") {
+                Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("rcc"),
+                _ => PathBuf::from(env::var_os("HOME")?).join(".cache/rcc"),
+            },
+        };
+        Some(Cache { dir })
+    }
+
+    fn object_path(&self, key: Key) -> PathBuf {
+        self.dir.join(format!("{}.o", key))
+    }
+
+    fn symbols_path(&self, key: Key) -> PathBuf {
+        self.dir.join(format!("{}.symbols", key))
+    }
+
+    /// The cached object file and its symbols for `key`, if there are any.
+    pub fn get(&self, key: Key) -> Option<(PathBuf, Vec<String>)> {
+        let symbols = fs::read_to_string(self.symbols_path(key)).ok()?;
+        let object = self.object_path(key);
+        if !object.is_file() {
+            return None;
+        }
+        Some((object, symbols.lines().map(String::from).collect()))
+    }
+
+    /// Add an object file and its symbols to the cache. Each file is
+    /// written under a temporary name first, so that other builds never see
+    /// a partial entry.
+    pub fn put(&self, key: Key, object: &Path, symbols: &[String]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let temp = |path: &Path| path.with_extension(format!("tmp{}", process::id()));
+        let object_path = self.object_path(key);
+        fs::copy(object, temp(&object_path))?;
+        fs::rename(temp(&object_path), &object_path)?;
+        // The symbols go last, since an entry without them is ignored
+        let symbols_path = self.symbols_path(key);
+        fs::write(temp(&symbols_path), symbols.join("\n"))?;
+        fs::rename(temp(&symbols_path), &symbols_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_hash() {
+        assert_eq!(fnv1a("".bytes()), 0x6c62272e07bb014262b821756295c58d);
+        assert_eq!(fnv1a("a".bytes()), 0xd228cb696f1a8caf78912b704e4a8964);
+    }
+
+    #[test]
+    fn keys_depend_on_source_and_options() {
+        let config = Config::default();
+        let key = Key::new(&config, Path::new("a.c"), "int main() { return 1; }");
+        assert_eq!(
+            key,
+            Key::new(&config, Path::new("a.c"), "int main() { return 1; }")
+        );
+        assert_ne!(
+            key,
+            Key::new(&config, Path::new("a.c"), "int main() { return 2; }")
+        );
+        assert_ne!(
+            key,
+            Key::new(&config, Path::new("b.c"), "int main() { return 1; }")
+        );
+        let debug = Config {
+            debug_info: true,
+            ..Default::default()
+        };
+        assert_ne!(
+            key,
+            Key::new(&debug, Path::new("a.c"), "int main() { return 1; }")
+        );
+        let aligned = Config {
+            align_functions: Some(16),
+            ..Default::default()
+        };
+        assert_ne!(
+            key,
+            Key::new(&aligned, Path::new("a.c"), "int main() { return 1; }")
+        );
+        // Another build of rcc may generate different code
+        assert_ne!(
+            key,
+            Key::for_build("0", &config, Path::new("a.c"), "int main() { return 1; }")
+        );
+    }
+
+    #[test]
+    fn store_and_retrieve() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache {
+            dir: dir.path().join("cache"),
+        };
+        let key = Key(42);
+        assert_eq!(cache.get(key), None);
+
+        let object = dir.path().join("a.o");
+        fs::write(&object, "object").unwrap();
+        let symbols = [String::from("main"), String::from("helper")];
+        cache.put(key, &object, &symbols).unwrap();
+        let (cached, cached_symbols) = cache.get(key).unwrap();
+        assert_eq!(fs::read_to_string(cached).unwrap(), "object");
+        assert_eq!(cached_symbols, symbols);
+        assert_eq!(cache.get(Key(43)), None);
+    }
+}
@@ -1,21 +1,1414 @@
-use std::env;
-use std::path::PathBuf;
+#[cfg(feature = "driver")]
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
+use super::diagnostic::Warning;
+#[cfg(feature = "driver")]
+use super::error;
+use super::observer::{Observer, Observers};
+use super::target::{self, Target};
+
+#[cfg(feature = "driver")]
+mod cli;
+
+/// Which functions get stack canaries to detect buffer overflows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StackProtector {
+    #[default]
+    Off,
+    /// Protect functions with arrays on the stack
+    Arrays,
+    All,
+}
+
+/// Which code generator lowers the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "driver", derive(clap::ValueEnum))]
+pub enum BackendKind {
+    /// rcc's own assembly generator for the target
+    #[default]
+    Native,
+    /// QBE intermediate language, compiled to assembly with `qbe`
+    Qbe,
+}
+
+/// Something the compiler can produce.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emit {
+    /// The tokens of each source, one per line with its position
+    Tokens,
+    /// The position, length and category of each token and comment in each
+    /// source as written, before preprocessing, for syntax highlighting
+    SemanticTokens,
+    /// The syntax tree of each source
+    Ast,
+    /// Textual LLVM IR
+    LlvmIr,
+    /// Assembly (or QBE IL with the QBE backend)
+    Assembly,
+    /// JSON mapping the lines of the assembly to the statements they were
+    /// generated for, which is empty with the QBE backend
+    AsmMap,
+    /// An object file for each source
+    Object,
+    /// A linked executable
+    Executable,
+}
+
+impl Emit {
+    #[cfg(feature = "driver")]
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tokens" => Some(Emit::Tokens),
+            "semantic-tokens" => Some(Emit::SemanticTokens),
+            "ast" => Some(Emit::Ast),
+            "ir" | "llvm-ir" => Some(Emit::LlvmIr),
+            "asm" => Some(Emit::Assembly),
+            "asm-map" => Some(Emit::AsmMap),
+            "obj" => Some(Emit::Object),
+            "exe" => Some(Emit::Executable),
+            _ => None,
+        }
+    }
+
+    /// The extension of the file the output is written to, next to its
+    /// source, or `None` for an executable.
+    pub fn extension(self, backend: BackendKind) -> Option<&'static str> {
+        match (self, backend) {
+            (Emit::Tokens, _) => Some("tokens"),
+            (Emit::SemanticTokens, _) => Some("semtok"),
+            (Emit::Ast, _) => Some("ast"),
+            (Emit::LlvmIr, _) => Some("ll"),
+            (Emit::Assembly, BackendKind::Native) => Some("s"),
+            (Emit::Assembly, BackendKind::Qbe) => Some("ssa"),
+            (Emit::AsmMap, _) => Some("asm.json"),
+            (Emit::Object, _) => Some("o"),
+            (Emit::Executable, _) => None,
+        }
+    }
+}
+
+/// How `#include`, macros and other directives are handled before lexing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Preprocessor {
+    /// Run the C compiler driver's preprocessor, `cc -E -P`
+    #[default]
+    System,
+    /// rcc's own preprocessor
+    Integrated,
+    /// Lex the source as written
+    Off,
+}
+
+/// Where object files are cached between builds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Cache {
+    #[default]
+    Off,
+    /// The user's cache directory, `$XDG_CACHE_HOME/rcc` or `~/.cache/rcc`
+    User,
+    Dir(PathBuf),
+}
+
+/// Which warnings are reported as errors instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum WarningsAsErrors {
+    #[default]
+    None,
+    All,
+    Only(Vec<Warning>),
+}
+
+#[derive(Debug, Default)]
 pub struct Config {
-    pub filename: PathBuf,
+    /// The source files to compile, linked together into one executable
+    pub filenames: Vec<PathBuf>,
+    pub target: Target,
+    pub preprocessor: Preprocessor,
+    /// Directories to search for `#include` files
+    pub include_dirs: Vec<PathBuf>,
+    /// Emit DWARF line information and call frame directives
+    pub debug_info: bool,
+    pub stack_protector: StackProtector,
+    /// Align the start of each function to this many bytes, a power of two,
+    /// or leave it to the assembler
+    pub align_functions: Option<u32>,
+    /// Report a zero divisor or an overflowing quotient and abort, rather
+    /// than let `idivl` raise SIGFPE
+    pub check_div: bool,
+    /// The optimisation level, from 0 to 3. Nothing is optimised yet.
+    pub opt_level: u8,
+    pub backend: BackendKind,
+    /// What to produce, with anything other than an executable written next
+    /// to each source. Nothing means an executable.
+    pub emit: Vec<Emit>,
+    /// Where to write the output, instead of deriving it from the filename
+    pub output: Option<PathBuf>,
+    /// The directory outputs named after their source are written to, and
+    /// kept intermediates, instead of next to the source
+    pub out_dir: Option<PathBuf>,
+    pub stop_after: Option<Stage>,
+    /// Encode object files directly instead of running the system assembler
+    pub integrated_as: bool,
+    /// The C compiler driver to preprocess and link with, instead of running the linker
+    /// directly
+    pub cc: Option<String>,
+    /// The assembler to run instead of the target's default
+    pub assembler: Option<String>,
+    /// Extra arguments for the link step
+    pub linker_args: Vec<String>,
+    /// Link the executable statically, so that it has no dynamic interpreter
+    pub static_link: bool,
+    /// Strip the symbol table from the executable
+    pub strip: bool,
+    /// Have the linker drop unused sections and libraries to make the
+    /// executable smaller
+    pub link_for_size: bool,
+    /// Leave the assembly and object files next to each source after linking
+    pub keep_intermediates: bool,
+    /// Reuse object files from earlier builds of unchanged sources
+    pub cache: Cache,
+    pub verbosity: Verbosity,
+    pub warnings_as_errors: WarningsAsErrors,
+    /// The most errors to report, or 0 for no limit
+    pub error_limit: usize,
+    /// Evaluate the program with the interpreter instead of compiling it
+    pub interpret: bool,
+    /// Compile again whenever a source file changes
+    pub watch: bool,
+    /// Print the assembler and linker commands instead of running them
+    pub dry_run: bool,
+    /// Print how long each stage took and how much memory it used
+    pub time_passes: bool,
+    /// Called with the output of each stage
+    pub observers: Observers,
+    pub cancellation: Cancellation,
+}
+
+/// A token for stopping a compilation from another thread, such as when an
+/// editor no longer needs the result. The compilation fails with
+/// [`CompilerError::Cancelled`](super::error::CompilerError::Cancelled) before
+/// the next stage it starts, or part of the way through lexing, parsing or
+/// code generation. Clones share the same token.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Options for compiling source text in memory, with
+/// [`compile_to_assembly`](crate::compile_to_assembly).
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub target: Target,
+    /// Emit DWARF line information and call frame directives
+    pub debug_info: bool,
+    pub stack_protector: StackProtector,
+    /// Run the integrated preprocessor over the source first, without any
+    /// include directories
+    pub preprocess: bool,
+    pub cancellation: Cancellation,
+}
+
+/// Builds a [`Config`] in code rather than from command line arguments, such
+/// as `Config::builder().input("prog.c").output("prog").build()`.
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+    target: Option<Target>,
+}
+
+impl ConfigBuilder {
+    /// Add a source file, or [`STDIN`] to read standard input.
+    pub fn input(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.filenames.push(path.into());
+        self
+    }
+
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.output = Some(path.into());
+        self
+    }
+
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn opt_level(mut self, level: u8) -> Self {
+        self.config.opt_level = level;
+        self
+    }
+
+    /// Add something to produce, instead of only an executable.
+    pub fn emit(mut self, emit: Emit) -> Self {
+        if !self.config.emit.contains(&emit) {
+            self.config.emit.push(emit);
+        }
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+        self.config.preprocessor = preprocessor;
+        self
+    }
+
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.include_dirs.push(dir.into());
+        self
+    }
+
+    pub fn debug_info(mut self, debug_info: bool) -> Self {
+        self.config.debug_info = debug_info;
+        self
+    }
+
+    pub fn stack_protector(mut self, stack_protector: StackProtector) -> Self {
+        self.config.stack_protector = stack_protector;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+        self.config.verbosity = verbosity;
+        self
+    }
+
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.config.observers.0.push(Box::new(observer));
+        self
+    }
+
+    pub fn cancellation(mut self, cancellation: Cancellation) -> Self {
+        self.config.cancellation = cancellation;
+        self
+    }
+
+    /// The configuration, or an error if the options don't make sense
+    /// together, as on the command line.
+    pub fn build(mut self) -> Result<Config, &'static str> {
+        self.config.target = self.target.or_else(Target::host).ok_or(NO_HOST_TARGET)?;
+        self.config.validate()
+    }
+}
+
+/// How much the compiler reports about what it's doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// Errors and warnings
+    #[default]
+    Normal,
+    /// Also each stage with its elapsed time, and the external commands run
+    Verbose,
+}
+
+/// A stage of the pipeline to stop after, without producing any output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Lex,
+    Parse,
+    /// Semantic analysis, which checks that `break` and `continue` are in
+    /// loops
+    Validate,
+    Codegen,
+}
+
+/// The input file name that means "read the source from standard input", and
+/// the output path that means "write to standard output"
+pub const STDIN: &str = "-";
+
+pub fn is_stdin(filename: &Path) -> bool {
+    filename == Path::new(STDIN)
+}
+
+/// The help `--help` prints, describing every option.
+#[cfg(feature = "driver")]
+pub fn usage() -> String {
+    cli::help()
+}
+
+pub const VERSION: &str = concat!("rcc ", env!("CARGO_PKG_VERSION"));
+
+/// The error for a configuration without a target on a host rcc can't
+/// compile for, which it would otherwise default to.
+const NO_HOST_TARGET: &str =
+    "rcc can't compile for this host, so a target must be given with --target";
+
+/// Add the options given by environment variables to the arguments, after
+/// the program name and any subcommand so that the command line takes
+/// precedence. `var` looks up a variable, which is ignored if it's empty.
+///
+/// - `RCC_CC` is the same as `--cc`
+/// - `RCC_TARGET` is the same as `--target`
+/// - `RCC_FLAGS` is a whitespace separated list of options
+#[cfg(feature = "driver")]
+pub fn with_environment<F>(mut args: Vec<String>, var: F) -> Vec<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let var = |name| var(name).filter(|value| !value.is_empty());
+    let mut options = Vec::new();
+    if let Some(cc) = var("RCC_CC") {
+        options.extend([String::from("--cc"), cc]);
+    }
+    if let Some(target) = var("RCC_TARGET") {
+        options.extend([String::from("--target"), target]);
+    }
+    if let Some(flags) = var("RCC_FLAGS") {
+        options.extend(flags.split_whitespace().map(String::from));
+    }
+    let at = match args.get(1) {
+        Some(arg) if cli::is_subcommand(arg) => 2,
+        _ => args.len().min(1),
+    };
+    args.splice(at..at, options);
+    args
+}
+
+/// What the driver logs with `tracing`: the filter in `RCC_LOG` if it's set,
+/// or else `debug` for `-vv` and `trace` for `-vvv`. Nothing is logged
+/// otherwise, since `-v` prints its own report.
+pub fn log_filter<I>(args: I, rcc_log: Option<String>) -> Option<String>
+where
+    I: Iterator<Item = String>,
+{
+    if let Some(filter) = rcc_log.filter(|filter| !filter.is_empty()) {
+        return Some(filter);
+    }
+    let mut level = None;
+    for arg in args.skip(1).take_while(|arg| arg != "--") {
+        match arg.as_str() {
+            "-vv" => level = Some("debug"),
+            "-vvv" => level = Some("trace"),
+            "-v" | "--verbose" | "-q" | "--quiet" => level = None,
+            _ => (),
+        }
+    }
+    level.map(String::from)
+}
+
+/// How deeply response files can refer to other response files, which stops
+/// one that refers to itself.
+const RESPONSE_FILE_DEPTH: usize = 16;
+
+/// Replace each `@file` argument after the program name with the arguments
+/// in the response file `file`, which `read` reads. Build systems use these
+/// to get around limits on the length of a command line. The arguments
+/// after `--`, which are for the program `rcc run` runs, are left alone.
+pub fn expand_response_files<F>(mut args: Vec<String>, read: F) -> Result<Vec<String>, String>
+where
+    F: Fn(&Path) -> io::Result<String>,
+{
+    let mut rest = args.split_off(args.len().min(1));
+    let program_args = match rest.iter().position(|arg| arg == "--") {
+        Some(i) => rest.split_off(i),
+        None => Vec::new(),
+    };
+    args.extend(expand_response_files_in(rest, &read, 0)?);
+    args.extend(program_args);
+    Ok(args)
+}
+
+fn expand_response_files_in<F>(
+    args: Vec<String>,
+    read: &F,
+    depth: usize,
+) -> Result<Vec<String>, String>
+where
+    F: Fn(&Path) -> io::Result<String>,
+{
+    let mut expanded = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(file) if !file.is_empty() => {
+                if depth == RESPONSE_FILE_DEPTH {
+                    return Err(format!("response files nested too deeply at {}", file));
+                }
+                let text = read(Path::new(file))
+                    .map_err(|e| format!("cannot read response file {}: {}", file, e))?;
+                expanded.extend(expand_response_files_in(
+                    split_response_file(&text),
+                    read,
+                    depth + 1,
+                )?);
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Split a response file into arguments, which are separated by whitespace,
+/// usually newlines. As in a shell, quotes keep whitespace in an argument,
+/// and a backslash escapes the next character outside single quotes.
+fn split_response_file(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    // The argument being read, if any, which may be an empty quoted one
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            (c, Some(q)) if c == q => quote = None,
+            ('\\', Some('"')) | ('\\', None) => {
+                if let Some(escaped) = chars.next() {
+                    arg.get_or_insert_with(String::new).push(escaped);
+                }
+            }
+            ('"', None) | ('\'', None) => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (c, None) if c.is_whitespace() => args.extend(arg.take()),
+            (c, _) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    args
+}
+
+/// Something to do other than compile, given as the first argument.
+#[derive(Debug, PartialEq)]
+pub enum Subcommand {
+    /// Run the compiled program with the given arguments
+    Run(Vec<String>),
+    /// Evaluate code interactively
+    Repl,
+    /// Compile and check a corpus of test programs
+    Test,
+    /// Format source files in place, or with `--check`, only report whether
+    /// any need it
+    Fmt { check: bool },
+}
+
+/// The `.c` files directly inside `dir`, in order of name, leaving out
+/// hidden ones as `dir/*.c` would.
+#[cfg(feature = "driver")]
+fn sources_in(dir: &Path) -> Result<Vec<PathBuf>, &'static str> {
+    const UNREADABLE: &str = "Cannot read the directory provided to --dir";
+    let mut sources = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|_| UNREADABLE)? {
+        let entry = entry.map_err(|_| UNREADABLE)?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        let path = entry.path();
+        if !hidden && path.extension().is_some_and(|ext| ext == "c") && path.is_file() {
+            sources.push(path);
+        }
+    }
+    if sources.is_empty() {
+        return Err("No .c files in the directory provided to --dir");
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+#[cfg(feature = "driver")]
+fn has_wildcards(text: &str) -> bool {
+    text.contains(['*', '?'])
+}
+
+/// The files matching `pattern`, in order of name. Only the file name can
+/// have wildcards, and like a shell they don't match a leading dot. Shells
+/// expand these themselves, except in quotes or on Windows.
+#[cfg(feature = "driver")]
+fn expand_wildcards(pattern: &str) -> Result<Vec<PathBuf>, &'static str> {
+    let path = Path::new(pattern);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| has_wildcards(name))
+        .ok_or("Wildcards are only supported in file names")?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let pattern: Vec<char> = name.chars().collect();
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|_| "Cannot read the directory of a wildcard pattern")? {
+        let entry = entry.map_err(|_| "Cannot read the directory of a wildcard pattern")?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+        let hidden = file_name.starts_with('.') && !name.starts_with('.');
+        let chars: Vec<char> = file_name.chars().collect();
+        if !hidden && wildcard_match(&pattern, &chars) && entry.path().is_file() {
+            matches.push(path.with_file_name(file_name));
+        }
+    }
+    if matches.is_empty() {
+        return Err("No files match a wildcard pattern");
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any characters
+/// and `?` matches one.
+#[cfg(feature = "driver")]
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// The targets `--target` accepts and the backends `--backend` does, with
+/// the host marked as the default.
+#[cfg(feature = "driver")]
+fn supported_targets() -> String {
+    let host = Target::host();
+    let mut info = String::from("Targets:\n");
+    for target in &target::SUPPORTED {
+        let mark = if host == Some(*target) { " (host)" } else { "" };
+        info += &format!("  {}{}\n", target, mark);
+    }
+    info += "\nBackends:\n";
+    info += "  native  rcc's own code generator, for every target\n";
+    info += "  qbe     QBE intermediate language, compiled to assembly with qbe\n";
+    info
+}
+
+/// Whether the arguments ask for help, version information, the supported
+/// targets or an error code's explanation rather than a compilation. Any of
+/// these takes precedence over other arguments, with help first, so that
+/// help is available even when the rest of the arguments are invalid.
+#[cfg(feature = "driver")]
+pub fn requested_info<I>(args: I) -> Option<String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut info = None;
+    let mut args = args.skip(1).take_while(|arg| arg != "--");
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Some(usage()),
+            "-V" | "--version" => info = Some(String::from(VERSION)),
+            "--print-targets" => info = Some(supported_targets()),
+            // Unknown codes are reported when the options are parsed
+            "--explain" => {
+                if let Some(explanation) = args.next().as_deref().and_then(error::explain) {
+                    info = Some(String::from(explanation));
+                }
+            }
+            _ => (),
+        }
+    }
+    info
+}
+
+/// Parse `rcc`'s command line, starting with the program name, into the
+/// configuration and the subcommand, if any.
+#[cfg(feature = "driver")]
+pub fn parse_command_line<I>(args: I) -> Result<(Config, Option<Subcommand>), clap::Error>
+where
+    I: Iterator<Item = String>,
+{
+    cli::parse(args)
 }
 
 impl Config {
-    pub fn new(mut args: env::Args) -> Result<Self, &'static str> {
-        args.next();
+    /// The configuration from a command line, as [`parse_command_line`]
+    /// gives it.
+    #[cfg(feature = "driver")]
+    pub fn new<I>(args: I) -> Result<Self, clap::Error>
+    where
+        I: Iterator<Item = String>,
+    {
+        parse_command_line(args).map(|(config, _)| config)
+    }
+
+    /// Start building a configuration in code, with the same defaults as
+    /// [`Config::default`].
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Check the options make sense together.
+    fn validate(self) -> Result<Self, &'static str> {
+        if self.filenames.is_empty() {
+            return Err("No file path provided");
+        }
+        match self.filenames.iter().filter(|f| is_stdin(f)).count() {
+            0 => (),
+            1 if self.output.is_none() => return Err("Reading from stdin requires -o"),
+            1 if self.watch => return Err("Can't watch stdin for changes"),
+            1 => (),
+            _ => return Err("stdin can only be read once"),
+        }
+        if self.opt_level > 3 {
+            return Err("Invalid optimisation level, expected 0 to 3");
+        }
+        // Only rcc's own x86-64 generator implements these
+        if self.backend == BackendKind::Qbe || self.target.arch != target::Arch::X86_64 {
+            if self.stack_protector != StackProtector::Off {
+                return Err("-fstack-protector is only supported by the native x86-64 backend");
+            }
+            if self.debug_info {
+                return Err("-g is only supported by the native x86-64 backend");
+            }
+            if self.check_div {
+                return Err("-fcheck-div is only supported by the native x86-64 backend");
+            }
+        }
+        Ok(self)
+    }
+
+    /// Whether `emit` is one of the outputs to produce.
+    pub fn emits(&self, emit: Emit) -> bool {
+        self.emit.contains(&emit) || (self.emit.is_empty() && emit == Emit::Executable)
+    }
+}
+
+// Each test reads a command line, which needs the driver's parser
+#[cfg(all(test, feature = "driver"))]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        let mut all = vec![String::from("rcc")];
+        all.extend(list.iter().map(|s| String::from(*s)));
+        all.into_iter()
+    }
+
+    /// The first line of clap's message for `error`, without the usage.
+    fn message(error: clap::Error) -> String {
+        let error = error.to_string();
+        let line = error.lines().next().unwrap_or_default();
+        String::from(line.strip_prefix("error: ").unwrap_or(line))
+    }
+
+    /// The message for the error the arguments `list` give.
+    fn error(list: &[&str]) -> String {
+        message(Config::new(args(list)).unwrap_err())
+    }
+
+    #[test]
+    fn filename_only() {
+        let config = Config::new(args(&["foo.c"])).unwrap();
+        assert_eq!(config.filenames, [PathBuf::from("foo.c")]);
+        assert_eq!(Some(config.target), Target::host());
+        assert!(!config.debug_info);
+        assert!(!config.keep_intermediates);
+    }
+
+    #[test]
+    fn verbosity_flags() {
+        let verbosity = |list: &[&str]| Config::new(args(list)).unwrap().verbosity;
+        assert_eq!(verbosity(&["foo.c"]), Verbosity::Normal);
+        assert_eq!(verbosity(&["-v", "foo.c"]), Verbosity::Verbose);
+        assert_eq!(
+            verbosity(&["--verbose", "--quiet", "foo.c"]),
+            Verbosity::Quiet
+        );
+    }
+
+    #[test]
+    fn preprocessor_flag() {
+        let preprocessor = |list: &[&str]| Config::new(args(list)).unwrap().preprocessor;
+        assert_eq!(preprocessor(&["foo.c"]), Preprocessor::System);
+        assert_eq!(
+            preprocessor(&["--no-preprocess", "foo.c"]),
+            Preprocessor::Off
+        );
+        assert_eq!(
+            preprocessor(&["-fintegrated-cpp", "foo.c"]),
+            Preprocessor::Integrated
+        );
+    }
+
+    #[test]
+    fn include_dirs() {
+        let config = Config::new(args(&["-Iinclude", "-I", "/opt/include", "foo.c"])).unwrap();
+        assert_eq!(
+            config.include_dirs,
+            [PathBuf::from("include"), PathBuf::from("/opt/include")]
+        );
+        assert_eq!(config.filenames, [PathBuf::from("foo.c")]);
+    }
+
+    #[test]
+    fn interpret_flag() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().interpret);
+        assert!(
+            Config::new(args(&["--interpret", "foo.c"]))
+                .unwrap()
+                .interpret
+        );
+    }
+
+    #[test]
+    fn watch_flag() {
+        assert!(Config::new(args(&["--watch", "foo.c"])).unwrap().watch);
+        assert_eq!(
+            error(&["--watch", "-", "-o", "prog"]),
+            "Can't watch stdin for changes"
+        );
+    }
+
+    #[test]
+    fn time_passes_flag() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().time_passes);
+        assert!(
+            Config::new(args(&["--time-passes", "foo.c"]))
+                .unwrap()
+                .time_passes
+        );
+    }
+
+    #[test]
+    fn dry_run_flag() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().dry_run);
+        assert!(Config::new(args(&["-###", "foo.c"])).unwrap().dry_run);
+        assert!(Config::new(args(&["--dry-run", "foo.c"])).unwrap().dry_run);
+    }
+
+    #[test]
+    fn warnings_as_errors_flags() {
+        let werror = |list: &[&str]| {
+            Config::new(args(list))
+                .map(|c| c.warnings_as_errors)
+                .map_err(message)
+        };
+        assert_eq!(werror(&["foo.c"]), Ok(WarningsAsErrors::None));
+        assert_eq!(werror(&["-Werror", "foo.c"]), Ok(WarningsAsErrors::All));
+        assert_eq!(
+            werror(&["-Werror=cpp", "-Werror=cpp", "foo.c"]),
+            Ok(WarningsAsErrors::Only(vec![Warning::Cpp]))
+        );
+        assert_eq!(
+            werror(&["-Werror", "-Werror=cpp", "foo.c"]),
+            Ok(WarningsAsErrors::All)
+        );
+        assert_eq!(
+            werror(&["-Werror", "-Wno-error", "foo.c"]),
+            Ok(WarningsAsErrors::None)
+        );
+        assert_eq!(
+            werror(&["-Werror=unused-variable", "foo.c"]).unwrap_err(),
+            "invalid value 'error=unused-variable' for '-W <OPTION>': Unknown warning provided to -Werror="
+        );
+    }
+
+    #[test]
+    fn out_dir_flag() {
+        assert_eq!(Config::new(args(&["foo.c"])).unwrap().out_dir, None);
+        let config = Config::new(args(&["--out-dir", "build", "foo.c"])).unwrap();
+        assert_eq!(config.out_dir, Some(PathBuf::from("build")));
+        assert_eq!(
+            error(&["foo.c", "--out-dir"]),
+            "a value is required for '--out-dir <DIR>' but none was supplied"
+        );
+    }
+
+    #[test]
+    fn keep_intermediates_flag() {
+        let config = Config::new(args(&["--keep-intermediates", "foo.c"])).unwrap();
+        assert!(config.keep_intermediates);
+    }
+
+    #[test]
+    fn error_limit_flag() {
+        let limit = |list: &[&str]| {
+            Config::new(args(list))
+                .map(|config| config.error_limit)
+                .map_err(message)
+        };
+        assert_eq!(limit(&["foo.c"]), Ok(20));
+        assert_eq!(limit(&["--error-limit", "0", "foo.c"]), Ok(0));
+        assert_eq!(
+            limit(&["--error-limit", "-1", "foo.c"]).unwrap_err(),
+            "unexpected argument '-1' found"
+        );
+    }
+
+    #[test]
+    fn opt_level_flags() {
+        let level = |list: &[&str]| {
+            Config::new(args(list))
+                .map(|config| config.opt_level)
+                .map_err(message)
+        };
+        assert_eq!(level(&["foo.c"]), Ok(0));
+        assert_eq!(level(&["-O2", "foo.c"]), Ok(2));
+        assert_eq!(level(&["-O", "3", "-O1", "foo.c"]), Ok(1));
+        assert_eq!(
+            level(&["-O4", "foo.c"]),
+            Err(String::from("Invalid optimisation level, expected 0 to 3"))
+        );
+    }
+
+    #[test]
+    fn cache_flags() {
+        let cache = |list: &[&str]| Config::new(args(list)).unwrap().cache;
+        assert_eq!(cache(&["foo.c"]), Cache::Off);
+        assert_eq!(cache(&["--cache", "foo.c"]), Cache::User);
+        assert_eq!(cache(&["--cache", "--no-cache", "foo.c"]), Cache::Off);
+        assert_eq!(
+            cache(&["--cache-dir", "build/cache", "foo.c"]),
+            Cache::Dir(PathBuf::from("build/cache"))
+        );
+        assert_eq!(
+            error(&["foo.c", "--cache-dir"]),
+            "a value is required for '--cache-dir <DIR>' but none was supplied"
+        );
+    }
 
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("No file path provided"),
+    #[test]
+    fn stack_protector_flags() {
+        let config = Config::new(args(&["-fstack-protector", "foo.c"])).unwrap();
+        assert_eq!(config.stack_protector, StackProtector::Arrays);
+        let config = Config::new(args(&["-fstack-protector-all", "foo.c"])).unwrap();
+        assert_eq!(config.stack_protector, StackProtector::All);
+        let config = Config::new(args(&[
+            "-fstack-protector",
+            "-fno-stack-protector",
+            "foo.c",
+        ]))
+        .unwrap();
+        assert_eq!(config.stack_protector, StackProtector::Off);
+    }
+
+    #[test]
+    fn align_functions_flags() {
+        let align = |flags: &[&str]| {
+            let mut all = flags.to_vec();
+            all.push("foo.c");
+            Config::new(args(&all))
+                .map(|config| config.align_functions)
+                .map_err(message)
         };
+        assert_eq!(align(&[]), Ok(None));
+        assert_eq!(align(&["-falign-functions"]), Ok(Some(16)));
+        assert_eq!(align(&["-falign-functions=32"]), Ok(Some(32)));
+        assert_eq!(align(&["-falign-functions=1"]), Ok(Some(1)));
+        assert_eq!(
+            align(&["-falign-functions=8", "-fno-align-functions"]),
+            Ok(None)
+        );
+        assert!(align(&["-falign-functions=12"]).is_err());
+        assert!(align(&["-falign-functions=0"]).is_err());
+        assert!(align(&["-falign-functions=x"]).is_err());
+    }
+
+    #[test]
+    fn check_div_flags() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().check_div);
+        assert!(
+            Config::new(args(&["-fcheck-div", "foo.c"]))
+                .unwrap()
+                .check_div
+        );
+        assert!(
+            !Config::new(args(&["-fcheck-div", "-fno-check-div", "foo.c"]))
+                .unwrap()
+                .check_div
+        );
+    }
+
+    #[test]
+    fn debug_info_flag() {
+        assert!(Config::new(args(&["-g", "foo.c"])).unwrap().debug_info);
+    }
+
+    #[test]
+    fn native_x86_64_only_flags() {
+        for flag in &[
+            "-fstack-protector",
+            "-fstack-protector-all",
+            "-g",
+            "-fcheck-div",
+        ] {
+            for other in &[
+                &["--target", "riscv64-linux-gnu"][..],
+                &["--backend", "qbe", "-S"][..],
+            ] {
+                let mut list = other.to_vec();
+                list.extend(&[flag, "foo.c"]);
+                assert!(
+                    error(&list).ends_with("is only supported by the native x86-64 backend"),
+                    "{:?}",
+                    list
+                );
+            }
+            assert!(Config::new(args(&["--target", "x86_64-linux-gnu", flag, "foo.c"])).is_ok());
+        }
+    }
+
+    #[test]
+    fn select_backend() {
+        let config = Config::new(args(&["--backend", "qbe", "-S", "foo.c"])).unwrap();
+        assert_eq!(config.backend, BackendKind::Qbe);
+        assert_eq!(config.emit, [Emit::Assembly]);
+        assert_eq!(
+            error(&["--backend", "gcc", "foo.c"]),
+            "invalid value 'gcc' for '--backend <BACKEND>'"
+        );
+    }
+
+    #[test]
+    fn select_output() {
+        let emit = |list: &[&str]| Config::new(args(list)).unwrap().emit;
+        assert_eq!(emit(&["foo.c"]), [Emit::Executable]);
+        assert_eq!(emit(&["--emit", "llvm-ir", "foo.c"]), [Emit::LlvmIr]);
+        assert_eq!(
+            emit(&["--emit", "semantic-tokens", "foo.c"]),
+            [Emit::SemanticTokens]
+        );
+        assert_eq!(
+            emit(&["--emit", "asm,asm-map", "foo.c"]),
+            [Emit::Assembly, Emit::AsmMap]
+        );
+        assert_eq!(
+            emit(&["--emit", "tokens,ast", "--emit", "obj,ast", "foo.c"]),
+            [Emit::Tokens, Emit::Ast, Emit::Object]
+        );
+        assert_eq!(
+            emit(&["--emit", "ir,exe", "-S", "foo.c"]),
+            [Emit::LlvmIr, Emit::Executable, Emit::Assembly]
+        );
+        assert_eq!(
+            error(&["--emit", "exe,bin", "foo.c"]),
+            "invalid value 'bin' for '--emit <KIND>': Unknown output, expected tokens, semantic-tokens, ast, ir, asm, asm-map, obj or exe"
+        );
+    }
+
+    #[test]
+    fn output_path() {
+        let config = Config::new(args(&["foo.c", "-o", "bin/foo"])).unwrap();
+        assert_eq!(config.output, Some(PathBuf::from("bin/foo")));
+        let config = Config::new(args(&["-obar", "foo.c"])).unwrap();
+        assert_eq!(config.output, Some(PathBuf::from("bar")));
+        assert_eq!(config.filenames, [PathBuf::from("foo.c")]);
+        assert_eq!(
+            error(&["foo.c", "-o"]),
+            "a value is required for '-o <PATH>' but none was supplied"
+        );
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert_eq!(error(&["-x", "foo.c"]), "unexpected argument '-x' found");
+    }
+
+    #[test]
+    fn help_and_version() {
+        let info = |list: &[&str]| requested_info(args(list));
+        assert_eq!(info(&["foo.c"]), None);
+        assert_eq!(info(&["--version", "foo.c"]).as_deref(), Some(VERSION));
+        assert_eq!(info(&["-V", "-h"]), Some(usage()));
+        // Help is still available when the rest of the arguments are invalid
+        assert_eq!(info(&["-x", "--help"]), Some(usage()));
+        assert_eq!(
+            info(&["--explain", "E0101"]).as_deref(),
+            error::explain("E0101")
+        );
+        assert_eq!(info(&["--explain", "E9999"]), None);
+        assert_eq!(info(&["run", "foo.c", "--", "--help"]), None);
+        assert_eq!(
+            error(&["--explain", "E9999"]),
+            "Unknown error code provided to --explain"
+        );
+    }
+
+    #[test]
+    fn print_targets() {
+        let targets = requested_info(args(&["--print-targets"])).unwrap();
+        match Target::host() {
+            Some(host) => {
+                assert!(targets.contains(&format!("  {} (host)\n", host)));
+                assert_eq!(targets.matches("(host)").count(), 1);
+            }
+            None => assert!(!targets.contains("(host)")),
+        }
+        assert!(targets.contains("  riscv64-unknown-linux-gnu"));
+        assert!(targets.contains("  x86_64-apple-darwin"));
+        assert!(targets.contains("  qbe "));
+    }
+
+    #[test]
+    fn environment_options() {
+        let env = |name: &str| match name {
+            "RCC_CC" => Some(String::from("clang")),
+            "RCC_TARGET" => Some(String::new()),
+            "RCC_FLAGS" => Some(String::from(" -g  -fintegrated-as ")),
+            _ => None,
+        };
+        let combined = with_environment(args(&["-fno-integrated-as", "foo.c"]).collect(), env);
+        assert_eq!(
+            combined,
+            [
+                "rcc",
+                "--cc",
+                "clang",
+                "-g",
+                "-fintegrated-as",
+                "-fno-integrated-as",
+                "foo.c"
+            ]
+        );
+        // The command line wins
+        let config = Config::new(combined.into_iter()).unwrap();
+        assert_eq!(config.cc.as_deref(), Some("clang"));
+        assert!(config.debug_info);
+        assert!(!config.integrated_as);
+
+        let unset = with_environment(args(&["foo.c"]).collect(), |_| None);
+        assert_eq!(unset, ["rcc", "foo.c"]);
+        // A subcommand has to stay first
+        let run = with_environment(args(&["run", "foo.c"]).collect(), env);
+        assert_eq!(&run[..3], ["rcc", "run", "--cc"]);
+    }
+
+    #[test]
+    fn log_filters() {
+        let filter = |list: &[&str], rcc_log: Option<&str>| {
+            log_filter(args(list), rcc_log.map(String::from))
+        };
+        assert_eq!(filter(&["foo.c"], None), None);
+        assert_eq!(filter(&["-v", "foo.c"], None), None);
+        assert_eq!(filter(&["-vv", "foo.c"], None).as_deref(), Some("debug"));
+        assert_eq!(filter(&["-vvv", "foo.c"], None).as_deref(), Some("trace"));
+        // The last verbosity flag wins
+        assert_eq!(filter(&["-vv", "-q", "foo.c"], None), None);
+        // RCC_LOG wins over the flags, unless it's empty
+        assert_eq!(
+            filter(&["-vv", "foo.c"], Some("rcc::compiler::toolchain=debug")).as_deref(),
+            Some("rcc::compiler::toolchain=debug")
+        );
+        assert_eq!(filter(&["foo.c"], Some("")), None);
+        assert_eq!(
+            Config::new(args(&["-vv", "foo.c"])).unwrap().verbosity,
+            Verbosity::Verbose
+        );
+    }
+
+    #[test]
+    fn response_files() {
+        let read = |path: &Path| match path.to_str() {
+            Some("flags") => Ok(String::from("-g\n-o 'my prog'\n@more\n")),
+            Some("more") => Ok(String::from("\"dir/a file.c\" b\\ c.c \"\" '\\n'")),
+            Some("loop") => Ok(String::from("@loop")),
+            _ => Err(io::Error::new(io::ErrorKind::NotFound, "not found")),
+        };
+        assert_eq!(
+            expand_response_files(args(&["-q", "@flags", "x.c", "@"]).collect(), read).unwrap(),
+            [
+                "rcc",
+                "-q",
+                "-g",
+                "-o",
+                "my prog",
+                "dir/a file.c",
+                "b c.c",
+                "",
+                "\\n",
+                "x.c",
+                "@"
+            ]
+        );
+        assert_eq!(
+            expand_response_files(args(&["@missing"]).collect(), read).unwrap_err(),
+            "cannot read response file missing: not found"
+        );
+        assert_eq!(
+            expand_response_files(args(&["@loop"]).collect(), read).unwrap_err(),
+            "response files nested too deeply at loop"
+        );
+        // The arguments for the program rcc runs are its own
+        assert_eq!(
+            expand_response_files(args(&["run", "x.c", "--", "@flags"]).collect(), read).unwrap(),
+            ["rcc", "run", "x.c", "--", "@flags"]
+        );
+    }
+
+    #[test]
+    fn subcommands() {
+        let command = |list: &[&str]| parse_command_line(args(list)).map(|(_, command)| command);
+        assert_eq!(command(&["foo.c"]).unwrap(), None);
+        let (config, run) = parse_command_line(args(&["run", "-g", "foo.c"])).unwrap();
+        assert!(config.debug_info);
+        assert_eq!(config.filenames, [PathBuf::from("foo.c")]);
+        assert_eq!(run, Some(Subcommand::Run(Vec::new())));
+        // Everything after -- is the program's, even rcc's own options
+        assert_eq!(
+            command(&["run", "foo.c", "--", "--help", "--", "x"]).unwrap(),
+            Some(Subcommand::Run(vec![
+                String::from("--help"),
+                String::from("--"),
+                String::from("x")
+            ]))
+        );
+        assert_eq!(
+            command(&["repl", "--no-preprocess"]).unwrap(),
+            Some(Subcommand::Repl)
+        );
+        assert_eq!(
+            command(&["test", "-fintegrated-as", "tests"]).unwrap(),
+            Some(Subcommand::Test)
+        );
+        assert_eq!(
+            command(&["fmt", "--check", "a.c"]).unwrap(),
+            Some(Subcommand::Fmt { check: true })
+        );
+        // A subcommand has to come first
+        assert_eq!(
+            Config::new(args(&["-g", "run"])).unwrap().filenames,
+            [PathBuf::from("run")]
+        );
+    }
+
+    #[test]
+    fn fmt_options() {
+        // Standard input is formatted to standard output, without -o
+        assert!(Config::new(args(&["fmt", "-"])).is_ok());
+        assert_eq!(error(&["fmt"]), "No file path provided");
+        assert_eq!(
+            error(&["fmt", "-", "--stdin"]),
+            "stdin can only be read once"
+        );
+    }
+
+    #[test]
+    fn repl_options() {
+        assert!(Config::new(args(&["repl", "-fintegrated-cpp"])).is_ok());
+        assert_eq!(
+            error(&["repl", "foo.c"]),
+            "rcc repl doesn't take input files"
+        );
+    }
+
+    #[test]
+    fn stage_flags() {
+        assert_eq!(Config::new(args(&["foo.c"])).unwrap().stop_after, None);
+        let config = Config::new(args(&["--lex", "foo.c"])).unwrap();
+        assert_eq!(config.stop_after, Some(Stage::Lex));
+        let config = Config::new(args(&["--parse", "--codegen", "foo.c"])).unwrap();
+        assert_eq!(config.stop_after, Some(Stage::Codegen));
+    }
+
+    #[test]
+    fn multiple_files() {
+        let config = Config::new(args(&["a.c", "-o", "prog", "b.c"])).unwrap();
+        assert_eq!(
+            config.filenames,
+            [PathBuf::from("a.c"), PathBuf::from("b.c")]
+        );
+    }
+
+    #[test]
+    fn stdin_input() {
+        let config = Config::new(args(&["--stdin", "-o", "prog"])).unwrap();
+        assert!(is_stdin(&config.filenames[0]));
+        let config = Config::new(args(&["a.c", "-", "-o", "prog"])).unwrap();
+        assert!(is_stdin(&config.filenames[1]));
+        assert_eq!(error(&["-"]), "Reading from stdin requires -o");
+        assert_eq!(
+            error(&["-", "--stdin", "-o", "prog"]),
+            "stdin can only be read once"
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let target = Target::parse("riscv64-linux-gnu").unwrap();
+        let config = Config::builder()
+            .input("prog.c")
+            .output("prog")
+            .target(target)
+            .opt_level(1)
+            .emit(Emit::Assembly)
+            .build()
+            .unwrap();
+        assert_eq!(config.filenames, [PathBuf::from("prog.c")]);
+        assert_eq!(config.output, Some(PathBuf::from("prog")));
+        assert_eq!(config.target, target);
+        assert_eq!(config.opt_level, 1);
+        assert!(config.emits(Emit::Assembly));
+        assert!(!config.emits(Emit::Executable));
+        // Defaults
+        let config = Config::builder().input("prog.c").build().unwrap();
+        assert!(config.emits(Emit::Executable));
+        assert_eq!(config.cache, Cache::Off);
+        // Validation
+        assert_eq!(
+            Config::builder().build().unwrap_err(),
+            "No file path provided"
+        );
+        assert_eq!(
+            Config::builder().input(STDIN).build().unwrap_err(),
+            "Reading from stdin requires -o"
+        );
+        assert_eq!(
+            Config::builder()
+                .input("prog.c")
+                .opt_level(4)
+                .build()
+                .unwrap_err(),
+            "Invalid optimisation level, expected 0 to 3"
+        );
+    }
+
+    #[test]
+    fn integrated_assembler_flags() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().integrated_as);
+        assert!(
+            Config::new(args(&["-fintegrated-as", "foo.c"]))
+                .unwrap()
+                .integrated_as
+        );
+        assert!(
+            !Config::new(args(&["-fintegrated-as", "-fno-integrated-as", "foo.c"]))
+                .unwrap()
+                .integrated_as
+        );
+    }
+
+    #[test]
+    fn toolchain_programs() {
+        let config = Config::new(args(&[
+            "--cc",
+            "clang",
+            "--assembler",
+            "llvm-mc",
+            "--linker-arg",
+            "-lm",
+            "--linker-arg",
+            "-s",
+            "foo.c",
+        ]))
+        .unwrap();
+        assert_eq!(config.cc.as_deref(), Some("clang"));
+        assert_eq!(config.assembler.as_deref(), Some("llvm-mc"));
+        assert_eq!(config.linker_args, ["-lm", "-s"]);
+        assert_eq!(
+            error(&["foo.c", "--cc"]),
+            "a value is required for '--cc <PROGRAM>' but none was supplied"
+        );
+    }
+
+    #[test]
+    fn linker_passthrough() {
+        let config = Config::new(args(&[
+            "main.c",
+            "-lm",
+            "-L/opt/lib",
+            "-l",
+            "z",
+            "-Wl,--gc-sections,-O1",
+        ]))
+        .unwrap();
+        assert_eq!(config.filenames, [PathBuf::from("main.c")]);
+        assert_eq!(
+            config.linker_args,
+            ["-lm", "-L/opt/lib", "-lz", "-Wl,--gc-sections,-O1"]
+        );
+    }
+
+    #[test]
+    fn static_flag() {
+        assert!(!Config::new(args(&["foo.c"])).unwrap().static_link);
+        let config = Config::new(args(&["-static", "foo.c"])).unwrap();
+        assert!(config.static_link);
+        assert!(config.linker_args.is_empty());
+    }
+
+    #[test]
+    fn size_flags() {
+        let config = Config::new(args(&["foo.c"])).unwrap();
+        assert!(!config.strip && !config.link_for_size);
+        let config = Config::new(args(&["-s", "--link-for-size", "foo.c"])).unwrap();
+        assert!(config.strip && config.link_for_size);
+        assert!(Config::new(args(&["--strip", "foo.c"])).unwrap().strip);
+    }
+
+    #[test]
+    fn wildcards() {
+        let pattern = |p: &str, name: &str| {
+            let chars = |s: &str| s.chars().collect::<Vec<_>>();
+            wildcard_match(&chars(p), &chars(name))
+        };
+        assert!(pattern("*.c", "main.c"));
+        assert!(pattern("*.c", ".c"));
+        assert!(pattern("a?c*", "abc.c"));
+        assert!(pattern("*", ""));
+        assert!(!pattern("*.c", "main.h"));
+        assert!(!pattern("a?c", "ac"));
+    }
+
+    #[test]
+    fn directory_and_wildcard_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in &["b.c", "a.c", "util.h", ".hidden.c"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        fs::create_dir(dir.path().join("sub.c")).unwrap();
+        let sorted = [dir.path().join("a.c"), dir.path().join("b.c")];
+
+        let dir_arg = dir.path().to_str().unwrap();
+        let config = Config::new(args(&["--dir", dir_arg])).unwrap();
+        assert_eq!(config.filenames, sorted);
+
+        let pattern = dir.path().join("*.c");
+        let config = Config::new(args(&[pattern.to_str().unwrap()])).unwrap();
+        assert_eq!(config.filenames, sorted);
+        // Files named again aren't compiled twice
+        let first = sorted[1].to_str().unwrap();
+        let config = Config::new(args(&[first, "--dir", dir_arg])).unwrap();
+        assert_eq!(config.filenames, [sorted[1].clone(), sorted[0].clone()]);
+
+        let none = dir.path().join("*.rs");
+        assert_eq!(
+            error(&[none.to_str().unwrap()]),
+            "No files match a wildcard pattern"
+        );
+        let empty = dir.path().join("sub.c");
+        assert_eq!(
+            error(&["--dir", empty.to_str().unwrap()]),
+            "No .c files in the directory provided to --dir"
+        );
+        let nested = dir.path().join("*").join("a.c");
+        assert_eq!(
+            error(&[nested.to_str().unwrap()]),
+            "Wildcards are only supported in file names"
+        );
+    }
+
+    #[test]
+    fn missing_filename() {
+        assert_eq!(error(&[]), "No file path provided");
+    }
 
-        Ok(Config {
-            filename: PathBuf::from(filename),
-        })
+    #[test]
+    fn select_target() {
+        let config = Config::new(args(&["--target", "riscv64-linux-gnu", "foo.c"])).unwrap();
+        assert_eq!(config.target, Target::parse("riscv64-linux-gnu").unwrap());
+        assert_eq!(
+            error(&["foo.c", "--target", "mips-linux-gnu"]),
+            "invalid value 'mips-linux-gnu' for '--target <TRIPLE>': Unknown target architecture"
+        );
     }
 }
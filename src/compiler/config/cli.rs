@@ -0,0 +1,607 @@
+//! The command line of `rcc`, parsed with clap.
+//!
+//! Options mostly follow GCC's, so the `-f` and `-W` families are short
+//! options with values, as in `-fstack-protector` and `-Werror=cpp`. Where
+//! options set the same thing, such as `--verbose` and `--quiet`, the last
+//! one given wins.
+
+use std::path::PathBuf;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+
+use super::{
+    expand_wildcards, has_wildcards, sources_in, BackendKind, Cache, Cancellation, Config, Emit,
+    Preprocessor, StackProtector, Stage, Subcommand, Verbosity, WarningsAsErrors, STDIN,
+};
+use crate::compiler::diagnostic::Warning;
+use crate::compiler::observer::Observers;
+use crate::compiler::target::Target;
+
+const USAGE: &str = "rcc [OPTIONS] <FILE>...
+       rcc run [OPTIONS] <FILE>... [-- <ARG>...]
+       rcc repl [OPTIONS]
+       rcc test [OPTIONS] <DIR>...
+       rcc fmt [--check] [OPTIONS] <FILE>...";
+
+const AFTER_HELP: &str = "Environment:
+  RCC_CC       C compiler driver, as with --cc
+  RCC_TARGET   Target triple, as with --target
+  RCC_FLAGS    Options to use before those on the command line
+  RCC_LOG      What to log to stderr, as a level such as debug or a tracing
+               filter such as rcc::compiler::toolchain
+
+Exit status (of rcc run and --interpret, the program's unless compilation
+fails):
+  0  Success
+  1  Usage error, or a file couldn't be read or written
+  2  Lexical error
+  3  Syntax error
+  4  Semantic error
+  5  Assembler or linker error
+  6  Code generation error
+  7  Runtime error in an interpreted program";
+
+/// A compiler for a small subset of C
+#[derive(Parser)]
+#[command(
+    name = "rcc",
+    version,
+    override_usage = USAGE,
+    after_long_help = AFTER_HELP,
+    args_override_self = true,
+    args_conflicts_with_subcommands = true,
+    disable_help_subcommand = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    flags: Flags,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Compile to a temporary executable and run it with the arguments after
+    /// --, exiting with its status
+    Run {
+        #[command(flatten)]
+        flags: Flags,
+        /// Arguments for the program
+        #[arg(last = true, value_name = "ARG")]
+        program_args: Vec<String>,
+    },
+    /// Read expressions or return statements and print their values
+    Repl {
+        #[command(flatten)]
+        flags: Flags,
+    },
+    /// Compile the programs in each stage_N/valid and stage_N/invalid
+    /// directory of a test corpus, check their exit statuses and errors, and
+    /// print a summary; exits with 1 if any fail
+    Test {
+        #[command(flatten)]
+        flags: Flags,
+    },
+    /// Rewrite each file with consistent indentation and spacing, or standard
+    /// input to standard output
+    Fmt {
+        /// List the files that would change, and exit with 1 if there are any
+        #[arg(long)]
+        check: bool,
+        #[command(flatten)]
+        flags: Flags,
+    },
+}
+
+#[derive(clap::Args)]
+struct Flags {
+    /// Source files, or - for standard input. A name may have * and ?
+    /// wildcards, as in src/*.c, which match files in order of name.
+    #[arg(value_name = "FILE")]
+    files: Vec<String>,
+    /// Read source from standard input; requires -o
+    #[arg(long)]
+    stdin: bool,
+    /// Compile every .c file in <DIR>, in order of name
+    #[arg(long, value_name = "DIR")]
+    dir: Vec<PathBuf>,
+    /// Write the output to <PATH>; with several input files, only when
+    /// linking
+    #[arg(short = 'o', value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Stop after lexing without writing output
+    #[arg(long, overrides_with_all = ["parse", "validate", "codegen"])]
+    lex: bool,
+    /// Stop after parsing without writing output
+    #[arg(long, overrides_with_all = ["lex", "validate", "codegen"])]
+    parse: bool,
+    /// Stop after semantic analysis without writing output
+    #[arg(long, overrides_with_all = ["lex", "parse", "codegen"])]
+    validate: bool,
+    /// Stop after code generation without writing output
+    #[arg(long, overrides_with_all = ["lex", "parse", "validate"])]
+    codegen: bool,
+    /// What to produce, any of tokens, semantic-tokens, ast, ir (LLVM IR),
+    /// asm, asm-map (which lines of the assembly each statement became, as
+    /// JSON), obj and exe (default: exe); each is written next to its
+    /// source, or to -o, where - means standard output
+    #[arg(long, value_name = "KIND", value_delimiter = ',', value_parser = parse_emit)]
+    emit: Vec<Emit>,
+    /// Same as --emit asm
+    #[arg(short = 'S')]
+    assembly: bool,
+    /// Code generator to use (default: native)
+    #[arg(long, value_enum)]
+    backend: Option<BackendKind>,
+    /// Compile for the given target (default: host)
+    #[arg(long, value_name = "TRIPLE", value_parser = Target::parse)]
+    target: Option<Target>,
+    /// Compile the source without running the system preprocessor
+    #[arg(long)]
+    no_preprocess: bool,
+    /// Search <DIR> for included files
+    #[arg(short = 'I', value_name = "DIR")]
+    include_dirs: Vec<PathBuf>,
+    /// Preprocess and link using the given C compiler driver (default: the
+    /// first of gcc, clang and cc found)
+    #[arg(long, value_name = "PROGRAM")]
+    cc: Option<String>,
+    /// Assemble using the given program
+    #[arg(long, value_name = "PROGRAM")]
+    assembler: Option<String>,
+    /// Pass an argument to the link step
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    linker_arg: Vec<String>,
+    /// Link with a library
+    #[arg(short = 'l', value_name = "LIB")]
+    libraries: Vec<String>,
+    /// Search <DIR> for libraries
+    #[arg(short = 'L', value_name = "DIR")]
+    library_dirs: Vec<String>,
+    /// Link statically, without shared libraries; also spelled -static
+    #[arg(long = "static")]
+    static_link: bool,
+    /// Strip symbols from the executable
+    #[arg(short, long)]
+    strip: bool,
+    /// Have the linker drop unused sections and shared libraries
+    #[arg(long)]
+    link_for_size: bool,
+    /// Evaluate the program instead of compiling it, and exit with the value
+    /// main returns
+    #[arg(long)]
+    interpret: bool,
+    /// Compile again whenever a source file changes
+    #[arg(long)]
+    watch: bool,
+    /// Compile, but print the assembler and linker commands instead of
+    /// running them; also spelled -###
+    #[arg(long)]
+    dry_run: bool,
+    /// Keep the .s and .o files next to each source
+    #[arg(long)]
+    keep_intermediates: bool,
+    /// Write outputs and kept intermediates to <DIR> instead of next to each
+    /// source, creating it if needed; -o paths are used as given
+    #[arg(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+    /// Reuse object files from earlier builds of unchanged sources, kept in
+    /// $XDG_CACHE_HOME/rcc or ~/.cache/rcc
+    #[arg(long, overrides_with_all = ["cache_dir", "no_cache"])]
+    cache: bool,
+    /// Cache object files in <DIR> instead
+    #[arg(long, value_name = "DIR", overrides_with_all = ["cache", "no_cache"])]
+    cache_dir: Option<PathBuf>,
+    /// Compile every source (default)
+    #[arg(long, overrides_with_all = ["cache", "cache_dir"])]
+    no_cache: bool,
+    /// Print each stage with timings, and the commands run; -vv and -vvv also
+    /// log the stages, functions and commands with their output, or
+    /// everything, as with RCC_LOG
+    #[arg(short, long, action = ArgAction::Count, overrides_with = "quiet")]
+    verbose: u8,
+    /// Only print errors
+    #[arg(short, long, overrides_with = "verbose")]
+    quiet: bool,
+    /// Print the total time and peak memory of each stage
+    #[arg(long)]
+    time_passes: bool,
+    /// Report at most <N> errors, or all of them for 0
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    error_limit: usize,
+    /// Emit debug information (native x86-64 backend only)
+    #[arg(short = 'g')]
+    debug_info: bool,
+    /// Optimisation level, from 0 to 3, as in -O2; reserved, since nothing
+    /// is optimised yet
+    #[arg(short = 'O', value_name = "N", default_value_t = 0)]
+    opt_level: u8,
+    /// Warning and linker options, as in GCC:
+    ///   -Werror             Treat warnings as errors
+    ///   -Werror=<WARNING>   Treat the given warning as an error, such as cpp
+    ///                       for #warning
+    ///   -Wno-error          Treat warnings as warnings again
+    ///   -Wl,<ARG>[,<ARG>...]
+    ///                       Pass comma separated arguments to the linker
+    #[arg(
+        short = 'W',
+        value_name = "OPTION",
+        value_parser = WOption::parse,
+        verbatim_doc_comment
+    )]
+    w_options: Vec<WOption>,
+    /// Code generation options, as in GCC:
+    ///   -fintegrated-cpp        Preprocess without the system preprocessor
+    ///                           (experimental)
+    ///   -fintegrated-as         Write object files without the system
+    ///                           assembler (x86-64 only, without -g or
+    ///                           -fcheck-div)
+    ///   -fstack-protector       Add stack canaries to functions with arrays
+    ///                           (native x86-64 backend only)
+    ///   -fstack-protector-all   Add stack canaries to all functions
+    ///   -falign-functions[=<N>] Align the start of each function to <N>
+    ///                           bytes, a power of two (default: 16)
+    ///   -fcheck-div             Abort with a message on division by zero or
+    ///                           INT_MIN / -1, which otherwise raise SIGFPE
+    ///                           (native x86-64 backend only)
+    /// Each is turned off again by its -fno- form, such as -fno-check-div.
+    #[arg(
+        short = 'f',
+        value_name = "OPTION",
+        value_parser = FOption::parse,
+        verbatim_doc_comment
+    )]
+    f_options: Vec<FOption>,
+    /// List the targets and backends rcc supports, marking the host
+    // Answered by requested_info before the options are parsed
+    #[allow(dead_code)]
+    #[arg(long)]
+    print_targets: bool,
+    /// Describe an error code, such as E0101
+    #[arg(long, value_name = "CODE")]
+    explain: Option<String>,
+}
+
+/// An option given with `-W`, which as in GCC is either about warnings or,
+/// as `-Wl,`, for the linker.
+#[derive(Clone, Debug, PartialEq)]
+enum WOption {
+    /// `-Werror`, or `-Wno-error` for false
+    Errors(bool),
+    /// `-Werror=<warning>`
+    Error(Warning),
+    /// `-Wl,<args>`, kept as written since the driver passes it on
+    Linker(String),
+}
+
+impl WOption {
+    fn parse(option: &str) -> Result<Self, &'static str> {
+        match option {
+            "error" => Ok(Self::Errors(true)),
+            "no-error" => Ok(Self::Errors(false)),
+            _ if option.starts_with("l,") => Ok(Self::Linker(format!("-W{}", option))),
+            _ => match option.strip_prefix("error=") {
+                Some(name) => Warning::parse(name)
+                    .map(Self::Error)
+                    .ok_or("Unknown warning provided to -Werror="),
+                None => Err("Unrecognised option, see --help"),
+            },
+        }
+    }
+}
+
+/// An option given with `-f`, with its `-fno-` form as the opposite.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FOption {
+    IntegratedCpp(bool),
+    IntegratedAs(bool),
+    StackProtector(StackProtector),
+    AlignFunctions(Option<u32>),
+    CheckDiv(bool),
+}
+
+impl FOption {
+    fn parse(option: &str) -> Result<Self, &'static str> {
+        Ok(match option {
+            "integrated-cpp" => Self::IntegratedCpp(true),
+            "no-integrated-cpp" => Self::IntegratedCpp(false),
+            "integrated-as" => Self::IntegratedAs(true),
+            "no-integrated-as" => Self::IntegratedAs(false),
+            "stack-protector" => Self::StackProtector(StackProtector::Arrays),
+            "stack-protector-all" => Self::StackProtector(StackProtector::All),
+            "no-stack-protector" => Self::StackProtector(StackProtector::Off),
+            // The usual alignment on both x86-64 and RISC-V
+            "align-functions" => Self::AlignFunctions(Some(16)),
+            "no-align-functions" => Self::AlignFunctions(None),
+            "check-div" => Self::CheckDiv(true),
+            "no-check-div" => Self::CheckDiv(false),
+            _ => match option.strip_prefix("align-functions=") {
+                Some(bytes) => bytes
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|bytes| bytes.is_power_of_two())
+                    .map(|bytes| Self::AlignFunctions(Some(bytes)))
+                    .ok_or(
+                        "Invalid alignment provided to -falign-functions, expected a power of two",
+                    )?,
+                None => return Err("Unrecognised option, see --help"),
+            },
+        })
+    }
+}
+
+fn parse_emit(kind: &str) -> Result<Emit, &'static str> {
+    Emit::parse(kind).ok_or(
+        "Unknown output, expected tokens, semantic-tokens, ast, ir, asm, asm-map, obj or exe",
+    )
+}
+
+/// A usage error that isn't clap's own, shown with the usage as clap's are.
+fn error(message: &str) -> clap::Error {
+    Cli::command().error(ErrorKind::InvalidValue, message)
+}
+
+/// The values of the argument `id` paired with where each was given, to
+/// merge arguments that add to the same list in order.
+fn indexed<T>(matches: &ArgMatches, id: &str, values: Vec<T>) -> Vec<(usize, T)> {
+    match matches.indices_of(id) {
+        Some(indices) => indices.zip(values).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Where the flag `id` was last given, if it was.
+fn flag_index(matches: &ArgMatches, id: &str) -> Option<usize> {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => matches.index_of(id),
+        _ => None,
+    }
+}
+
+/// An input named on the command line, in one of the ways it can be.
+enum Input {
+    File(String),
+    Stdin,
+    Dir(PathBuf),
+}
+
+impl Flags {
+    fn into_config(self, matches: &ArgMatches) -> Result<Config, clap::Error> {
+        // Unknown codes are all that's left, since requested_info answers
+        // the rest
+        if self.explain.is_some() {
+            return Err(error("Unknown error code provided to --explain"));
+        }
+
+        let mut inputs = indexed(
+            matches,
+            "files",
+            self.files.into_iter().map(Input::File).collect(),
+        );
+        inputs.extend(flag_index(matches, "stdin").map(|index| (index, Input::Stdin)));
+        inputs.extend(indexed(
+            matches,
+            "dir",
+            self.dir.into_iter().map(Input::Dir).collect(),
+        ));
+        inputs.sort_by_key(|(index, _)| *index);
+        let mut filenames = Vec::new();
+        for (_, input) in inputs {
+            let sources = match input {
+                Input::File(file) if has_wildcards(&file) => expand_wildcards(&file),
+                Input::File(file) => {
+                    filenames.push(PathBuf::from(file));
+                    continue;
+                }
+                Input::Stdin => {
+                    filenames.push(PathBuf::from(STDIN));
+                    continue;
+                }
+                Input::Dir(dir) => sources_in(&dir),
+            };
+            for source in sources.map_err(error)? {
+                if !filenames.contains(&source) {
+                    filenames.push(source);
+                }
+            }
+        }
+
+        let mut emits = indexed(matches, "emit", self.emit);
+        emits.extend(flag_index(matches, "assembly").map(|index| (index, Emit::Assembly)));
+        emits.sort_by_key(|(index, _)| *index);
+        let mut emit = Vec::new();
+        for (_, kind) in emits {
+            if !emit.contains(&kind) {
+                emit.push(kind);
+            }
+        }
+        if emit.is_empty() {
+            emit.push(Emit::Executable);
+        }
+
+        let mut linker_args = indexed(matches, "linker_arg", self.linker_arg);
+        linker_args.extend(indexed(
+            matches,
+            "libraries",
+            self.libraries
+                .iter()
+                .map(|lib| format!("-l{}", lib))
+                .collect(),
+        ));
+        linker_args.extend(indexed(
+            matches,
+            "library_dirs",
+            self.library_dirs
+                .iter()
+                .map(|dir| format!("-L{}", dir))
+                .collect(),
+        ));
+
+        let mut warnings_as_errors = WarningsAsErrors::None;
+        for (index, option) in indexed(matches, "w_options", self.w_options) {
+            match option {
+                WOption::Errors(true) => warnings_as_errors = WarningsAsErrors::All,
+                WOption::Errors(false) => warnings_as_errors = WarningsAsErrors::None,
+                WOption::Error(warning) => match &mut warnings_as_errors {
+                    WarningsAsErrors::All => (),
+                    WarningsAsErrors::Only(warnings) if warnings.contains(&warning) => (),
+                    WarningsAsErrors::Only(warnings) => warnings.push(warning),
+                    WarningsAsErrors::None => {
+                        warnings_as_errors = WarningsAsErrors::Only(vec![warning])
+                    }
+                },
+                WOption::Linker(arg) => linker_args.push((index, arg)),
+            }
+        }
+        linker_args.sort_by_key(|(index, _)| *index);
+
+        let mut preprocessors: Vec<_> = flag_index(matches, "no_preprocess")
+            .map(|index| (index, Preprocessor::Off))
+            .into_iter()
+            .collect();
+        let mut integrated_as = false;
+        let mut stack_protector = StackProtector::Off;
+        let mut align_functions = None;
+        let mut check_div = false;
+        for (index, option) in indexed(matches, "f_options", self.f_options) {
+            match option {
+                FOption::IntegratedCpp(true) => {
+                    preprocessors.push((index, Preprocessor::Integrated))
+                }
+                FOption::IntegratedCpp(false) => preprocessors.push((index, Preprocessor::System)),
+                FOption::IntegratedAs(integrated) => integrated_as = integrated,
+                FOption::StackProtector(protector) => stack_protector = protector,
+                FOption::AlignFunctions(bytes) => align_functions = bytes,
+                FOption::CheckDiv(check) => check_div = check,
+            }
+        }
+        let preprocessor = preprocessors
+            .into_iter()
+            .max_by_key(|(index, _)| *index)
+            .map_or(Preprocessor::System, |(_, preprocessor)| preprocessor);
+
+        let stop_after = [
+            (self.lex, Stage::Lex),
+            (self.parse, Stage::Parse),
+            (self.validate, Stage::Validate),
+            (self.codegen, Stage::Codegen),
+        ]
+        .iter()
+        .find(|(given, _)| *given)
+        .map(|(_, stage)| *stage);
+        // At most one of these is set, since each overrides the others
+        let cache = match (self.cache, self.cache_dir, self.no_cache) {
+            (_, Some(dir), _) => Cache::Dir(dir),
+            (true, None, _) => Cache::User,
+            (false, None, _) => Cache::Off,
+        };
+        let verbosity = match (self.verbose, self.quiet) {
+            (_, true) => Verbosity::Quiet,
+            (0, false) => Verbosity::Normal,
+            (_, false) => Verbosity::Verbose,
+        };
+
+        Ok(Config {
+            filenames,
+            target: match self.target.or_else(Target::host) {
+                Some(target) => target,
+                None => return Err(error(super::NO_HOST_TARGET)),
+            },
+            preprocessor,
+            include_dirs: self.include_dirs,
+            debug_info: self.debug_info,
+            stack_protector,
+            align_functions,
+            check_div,
+            opt_level: self.opt_level,
+            backend: self.backend.unwrap_or_default(),
+            emit,
+            output: self.output,
+            out_dir: self.out_dir,
+            stop_after,
+            integrated_as,
+            cc: self.cc,
+            assembler: self.assembler,
+            linker_args: linker_args.into_iter().map(|(_, arg)| arg).collect(),
+            static_link: self.static_link,
+            strip: self.strip,
+            link_for_size: self.link_for_size,
+            keep_intermediates: self.keep_intermediates,
+            cache,
+            verbosity,
+            warnings_as_errors,
+            error_limit: self.error_limit,
+            interpret: self.interpret,
+            watch: self.watch,
+            dry_run: self.dry_run,
+            time_passes: self.time_passes,
+            observers: Observers::default(),
+            cancellation: Cancellation::default(),
+        })
+    }
+}
+
+/// Parse a command line, starting with the program name, into the
+/// configuration and the subcommand, if any, checking the options make
+/// sense for it.
+pub fn parse<I>(args: I) -> Result<(Config, Option<Subcommand>), clap::Error>
+where
+    I: Iterator<Item = String>,
+{
+    // clap has no long options with a single dash, which GCC spells these with
+    let args = args.map(|arg| match arg.as_str() {
+        "-static" => String::from("--static"),
+        "-###" => String::from("--dry-run"),
+        _ => arg,
+    });
+    let matches = Cli::command().try_get_matches_from(args)?;
+    let cli = Cli::from_arg_matches(&matches)?;
+    let (flags, subcommand) = match cli.command {
+        None => (cli.flags, None),
+        Some(Command::Run {
+            flags,
+            program_args,
+        }) => (flags, Some(Subcommand::Run(program_args))),
+        Some(Command::Repl { flags }) => (flags, Some(Subcommand::Repl)),
+        Some(Command::Test { flags }) => (flags, Some(Subcommand::Test)),
+        Some(Command::Fmt { check, flags }) => (flags, Some(Subcommand::Fmt { check })),
+    };
+    let matches = match matches.subcommand() {
+        Some((_, matches)) => matches,
+        None => &matches,
+    };
+    let config = flags.into_config(matches)?;
+    let config = match subcommand {
+        Some(Subcommand::Repl) if !config.filenames.is_empty() => {
+            Err("rcc repl doesn't take input files")
+        }
+        Some(Subcommand::Repl) => Ok(config),
+        // Standard input is formatted to standard output, without -o
+        Some(Subcommand::Fmt { .. }) if config.filenames.is_empty() => Err("No file path provided"),
+        Some(Subcommand::Fmt { .. })
+            if config
+                .filenames
+                .iter()
+                .filter(|f| super::is_stdin(f))
+                .count()
+                > 1 =>
+        {
+            Err("stdin can only be read once")
+        }
+        Some(Subcommand::Fmt { .. }) => Ok(config),
+        _ => config.validate(),
+    };
+    Ok((config.map_err(error)?, subcommand))
+}
+
+/// Whether `name` is one of the subcommands, which have to come first.
+pub fn is_subcommand(name: &str) -> bool {
+    Cli::command().find_subcommand(name).is_some()
+}
+
+/// The help `--help` prints.
+pub fn help() -> String {
+    Cli::command().render_long_help().to_string()
+}
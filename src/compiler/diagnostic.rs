@@ -0,0 +1,219 @@
+//! Showing where in the source an error is, in the style of C compilers:
+//!
+//! ```text
+//! prog.c:1:23: Syntax Error: Expected semicolon
+//!     1 | int main() { return 1 }
+//!       |                       ^
+//! ```
+//!
+//! Warnings are reported here too, so that `-Werror` applies to all of them
+//! in the same way.
+
+use std::path::{Path, PathBuf};
+
+use super::config::{Config, Verbosity, WarningsAsErrors};
+
+/// A place in a source file, with the line it's on to show it in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: PathBuf,
+    /// 1-based line and column, counting characters
+    pub line: usize,
+    pub column: usize,
+    /// How many characters to underline
+    pub len: usize,
+    pub text: String,
+}
+
+impl Location {
+    pub fn new(file: &Path, source: &str, line: usize, column: usize, len: usize) -> Self {
+        Location {
+            file: file.to_path_buf(),
+            line,
+            column,
+            len: len.max(1),
+            text: String::from(source.lines().nth(line - 1).unwrap_or_default()),
+        }
+    }
+}
+
+/// Render `message` with the file name, position and source line of
+/// `location`, and a `^~~~` underline beneath the part it's about.
+pub fn render(message: &str, location: &Location) -> String {
+    let number = location.line.to_string();
+    let gutter = " ".repeat(number.len());
+    // Keep tabs so that the underline lines up however they're displayed
+    let indent: String = location
+        .text
+        .chars()
+        .take(location.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "{}:{}:{}: {}\n {} | {}\n {} | {}^{}",
+        location.file.display(),
+        location.line,
+        location.column,
+        message,
+        number,
+        location.text,
+        gutter,
+        indent,
+        "~".repeat(location.len - 1)
+    )
+}
+
+/// The length of the token starting at `column` in `text`: a run of
+/// identifier characters, or a single character otherwise.
+pub fn token_len(text: &str, column: usize) -> usize {
+    let word = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    let mut rest = text.chars().skip(column - 1).peekable();
+    match rest.peek() {
+        Some(c) if word(c) => rest.take_while(word).count(),
+        _ => 1,
+    }
+}
+
+/// A kind of warning, named as in `-Werror=<name>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Warning {
+    /// A `#warning` directive
+    Cpp,
+    /// A character constant holding more than one character
+    Multichar,
+    /// Constant arithmetic that overflows an `int`
+    Overflow,
+}
+
+/// A warning found in a source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub warning: Warning,
+    pub message: String,
+}
+
+/// Every kind of warning, to look names up in
+const WARNINGS: [Warning; 3] = [Warning::Cpp, Warning::Multichar, Warning::Overflow];
+
+impl Warning {
+    /// The name used in `-W` options, the same as GCC's for the warning.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cpp => "cpp",
+            Self::Multichar => "multichar",
+            Self::Overflow => "overflow",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        WARNINGS
+            .iter()
+            .find(|warning| warning.name() == name)
+            .copied()
+    }
+}
+
+/// Report each warning, unless the output is quiet, or return them as one
+/// error if the configuration makes any of them errors.
+pub fn warn(config: &Config, warnings: &[Diagnostic]) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for Diagnostic { warning, message } in warnings {
+        let promoted = match &config.warnings_as_errors {
+            WarningsAsErrors::None => false,
+            WarningsAsErrors::All => true,
+            WarningsAsErrors::Only(promoted) => promoted.contains(warning),
+        };
+        if promoted {
+            errors.push(format!("{} [-Werror={}]", message, warning.name()));
+        } else if config.verbosity != Verbosity::Quiet {
+            eprintln!("warning: {} [-W{}]", message, warning.name());
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underline_token() {
+        let source = "int main() {\n    return 1 + foo;\n}\n";
+        let location = Location::new(Path::new("prog.c"), source, 2, 16, 3);
+        assert_eq!(
+            render("Syntax Error: Invalid factor", &location),
+            "prog.c:2:16: Syntax Error: Invalid factor\n \
+             2 |     return 1 + foo;\n   \
+             |                ^~~"
+        );
+    }
+
+    #[test]
+    fn tabs_are_kept() {
+        let location = Location::new(Path::new("a.c"), "\treturn 1 }", 1, 11, 1);
+        assert_eq!(
+            render("Syntax Error: Expected semicolon", &location),
+            "a.c:1:11: Syntax Error: Expected semicolon\n 1 | \treturn 1 }\n   | \t         ^"
+        );
+    }
+
+    #[test]
+    fn past_the_end() {
+        // Errors at the end of the input point just after the last character
+        let location = Location::new(Path::new("a.c"), "int main() {", 1, 13, 1);
+        assert_eq!(
+            render("Syntax Error: Expected closing brace", &location),
+            "a.c:1:13: Syntax Error: Expected closing brace\n 1 | int main() {\n   |             ^"
+        );
+    }
+
+    #[test]
+    fn token_lengths() {
+        assert_eq!(token_len("return foo_1;", 1), 6);
+        assert_eq!(token_len("return foo_1;", 8), 5);
+        assert_eq!(token_len("return foo_1;", 13), 1);
+        assert_eq!(token_len("x", 2), 1);
+    }
+
+    #[test]
+    fn warning_names() {
+        assert_eq!(Warning::parse("cpp"), Some(Warning::Cpp));
+        assert_eq!(Warning::parse("multichar"), Some(Warning::Multichar));
+        assert_eq!(Warning::parse("overflow"), Some(Warning::Overflow));
+        assert_eq!(Warning::parse(Warning::Cpp.name()), Some(Warning::Cpp));
+        assert_eq!(Warning::parse("unused-variable"), None);
+    }
+
+    #[test]
+    fn promoted_warnings() {
+        let warnings = [Diagnostic {
+            warning: Warning::Cpp,
+            message: String::from("a.c:1: #warning one"),
+        }];
+        let quiet = |warnings_as_errors| Config {
+            warnings_as_errors,
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        assert_eq!(warn(&quiet(WarningsAsErrors::None), &warnings), Ok(()));
+        assert_eq!(
+            warn(&quiet(WarningsAsErrors::Only(vec![])), &warnings),
+            Ok(())
+        );
+        assert_eq!(
+            warn(&quiet(WarningsAsErrors::All), &warnings),
+            Err(String::from("a.c:1: #warning one [-Werror=cpp]"))
+        );
+        assert_eq!(
+            warn(
+                &quiet(WarningsAsErrors::Only(vec![Warning::Cpp])),
+                &warnings
+            ),
+            Err(String::from("a.c:1: #warning one [-Werror=cpp]"))
+        );
+    }
+}
@@ -0,0 +1,574 @@
+//! Compiling source files to executables and other outputs on disk, with
+//! the system toolchain. This needs processes and a filesystem, so it's
+//! behind the `driver` feature.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::config::{self, Emit};
+use super::error::{CompilerError, IoContext};
+use super::observer::Observer;
+use super::{
+    assembler, ast, backend, cache, check_cancelled, codegen_error, diagnostic, generate_assembly,
+    get_exe_file, in_out_dir, interp, lexer, object, output_path, parse_source, preprocessor, qbe,
+    replace_ext, source_map, step, target, toolchain, write_output, CompileOutput, StageCost,
+    StageTimings,
+};
+
+fn get_temp_assembly_file(input_file: &Path) -> PathBuf {
+    replace_ext(input_file, "s")
+}
+
+/// A translation unit that has been compiled to assembly
+struct Unit {
+    /// The source file, or `<stdin>`
+    source: PathBuf,
+    /// The functions the unit defines, for detecting duplicates when linking
+    symbols: Vec<String>,
+    code: String,
+    /// Which lines of the code came from which statements, if it's wanted
+    map: Option<source_map::SourceMap>,
+    /// Where to cache the unit's object file once it's built
+    key: Option<cache::Key>,
+    /// An object file for the unit from an earlier build, used instead of
+    /// the code
+    cached: Option<PathBuf>,
+}
+
+/// A source file that's ready for code generation
+enum Checked {
+    /// Parsed, with where to cache its object file
+    Parsed(ast::Node, Option<cache::Key>),
+    /// Compiled by an earlier build
+    Cached(Unit),
+}
+
+/// Read and preprocess a source file, or stdin, returning the text with
+/// where its lines came from and the warnings reported, and the name to
+/// report the source by.
+fn read_source<'a>(
+    filename: &'a Path,
+    config: &config::Config,
+    timings: &mut StageTimings,
+) -> Result<(preprocessor::Preprocessed, &'a Path), CompilerError> {
+    let (contents, source) = if config::is_stdin(filename) {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .context("read", Path::new("<stdin>"))?;
+        (contents, Path::new("<stdin>"))
+    } else {
+        (
+            fs::read_to_string(filename).context("read", filename)?,
+            filename,
+        )
+    };
+    if config.emits(Emit::SemanticTokens) {
+        // Classified as written, so the positions are the ones an editor has
+        let text: String = lexer::semantic_tokens(&contents)
+            .iter()
+            .map(|t| {
+                format!(
+                    "{}:{}: {} {}\n",
+                    t.span.line, t.span.column, t.category, t.len
+                )
+            })
+            .collect();
+        write_output(source, Emit::SemanticTokens, text.as_bytes(), config)?;
+    }
+    check_cancelled(config)?;
+    let name = format!("preprocess {}", source.display());
+    let cost = &mut timings.preprocess;
+    let preprocessed = match config.preprocessor {
+        config::Preprocessor::System => step(config, cost, &name, || {
+            toolchain::preprocess(config, filename, &contents)
+                .map(preprocessor::Preprocessed::unmapped)
+        }),
+        config::Preprocessor::Integrated => step(config, cost, &name, || {
+            let output = preprocessor::preprocess(&contents, source, &config.include_dirs)?;
+            diagnostic::warn(config, &output.warnings)?;
+            Ok(output)
+        }),
+        config::Preprocessor::Off => Ok(preprocessor::Preprocessed::unmapped(contents)),
+    }
+    .map_err(|e| CompilerError::Preprocess(e.to_string()))?;
+    Ok((preprocessed, source))
+}
+
+/// Evaluate the function `function` in `filename` with the interpreter.
+pub fn interpret_file(
+    filename: &Path,
+    function: &str,
+    config: &config::Config,
+) -> Result<i32, CompilerError> {
+    let mut timings = StageTimings::default();
+    let (preprocessed, source) = read_source(filename, config, &mut timings)?;
+    match parse_source(&preprocessed, source, config, &mut timings)? {
+        Some(ast) => step(
+            config,
+            &mut StageCost::default(),
+            &format!("interpret {}", source.display()),
+            || interp::call(&ast, function),
+        )
+        .map_err(|e| CompilerError::Runtime(e.to_string())),
+        // Stopped before there was anything to run
+        None => Ok(0),
+    }
+}
+
+/// Run a single source file's `main` with the interpreter instead of
+/// compiling it, returning the value it returns.
+pub fn interpret(config: &config::Config) -> Result<i32, CompilerError> {
+    match &config.filenames[..] {
+        [filename] => interpret_file(filename, "main", config),
+        _ => Err(CompilerError::Usage(String::from(
+            "--interpret takes a single source file",
+        ))),
+    }
+}
+
+/// Report a function defined by more than one unit, which would otherwise
+/// only be caught by the linker.
+fn check_duplicate_symbols<'a>(
+    units: impl IntoIterator<Item = &'a Unit>,
+) -> Result<(), CompilerError> {
+    let mut defined: HashMap<&str, &Path> = HashMap::new();
+    for unit in units {
+        for symbol in &unit.symbols {
+            if let Some(first) = defined.insert(symbol, &unit.source) {
+                let message = format!(
+                    "Duplicate symbol `{}` defined in {} and {}",
+                    symbol,
+                    first.display(),
+                    unit.source.display()
+                );
+                return Err(CompilerError::Link(message));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a unit's assembly, running it through qbe first when that backend is
+/// selected, and return the path of the assembly file.
+fn write_assembly(
+    filename: &Path,
+    code: &str,
+    config: &config::Config,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let output_file = get_temp_assembly_file(filename);
+    if config.backend != config::BackendKind::Qbe {
+        fs::write(&output_file, code).context("write assembly to", &output_file)?;
+        return Ok(output_file);
+    }
+
+    let il_file = replace_ext(filename, "ssa");
+    fs::write(&il_file, code).context("write QBE IL to", &il_file)?;
+    let mut command = Command::new("qbe");
+    command
+        .args(["-t", qbe::Qbe::qbe_target(&config.target)])
+        .arg("-o")
+        .arg(&output_file)
+        .arg(&il_file);
+    if config.dry_run {
+        eprintln!("{}", toolchain::command_line(&command));
+        return Ok(output_file);
+    }
+    if config.verbosity == config::Verbosity::Verbose {
+        eprintln!("rcc: running {}", toolchain::command_line(&command));
+    }
+    tracing::debug!(command = %toolchain::command_line(&command), "running");
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            return Err("qbe not found; use -S to write the IL without compiling it".into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).into_owned().into());
+    }
+    Ok(output_file)
+}
+
+/// Assemble a unit's code with the integrated assembler, writing an object
+/// file to `path`.
+fn write_object(path: &Path, code: &str, config: &config::Config) -> Result<(), Box<dyn Error>> {
+    let supported =
+        config.target.arch == target::Arch::X86_64 && config.backend == config::BackendKind::Native;
+    if !supported {
+        return Err("The integrated assembler only supports the native x86-64 backend".into());
+    }
+    let object = assembler::assemble(code, config.target.os)?;
+    let bytes = match config.target.os {
+        target::Os::Linux => object::elf::write(&object),
+        target::Os::MacOs => object::macho::write(&object),
+    };
+    fs::write(path, bytes).context("write object file to", path)?;
+    Ok(())
+}
+
+/// Compile the configured sources, returning the files produced, and print
+/// what each stage cost with `--time-passes`.
+pub fn compile(config: &config::Config) -> Result<CompileOutput, CompilerError> {
+    let output = compile_sources(config)?;
+    if config.time_passes {
+        eprint!("{}", output.timings);
+    }
+    Ok(output)
+}
+
+fn compile_sources(config: &config::Config) -> Result<CompileOutput, CompilerError> {
+    let first_file = match config.filenames.first() {
+        Some(filename) => filename,
+        None => return Err(CompilerError::Usage(String::from("No input files"))),
+    };
+
+    let linking = config.emits(Emit::Executable);
+    if let Some(output) = &config.output {
+        let usage_error = |message: &str| Err(CompilerError::Usage(String::from(message)));
+        if config.emit.len() > 1 {
+            return usage_error("Cannot use -o with more than one kind of output");
+        }
+        if !linking && config.filenames.len() > 1 {
+            return usage_error("Cannot use -o with multiple files unless linking");
+        }
+        if config::is_stdin(output) && (linking || config.emits(Emit::Object)) {
+            return usage_error("Cannot write object files or executables to standard output");
+        }
+    }
+    if let Some(dir) = &config.out_dir {
+        fs::create_dir_all(dir).context("create output directory", dir)?;
+    }
+    let objects = linking || config.emits(Emit::Object);
+    let mut output = CompileOutput::default();
+    let timings = &mut output.timings;
+    let assembling = objects || config.emits(Emit::Assembly) || config.emits(Emit::AsmMap);
+
+    // Intermediate files go in a temporary directory, which is removed when
+    // it goes out of scope, unless they're being kept next to the sources
+    let temp_dir = if config.keep_intermediates {
+        None
+    } else {
+        Some(tempfile::tempdir().context("create a temporary directory in", &env::temp_dir())?)
+    };
+
+    // Only object files are cached, and other outputs need every stage to run
+    let cache = if config.emit.len() <= 1 && linking && config.stop_after.is_none() {
+        cache::Cache::for_config(config)
+    } else {
+        None
+    };
+
+    // Every source is checked before any code is generated, so that all of
+    // their errors are reported together
+    let mut checked = Vec::new();
+    let mut errors = Vec::new();
+    for (i, filename) in config.filenames.iter().enumerate() {
+        let (preprocessed, source) = match read_source(filename, config, timings) {
+            Ok((mut preprocessed, source)) => {
+                output.diagnostics.append(&mut preprocessed.warnings);
+                (preprocessed, source)
+            }
+            Err(e) if e.is_source_error() => {
+                errors.push(e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let base = match (&temp_dir, config::is_stdin(filename)) {
+            // Sources from different directories may share a name
+            (Some(dir), _) => {
+                let name = source.file_name().unwrap_or_default().to_string_lossy();
+                dir.path().join(format!("{}-{}", i, name))
+            }
+            (None, true) => in_out_dir(PathBuf::from("stdin.c"), config),
+            (None, false) => in_out_dir(filename.clone(), config),
+        };
+        // Sources are parsed even when their object is cached, so that their
+        // warnings are reported every time
+        let ast = match parse_source(&preprocessed, source, config, timings) {
+            Ok(Some(ast)) => ast,
+            Ok(None) => continue,
+            Err(e) if e.is_source_error() => {
+                errors.push(e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let key = cache
+            .as_ref()
+            .map(|_| cache::Key::new(config, source, &preprocessed.text));
+        let hit = cache
+            .as_ref()
+            .zip(key)
+            .and_then(|(cache, key)| cache.get(key));
+        if let Some(key) = key {
+            tracing::debug!(source = %source.display(), %key, hit = hit.is_some(), "object cache");
+        }
+        if let Some((object, symbols)) = hit {
+            if config.verbosity == config::Verbosity::Verbose {
+                eprintln!("rcc: using cached object for {}", source.display());
+            }
+            let unit = Unit {
+                source: source.to_path_buf(),
+                symbols,
+                code: String::new(),
+                map: None,
+                key: None,
+                cached: Some(object),
+            };
+            checked.push((base, source, Checked::Cached(unit)));
+        } else {
+            checked.push((base, source, Checked::Parsed(ast, key)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(CompilerError::report(errors, config.error_limit));
+    }
+
+    // Each unit is paired with the path its intermediate files are named after
+    let mut units = Vec::new();
+    for (base, source, checked) in checked {
+        let (ast, key) = match checked {
+            Checked::Parsed(ast, key) => (ast, key),
+            Checked::Cached(unit) => {
+                units.push((base, unit));
+                continue;
+            }
+        };
+        if config.emits(Emit::LlvmIr) {
+            check_cancelled(config)?;
+            let ir = step(
+                config,
+                &mut timings.codegen,
+                &format!("codegen {} to LLVM IR", source.display()),
+                || backend::generate(&*backend::llvm_ir(config), &ast),
+            )
+            .map_err(|e| codegen_error(e, Some(source)))?;
+            config.observers.ir(source, &ir);
+            write_output(source, Emit::LlvmIr, ir.as_bytes(), config)?;
+        }
+        if assembling {
+            if let Some((code, map)) = generate_assembly(&ast, source, config, timings)? {
+                let unit = Unit {
+                    source: source.to_path_buf(),
+                    symbols: ast.function_names().into_iter().map(String::from).collect(),
+                    code,
+                    map,
+                    key,
+                    cached: None,
+                };
+                units.push((base, unit));
+            }
+        }
+    }
+
+    if config.emits(Emit::Assembly) {
+        for (_, unit) in &units {
+            write_output(&unit.source, Emit::Assembly, unit.code.as_bytes(), config)?;
+            output
+                .assembly
+                .extend(output_path(&unit.source, Emit::Assembly, config));
+        }
+    }
+    for (_, unit) in &units {
+        if let Some(map) = &unit.map {
+            let json = format!("{}\n", map);
+            write_output(&unit.source, Emit::AsmMap, json.as_bytes(), config)?;
+        }
+    }
+    if !objects || units.is_empty() {
+        // Nothing more was requested, or stopped before code generation
+        return Ok(output);
+    }
+
+    if linking {
+        check_duplicate_symbols(units.iter().map(|(_, unit)| unit))?;
+    }
+    let mut object_files = Vec::new();
+    for (base, unit) in &units {
+        let object = match output_path(&unit.source, Emit::Object, config) {
+            Some(path) if config.emits(Emit::Object) => path,
+            _ => replace_ext(base, "o"),
+        };
+        build_object(base, unit, &object, cache.as_ref(), config, timings)?;
+        object_files.push(object);
+    }
+    if !linking {
+        return Ok(output);
+    }
+
+    check_cancelled(config)?;
+    let exe_file = match &config.output {
+        Some(path) => path.clone(),
+        None => in_out_dir(get_exe_file(first_file), config),
+    };
+    step(config, &mut timings.link, "link", || {
+        toolchain::link(config, &object_files, &exe_file)
+    })
+    .map_err(|e| CompilerError::Link(e.to_string()))?;
+    if config.verbosity == config::Verbosity::Verbose && !config.dry_run {
+        let size = fs::metadata(&exe_file).context("read", &exe_file)?.len();
+        eprintln!("rcc: {} is {} bytes", exe_file.display(), size);
+    }
+    output.executable = Some(exe_file);
+    Ok(output)
+}
+
+/// Compile an executable into a temporary directory and run it with `args`,
+/// letting it use this process's standard streams. Returns the program's exit
+/// status, or 128 plus the signal number if a signal killed it. With
+/// `--interpret`, the program is interpreted instead and `args` are unused.
+pub fn run(mut config: config::Config, args: &[String]) -> Result<i32, CompilerError> {
+    if config.interpret {
+        return interpret(&config);
+    }
+    if config.emit.iter().any(|&emit| emit != Emit::Executable) || config.stop_after.is_some() {
+        return Err(CompilerError::Usage(String::from(
+            "rcc run only builds executables",
+        )));
+    }
+    let dir = tempfile::tempdir().context("create a temporary directory in", &env::temp_dir())?;
+    let exe = dir.path().join("a.out");
+    config.output = Some(exe.clone());
+    compile(&config)?;
+
+    let status = Command::new(&exe)
+        .args(args)
+        .status()
+        .context("run", &exe)?;
+    Ok(match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    })
+}
+
+/// How often watch mode checks the sources for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Compile, then compile again whenever a source file changes, reporting
+/// the result each time. This never returns.
+pub fn watch(config: &config::Config) -> ! {
+    let modified = || -> io::Result<Vec<SystemTime>> {
+        config
+            .filenames
+            .iter()
+            .map(|filename| fs::metadata(filename)?.modified())
+            .collect()
+    };
+    let mut last = None;
+    let mut first = true;
+    loop {
+        // Editors can briefly remove a file while saving it, so only a
+        // complete set of times counts as a change
+        let current = modified().ok();
+        if first || (current.is_some() && current != last) {
+            first = false;
+            last = current;
+            match compile(config) {
+                Ok(_) if config.verbosity != config::Verbosity::Quiet => {
+                    eprintln!("rcc: compiled, watching for changes")
+                }
+                Ok(_) => (),
+                Err(e) => eprintln!("Compilation error: {}", e),
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Assemble a unit into `object`, or copy its object from an earlier build,
+/// adding new objects to the cache if there is one.
+fn build_object(
+    base: &Path,
+    unit: &Unit,
+    object: &Path,
+    cache: Option<&cache::Cache>,
+    config: &config::Config,
+    timings: &mut StageTimings,
+) -> Result<(), CompilerError> {
+    check_cancelled(config)?;
+    if let Some(cached) = &unit.cached {
+        fs::copy(cached, object).context("copy cached object to", object)?;
+        return Ok(());
+    }
+    let link_error =
+        |e: Box<dyn Error>| CompilerError::Link(format!("{}: {}", unit.source.display(), e));
+    let name = format!("assemble {}", unit.source.display());
+    if config.integrated_as {
+        step(config, &mut timings.assemble, &name, || {
+            write_object(object, &unit.code, config)
+        })
+        .map_err(link_error)?;
+    } else {
+        let assembly = write_assembly(base, &unit.code, config)
+            .map_err(|e| CompilerError::Codegen(e.to_string()))?;
+        step(config, &mut timings.assemble, &name, || {
+            toolchain::assemble(config, &assembly, object)
+        })
+        .map_err(link_error)?;
+    }
+    // A dry run doesn't produce the object, and a failure to cache it only
+    // costs the next build some time
+    if let (Some(cache), Some(key), false) = (cache, unit.key, config.dry_run) {
+        if let Err(e) = cache.put(key, object, &unit.symbols) {
+            if config.verbosity == config::Verbosity::Verbose {
+                eprintln!("rcc: couldn't cache {}: {}", unit.source.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembly_file_names() {
+        let cases = [
+            ("test.c", "test.s"),
+            ("mydir/src.c", "mydir/src.s"),
+            ("src.c.c", "src.c.s"),
+            ("/my/abs/path/to/file.c", "/my/abs/path/to/file.s"),
+        ];
+        for (input, output) in &cases {
+            assert_eq!(
+                get_temp_assembly_file(&PathBuf::from(input)),
+                PathBuf::from(output)
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_symbols() {
+        let unit = |source: &str, name: &str| Unit {
+            source: PathBuf::from(source),
+            symbols: vec![String::from(name)],
+            code: String::new(),
+            map: None,
+            key: None,
+            cached: None,
+        };
+        assert!(check_duplicate_symbols(&[unit("a.c", "main"), unit("b.c", "foo")]).is_ok());
+        assert_eq!(
+            check_duplicate_symbols(&[
+                unit("a.c", "main"),
+                unit("b.c", "foo"),
+                unit("c.c", "main"),
+            ])
+            .unwrap_err()
+            .to_string(),
+            "Duplicate symbol `main` defined in a.c and c.c"
+        );
+    }
+}
@@ -1,5 +1,14 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::path::Path;
+
+use super::ast::Jump;
+use super::diagnostic::{self, Location};
+
+mod explanations;
+
+pub use explanations::explain;
 
 #[derive(Debug, PartialEq)]
 pub enum SyntaxError {
@@ -12,30 +21,289 @@ pub enum SyntaxError {
     MissingIdentifier,
     MissingKeyword(String),
     InvalidIdentifier(String),
+    InvalidIntLiteral(String),
     InvalidExpression,
     InvalidFactor,
     UnexpectedToken,
+    NestedTooDeeply,
+    UnterminatedString,
+    UnterminatedChar,
+    EmptyCharConstant,
+    InvalidEscape(String),
+    EscapeOutOfRange(String),
+}
+
+impl SyntaxError {
+    /// The error's code, which `rcc --explain` describes. Codes never change
+    /// meaning, even if the error is no longer reported.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Unknown => "E0100",
+            Self::MissingSemicolon => "E0101",
+            Self::MissingOpenParen => "E0102",
+            Self::MissingCloseParen => "E0103",
+            Self::MissingOpenBrace => "E0104",
+            Self::MissingCloseBrace => "E0105",
+            Self::MissingIdentifier => "E0106",
+            Self::MissingKeyword(_) => "E0107",
+            Self::InvalidIdentifier(_) => "E0108",
+            Self::InvalidIntLiteral(_) => "E0109",
+            Self::InvalidExpression => "E0110",
+            Self::InvalidFactor => "E0111",
+            Self::UnexpectedToken => "E0112",
+            Self::NestedTooDeeply => "E0113",
+            Self::UnterminatedString => "E0114",
+            Self::UnterminatedChar => "E0115",
+            Self::EmptyCharConstant => "E0116",
+            Self::InvalidEscape(_) => "E0117",
+            Self::EscapeOutOfRange(_) => "E0118",
+        }
+    }
 }
 
 // TODO: write_error! macro
 
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Syntax Error [{}]: ", self.code())?;
         match self {
-            Self::InvalidIdentifier(id) => write!(f, "Syntax Error: Invalid identifier: {}", id),
-            Self::MissingKeyword(kw) => write!(f, "Syntax Error: Expected \"{}\" keyword", kw),
-            Self::MissingOpenParen => write!(f, "Syntax Error: Expected opening parenthesis"),
-            Self::MissingCloseParen => write!(f, "Syntax Error: Expected closing parenthesis"),
-            Self::MissingOpenBrace => write!(f, "Syntax Error: Expected opening brace"),
-            Self::MissingCloseBrace => write!(f, "Syntax Error: Expected closing brace"),
-            Self::MissingSemicolon => write!(f, "Syntax Error: Expected semicolon"),
-            Self::MissingIdentifier => write!(f, "Syntax Error: Expected identifier"),
-            Self::InvalidExpression => write!(f, "Syntax Error: Invalid expression"),
-            Self::InvalidFactor => write!(f, "Syntax Error: Invalid factor"),
-            Self::UnexpectedToken => write!(f, "Syntax Error: Uexpected token"),
-            SyntaxError::Unknown => write!(f, "Syntax Error: Unknown error"),
+            Self::InvalidIdentifier(id) => write!(f, "Invalid identifier: {}", id),
+            Self::InvalidIntLiteral(literal) => {
+                write!(f, "Invalid integer literal: {}", literal)
+            }
+            Self::MissingKeyword(kw) => write!(f, "Expected \"{}\" keyword", kw),
+            Self::MissingOpenParen => write!(f, "Expected opening parenthesis"),
+            Self::MissingCloseParen => write!(f, "Expected closing parenthesis"),
+            Self::MissingOpenBrace => write!(f, "Expected opening brace"),
+            Self::MissingCloseBrace => write!(f, "Expected closing brace"),
+            Self::MissingSemicolon => write!(f, "Expected semicolon"),
+            Self::MissingIdentifier => write!(f, "Expected identifier"),
+            Self::InvalidExpression => write!(f, "Invalid expression"),
+            Self::InvalidFactor => write!(f, "Invalid factor"),
+            Self::UnexpectedToken => write!(f, "Unexpected token"),
+            Self::NestedTooDeeply => write!(f, "Expression or statement nested too deeply"),
+            Self::UnterminatedString => write!(f, "Unterminated string literal"),
+            Self::UnterminatedChar => write!(f, "Unterminated character constant"),
+            Self::EmptyCharConstant => write!(f, "Empty character constant"),
+            Self::InvalidEscape(escape) => write!(f, "Invalid escape sequence: {}", escape),
+            Self::EscapeOutOfRange(escape) => {
+                write!(f, "Escape sequence out of range: {}", escape)
+            }
+            SyntaxError::Unknown => write!(f, "Unknown error"),
         }
     }
 }
 
 impl Error for SyntaxError {}
+
+/// A program that parses but breaks one of the language's constraints.
+#[derive(Debug, PartialEq)]
+pub enum SemanticError {
+    /// A `break` or `continue` that isn't in a loop
+    JumpOutsideLoop(Jump),
+}
+
+impl SemanticError {
+    /// The error's code, which `rcc --explain` describes, numbered after
+    /// the syntax errors.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::JumpOutsideLoop(_) => "E0200",
+        }
+    }
+}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Semantic Error [{}]: ", self.code())?;
+        match self {
+            Self::JumpOutsideLoop(Jump::Break) => write!(f, "break statement not within a loop"),
+            Self::JumpOutsideLoop(Jump::Continue) => {
+                write!(f, "continue statement not within a loop")
+            }
+        }
+    }
+}
+
+impl Error for SemanticError {}
+
+/// An error from compilation, by the part of compilation that failed.
+#[derive(Debug)]
+pub enum CompilerError {
+    /// The driver was used incorrectly
+    Usage(String),
+    /// A file couldn't be read or written
+    Io(io::Error),
+    /// The preprocessor rejected the source, or couldn't be run
+    Preprocess(String),
+    Lex(SyntaxError, Location),
+    Parse(SyntaxError, Location),
+    Semantic(String),
+    Codegen(String),
+    /// Assembling or linking failed
+    Link(String),
+    /// An interpreted program did something that would trap when compiled
+    Runtime(String),
+    /// Several errors, reported together so that they can be fixed at once
+    Report(Report),
+    /// The configuration's cancellation token was used
+    Cancelled,
+}
+
+/// Errors in the sources found in one invocation.
+#[derive(Debug)]
+pub struct Report {
+    pub errors: Vec<CompilerError>,
+    /// How many more errors there were beyond the limit
+    pub omitted: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        if self.omitted > 0 {
+            write!(
+                f,
+                "\n{} more errors not shown; see --error-limit",
+                self.omitted
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl CompilerError {
+    /// Combine errors into one, keeping the first `limit` of them, or all of
+    /// them if `limit` is 0.
+    pub fn report(errors: Vec<CompilerError>, limit: usize) -> Self {
+        let mut all = Vec::new();
+        let mut omitted = 0;
+        for error in errors {
+            match error {
+                Self::Report(report) => {
+                    all.extend(report.errors);
+                    omitted += report.omitted;
+                }
+                error => all.push(error),
+            }
+        }
+        if limit != 0 && all.len() > limit {
+            omitted += all.len() - limit;
+            all.truncate(limit);
+        }
+        if all.len() == 1 && omitted == 0 {
+            return all.pop().unwrap();
+        }
+        Self::Report(Report {
+            errors: all,
+            omitted,
+        })
+    }
+
+    /// Whether the error is a problem with a source file, which compilation
+    /// can carry on past to find any others.
+    pub fn is_source_error(&self) -> bool {
+        matches!(
+            self,
+            Self::Preprocess(_)
+                | Self::Lex(..)
+                | Self::Parse(..)
+                | Self::Semantic(_)
+                | Self::Report(_)
+        )
+    }
+
+    /// The exit status the `rcc` binary uses for this kind of failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            // The first error is the one to fix first
+            Self::Report(report) => report.errors.first().map_or(1, Self::exit_code),
+            Self::Usage(_) | Self::Io(_) => 1,
+            Self::Preprocess(_) | Self::Lex(..) => 2,
+            Self::Parse(..) => 3,
+            Self::Semantic(_) => 4,
+            Self::Link(_) => 5,
+            Self::Codegen(_) => 6,
+            Self::Runtime(_) => 7,
+            // As for a shell command stopped by Ctrl-C
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Lex(e, location) | Self::Parse(e, location) => {
+                write!(f, "{}", diagnostic::render(&e.to_string(), location))
+            }
+            Self::Report(report) => write!(f, "{}", report),
+            Self::Cancelled => write!(f, "Compilation cancelled"),
+            Self::Usage(message)
+            | Self::Preprocess(message)
+            | Self::Semantic(message)
+            | Self::Codegen(message)
+            | Self::Link(message)
+            | Self::Runtime(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for CompilerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Lex(e, _) | Self::Parse(e, _) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CompilerError {
+    fn from(error: io::Error) -> Self {
+        CompilerError::Io(error)
+    }
+}
+
+/// Adds what was being done, and to which file, to I/O errors, which
+/// otherwise only say what went wrong.
+pub trait IoContext<T> {
+    /// Describe the error as in `cannot write assembly to foo.s: Permission
+    /// denied`, for `action` "write assembly to".
+    fn context(self, action: &str, path: &Path) -> io::Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn context(self, action: &str, path: &Path) -> io::Result<T> {
+        self.map_err(|e| {
+            let message = format!("cannot {} {}: {}", action, path.display(), e);
+            io::Error::new(e.kind(), message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_name_the_file() {
+        let error = std::fs::read_to_string("/nonexistent/foo.c")
+            .context("read", Path::new("/nonexistent/foo.c"))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(
+            error
+                .to_string()
+                .starts_with("cannot read /nonexistent/foo.c: "),
+            "{}",
+            error
+        );
+    }
+}
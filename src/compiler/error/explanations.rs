@@ -0,0 +1,347 @@
+//! Longer descriptions of each error code, for `rcc --explain`.
+
+/// Each code with its description, which starts with the code and a summary
+/// and, where the error can be caused on purpose, shows an example and its fix.
+const EXPLANATIONS: [(&str, &str); 20] = [
+    (
+        "E0100",
+        "E0100: unknown syntax error
+
+The lexer failed in a way that none of the more specific errors describe.
+This shouldn't happen; please report the source that caused it.",
+    ),
+    (
+        "E0101",
+        "E0101: expected `;`
+
+Every statement ends with a semicolon.
+
+Erroneous code example:
+
+    int main() { return 1 }
+
+Add the missing semicolon:
+
+    int main() { return 1; }",
+    ),
+    (
+        "E0102",
+        "E0102: expected `(`
+
+A function's name is followed by its parameter list in parentheses, which
+may be empty.
+
+Erroneous code example:
+
+    int main { return 0; }
+
+Add the parameter list:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0103",
+        "E0103: expected `)`
+
+An opening parenthesis, around a parameter list or an expression, has no
+matching closing parenthesis.
+
+Erroneous code example:
+
+    int main() { return (1 + 2; }
+
+Close the parenthesis:
+
+    int main() { return (1 + 2); }",
+    ),
+    (
+        "E0104",
+        "E0104: expected `{`
+
+A function's body is a block in braces, after its parameter list.
+
+Erroneous code example:
+
+    int main() return 0;
+
+Put the body in braces:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0105",
+        "E0105: expected `}`
+
+A function's body has no closing brace, or has more than one statement,
+which isn't supported yet.
+
+Erroneous code example:
+
+    int main() { return 0;
+
+Close the body:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0106",
+        "E0106: expected an identifier
+
+A function's return type is followed by its name.
+
+Erroneous code example:
+
+    int () { return 0; }
+
+Name the function:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0107",
+        "E0107: expected a keyword
+
+A particular keyword was expected, such as the return type `int` at the start
+of a function.
+
+Erroneous code example:
+
+    main() { return 0; }
+
+Give the function its return type:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0108",
+        "E0108: invalid identifier
+
+Identifiers start with a letter, followed by letters, digits and
+underscores.
+
+Erroneous code example:
+
+    int $main() { return 0; }
+
+Use only the characters identifiers can contain:
+
+    int main() { return 0; }",
+    ),
+    (
+        "E0109",
+        "E0109: invalid integer literal
+
+An integer literal is too large for an `int`, whose largest value is
+2147483647.
+
+Erroneous code example:
+
+    int main() { return 4294967296; }
+
+Use a value that fits:
+
+    int main() { return 42; }",
+    ),
+    (
+        "E0110",
+        "E0110: invalid expression
+
+An expression has an operator the compiler can't use there. Only unary `-`,
+`~` and `!`, and binary `+`, `-`, `*` and `/` are supported so far, as in:
+
+    int main() { return -(1 + 2) * 3; }",
+    ),
+    (
+        "E0111",
+        "E0111: invalid factor
+
+An operand was expected, such as an integer, a parenthesized expression or a
+unary operator applied to an operand, but something else was found.
+
+Erroneous code example:
+
+    int main() { return 1 + ; }
+
+Complete the expression:
+
+    int main() { return 1 + 2; }",
+    ),
+    (
+        "E0112",
+        "E0112: unexpected token
+
+A statement started with a token that can't start one. Only `return` and
+`asm` statements are supported so far.
+
+Erroneous code example:
+
+    int main() { 1; }
+
+Return the value:
+
+    int main() { return 1; }",
+    ),
+    (
+        "E0113",
+        "E0113: expression or statement nested too deeply
+
+Parentheses, unary operators and chains of binary operators can be nested
+at most 1024 deep in one expression, so that deeply nested input can't
+exhaust the compiler's stack. A statement nested in a block, an `if` or a
+`do` loop counts as 4 levels, so statements can nest about 250 deep.
+
+Erroneous code example, with 2000 pairs of parentheses:
+
+    int main() { return ((((((((((((((((((((...1...)))))))))))))))))))); }
+
+Split the expression up, or remove parentheses and braces that aren't
+needed.",
+    ),
+    (
+        "E0114",
+        "E0114: unterminated string literal
+
+A string literal has no closing `\"` before the end of its line. A string
+can't span lines, though a `\\n` escape can put a newline in one.
+
+Erroneous code example:
+
+    int main() { asm(\"nop); return 0; }
+
+Close the string:
+
+    int main() { asm(\"nop\"); return 0; }",
+    ),
+    (
+        "E0115",
+        "E0115: unterminated character constant
+
+A character constant has no closing `'` before the end of its line.
+
+Erroneous code example:
+
+    int main() { return 'a; }
+
+Close the constant:
+
+    int main() { return 'a'; }",
+    ),
+    (
+        "E0116",
+        "E0116: empty character constant
+
+A character constant must hold at least one character.
+
+Erroneous code example:
+
+    int main() { return ''; }
+
+Write the character, or `\\0` for a null character:
+
+    int main() { return '\\0'; }",
+    ),
+    (
+        "E0117",
+        "E0117: invalid escape sequence
+
+A backslash in a character constant starts an escape sequence, which is one
+of `\\'`, `\\\"`, `\\?`, `\\\\`, `\\a`, `\\b`, `\\f`, `\\n`, `\\r`, `\\t` and `\\v`, an
+octal escape of one to three octal digits, or `\\x` and hexadecimal digits.
+
+Erroneous code example:
+
+    int main() { return '\\q'; }
+
+Use one of the escape sequences, or escape the backslash itself:
+
+    int main() { return '\\\\'; }",
+    ),
+    (
+        "E0118",
+        "E0118: escape sequence out of range
+
+An octal or hexadecimal escape sequence stands for a value too large for a
+byte, whose largest value is 255, or `\\377` in octal and `\\xff` in
+hexadecimal. A hexadecimal escape takes every hexadecimal digit after it.
+
+Erroneous code example:
+
+    int main() { return '\\x100'; }
+
+Use a value that fits:
+
+    int main() { return '\\xff'; }",
+    ),
+    (
+        "E0200",
+        "E0200: break or continue statement not within a loop
+
+A `break` statement leaves the innermost loop, and a `continue` statement
+goes on to its next iteration, so each must be inside a loop.
+
+Erroneous code example:
+
+    int main() { if (1) break; return 0; }
+
+Put the statement in a loop, or return instead:
+
+    int main() { do { break; } while (1); return 0; }",
+    ),
+];
+
+/// The description of an error code such as `E0101`, ignoring case.
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ast::Jump;
+    use crate::compiler::error::{SemanticError, SyntaxError};
+
+    #[test]
+    fn every_code_explained() {
+        let errors = [
+            SyntaxError::Unknown,
+            SyntaxError::MissingSemicolon,
+            SyntaxError::MissingOpenParen,
+            SyntaxError::MissingCloseParen,
+            SyntaxError::MissingOpenBrace,
+            SyntaxError::MissingCloseBrace,
+            SyntaxError::MissingIdentifier,
+            SyntaxError::MissingKeyword(String::from("int")),
+            SyntaxError::InvalidIdentifier(String::from("$")),
+            SyntaxError::InvalidIntLiteral(String::from("0")),
+            SyntaxError::InvalidExpression,
+            SyntaxError::InvalidFactor,
+            SyntaxError::UnexpectedToken,
+            SyntaxError::NestedTooDeeply,
+            SyntaxError::UnterminatedString,
+            SyntaxError::UnterminatedChar,
+            SyntaxError::EmptyCharConstant,
+            SyntaxError::InvalidEscape(String::from("\\q")),
+            SyntaxError::EscapeOutOfRange(String::from("\\400")),
+        ];
+        let semantic = [SemanticError::JumpOutsideLoop(Jump::Break)];
+        let codes = errors
+            .iter()
+            .map(SyntaxError::code)
+            .chain(semantic.iter().map(SemanticError::code));
+        for code in codes {
+            let explanation = explain(code).unwrap();
+            assert!(explanation.starts_with(code), "{}", explanation);
+        }
+        assert_eq!(errors.len() + semantic.len(), EXPLANATIONS.len());
+    }
+
+    #[test]
+    fn lookup() {
+        assert!(explain("e0101").unwrap().starts_with("E0101: expected `;`"));
+        assert_eq!(explain("E9999"), None);
+        assert_eq!(explain(""), None);
+    }
+}
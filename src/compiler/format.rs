@@ -0,0 +1,568 @@
+//! Formatting C source, for `rcc fmt`. A source is parsed and its syntax
+//! tree written back out as C with consistent indentation and spacing, with
+//! the spelling of its literals and its comments kept from the original.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::vec;
+
+use super::ast;
+use super::config::{self, Config};
+use super::error::{CompilerError, IoContext};
+use super::lexer::{self, Category, SemanticToken};
+use super::preprocessor::Preprocessed;
+use super::{parse_source, StageTimings};
+
+const INDENT: &str = "    ";
+
+/// A line of formatted output.
+struct Line {
+    depth: usize,
+    text: String,
+    /// How many of the source's tokens have been written by the end of it
+    tokens: usize,
+}
+
+/// Writes a syntax tree out as C, a line at a time.
+struct Emitter<'a> {
+    lines: Vec<Line>,
+    text: String,
+    depth: usize,
+    tokens: usize,
+    /// How each literal was written in the source, in order, or `None` to
+    /// write them in decimal
+    literals: Option<vec::IntoIter<&'a str>>,
+}
+
+impl<'a> Emitter<'a> {
+    fn new(literals: Option<vec::IntoIter<&'a str>>) -> Self {
+        Emitter {
+            lines: Vec::new(),
+            text: String::new(),
+            depth: 0,
+            tokens: 0,
+            literals,
+        }
+    }
+
+    /// Write the text of `count` tokens.
+    fn write(&mut self, text: &str, count: usize) {
+        self.text.push_str(text);
+        self.tokens += count;
+    }
+
+    fn end_line(&mut self) {
+        self.lines.push(Line {
+            depth: self.depth,
+            text: std::mem::take(&mut self.text),
+            tokens: self.tokens,
+        });
+    }
+
+    fn node(&mut self, node: &ast::Node) {
+        match node {
+            ast::Node::Program(node) | ast::Node::Located(_, node) => self.node(node),
+            ast::Node::Function(name, attributes, body) => {
+                if attributes.inline {
+                    self.write("inline ", 1);
+                }
+                if attributes.prototype {
+                    self.write(&format!("int {}(void) {{", name), 6);
+                } else {
+                    self.write(&format!("int {}() {{", name), 5);
+                }
+                self.end_line();
+                self.statements(body);
+                self.write("}", 1);
+                self.end_line();
+            }
+            ast::Node::Block(_) => {
+                self.write("{", 1);
+                self.end_line();
+                self.statements(node);
+                self.write("}", 1);
+                self.end_line();
+            }
+            ast::Node::InlineAsm { text, volatile } => {
+                let literal = match self.literals.as_mut().and_then(Iterator::next) {
+                    Some(literal) => String::from(literal),
+                    None => string_literal(text),
+                };
+                if *volatile {
+                    self.write(&format!("asm volatile({});", literal), 6);
+                } else {
+                    self.write(&format!("asm({});", literal), 5);
+                }
+                self.end_line();
+            }
+            ast::Node::Jump(ast::Jump::Break) => {
+                self.write("break;", 2);
+                self.end_line();
+            }
+            ast::Node::Jump(ast::Jump::Continue) => {
+                self.write("continue;", 2);
+                self.end_line();
+            }
+            ast::Node::Statement(ast::Statement::Return, expr) => {
+                self.write("return ", 1);
+                self.expr(expr);
+                self.write(";", 1);
+                self.end_line();
+            }
+            ast::Node::Statement(ast::Statement::If(then, otherwise), condition) => {
+                self.write("if (", 2);
+                self.expr(condition);
+                self.write(")", 1);
+                let closed = self.branch(then);
+                match otherwise {
+                    Some(otherwise) => {
+                        self.write(if closed { " else" } else { "else" }, 1);
+                        // Keep `else if` chains flat
+                        if let ast::Node::Statement(ast::Statement::If(..), _) =
+                            otherwise.unlocated()
+                        {
+                            self.write(" ", 0);
+                            self.node(otherwise);
+                        } else if self.branch(otherwise) {
+                            self.end_line();
+                        }
+                    }
+                    None if closed => self.end_line(),
+                    None => (),
+                }
+            }
+            ast::Node::Statement(ast::Statement::DoWhile(body), condition) => {
+                self.write("do", 1);
+                let closed = self.branch(body);
+                self.write(if closed { " while (" } else { "while (" }, 2);
+                self.expr(condition);
+                self.write(");", 2);
+                self.end_line();
+            }
+            // Only sources that parse are formatted
+            ast::Node::Error => (),
+        }
+    }
+
+    /// Write the statements of a block, or a single statement, a level
+    /// deeper than the braces around them.
+    fn statements(&mut self, node: &ast::Node) {
+        self.depth += 1;
+        match node {
+            ast::Node::Block(statements) => {
+                for statement in statements {
+                    self.node(statement);
+                }
+            }
+            node => self.node(node),
+        }
+        self.depth -= 1;
+    }
+
+    /// Write the statement controlled by an `if` or `do`, whose line has been
+    /// started. A block opens on that line, and returns true to have what
+    /// follows go after the `}` that closes it.
+    fn branch(&mut self, node: &ast::Node) -> bool {
+        match node.unlocated() {
+            block @ ast::Node::Block(_) => {
+                self.write(" {", 1);
+                self.end_line();
+                self.statements(block);
+                self.write("}", 1);
+                true
+            }
+            _ => {
+                self.end_line();
+                self.statements(node);
+                false
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &ast::Expr) {
+        match expr {
+            ast::Expr::Term(term) => self.term(term),
+        }
+    }
+
+    fn term(&mut self, term: &ast::Term) {
+        match term {
+            ast::Term::Factor(factor) => self.factor(factor),
+            ast::Term::BinOp(op, t1, t2) => {
+                self.term(t1);
+                self.write(&format!(" {} ", op), 1);
+                self.term(t2);
+            }
+        }
+    }
+
+    fn factor(&mut self, factor: &ast::Factor) {
+        match factor {
+            ast::Factor::Expr(expr) => {
+                self.write("(", 1);
+                self.expr(expr);
+                self.write(")", 1);
+            }
+            ast::Factor::UnOp(op, inner) => {
+                self.write(&op.to_string(), 1);
+                // Keep `- -1` from reading as a decrement
+                if let (ast::UnOp::Negate, ast::Factor::UnOp(ast::UnOp::Negate, _)) = (op, &**inner)
+                {
+                    self.write(" ", 0);
+                }
+                self.factor(inner);
+            }
+            ast::Factor::IntLiteral(n) => {
+                let literal = match self.literals.as_mut().and_then(Iterator::next) {
+                    Some(literal) => String::from(literal),
+                    None => n.to_string(),
+                };
+                self.write(&literal, 1);
+            }
+            ast::Factor::BinOp(op, f1, f2) => {
+                self.factor(f1);
+                self.write(&format!(" {} ", op), 1);
+                self.factor(f2);
+            }
+        }
+    }
+}
+
+/// Write an expression out as C.
+pub fn expr_to_c(expr: &ast::Expr) -> String {
+    let mut emitter = Emitter::new(None);
+    emitter.expr(expr);
+    emitter.text
+}
+
+/// Quote `text` as a C string literal.
+fn string_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\r' => literal.push_str("\\r"),
+            '\0' => literal.push_str("\\0"),
+            '\\' | '"' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Write a syntax tree out as C source.
+pub fn to_c(ast: &ast::Node) -> String {
+    let mut emitter = Emitter::new(None);
+    emitter.node(ast);
+    render(&emitter.lines, &[])
+}
+
+/// The text of a token or comment in `source`.
+fn text<'a>(source: &'a str, token: &SemanticToken) -> &'a str {
+    let line = source.lines().nth(token.span.line - 1).unwrap_or_default();
+    let start = line
+        .char_indices()
+        .nth(token.span.column - 1)
+        .map_or(line.len(), |(i, _)| i);
+    let end = line[start..]
+        .char_indices()
+        .nth(token.len)
+        .map_or(line.len(), |(i, _)| start + i);
+    &line[start..end]
+}
+
+/// Where a comment goes in the output.
+enum Placement {
+    /// At the end of a line
+    After(usize),
+    /// On its own line, before a line or after them all
+    Before(usize),
+}
+
+/// Join the lines, with each comment placed after the line holding the token
+/// it followed on the same source line, or else before the line holding the
+/// token after it.
+fn render(lines: &[Line], comments: &[(Placement, &str)]) -> String {
+    let mut out = String::new();
+    for i in 0..=lines.len() {
+        // Comments before the end of a block are indented inside it
+        let depth = lines[i.saturating_sub(1)..lines.len().min(i + 1)]
+            .iter()
+            .map(|line| line.depth)
+            .max()
+            .unwrap_or(0);
+        for (_, comment) in comments
+            .iter()
+            .filter(|(place, _)| matches!(place, Placement::Before(line) if *line == i))
+        {
+            out.push_str(&INDENT.repeat(depth));
+            out.push_str(comment);
+            out.push('\n');
+        }
+        if let Some(line) = lines.get(i) {
+            out.push_str(&INDENT.repeat(line.depth));
+            out.push_str(&line.text);
+            for (_, comment) in comments
+                .iter()
+                .filter(|(place, _)| matches!(place, Placement::After(line) if *line == i))
+            {
+                out.push(' ');
+                out.push_str(comment);
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Format the source text of `filename`, or return the errors that stop it
+/// from parsing.
+pub fn format(source: &str, filename: &Path) -> Result<String, CompilerError> {
+    let config = Config {
+        verbosity: config::Verbosity::Quiet,
+        ..Default::default()
+    };
+    let unmapped = Preprocessed::unmapped(source);
+    let ast = parse_source(&unmapped, filename, &config, &mut StageTimings::default())?
+        .expect("no stage to stop after");
+    let (comments, tokens): (Vec<_>, Vec<_>) = lexer::semantic_tokens(source)
+        .into_iter()
+        .partition(|t| t.category == Category::Comment);
+    // Most GNU attributes aren't kept in the tree, so they'd be lost
+    if tokens.iter().any(|t| {
+        matches!(
+            text(source, t),
+            "__attribute__" | "__attribute" | "__extension__"
+        )
+    }) {
+        return Err(CompilerError::Usage(format!(
+            "{}: can't format __attribute__ or __extension__ yet",
+            filename.display()
+        )));
+    }
+    let literals = tokens
+        .iter()
+        .filter(|t| t.category == Category::Literal)
+        .map(|t| text(source, t))
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let mut emitter = Emitter::new(Some(literals));
+    emitter.node(&ast);
+    let lines = emitter.lines;
+    // The line holding the token at an index
+    let line_of = |index| {
+        lines
+            .iter()
+            .position(|line| line.tokens > index)
+            .unwrap_or(lines.len())
+    };
+    let placed: Vec<(Placement, &str)> = comments
+        .iter()
+        .map(|comment| {
+            let before = tokens
+                .iter()
+                .take_while(|t| {
+                    t.span.line < comment.span.line
+                        || (t.span.line == comment.span.line && t.span.column < comment.span.column)
+                })
+                .count();
+            let place = match before.checked_sub(1).map(|i| &tokens[i]) {
+                Some(token) if token.span.line == comment.span.line => {
+                    Placement::After(line_of(before - 1))
+                }
+                _ => Placement::Before(line_of(before)),
+            };
+            (place, text(source, comment).trim_end())
+        })
+        .collect();
+    Ok(render(&lines, &placed))
+}
+
+/// Format each configured source in place, or standard input to standard
+/// output, returning the files that changed. With `check`, nothing is
+/// written, and the files returned are those that would change.
+pub fn run(config: &Config, check: bool) -> Result<Vec<PathBuf>, CompilerError> {
+    if config.filenames.is_empty() {
+        return Err(CompilerError::Usage(String::from("No input files")));
+    }
+    let mut changed = Vec::new();
+    for filename in &config.filenames {
+        let (source, name) = if config::is_stdin(filename) {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .context("read", Path::new("<stdin>"))?;
+            (source, Path::new("<stdin>"))
+        } else {
+            let source = fs::read_to_string(filename).context("read", filename)?;
+            (source, filename.as_path())
+        };
+        let formatted = format(&source, name)?;
+        if formatted == source {
+            continue;
+        }
+        if !check {
+            if config::is_stdin(filename) {
+                print!("{}", formatted);
+            } else {
+                fs::write(filename, &formatted).context("write", filename)?;
+            }
+        }
+        changed.push(filename.clone());
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::error::SyntaxError;
+    use crate::compiler::parser;
+
+    fn fmt(source: &str) -> String {
+        format(source, Path::new("test.c")).unwrap()
+    }
+
+    #[test]
+    fn indentation_and_spacing() {
+        assert_eq!(
+            fmt("int   main(){return 1+2*(3-~4)/ -5;}"),
+            "int main() {\n    return 1 + 2 * (3 - ~4) / -5;\n}\n"
+        );
+        assert_eq!(
+            fmt("int main() {\n    return - -0x1F;\n}\n"),
+            "int main() {\n    return - -0x1F;\n}\n"
+        );
+    }
+
+    #[test]
+    fn comments_kept() {
+        let source = "// expect-exit: 3\nint main()   {  // entry\n// result\n  return 3 ;  // three\n  // done\n}\n// end\n";
+        assert_eq!(
+            fmt(source),
+            "// expect-exit: 3\nint main() { // entry\n    // result\n    return 3; // three\n    // done\n}\n// end\n"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for entry in fs::read_dir("tests/testfiles/valid").unwrap() {
+            let path = entry.unwrap().path();
+            let source = fs::read_to_string(&path).unwrap();
+            // Not every test file is supported by the compiler yet
+            let formatted = match format(&source, &path) {
+                Ok(formatted) => formatted,
+                Err(_) => continue,
+            };
+            assert_eq!(fmt(&formatted), formatted, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn syntax_errors_reported() {
+        assert!(matches!(
+            format("int main() { return 1 }", Path::new("test.c")),
+            Err(CompilerError::Parse(SyntaxError::MissingSemicolon, _))
+        ));
+    }
+
+    #[test]
+    fn gnu_attributes_not_formatted() {
+        let source = "__attribute__((noreturn)) int main() { return 1; }";
+        assert!(matches!(
+            format(source, Path::new("test.c")),
+            Err(CompilerError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn syntax_tree_to_c() {
+        let tokens = lexer::tokenize("int f(){return (010);}").unwrap();
+        let ast = parser::parse(&tokens).unwrap();
+        assert_eq!(to_c(&ast), "int f() {\n    return (8);\n}\n");
+    }
+
+    #[test]
+    fn inline_asm() {
+        assert_eq!(
+            fmt("int main(){asm ( \"nop\\n\\tnop\" ) ;return 0;}"),
+            "int main() {\n    asm(\"nop\\n\\tnop\");\n    return 0;\n}\n"
+        );
+        assert_eq!(
+            fmt("int main(){__asm__ __volatile__(\"nop\");return 0;}"),
+            "int main() {\n    asm volatile(\"nop\");\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn if_statements() {
+        assert_eq!(
+            fmt("int main(){if(1)if(2)return 3;else return 4;else if(5)return 6;}"),
+            "int main() {
+    if (1)
+        if (2)
+            return 3;
+        else
+            return 4;
+    else if (5)
+        return 6;
+}
+"
+        );
+        // Comments stay with the branch they follow
+        assert_eq!(
+            fmt("int main(){if(1) // one\nreturn 1; else // other\nreturn 2;}"),
+            "int main() {\n    if (1) // one\n        return 1;\n    else // other\n        return 2;\n}\n"
+        );
+    }
+
+    #[test]
+    fn blocks_and_loops() {
+        assert_eq!(
+            fmt("int main(){do{if(1){return 1;}else{}}while(2);do return 3;while(4);{}do{break;continue;}while(5);}"),
+            "int main() {
+    do {
+        if (1) {
+            return 1;
+        } else {
+        }
+    } while (2);
+    do
+        return 3;
+    while (4);
+    {
+    }
+    do {
+        break;
+        continue;
+    } while (5);
+}
+"
+        );
+    }
+
+    #[test]
+    fn inline_specifier_first() {
+        assert_eq!(
+            fmt("int inline f(){return 0;}"),
+            "inline int f() {\n    return 0;\n}\n"
+        );
+        assert_eq!(
+            fmt("int main( void ){return 0;}"),
+            "int main(void) {\n    return 0;\n}\n"
+        );
+        let tokens = lexer::tokenize("int f(){__asm__(\"mov \\\"a\\\"\\n\");return 0;}").unwrap();
+        let ast = parser::parse(&tokens).unwrap();
+        assert_eq!(
+            to_c(&ast),
+            "int f() {\n    asm(\"mov \\\"a\\\"\\n\");\n    return 0;\n}\n"
+        );
+    }
+}
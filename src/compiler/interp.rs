@@ -0,0 +1,416 @@
+//! Evaluates the AST directly, as a reference for the semantics the code
+//! generators should implement.
+//!
+//! Arithmetic wraps like two's complement hardware does, and the cases where
+//! compiled code would trap, such as division by zero, are errors.
+
+use super::ast;
+use super::lexer::Span;
+
+/// Call the function `name` defined in `ast`, returning its value, which is
+/// 0 if it ends without a return statement as `main` does.
+#[cfg(feature = "driver")]
+pub fn call(ast: &ast::Node, name: &str) -> Result<i32, &'static str> {
+    match find_function(ast, name) {
+        Some(body) => match execute(body, &mut false)? {
+            Completion::Normal => Ok(0),
+            Completion::Return(value) => Ok(value),
+            Completion::Jump(_) => unreachable!("{}", ast::JUMP_OUTSIDE_LOOP),
+        },
+        None if name == "main" => Err("No main function"),
+        None => Err("Undefined function"),
+    }
+}
+
+#[cfg(feature = "driver")]
+fn find_function<'a>(node: &'a ast::Node, name: &str) -> Option<&'a ast::Node> {
+    match node {
+        ast::Node::Program(node) | ast::Node::Located(_, node) => find_function(node, name),
+        ast::Node::Function(id, _, body) if &**id == name => Some(body),
+        ast::Node::Function(..)
+        | ast::Node::Statement(..)
+        | ast::Node::Block(_)
+        | ast::Node::Jump(_)
+        | ast::Node::InlineAsm { .. }
+        | ast::Node::Error => None,
+    }
+}
+
+/// An expression whose `int` arithmetic overflows, where it is if the
+/// tree records that, and the value it wraps around to.
+#[derive(Debug, PartialEq)]
+pub struct Overflow<'a> {
+    pub span: Option<Span>,
+    pub expr: &'a ast::Expr,
+    pub value: i32,
+}
+
+/// Find every expression in `node` that overflows. Each is a constant,
+/// so this is known without running the program.
+pub fn overflows(node: &ast::Node) -> Vec<Overflow<'_>> {
+    let mut found = Vec::new();
+    find_overflows(node, None, &mut found);
+    found
+}
+
+fn find_overflows<'a>(node: &'a ast::Node, span: Option<Span>, found: &mut Vec<Overflow<'a>>) {
+    match node {
+        ast::Node::Program(node) | ast::Node::Function(_, _, node) => {
+            find_overflows(node, span, found)
+        }
+        ast::Node::Located(span, node) => find_overflows(node, Some(*span), found),
+        ast::Node::Block(statements) => {
+            for statement in statements {
+                find_overflows(statement, span, found);
+            }
+        }
+        ast::Node::Statement(statement, expr) => {
+            let mut overflowed = false;
+            // Traps are for the code generators to handle
+            if let Ok(value) = expr.evaluate(&mut overflowed) {
+                if overflowed {
+                    found.push(Overflow { span, expr, value });
+                }
+            }
+            match statement {
+                ast::Statement::Return => (),
+                ast::Statement::If(then, otherwise) => {
+                    find_overflows(then, span, found);
+                    if let Some(otherwise) = otherwise {
+                        find_overflows(otherwise, span, found);
+                    }
+                }
+                ast::Statement::DoWhile(body) => find_overflows(body, span, found),
+            }
+        }
+        ast::Node::Jump(_) | ast::Node::InlineAsm { .. } | ast::Node::Error => (),
+    }
+}
+
+/// How running a statement ended.
+#[cfg(feature = "driver")]
+enum Completion {
+    /// By carrying on to whatever follows it
+    Normal,
+    Return(i32),
+    /// By a `break` or `continue` for the enclosing loop to handle
+    Jump(ast::Jump),
+}
+
+/// Run the statements in `node`, returning how they ended.
+#[cfg(feature = "driver")]
+fn execute(node: &ast::Node, overflowed: &mut bool) -> Result<Completion, &'static str> {
+    match node {
+        ast::Node::Program(node) | ast::Node::Located(_, node) => execute(node, overflowed),
+        ast::Node::Function(_, _, body) => execute(body, overflowed),
+        ast::Node::Statement(ast::Statement::Return, expr) => {
+            Ok(Completion::Return(expr.evaluate(overflowed)?))
+        }
+        ast::Node::Statement(ast::Statement::If(then, otherwise), condition) => {
+            if condition.evaluate(overflowed)? != 0 {
+                execute(then, overflowed)
+            } else {
+                match otherwise {
+                    Some(otherwise) => execute(otherwise, overflowed),
+                    None => Ok(Completion::Normal),
+                }
+            }
+        }
+        ast::Node::Statement(ast::Statement::DoWhile(body), condition) => loop {
+            match execute(body, overflowed)? {
+                Completion::Return(value) => return Ok(Completion::Return(value)),
+                Completion::Jump(ast::Jump::Break) => return Ok(Completion::Normal),
+                Completion::Normal | Completion::Jump(ast::Jump::Continue) => (),
+            }
+            if condition.evaluate(overflowed)? == 0 {
+                return Ok(Completion::Normal);
+            }
+        },
+        ast::Node::Block(statements) => {
+            for statement in statements {
+                match execute(statement, overflowed)? {
+                    Completion::Normal => (),
+                    completion => return Ok(completion),
+                }
+            }
+            Ok(Completion::Normal)
+        }
+        ast::Node::Jump(jump) => Ok(Completion::Jump(*jump)),
+        ast::Node::InlineAsm { .. } => Err("Inline assembly can't be interpreted"),
+        ast::Node::Error => Err("A program with syntax errors can't be interpreted"),
+    }
+}
+
+/// Evaluating sets `overflowed` when any arithmetic wraps around.
+trait Evaluate {
+    fn evaluate(&self, overflowed: &mut bool) -> Result<i32, &'static str>;
+}
+
+impl Evaluate for ast::Expr {
+    fn evaluate(&self, overflowed: &mut bool) -> Result<i32, &'static str> {
+        match self {
+            ast::Expr::Term(t) => t.evaluate(overflowed),
+        }
+    }
+}
+
+impl Evaluate for ast::Term {
+    fn evaluate(&self, overflowed: &mut bool) -> Result<i32, &'static str> {
+        match self {
+            ast::Term::Factor(fac) => fac.evaluate(overflowed),
+            ast::Term::BinOp(op, t1, t2) => {
+                let (a, b) = (t1.evaluate(overflowed)?, t2.evaluate(overflowed)?);
+                binary_op(op, a, b, overflowed)
+            }
+        }
+    }
+}
+
+impl Evaluate for ast::Factor {
+    fn evaluate(&self, overflowed: &mut bool) -> Result<i32, &'static str> {
+        match self {
+            ast::Factor::IntLiteral(n) => Ok(*n),
+            ast::Factor::UnOp(op, fac) => {
+                let value = fac.evaluate(overflowed)?;
+                Ok(match op {
+                    ast::UnOp::Negate => wrap(value.overflowing_neg(), overflowed),
+                    ast::UnOp::Complement => !value,
+                    ast::UnOp::LogicalNegate => (value == 0) as i32,
+                })
+            }
+            ast::Factor::BinOp(op, f1, f2) => {
+                let (a, b) = (f1.evaluate(overflowed)?, f2.evaluate(overflowed)?);
+                binary_op(op, a, b, overflowed)
+            }
+            ast::Factor::Expr(e) => e.evaluate(overflowed),
+        }
+    }
+}
+
+/// The wrapped result of an overflowing operation, noting if it overflowed.
+fn wrap((value, overflow): (i32, bool), overflowed: &mut bool) -> i32 {
+    *overflowed |= overflow;
+    value
+}
+
+fn binary_op(op: &ast::BinOp, a: i32, b: i32, overflowed: &mut bool) -> Result<i32, &'static str> {
+    match op {
+        ast::BinOp::Add => Ok(wrap(a.overflowing_add(b), overflowed)),
+        ast::BinOp::Subtract => Ok(wrap(a.overflowing_sub(b), overflowed)),
+        ast::BinOp::Multiply => Ok(wrap(a.overflowing_mul(b), overflowed)),
+        ast::BinOp::Divide if b == 0 => Err("Division by zero"),
+        // The quotient doesn't fit, so idiv traps
+        ast::BinOp::Divide => a.checked_div(b).ok_or("Division overflow"),
+    }
+}
+
+#[cfg(all(test, feature = "driver"))]
+mod tests {
+    use super::*;
+    use ast::*;
+
+    fn function(name: &str, expr: Expr) -> Node {
+        Node::Program(
+            Node::Function(
+                name.into(),
+                Attributes::default(),
+                Node::Statement(Statement::Return, expr.into()).into(),
+            )
+            .into(),
+        )
+    }
+
+    fn int(n: i32) -> Box<Factor> {
+        Factor::IntLiteral(n).into()
+    }
+
+    fn divide(a: i32, b: i32) -> Expr {
+        Expr::Term(Term::Factor(Factor::BinOp(BinOp::Divide, int(a), int(b)).into()).into())
+    }
+
+    fn run(ast: &Node) -> Result<i32, &'static str> {
+        call(ast, "main")
+    }
+
+    #[test]
+    fn arithmetic() {
+        // 1 - 6 / (2 * 3)
+        let ast = function(
+            "main",
+            Expr::Term(
+                Term::BinOp(
+                    BinOp::Subtract,
+                    Term::Factor(int(1)).into(),
+                    Term::Factor(
+                        Factor::BinOp(
+                            BinOp::Divide,
+                            int(6),
+                            Factor::BinOp(BinOp::Multiply, int(2), int(3)).into(),
+                        )
+                        .into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            ),
+        );
+        assert_eq!(run(&ast), Ok(0));
+        assert_eq!(run(&function("main", divide(-7, 2))), Ok(-3));
+    }
+
+    #[test]
+    fn unary_operators() {
+        let ast = function(
+            "main",
+            Expr::Term(
+                Term::Factor(
+                    Factor::UnOp(
+                        UnOp::LogicalNegate,
+                        Factor::UnOp(UnOp::Complement, Factor::UnOp(UnOp::Negate, int(1)).into())
+                            .into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            ),
+        );
+        assert_eq!(run(&ast), Ok(1));
+    }
+
+    #[test]
+    fn wrapping_and_traps() {
+        let negate_min =
+            Expr::Term(Term::Factor(Factor::UnOp(UnOp::Negate, int(i32::MIN)).into()).into());
+        assert_eq!(run(&function("main", negate_min)), Ok(i32::MIN));
+        assert_eq!(
+            run(&function("main", divide(1, 0))),
+            Err("Division by zero")
+        );
+        assert_eq!(
+            run(&function("main", divide(i32::MIN, -1))),
+            Err("Division overflow")
+        );
+    }
+
+    #[test]
+    fn overflowing_returns() {
+        let add = |a, b| {
+            Expr::Term(
+                Term::BinOp(
+                    BinOp::Add,
+                    Term::Factor(int(a)).into(),
+                    Term::Factor(int(b)).into(),
+                )
+                .into(),
+            )
+        };
+        let ast = function("main", add(i32::MAX, 1));
+        assert_eq!(run(&ast), Ok(i32::MIN));
+        let Node::Program(function_node) = &ast else {
+            unreachable!()
+        };
+        let Node::Function(_, _, statement) = &**function_node else {
+            unreachable!()
+        };
+        let Node::Statement(_, expr) = &**statement else {
+            unreachable!()
+        };
+        assert_eq!(
+            overflows(&ast),
+            [Overflow {
+                span: None,
+                expr,
+                value: i32::MIN
+            }]
+        );
+        assert_eq!(overflows(&function("main", add(i32::MAX, -1))), []);
+        // Dividing INT_MIN by -1 traps rather than wrapping
+        assert_eq!(overflows(&function("main", divide(i32::MIN, -1))), []);
+    }
+
+    #[test]
+    fn if_statements() {
+        let literal = |n| Expr::Term(Term::Factor(int(n)).into());
+        let program = |condition: Expr, otherwise: Option<Box<Node>>| {
+            let then = Node::Statement(Statement::Return, literal(1).into());
+            Node::Program(
+                Node::Function(
+                    "main".into(),
+                    Attributes::default(),
+                    Node::Statement(Statement::If(then.into(), otherwise), condition.into()).into(),
+                )
+                .into(),
+            )
+        };
+        let two = Node::Statement(Statement::Return, literal(2).into());
+        assert_eq!(run(&program(literal(3), Some(two.into()))), Ok(1));
+        let two = Node::Statement(Statement::Return, literal(2).into());
+        assert_eq!(run(&program(literal(0), Some(two.into()))), Ok(2));
+        // Running off the end of the function returns 0
+        assert_eq!(run(&program(literal(0), None)), Ok(0));
+        assert_eq!(run(&program(divide(1, 0), None)), Err("Division by zero"));
+        // Conditions are checked for overflow too
+        let overflowing = Expr::Term(
+            Term::BinOp(
+                BinOp::Multiply,
+                Term::Factor(int(i32::MAX)).into(),
+                Term::Factor(int(2)).into(),
+            )
+            .into(),
+        );
+        assert_eq!(overflows(&program(overflowing, None)).len(), 1);
+    }
+
+    #[test]
+    fn do_while_loops() {
+        let literal = |n| Expr::Term(Term::Factor(int(n)).into());
+        let program = |body: Node, condition: Expr| {
+            Node::Program(
+                Node::Function(
+                    "main".into(),
+                    Attributes::default(),
+                    Node::Statement(Statement::DoWhile(body.into()), condition.into()).into(),
+                )
+                .into(),
+            )
+        };
+        // The body runs before the condition is checked
+        assert_eq!(run(&program(Node::Block(vec![]), literal(0))), Ok(0));
+        assert_eq!(
+            run(&program(Node::Block(vec![]), divide(1, 0))),
+            Err("Division by zero")
+        );
+        let body = Node::Statement(Statement::Return, literal(4).into());
+        assert_eq!(run(&program(body, literal(1))), Ok(4));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let literal = |n| Expr::Term(Term::Factor(int(n)).into());
+        let program = |body: Node| {
+            Node::Program(Node::Function("main".into(), Attributes::default(), body.into()).into())
+        };
+        let do_while = |body: Vec<Node>, condition| {
+            Node::Statement(
+                Statement::DoWhile(Node::Block(body).into()),
+                literal(condition).into(),
+            )
+        };
+        let return_value = |n| Node::Statement(Statement::Return, literal(n).into());
+        // The statements after a jump are skipped
+        let ast = program(Node::Block(vec![
+            do_while(vec![Node::Jump(Jump::Break), return_value(1)], 1),
+            do_while(vec![Node::Jump(Jump::Continue), return_value(2)], 0),
+            return_value(3),
+        ]));
+        assert_eq!(run(&ast), Ok(3));
+    }
+
+    #[test]
+    fn named_functions() {
+        let ast = function("value", divide(9, 3));
+        assert_eq!(call(&ast, "value"), Ok(3));
+        assert_eq!(run(&ast), Err("No main function"));
+        assert_eq!(call(&ast, "other"), Err("Undefined function"));
+    }
+}
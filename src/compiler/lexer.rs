@@ -1,11 +1,15 @@
-use std::error::Error;
-use std::i32;
+//! Splitting source text into tokens, the first stage of compilation.
+
+use std::fmt;
+use std::sync::Arc;
 
 use lazy_static::lazy_static;
 use regex::Regex;
 
+use super::config::Cancellation;
 use super::error::SyntaxError;
 
+/// The smallest meaningful unit of source text.
 #[derive(Debug, PartialEq)]
 pub enum Token {
     // Syntax elements
@@ -14,6 +18,7 @@ pub enum Token {
     OpenParen,
     CloseParen,
     Semicolon,
+    Comma,
     // Operators
     Minus,
     Tilde,
@@ -32,11 +37,107 @@ pub enum Token {
     // Keywords
     IntKw,
     ReturnKw,
+    AsmKw,
+    VolatileKw,
+    RestrictKw,
+    InlineKw,
+    VoidKw,
+    IfKw,
+    ElseKw,
+    DoKw,
+    WhileKw,
+    BreakKw,
+    ContinueKw,
+    /// GNU `__attribute__`
+    AttributeKw,
+    /// GNU `__extension__`
+    ExtensionKw,
     // Identifiers and literals
-    Identifier(String),
+    Identifier(Name),
     IntLiteral(i32),
+    /// A character constant's `int` value, and how many characters it holds
+    CharLiteral {
+        value: i32,
+        chars: usize,
+    },
+    /// A string literal, with its escape sequences replaced
+    StringLiteral(String),
+}
+
+impl Token {
+    pub fn category(&self) -> Category {
+        match self {
+            Token::IntKw
+            | Token::ReturnKw
+            | Token::AsmKw
+            | Token::VolatileKw
+            | Token::RestrictKw
+            | Token::InlineKw
+            | Token::VoidKw
+            | Token::IfKw
+            | Token::ElseKw
+            | Token::DoKw
+            | Token::WhileKw
+            | Token::BreakKw
+            | Token::ContinueKw
+            | Token::AttributeKw
+            | Token::ExtensionKw => Category::Keyword,
+            Token::Identifier(_) => Category::Identifier,
+            Token::IntLiteral(_) | Token::CharLiteral { .. } | Token::StringLiteral(_) => {
+                Category::Literal
+            }
+            _ => Category::Operator,
+        }
+    }
+}
+
+/// What a piece of source text is, for syntax highlighting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    Keyword,
+    Identifier,
+    Literal,
+    /// Operators and punctuation
+    Operator,
+    Comment,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::Keyword => "keyword",
+            Category::Identifier => "identifier",
+            Category::Literal => "literal",
+            Category::Operator => "operator",
+            Category::Comment => "comment",
+        };
+        write!(f, "{}", name)
+    }
 }
 
+/// Where a token or comment is in the source, and what it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SemanticToken {
+    pub span: Span,
+    /// The length in characters
+    pub len: usize,
+    pub category: Category,
+}
+
+/// A name in the source, shared by its token and the syntax tree rather than
+/// copied into each.
+pub type Name = Arc<str>;
+
+/// The position of a token in the source text, with 1-based line and column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A syntax error and where in the source it was found
+pub type SpannedError = (SyntaxError, Span);
+
 fn symbols_to_token(s: &str) -> Option<Token> {
     match s {
         "{" => Some(Token::OpenBrace),
@@ -44,6 +145,7 @@ fn symbols_to_token(s: &str) -> Option<Token> {
         "(" => Some(Token::OpenParen),
         ")" => Some(Token::CloseParen),
         ";" => Some(Token::Semicolon),
+        "," => Some(Token::Comma),
         "-" => Some(Token::Minus),
         "~" => Some(Token::Tilde),
         "!" => Some(Token::Bang),
@@ -64,7 +166,7 @@ fn symbols_to_token(s: &str) -> Option<Token> {
 
 fn get_keyword_or_id(input: &str) -> Result<(Token, &str), SyntaxError> {
     lazy_static! {
-        static ref ID_REGEX: Regex = Regex::new(r"^[a-zA-Z]\w*").unwrap();
+        static ref ID_REGEX: Regex = Regex::new(r"^[a-zA-Z_]\w*").unwrap();
         static ref INVALID_ID_REGEX: Regex = Regex::new(r"^[^\(\)\{\}\s]+").unwrap();
     }
     match ID_REGEX.find(input) {
@@ -72,31 +174,60 @@ fn get_keyword_or_id(input: &str) -> Result<(Token, &str), SyntaxError> {
             match m.as_str() {
                 "int" => Token::IntKw,
                 "return" => Token::ReturnKw,
-                other => Token::Identifier(String::from(other)),
+                "void" => Token::VoidKw,
+                "if" => Token::IfKw,
+                "else" => Token::ElseKw,
+                "do" => Token::DoKw,
+                "while" => Token::WhileKw,
+                "break" => Token::BreakKw,
+                "continue" => Token::ContinueKw,
+                "asm" | "__asm" | "__asm__" => Token::AsmKw,
+                "volatile" | "__volatile" | "__volatile__" => Token::VolatileKw,
+                "restrict" | "__restrict" | "__restrict__" => Token::RestrictKw,
+                "inline" | "__inline" | "__inline__" => Token::InlineKw,
+                "__attribute__" | "__attribute" => Token::AttributeKw,
+                "__extension__" => Token::ExtensionKw,
+                other => Token::Identifier(Name::from(other)),
             },
             &input[m.end()..],
         )),
         None => match INVALID_ID_REGEX.find(input) {
-            Some(m) => Err(SyntaxError::InvalidIdentifier(
-                String::from(m.as_str().split_whitespace().next().unwrap()).into(),
-            )),
+            Some(m) => Err(SyntaxError::InvalidIdentifier(String::from(
+                m.as_str().split_whitespace().next().unwrap(),
+            ))),
             None => Err(SyntaxError::Unknown),
         },
     }
 }
 
-fn tokenize_int_literal(input: &str) -> Result<Option<(i32, usize)>, Box<dyn Error>> {
+fn tokenize_int_literal(input: &str) -> Result<Option<(i32, usize)>, SyntaxError> {
     lazy_static! {
         static ref INT_REGEX: Regex =
-            Regex::new(r"^(0x[0-9a-fA-F]+)|^(0[0-7]+)|^([0-9]+)").unwrap();
+            Regex::new(r"^(?:(0x[0-9a-fA-F]+)|(0[0-7]+)|([0-9]+))").unwrap();
     }
-    match INT_REGEX.captures(input) {
+    let invalid = |m: regex::Match| SyntaxError::InvalidIntLiteral(String::from(m.as_str()));
+    // Capturing searches the whole input even when the start doesn't match,
+    // so only capture within a match
+    let literal = match INT_REGEX.find(input) {
+        Some(m) => m.as_str(),
+        None => return Ok(None),
+    };
+    match INT_REGEX.captures(literal) {
         Some(caps) => match caps.get(1) {
-            Some(m) => Ok(Some((i32::from_str_radix(&m.as_str()[2..], 16)?, m.end()))),
+            Some(m) => match i32::from_str_radix(&m.as_str()[2..], 16) {
+                Ok(n) => Ok(Some((n, m.end()))),
+                Err(_) => Err(invalid(m)),
+            },
             None => match caps.get(2) {
-                Some(m) => Ok(Some((i32::from_str_radix(&m.as_str()[1..], 8)?, m.end()))),
+                Some(m) => match i32::from_str_radix(&m.as_str()[1..], 8) {
+                    Ok(n) => Ok(Some((n, m.end()))),
+                    Err(_) => Err(invalid(m)),
+                },
                 None => match caps.get(3) {
-                    Some(m) => Ok(Some((m.as_str().parse()?, m.end()))),
+                    Some(m) => match m.as_str().parse() {
+                        Ok(n) => Ok(Some((n, m.end()))),
+                        Err(_) => Err(invalid(m)),
+                    },
                     None => Ok(None),
                 },
             },
@@ -105,56 +236,345 @@ fn tokenize_int_literal(input: &str) -> Result<Option<(i32, usize)>, Box<dyn Err
     }
 }
 
-fn tokenize_const_or_id(input: &str) -> Result<Vec<Token>, Box<dyn Error>> {
-    let int_match = tokenize_int_literal(input)?;
-    match int_match {
-        Some((num, end)) => {
-            let mut res = vec![Token::IntLiteral(num)];
-            res.extend(tokenize(&input[end..])?);
-            return Ok(res);
+/// A syntax error and the byte offset in the source where it was found
+type LexError = (SyntaxError, usize);
+
+fn tokenize_const_or_id(input: &str, len: usize) -> Result<(Token, &str), LexError> {
+    let start = len - input.len();
+    if let Some((num, end)) = tokenize_int_literal(input).map_err(|e| (e, start))? {
+        return Ok((Token::IntLiteral(num), &input[end..]));
+    }
+    get_keyword_or_id(input).map_err(|e| (e, start))
+}
+
+/// Tokenize a character constant at the start of `input`, which begins with
+/// `'`. Each character is a byte of the source, as in GCC, and a constant
+/// holding one of them has the value of a signed `char`, as on x86-64.
+fn tokenize_char(input: &str) -> Result<(Token, &str), LexError> {
+    let bytes = input.as_bytes();
+    let mut value: i32 = 0;
+    let mut chars = 0;
+    let mut i = 1;
+    loop {
+        let byte = match bytes.get(i) {
+            Some(b'\'') => break,
+            Some(b'\n') | None => return Err((SyntaxError::UnterminatedChar, 0)),
+            Some(b'\\') => {
+                let (byte, len) = escape(&input[i..]).map_err(|e| (e, i))?;
+                i += len;
+                byte
+            }
+            Some(&byte) => {
+                i += 1;
+                byte
+            }
+        };
+        // Later characters are the lower bytes, and earlier ones overflow
+        value = (value << 8) | i32::from(byte);
+        chars += 1;
+    }
+    let value = match chars {
+        0 => return Err((SyntaxError::EmptyCharConstant, 0)),
+        1 => i32::from(value as u8 as i8),
+        _ => value,
+    };
+    Ok((Token::CharLiteral { value, chars }, &input[i + 1..]))
+}
+
+/// The byte an escape sequence at the start of `input` stands for, and the
+/// length of the sequence.
+fn escape(input: &str) -> Result<(u8, usize), SyntaxError> {
+    let bytes = input.as_bytes();
+    let simple = match bytes.get(1) {
+        Some(b'\'') => Some(b'\''),
+        Some(b'"') => Some(b'"'),
+        Some(b'?') => Some(b'?'),
+        Some(b'\\') => Some(b'\\'),
+        Some(b'a') => Some(0x07),
+        Some(b'b') => Some(0x08),
+        Some(b'f') => Some(0x0c),
+        Some(b'n') => Some(b'\n'),
+        Some(b'r') => Some(b'\r'),
+        Some(b't') => Some(b'\t'),
+        Some(b'v') => Some(0x0b),
+        _ => None,
+    };
+    if let Some(byte) = simple {
+        return Ok((byte, 2));
+    }
+    let (radix, start, max_digits) = match bytes.get(1) {
+        Some(b'0'..=b'7') => (8, 1, 3),
+        Some(b'x') => (16, 2, usize::MAX),
+        Some(b'\n') | None => return Err(SyntaxError::UnterminatedChar),
+        _ => {
+            // Only the backslash and the character after it are the escape
+            let len = 1 + input[1..].chars().next().map_or(0, char::len_utf8);
+            return Err(SyntaxError::InvalidEscape(String::from(&input[..len])));
         }
-        None => (),
+    };
+    let digits = input[start..]
+        .chars()
+        .take(max_digits)
+        .take_while(|c| c.is_digit(radix))
+        .count();
+    let end = start + digits;
+    if digits == 0 {
+        return Err(SyntaxError::InvalidEscape(String::from(&input[..end])));
+    }
+    match u8::from_str_radix(&input[start..end], radix) {
+        Ok(byte) => Ok((byte, end)),
+        Err(_) => Err(SyntaxError::EscapeOutOfRange(String::from(&input[..end]))),
     }
-    let (t, input) = get_keyword_or_id(input)?;
-    let mut res = vec![t];
-    res.extend(tokenize(&input)?);
-    Ok(res)
 }
 
-fn tokenize_symbol(input: &str) -> Result<Option<(Token, &str)>, Box<dyn Error>> {
+/// Tokenize a string literal at the start of `input`, which begins with `"`.
+fn tokenize_string(input: &str) -> Result<(Token, &str), SyntaxError> {
+    let mut text = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Token::StringLiteral(text), &input[i + 1..])),
+            '\n' => break,
+            '\\' => match chars.next() {
+                Some((_, 'n')) => text.push('\n'),
+                Some((_, 't')) => text.push('\t'),
+                Some((_, 'r')) => text.push('\r'),
+                Some((_, '0')) => text.push('\0'),
+                Some((_, c @ ('\\' | '"' | '\''))) => text.push(c),
+                Some((_, '\n')) | None => break,
+                // Other escapes are kept as written
+                Some((_, c)) => {
+                    text.push('\\');
+                    text.push(c);
+                }
+            },
+            c => text.push(c),
+        }
+    }
+    Err(SyntaxError::UnterminatedString)
+}
+
+fn tokenize_symbol(input: &str) -> Result<Option<(Token, &str)>, SyntaxError> {
     lazy_static! {
-        static ref SYMBOL_REGEX: Regex = Regex::new(
-            r"^(?:&&|\|\||==|!=|>=|<=|>|<|\{|\}|\(|\)|;|-|~|!|\+|/|\*)"
-        ).unwrap();
+        static ref SYMBOL_REGEX: Regex =
+            Regex::new(r"^(?:&&|\|\||==|!=|>=|<=|>|<|\{|\}|\(|\)|;|,|-|~|!|\+|/|\*)").unwrap();
     }
     match SYMBOL_REGEX.find(input) {
         Some(m) => match symbols_to_token(m.as_str()) {
             Some(t) => Ok(Some((t, &input[m.end()..]))),
-            None => Err("Unexpected symbols".into()),
+            None => Err(SyntaxError::UnexpectedToken),
         },
         None => Ok(None),
     }
 }
 
-pub fn tokenize(input: &str) -> Result<Vec<Token>, Box<dyn Error>> {
-    match tokenize_symbol(input)? {
-        Some((t, input)) => {
-            let mut tokens = vec![t];
-            tokens.extend(tokenize(input)?);
-            Ok(tokens)
-        },
-        None => match input.chars().next() {
-            Some(c) => {
-                if c.is_whitespace() {
-                    tokenize(&input[1..])
-                } else {
-                    tokenize_const_or_id(input)
+/// Tokens with their positions in a source text, found in order so that
+/// each position follows on from the last.
+struct Tokens<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, Span)>,
+    /// Every token and comment with its category, if wanted
+    semantic: Option<Vec<SemanticToken>>,
+    offset: usize,
+    span: Span,
+    /// Checked every [`CANCEL_INTERVAL`] tokens, if lexing can be cancelled
+    cancellation: Option<&'a Cancellation>,
+    /// Whether lexing stopped early because it was cancelled
+    cancelled: bool,
+}
+
+/// How many tokens are found between checks for cancellation
+const CANCEL_INTERVAL: usize = 1024;
+
+impl<'a> Tokens<'a> {
+    fn new(source: &'a str) -> Self {
+        Tokens {
+            source,
+            // Source text averages a few bytes per token
+            tokens: Vec::with_capacity(source.len() / 4),
+            semantic: None,
+            offset: 0,
+            span: Span { line: 1, column: 1 },
+            cancellation: None,
+            cancelled: false,
+        }
+    }
+
+    /// The position of the byte `offset`, which mustn't be before the last.
+    fn span_at(&mut self, offset: usize) -> Span {
+        advance(&mut self.span, &self.source[self.offset..offset]);
+        self.offset = offset;
+        self.span
+    }
+
+    /// Add the token found from byte `start` to `end`.
+    fn push(&mut self, token: Token, start: usize, end: usize) {
+        let span = self.span_at(start);
+        self.classify(token.category(), span, start, end);
+        self.tokens.push((token, span));
+        if self.tokens.len().is_multiple_of(CANCEL_INTERVAL) {
+            self.cancelled = self.cancellation.is_some_and(Cancellation::is_cancelled);
+        }
+    }
+
+    /// Add the comment found from byte `start` to `end`.
+    fn comment(&mut self, start: usize, end: usize) {
+        if self.semantic.is_some() {
+            let span = self.span_at(start);
+            self.classify(Category::Comment, span, start, end);
+        }
+    }
+
+    fn classify(&mut self, category: Category, span: Span, start: usize, end: usize) {
+        if let Some(semantic) = &mut self.semantic {
+            semantic.push(SemanticToken {
+                span,
+                len: self.source[start..end].chars().count(),
+                category,
+            });
+        }
+    }
+}
+
+/// Tokenize the rest of the source from the byte `offset`, pushing each token
+/// onto `tokens`, or stop early if lexing is cancelled.
+fn lex(offset: usize, tokens: &mut Tokens) -> Result<(), LexError> {
+    let len = tokens.source.len();
+    let mut input = &tokens.source[offset..];
+    loop {
+        if tokens.cancelled {
+            return Ok(());
+        }
+        // Line comments are skipped here so that annotated test programs lex
+        // without the preprocessor; block comments need it
+        let start = len - input.len();
+        if let Some(comment) = input.strip_prefix("//") {
+            input = &comment[comment.find('\n').unwrap_or(comment.len())..];
+            tokens.comment(start, len - input.len());
+            continue;
+        }
+        if input.starts_with('\'') {
+            let (t, rest) = tokenize_char(input).map_err(|(e, at)| (e, start + at))?;
+            tokens.push(t, start, len - rest.len());
+            input = rest;
+            continue;
+        }
+        if input.starts_with('"') {
+            let (t, rest) = tokenize_string(input).map_err(|e| (e, start))?;
+            tokens.push(t, start, len - rest.len());
+            input = rest;
+            continue;
+        }
+        let (t, rest) = match tokenize_symbol(input).map_err(|e| (e, start))? {
+            Some(symbol) => symbol,
+            None => match input.chars().next() {
+                Some(c) if c.is_whitespace() => {
+                    input = &input[c.len_utf8()..];
+                    continue;
                 }
-            }
-            None => Ok(Vec::new())
+                Some(_) => tokenize_const_or_id(input, len)?,
+                None => return Ok(()),
+            },
+        };
+        tokens.push(t, start, len - rest.len());
+        input = rest;
+    }
+}
+
+/// Move `span` past `text`.
+fn advance(span: &mut Span, text: &str) {
+    for c in text.chars() {
+        if c == '\n' {
+            span.line += 1;
+            span.column = 1;
+        } else {
+            span.column += 1;
         }
     }
 }
 
+/// The position of the byte `offset` in `input`.
+pub fn span_at(input: &str, offset: usize) -> Span {
+    let mut span = Span { line: 1, column: 1 };
+    advance(&mut span, &input[..offset]);
+    span
+}
+
+/// Tokenize the whole source, returning every error found, skipping over the
+/// invalid text after each one.
+fn lex_all(tokens: &mut Tokens) -> Vec<SpannedError> {
+    let input = tokens.source;
+    let mut errors = Vec::new();
+    let mut offset = 0;
+    while let Err((e, at)) = lex(offset, tokens) {
+        let skip = match &e {
+            SyntaxError::InvalidIdentifier(text) | SyntaxError::InvalidIntLiteral(text) => {
+                text.len()
+            }
+            // The rest of the line is in the string
+            SyntaxError::UnterminatedString | SyntaxError::UnterminatedChar => {
+                input[at..].find('\n').unwrap_or(input.len() - at)
+            }
+            // The rest of the character constant, up to its closing quote
+            SyntaxError::InvalidEscape(text) | SyntaxError::EscapeOutOfRange(text) => {
+                let rest = &input[at + text.len()..];
+                let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+                text.len() + line.find('\'').map_or(line.len(), |quote| quote + 1)
+            }
+            SyntaxError::EmptyCharConstant => 2,
+            _ => 0,
+        };
+        // Always make progress, even past a single unknown character
+        let skip = skip.max(input[at..].chars().next().map_or(1, char::len_utf8));
+        errors.push((e, tokens.span_at(at)));
+        offset = (at + skip).min(input.len());
+    }
+    errors
+}
+
+/// Tokenize the input, recording where each token starts in the source, or
+/// return every error found.
+pub fn tokenize(input: &str) -> Result<Vec<(Token, Span)>, Vec<SpannedError>> {
+    tokenize_with(input, None).expect("only cancellation stops lexing early")
+}
+
+/// The tokens of a source with their positions, or every error found in it
+type Tokenized = Result<Vec<(Token, Span)>, Vec<SpannedError>>;
+
+/// Tokenize the input as [`tokenize`] does, or return `None` if
+/// `cancellation` is used before it's done.
+pub(crate) fn tokenize_cancellable(input: &str, cancellation: &Cancellation) -> Option<Tokenized> {
+    tokenize_with(input, Some(cancellation))
+}
+
+fn tokenize_with(input: &str, cancellation: Option<&Cancellation>) -> Option<Tokenized> {
+    let mut tokens = Tokens::new(input);
+    tokens.cancellation = cancellation;
+    let errors = lex_all(&mut tokens);
+    if tokens.cancelled {
+        None
+    } else if errors.is_empty() {
+        Some(Ok(tokens.tokens))
+    } else {
+        Some(Err(errors))
+    }
+}
+
+/// Classify each token and comment in the input, for highlighting it in an
+/// editor. Invalid text is left out rather than stopping the rest being
+/// classified.
+pub fn semantic_tokens(input: &str) -> Vec<SemanticToken> {
+    let mut tokens = Tokens::new(input);
+    tokens.semantic = Some(Vec::new());
+    lex_all(&mut tokens);
+    tokens.semantic.unwrap_or_default()
+}
+
+/// Tokenize arbitrary bytes, such as from a fuzzer, replacing invalid UTF-8
+/// and returning an error rather than panicking on any input.
+pub fn tokenize_lossy(input: &[u8]) -> Result<Vec<(Token, Span)>, Vec<SpannedError>> {
+    tokenize(&String::from_utf8_lossy(input))
+}
+
 #[cfg(test)]
 mod tests;
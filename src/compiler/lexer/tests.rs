@@ -4,6 +4,14 @@ use std::path::PathBuf;
 use super::Token::*;
 use super::*;
 
+fn tokenize(input: &str) -> Result<Vec<Token>, SyntaxError> {
+    Ok(super::tokenize(input)
+        .map_err(|errors| errors.into_iter().next().unwrap().0)?
+        .into_iter()
+        .map(|(t, _)| t)
+        .collect())
+}
+
 #[test]
 fn decimal_literals() {
     for i in 0..11 {
@@ -48,6 +56,45 @@ fn tab_separated_ints() {
 fn basic_keywords() {
     assert_eq!(tokenize("int").unwrap(), vec![IntKw]);
     assert_eq!(tokenize("return").unwrap(), vec![ReturnKw]);
+    assert_eq!(
+        tokenize("if else elif").unwrap(),
+        vec![IfKw, ElseKw, Identifier("elif".into())]
+    );
+    assert_eq!(
+        tokenize("do while done").unwrap(),
+        vec![DoKw, WhileKw, Identifier("done".into())]
+    );
+    assert_eq!(
+        tokenize("break continue breaks").unwrap(),
+        vec![BreakKw, ContinueKw, Identifier("breaks".into())]
+    );
+    assert_eq!(
+        tokenize("asm __asm __asm__ _asm").unwrap(),
+        vec![AsmKw, AsmKw, AsmKw, Identifier("_asm".into())]
+    );
+    assert_eq!(
+        tokenize("volatile __volatile__ restrict __restrict").unwrap(),
+        vec![VolatileKw, VolatileKw, RestrictKw, RestrictKw]
+    );
+    assert_eq!(
+        tokenize("inline __inline __inline__").unwrap(),
+        vec![InlineKw, InlineKw, InlineKw]
+    );
+    assert_eq!(
+        tokenize("__attribute__((a, b)) __attribute __extension__").unwrap(),
+        vec![
+            AttributeKw,
+            OpenParen,
+            OpenParen,
+            Identifier("a".into()),
+            Comma,
+            Identifier("b".into()),
+            CloseParen,
+            CloseParen,
+            AttributeKw,
+            ExtensionKw
+        ]
+    );
 }
 
 #[test]
@@ -86,7 +133,7 @@ fn empty_function_one_line() {
         tokenize("int foo() {}").unwrap(),
         vec![
             IntKw,
-            Identifier(String::from("foo")),
+            Identifier("foo".into()),
             OpenParen,
             CloseParen,
             OpenBrace,
@@ -101,7 +148,7 @@ fn empty_function() {
         tokenize("int foo() {\n}").unwrap(),
         vec![
             IntKw,
-            Identifier(String::from("foo")),
+            Identifier("foo".into()),
             OpenParen,
             CloseParen,
             OpenBrace,
@@ -116,7 +163,7 @@ fn function_return_0() {
         tokenize("int foo() {\n\treturn 0;\n}").unwrap(),
         vec![
             IntKw,
-            Identifier(String::from("foo")),
+            Identifier("foo".into()),
             OpenParen,
             CloseParen,
             OpenBrace,
@@ -131,15 +178,41 @@ fn function_return_0() {
 #[test]
 fn syntax_error_with_invalid_identifier() {
     assert_eq!(
-        *tokenize("int $foo() {}")
-            .err()
-            .unwrap()
-            .downcast::<SyntaxError>()
-            .unwrap(),
+        tokenize("int $foo() {}").unwrap_err(),
         SyntaxError::InvalidIdentifier(String::from("$foo"))
     );
 }
 
+#[test]
+fn token_spans() {
+    assert_eq!(
+        super::tokenize("int foo() {\n\treturn 0;\n}").unwrap(),
+        vec![
+            (IntKw, Span { line: 1, column: 1 }),
+            (Identifier("foo".into()), Span { line: 1, column: 5 }),
+            (OpenParen, Span { line: 1, column: 8 }),
+            (CloseParen, Span { line: 1, column: 9 }),
+            (
+                OpenBrace,
+                Span {
+                    line: 1,
+                    column: 11
+                }
+            ),
+            (ReturnKw, Span { line: 2, column: 2 }),
+            (IntLiteral(0), Span { line: 2, column: 9 }),
+            (
+                Semicolon,
+                Span {
+                    line: 2,
+                    column: 10
+                }
+            ),
+            (CloseBrace, Span { line: 3, column: 1 }),
+        ]
+    );
+}
+
 macro_rules! file_tests {
     ($
         (
@@ -170,7 +243,7 @@ file_tests! {
         file_abundant_spaces: ("abundant_spaces.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -183,7 +256,7 @@ file_tests! {
         file_add: ("add.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -198,7 +271,7 @@ file_tests! {
         file_and_false: ("and_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -213,7 +286,7 @@ file_tests! {
         file_and_true: ("and_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -229,7 +302,7 @@ file_tests! {
         file_associativity_div: ("associativity_div.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -246,7 +319,7 @@ file_tests! {
         file_associativity: ("associativity.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -263,7 +336,7 @@ file_tests! {
         file_bitwise: ("bitwise.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -277,7 +350,7 @@ file_tests! {
         file_bitwise_zero: ("bitwise_zero.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -291,7 +364,7 @@ file_tests! {
         file_comp_precedence_2: ("comp_precedence_2.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -308,7 +381,7 @@ file_tests! {
         file_comp_precedence: ("comp_precedence.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -325,7 +398,7 @@ file_tests! {
         file_div_neg: ("div_neg.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -343,7 +416,7 @@ file_tests! {
         file_div: ("div.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -358,7 +431,7 @@ file_tests! {
         file_eq_false: ("eq_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -373,7 +446,7 @@ file_tests! {
         file_eq_true: ("eq_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -388,7 +461,7 @@ file_tests! {
         file_ge_false: ("ge_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -403,7 +476,7 @@ file_tests! {
         file_ge_true: ("ge_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -418,7 +491,7 @@ file_tests! {
         file_gt_false: ("gt_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -433,7 +506,7 @@ file_tests! {
         file_gt_true: ("gt_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -448,7 +521,7 @@ file_tests! {
         file_le_false: ("le_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -464,7 +537,7 @@ file_tests! {
         file_le_true: ("le_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -479,7 +552,7 @@ file_tests! {
         file_logical_precedence_2: ("logical_precedence_2.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -498,7 +571,7 @@ file_tests! {
         file_logical_precedence: ("logical_precedence.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -515,7 +588,7 @@ file_tests! {
         file_lt_false: ("lt_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -530,7 +603,7 @@ file_tests! {
         file_lt_true: ("lt_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -545,7 +618,7 @@ file_tests! {
         file_multi_digit: ("multi_digit.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -558,7 +631,7 @@ file_tests! {
         file_many_newlines: ("many_newlines.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -571,7 +644,7 @@ file_tests! {
         file_minimal_whitespace: ("minimal_whitespace.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -584,7 +657,7 @@ file_tests! {
         file_mult: ("mult.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -599,7 +672,7 @@ file_tests! {
         file_ne_false: ("ne_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -614,7 +687,7 @@ file_tests! {
         file_ne_true: ("ne_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -631,7 +704,7 @@ file_tests! {
         file_neg: ("neg.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -645,7 +718,7 @@ file_tests! {
         file_nested_ops: ("nested_ops.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -660,7 +733,7 @@ file_tests! {
         file_nested_ops_2: ("nested_ops_2.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -675,7 +748,7 @@ file_tests! {
         file_not_0: ("not_0.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -689,7 +762,7 @@ file_tests! {
         file_not_5: ("not_5.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -703,7 +776,7 @@ file_tests! {
         file_or_false: ("or_false.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -718,7 +791,7 @@ file_tests! {
         file_or_true: ("or_true.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -733,7 +806,7 @@ file_tests! {
         file_parens: ("parens.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -752,7 +825,7 @@ file_tests! {
         file_precedence: ("precedence.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -769,7 +842,7 @@ file_tests! {
         file_return_0: ("return_0.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -782,7 +855,7 @@ file_tests! {
         file_return_2: ("return_2.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -796,7 +869,7 @@ file_tests! {
         file_sub_neg: ("sub_neg.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -812,7 +885,7 @@ file_tests! {
         file_sub: ("sub.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -827,7 +900,7 @@ file_tests! {
         file_unop_add: ("unop_add.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -843,7 +916,7 @@ file_tests! {
         file_unop_parens: ("unop_parens.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -863,7 +936,7 @@ file_tests! {
         file_missing_paren: ("missing_paren.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 OpenBrace,
                 ReturnKw,
@@ -875,7 +948,7 @@ file_tests! {
         file_missing_return_val: ("missing_return_val.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -887,7 +960,7 @@ file_tests! {
         file_missing_closing_brace: ("missing_closing_brace.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -899,7 +972,7 @@ file_tests! {
         file_missing_semicolon: ("missing_semicolon.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -911,11 +984,11 @@ file_tests! {
         file_missing_return_space: ("missing_return_space.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
-                Identifier(String::from("return0")),
+                Identifier("return0".into()),
                 Semicolon,
                 CloseBrace,
             ]
@@ -923,11 +996,11 @@ file_tests! {
         file_wrong_return_case: ("wrong_return_case.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
-                Identifier(String::from("RETURN")),
+                Identifier("RETURN".into()),
                 IntLiteral(0),
                 Semicolon,
                 CloseBrace,
@@ -936,7 +1009,7 @@ file_tests! {
         file_missing_const: ("missing_const.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -949,7 +1022,7 @@ file_tests! {
         file_missing_semicolon_2: ("missing_semicolon_2.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -962,7 +1035,7 @@ file_tests! {
         file_nested_missing_const: ("nested_missing_const.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -976,7 +1049,7 @@ file_tests! {
         file_wrong_unary_order: ("wrong_unary_order.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -990,7 +1063,7 @@ file_tests! {
         file_malformed_paren: ("malformed_paren.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -1007,7 +1080,7 @@ file_tests! {
         file_missing_first_op: ("missing_first_op.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -1021,7 +1094,7 @@ file_tests! {
         file_missing_second_op: ("missing_second_op.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -1035,7 +1108,7 @@ file_tests! {
         file_no_semicolon: ("no_semicolon.c",
             vec![
                 IntKw,
-                Identifier(String::from("main")),
+                Identifier("main".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -1047,4 +1120,261 @@ file_tests! {
             ]
         ),
     ),
-}
\ No newline at end of file
+}
+
+#[test]
+fn syntax_error_with_out_of_range_literal() {
+    assert_eq!(
+        tokenize("return 2147483648;").unwrap_err(),
+        SyntaxError::InvalidIntLiteral(String::from("2147483648"))
+    );
+}
+
+#[test]
+fn error_spans() {
+    assert_eq!(
+        super::tokenize("int main() {\n  return 1 + $x ;\n}").unwrap_err(),
+        [(
+            SyntaxError::InvalidIdentifier(String::from("$x")),
+            Span {
+                line: 2,
+                column: 14
+            }
+        )]
+    );
+    assert_eq!(span_at("ab\ncd", 4), Span { line: 2, column: 2 });
+}
+
+#[test]
+fn every_error_reported() {
+    let errors = super::tokenize("int $a() {\n  return 99999999999 + $b ;\n}").unwrap_err();
+    assert_eq!(
+        errors,
+        [
+            (
+                SyntaxError::InvalidIdentifier(String::from("$a")),
+                Span { line: 1, column: 5 }
+            ),
+            (
+                SyntaxError::InvalidIntLiteral(String::from("99999999999")),
+                Span {
+                    line: 2,
+                    column: 10
+                }
+            ),
+            (
+                SyntaxError::InvalidIdentifier(String::from("$b")),
+                Span {
+                    line: 2,
+                    column: 24
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn lossy_bytes() {
+    let tokens = super::tokenize_lossy(b"return 1;").unwrap();
+    assert_eq!(tokens.len(), 3);
+    let errors = super::tokenize_lossy(b"return \xff\xfe;").unwrap_err();
+    assert_eq!(errors[0].1, Span { line: 1, column: 8 });
+}
+
+#[test]
+fn lossy_large_input() {
+    let input = "1 + ".repeat(10_000);
+    assert_eq!(
+        super::tokenize_lossy(input.as_bytes()).unwrap().len(),
+        20_000
+    );
+}
+
+#[test]
+fn many_tokens() {
+    // Far more tokens and whitespace than would fit on the stack if the
+    // lexer recursed for each one
+    let input = "return 1 ;\n".repeat(50_000);
+    let tokens = super::tokenize(&input).unwrap();
+    assert_eq!(tokens.len(), 150_000);
+    assert_eq!(
+        tokens[149_999],
+        (
+            Semicolon,
+            Span {
+                line: 50_000,
+                column: 10
+            }
+        )
+    );
+}
+
+#[test]
+fn semantic_token_categories() {
+    let classified = |input| {
+        semantic_tokens(input)
+            .into_iter()
+            .map(|t| (t.span.line, t.span.column, t.len, t.category))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        classified("int f() { // é\n  return 10; }"),
+        [
+            (1, 1, 3, Category::Keyword),
+            (1, 5, 1, Category::Identifier),
+            (1, 6, 1, Category::Operator),
+            (1, 7, 1, Category::Operator),
+            (1, 9, 1, Category::Operator),
+            (1, 11, 4, Category::Comment),
+            (2, 3, 6, Category::Keyword),
+            (2, 10, 2, Category::Literal),
+            (2, 12, 1, Category::Operator),
+            (2, 14, 1, Category::Operator),
+        ]
+    );
+    // Invalid text is skipped, and the rest still classified
+    assert_eq!(
+        classified("return $x + 1;"),
+        [
+            (1, 1, 6, Category::Keyword),
+            (1, 11, 1, Category::Operator),
+            (1, 13, 1, Category::Literal),
+            (1, 14, 1, Category::Operator),
+        ]
+    );
+}
+
+#[test]
+fn line_comments() {
+    assert_eq!(
+        tokenize("// expect-exit: 2\nreturn 2; // two").unwrap(),
+        [ReturnKw, IntLiteral(2), Semicolon]
+    );
+    assert_eq!(
+        tokenize("4 / 2").unwrap(),
+        [IntLiteral(4), Slash, IntLiteral(2)]
+    );
+}
+
+#[test]
+fn string_literals() {
+    assert_eq!(
+        tokenize(r#"asm("nop\n\tmov \"a\", \x41");"#).unwrap(),
+        [
+            AsmKw,
+            OpenParen,
+            StringLiteral(String::from("nop\n\tmov \"a\", \\x41")),
+            CloseParen,
+            Semicolon
+        ]
+    );
+    assert_eq!(tokenize(r#""""#).unwrap(), [StringLiteral(String::new())]);
+}
+
+#[test]
+fn unterminated_string_literal() {
+    let errors = super::tokenize("asm(\"nop);\nreturn 0;").unwrap_err();
+    assert_eq!(
+        errors,
+        [(SyntaxError::UnterminatedString, Span { line: 1, column: 5 })]
+    );
+    assert_eq!(
+        tokenize("\"a\\\nb\"").unwrap_err(),
+        SyntaxError::UnterminatedString
+    );
+}
+
+#[test]
+fn char_constants() {
+    let values = |input| -> Vec<i32> {
+        tokenize(input)
+            .unwrap()
+            .into_iter()
+            .map(|t| match t {
+                CharLiteral { value, .. } => value,
+                t => panic!("Expected a character constant, found {:?}", t),
+            })
+            .collect()
+    };
+    assert_eq!(
+        values(r"'A' '\101' '\x41' '\x0041' '7' ' '"),
+        [65, 65, 65, 65, 55, 32]
+    );
+    assert_eq!(
+        values(r#"'\'' '\"' '"' '\?' '\\' '\a' '\b' '\f' '\n' '\r' '\t' '\v'"#),
+        [39, 34, 34, 63, 92, 7, 8, 12, 10, 13, 9, 11]
+    );
+    // Octal escapes take at most three digits
+    assert_eq!(values(r"'\0' '\1234'"), [0, (0o123 << 8) | 0x34]);
+    // A single character is a signed char, but several make up an int
+    assert_eq!(
+        values(r"'\377' '\x80' 'ab' '\377\377'"),
+        [-1, -128, 0x6162, 0xffff]
+    );
+    assert_eq!(values("'abcde'"), [0x62636465]);
+    assert_eq!(
+        tokenize("'é'").unwrap(),
+        [CharLiteral {
+            value: 0xc3a9,
+            chars: 2
+        }]
+    );
+}
+
+#[test]
+fn char_constant_errors() {
+    let errors = |input| -> Vec<SyntaxError> {
+        super::tokenize(input)
+            .unwrap_err()
+            .into_iter()
+            .map(|(e, _)| e)
+            .collect()
+    };
+    assert_eq!(errors("''"), [SyntaxError::EmptyCharConstant]);
+    assert_eq!(
+        errors("'a\n'"),
+        [SyntaxError::UnterminatedChar, SyntaxError::UnterminatedChar]
+    );
+    assert_eq!(errors("'\\"), [SyntaxError::UnterminatedChar]);
+    assert_eq!(
+        errors(r"'\q' '\x' '\8'"),
+        [
+            SyntaxError::InvalidEscape(String::from(r"\q")),
+            SyntaxError::InvalidEscape(String::from(r"\x")),
+            SyntaxError::InvalidEscape(String::from(r"\8"))
+        ]
+    );
+    assert_eq!(
+        errors(r"'\400' '\x100'"),
+        [
+            SyntaxError::EscapeOutOfRange(String::from(r"\400")),
+            SyntaxError::EscapeOutOfRange(String::from(r"\x100"))
+        ]
+    );
+    // Lexing carries on after the constant
+    let result = super::tokenize("return 'a\\9b' + 1;");
+    assert_eq!(
+        result.unwrap_err(),
+        [(
+            SyntaxError::InvalidEscape(String::from(r"\9")),
+            Span {
+                line: 1,
+                column: 10
+            }
+        )]
+    );
+}
+
+#[test]
+fn cancelled_mid_lex() {
+    let source = "return 1;\n".repeat(CANCEL_INTERVAL);
+    let cancellation = Cancellation::default();
+    assert!(tokenize_cancellable(&source, &cancellation).is_some());
+    cancellation.cancel();
+    assert!(tokenize_cancellable(&source, &cancellation).is_none());
+    // Lexing stops at the first check, well before the end
+    let mut tokens = Tokens::new(&source);
+    tokens.cancellation = Some(&cancellation);
+    assert!(lex_all(&mut tokens).is_empty());
+    assert_eq!(tokens.tokens.len(), CANCEL_INTERVAL);
+}
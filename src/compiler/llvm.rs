@@ -0,0 +1,435 @@
+use super::ast;
+use super::backend::{self, Backend};
+use super::config::Cancellation;
+use super::target::Target;
+
+/// Lowers the AST to textual LLVM IR, which can be run with `lli` or
+/// compiled with `clang` without rcc linking against LLVM.
+///
+/// Every intermediate value is assigned to a fresh named register.
+pub struct Llvm {
+    pub target: Target,
+    /// Align the start of each function to this many bytes
+    pub align_functions: Option<u32>,
+    pub cancellation: Cancellation,
+}
+
+impl Backend for Llvm {
+    fn generate(&self, ast: &ast::Node) -> Result<String, &'static str> {
+        let mut function = Function {
+            align: self.align_functions.filter(|&bytes| bytes > 1),
+            cancellation: self.cancellation.clone(),
+            ..Function::default()
+        };
+        function
+            .code
+            .push(format!("target triple = \"{}\"", self.target));
+        ast.lower(&mut function)?;
+        Ok(function.code.join("\n") + "\n")
+    }
+}
+
+/// The instructions emitted so far and the next free register and label
+#[derive(Default)]
+struct Function {
+    code: Vec<String>,
+    registers: usize,
+    labels: usize,
+    /// Whether the current basic block has ended with a terminator
+    terminated: bool,
+    /// The labels `continue` and `break` jump to in each loop being
+    /// lowered, innermost last
+    loops: Vec<(String, String)>,
+    /// The alignment each function is given, if any
+    align: Option<u32>,
+    cancellation: Cancellation,
+}
+
+impl Function {
+    /// Emit an instruction, returning the register holding its result
+    fn assign(&mut self, instruction: String) -> String {
+        self.start_reachable();
+        self.registers += 1;
+        let register = format!("%t{}", self.registers);
+        self.code.push(format!("  {} = {}", register, instruction));
+        register
+    }
+
+    /// Emit an instruction with no result.
+    fn emit(&mut self, instruction: String) {
+        self.start_reachable();
+        self.code.push(format!("  {}", instruction));
+    }
+
+    /// Emit an instruction that ends the current basic block.
+    fn terminate(&mut self, instruction: String) {
+        self.emit(instruction);
+        self.terminated = true;
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    /// Start the basic block `label`, branching to it from the current one
+    /// if that hasn't ended.
+    fn start_block(&mut self, label: &str) {
+        if !self.terminated {
+            self.code.push(format!("  br label %{}", label));
+        }
+        self.code.push(format!("{}:", label));
+        self.terminated = false;
+    }
+
+    /// Start a block for code following a terminator, which can't be in
+    /// the same block even though it's never run.
+    fn start_reachable(&mut self) {
+        if self.terminated {
+            let label = self.label();
+            self.start_block(&label);
+        }
+    }
+}
+
+trait Lower {
+    /// Emit the IR for this node, returning the `i32` operand holding its value
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str>;
+}
+
+impl Lower for ast::Node {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Node::Program(node) => {
+                node.lower(f)?;
+            }
+            ast::Node::Function(id, _, node) => {
+                let _span = tracing::debug_span!("function", name = &**id).entered();
+                f.code.push(String::new());
+                let align = f.align.map(|bytes| format!(" align {}", bytes));
+                f.code.push(format!(
+                    "define i32 @{}(){} {{",
+                    id,
+                    align.unwrap_or_default()
+                ));
+                f.terminated = false;
+                node.lower(f)?;
+                if !f.terminated {
+                    ast::Node::implicit_return().lower(f)?;
+                }
+                f.code.push(String::from("}"));
+            }
+            ast::Node::Statement(st, expr) => match st {
+                ast::Statement::Return => {
+                    let value = expr.lower(f)?;
+                    f.terminate(format!("ret i32 {}", value));
+                }
+                ast::Statement::If(then, otherwise) => {
+                    let value = expr.lower(f)?;
+                    let condition = f.assign(format!("icmp ne i32 {}, 0", value));
+                    let (then_label, end) = (f.label(), f.label());
+                    let other = match otherwise {
+                        Some(_) => f.label(),
+                        None => end.clone(),
+                    };
+                    f.terminate(format!(
+                        "br i1 {}, label %{}, label %{}",
+                        condition, then_label, other
+                    ));
+                    f.start_block(&then_label);
+                    then.lower(f)?;
+                    if let Some(otherwise) = otherwise {
+                        if !f.terminated {
+                            f.terminate(format!("br label %{}", end));
+                        }
+                        f.start_block(&other);
+                        otherwise.lower(f)?;
+                    }
+                    f.start_block(&end);
+                }
+                ast::Statement::DoWhile(body) => {
+                    let (start, next, end) = (f.label(), f.label(), f.label());
+                    f.start_block(&start);
+                    f.loops.push((next.clone(), end.clone()));
+                    let lowered = body.lower(f);
+                    f.loops.pop();
+                    lowered?;
+                    f.start_block(&next);
+                    let value = expr.lower(f)?;
+                    let condition = f.assign(format!("icmp ne i32 {}, 0", value));
+                    f.terminate(format!(
+                        "br i1 {}, label %{}, label %{}",
+                        condition, start, end
+                    ));
+                    f.start_block(&end);
+                }
+            },
+            ast::Node::Block(statements) => {
+                for statement in statements {
+                    backend::check_cancelled(&f.cancellation)?;
+                    statement.lower(f)?;
+                }
+            }
+            ast::Node::Jump(jump) => {
+                let (next, end) = f.loops.last().expect(ast::JUMP_OUTSIDE_LOOP);
+                let target = match jump {
+                    ast::Jump::Break => end.clone(),
+                    ast::Jump::Continue => next.clone(),
+                };
+                f.terminate(format!("br label %{}", target));
+            }
+            ast::Node::InlineAsm { text, .. } => {
+                f.emit(format!(
+                    "call void asm sideeffect \"{}\", \"\"()",
+                    asm_string(text)
+                ));
+            }
+            ast::Node::Located(_, node) => {
+                node.lower(f)?;
+            }
+            ast::Node::Error => return Err("Can't generate code for a program with syntax errors"),
+        };
+        Ok(String::new())
+    }
+}
+
+/// Escape the text of an `asm` statement for an LLVM string constant, where
+/// a `$` would start an operand.
+fn asm_string(text: &str) -> String {
+    let mut escaped = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'$' => escaped.push_str("$$"),
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => escaped.push(char::from(byte)),
+            _ => escaped.push_str(&format!("\\{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+impl Lower for ast::Expr {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Expr::Term(t) => t.lower(f),
+        }
+    }
+}
+
+impl Lower for ast::Term {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Term::Factor(fac) => fac.lower(f),
+            ast::Term::BinOp(op, t1, t2) => lower_binary_op(op, &**t1, &**t2, f),
+        }
+    }
+}
+
+impl Lower for ast::Factor {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            // Constants can be used directly as operands
+            ast::Factor::IntLiteral(n) => Ok(n.to_string()),
+            ast::Factor::UnOp(op, fac) => {
+                let value = fac.lower(f)?;
+                Ok(match op {
+                    ast::UnOp::Negate => f.assign(format!("sub i32 0, {}", value)),
+                    ast::UnOp::Complement => f.assign(format!("xor i32 {}, -1", value)),
+                    ast::UnOp::LogicalNegate => {
+                        // Compare to zero, then widen the i1 result back to i32
+                        let is_zero = f.assign(format!("icmp eq i32 {}, 0", value));
+                        f.assign(format!("zext i1 {} to i32", is_zero))
+                    }
+                })
+            }
+            ast::Factor::BinOp(op, f1, f2) => lower_binary_op(op, &**f1, &**f2, f),
+            ast::Factor::Expr(e) => e.lower(f),
+        }
+    }
+}
+
+fn lower_binary_op(
+    op: &ast::BinOp,
+    a: &impl Lower,
+    b: &impl Lower,
+    f: &mut Function,
+) -> Result<String, &'static str> {
+    let a = a.lower(f)?;
+    let b = b.lower(f)?;
+    let instruction = match op {
+        ast::BinOp::Add => "add",
+        ast::BinOp::Subtract => "sub",
+        ast::BinOp::Multiply => "mul",
+        ast::BinOp::Divide => "sdiv",
+    };
+    Ok(f.assign(format!("{} i32 {}, {}", instruction, a, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::*;
+
+    fn generate(ast: &Node) -> Result<String, &'static str> {
+        Llvm {
+            target: Target::parse("x86_64-unknown-linux-gnu").unwrap(),
+            align_functions: None,
+            cancellation: Cancellation::default(),
+        }
+        .generate(ast)
+    }
+
+    fn function(expr: Expr) -> Node {
+        Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(Statement::Return, expr.into()).into(),
+            )
+            .into(),
+        )
+    }
+
+    fn int(n: i32) -> Box<Factor> {
+        Factor::IntLiteral(n).into()
+    }
+
+    #[test]
+    fn inline_asm() {
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Block(vec![
+                    Node::InlineAsm {
+                        text: String::from("movl $1, %eax\n\"\\"),
+                        volatile: true,
+                    },
+                    Node::Statement(
+                        Statement::Return,
+                        Expr::Term(Term::Factor(int(0)).into()).into(),
+                    ),
+                ])
+                .into(),
+            )
+            .into(),
+        );
+        assert!(generate(&ast).unwrap().contains(
+            "  call void asm sideeffect \"movl $$1, %eax\\0A\\22\\5C\", \"\"()\n  ret i32 0\n"
+        ));
+    }
+
+    #[test]
+    fn if_statement() {
+        // if (1) return 2; else {} and then the implicit return 0
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::If(
+                        Node::Statement(
+                            Statement::Return,
+                            Expr::Term(Term::Factor(int(2)).into()).into(),
+                        )
+                        .into(),
+                        Some(Node::Block(vec![]).into()),
+                    ),
+                    Expr::Term(Term::Factor(int(1)).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "target triple = \"x86_64-unknown-linux-gnu\"
+
+define i32 @main() {
+  %t1 = icmp ne i32 1, 0
+  br i1 %t1, label %L1, label %L3
+L1:
+  ret i32 2
+L3:
+  br label %L2
+L2:
+  ret i32 0
+}
+"
+        );
+    }
+
+    #[test]
+    fn return_constant() {
+        let ast = function(Expr::Term(Term::Factor(int(2)).into()));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "target triple = \"x86_64-unknown-linux-gnu\"
+
+define i32 @main() {
+  ret i32 2
+}
+"
+        );
+    }
+
+    #[test]
+    fn unary_operators() {
+        let ast = function(Expr::Term(
+            Term::Factor(
+                Factor::UnOp(
+                    UnOp::LogicalNegate,
+                    Factor::UnOp(UnOp::Complement, Factor::UnOp(UnOp::Negate, int(3)).into())
+                        .into(),
+                )
+                .into(),
+            )
+            .into(),
+        ));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "target triple = \"x86_64-unknown-linux-gnu\"
+
+define i32 @main() {
+  %t1 = sub i32 0, 3
+  %t2 = xor i32 %t1, -1
+  %t3 = icmp eq i32 %t2, 0
+  %t4 = zext i1 %t3 to i32
+  ret i32 %t4
+}
+"
+        );
+    }
+
+    #[test]
+    fn binary_operators() {
+        // 1 - 6 / (2 * 3)
+        let ast = function(Expr::Term(
+            Term::BinOp(
+                BinOp::Subtract,
+                Term::Factor(int(1)).into(),
+                Term::Factor(
+                    Factor::BinOp(
+                        BinOp::Divide,
+                        int(6),
+                        Factor::BinOp(BinOp::Multiply, int(2), int(3)).into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        ));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "target triple = \"x86_64-unknown-linux-gnu\"
+
+define i32 @main() {
+  %t1 = mul i32 2, 3
+  %t2 = sdiv i32 6, %t1
+  %t3 = sub i32 1, %t2
+  ret i32 %t3
+}
+"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Relocatable object files produced by the integrated assembler.
+
+pub mod elf;
+pub mod macho;
+
+/// The machine code and symbols for one translation unit. All code is in a
+/// single text section.
+#[derive(Debug, Default, PartialEq)]
+pub struct Object {
+    /// The source file name given by the `.file` directive
+    pub source_file: Option<String>,
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    /// The largest alignment asked for by a `.p2align` directive
+    pub alignment: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// The offset into the text section, or `None` for undefined symbols
+    pub value: Option<u64>,
+    pub size: u64,
+    pub global: bool,
+    pub function: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Relocation {
+    /// The offset into the text section of the field to patch
+    pub offset: u64,
+    /// The index of the referenced symbol in `Object::symbols`
+    pub symbol: usize,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelocationKind {
+    /// A 32-bit PC-relative call target, which may go through the PLT
+    Call,
+}
+
+/// A string table, in which strings are referred to by their offset. Offset
+/// 0 is the empty string.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable(vec![0])
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
@@ -0,0 +1,277 @@
+use super::{Object, RelocationKind, StringTable};
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_FILE: u8 = 4;
+const SHN_ABS: u16 = 0xfff1;
+
+const R_X86_64_PLT32: u64 = 4;
+
+// Section header indices, in the order the sections are written
+const TEXT: u16 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SECTION_NAMES: [&str; 7] = [
+    "",
+    ".text",
+    ".rela.text",
+    ".symtab",
+    ".strtab",
+    ".note.GNU-stack",
+    ".shstrtab",
+];
+
+struct SectionHeader {
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn new(kind: u32, data: Vec<u8>) -> Self {
+        SectionHeader {
+            kind,
+            flags: 0,
+            data,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_size: 0,
+        }
+    }
+}
+
+fn push_symbol(out: &mut Vec<u8>, name: u32, info: u8, section: u16, value: u64, size: u64) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.push(info);
+    // st_other: default visibility
+    out.push(0);
+    out.extend_from_slice(&section.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+}
+
+/// Encode an object as an x86-64 ELF64 relocatable file.
+pub fn write(object: &Object) -> Vec<u8> {
+    let mut strings = StringTable::new();
+
+    // The null symbol, then local symbols, then global symbols
+    let mut symtab = vec![0; 24];
+    let mut symbol_index = vec![0; object.symbols.len()];
+    let mut next_index = 1;
+    if let Some(file) = &object.source_file {
+        let name = strings.add(file);
+        push_symbol(&mut symtab, name, STT_FILE, SHN_ABS, 0, 0);
+        next_index += 1;
+    }
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|&i| object.symbols[i].global);
+    let mut locals = next_index;
+    for i in order {
+        let symbol = &object.symbols[i];
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        let kind = if symbol.function {
+            STT_FUNC
+        } else {
+            STT_NOTYPE
+        };
+        let section = if symbol.value.is_some() { TEXT } else { 0 };
+        let name = strings.add(&symbol.name);
+        push_symbol(
+            &mut symtab,
+            name,
+            bind << 4 | kind,
+            section,
+            symbol.value.unwrap_or(0),
+            symbol.size,
+        );
+        symbol_index[i] = next_index;
+        next_index += 1;
+        if !symbol.global {
+            locals = next_index;
+        }
+    }
+
+    let mut rela = Vec::new();
+    for relocation in &object.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Call => R_X86_64_PLT32,
+        };
+        let info = (symbol_index[relocation.symbol] as u64) << 32 | kind;
+        rela.extend_from_slice(&relocation.offset.to_le_bytes());
+        rela.extend_from_slice(&info.to_le_bytes());
+        rela.extend_from_slice(&relocation.addend.to_le_bytes());
+    }
+
+    let mut section_names = StringTable::new();
+    let names: Vec<u32> = SECTION_NAMES
+        .iter()
+        .map(|name| {
+            if name.is_empty() {
+                0
+            } else {
+                section_names.add(name)
+            }
+        })
+        .collect();
+
+    let mut text = SectionHeader::new(SHT_PROGBITS, object.text.clone());
+    text.flags = SHF_ALLOC | SHF_EXECINSTR;
+    text.align = object.alignment.max(16);
+    let mut rela_text = SectionHeader::new(SHT_RELA, rela);
+    rela_text.flags = SHF_INFO_LINK;
+    rela_text.link = SYMTAB;
+    rela_text.info = TEXT as u32;
+    rela_text.align = 8;
+    rela_text.entry_size = 24;
+    let mut symbols = SectionHeader::new(SHT_SYMTAB, symtab);
+    symbols.link = STRTAB;
+    symbols.info = locals as u32;
+    symbols.align = 8;
+    symbols.entry_size = 24;
+    let sections = [
+        SectionHeader::new(0, Vec::new()),
+        text,
+        rela_text,
+        symbols,
+        SectionHeader::new(SHT_STRTAB, strings.0),
+        // An empty note marks the stack as non-executable
+        SectionHeader::new(SHT_PROGBITS, Vec::new()),
+        SectionHeader::new(SHT_STRTAB, section_names.0),
+    ];
+
+    // Section contents follow the file header, then the section headers
+    let mut out = vec![0; 64];
+    let mut offsets = Vec::new();
+    for section in &sections {
+        out.resize(out.len().next_multiple_of(section.align as usize), 0);
+        offsets.push(out.len() as u64);
+        out.extend_from_slice(&section.data);
+    }
+    out.resize(out.len().next_multiple_of(8), 0);
+    let header_offset = out.len() as u64;
+    for (i, section) in sections.iter().enumerate() {
+        out.extend_from_slice(&names[i].to_le_bytes());
+        out.extend_from_slice(&section.kind.to_le_bytes());
+        out.extend_from_slice(&section.flags.to_le_bytes());
+        // sh_addr: relocatable files aren't loaded at an address
+        out.extend_from_slice(&0u64.to_le_bytes());
+        let offset = if i == 0 { 0 } else { offsets[i] };
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&(section.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&section.link.to_le_bytes());
+        out.extend_from_slice(&section.info.to_le_bytes());
+        let align = if i == 0 { 0 } else { section.align };
+        out.extend_from_slice(&align.to_le_bytes());
+        out.extend_from_slice(&section.entry_size.to_le_bytes());
+    }
+
+    let mut header = Vec::with_capacity(64);
+    // Magic, 64-bit, little endian, version 1, System V ABI
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    // e_type: relocatable, e_machine: x86-64, e_version
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&62u16.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // e_entry, e_phoff, e_shoff
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&header_offset.to_le_bytes());
+    // e_flags, e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&64u16.to_le_bytes());
+    header.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(sections.len() as u16 - 1).to_le_bytes());
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Relocation, Symbol};
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    #[test]
+    fn file_header() {
+        let bytes = write(&Object::default());
+        assert_eq!(bytes[..4], [0x7f, b'E', b'L', b'F']);
+        // Relocatable x86-64 file with seven sections
+        assert_eq!(u16_at(&bytes, 16), 1);
+        assert_eq!(u16_at(&bytes, 18), 62);
+        assert_eq!(u16_at(&bytes, 60), 7);
+        assert_eq!(u16_at(&bytes, 62), 6);
+    }
+
+    #[test]
+    fn symbols_and_relocations() {
+        let object = Object {
+            source_file: Some(String::from("foo.c")),
+            text: vec![0xe8, 0, 0, 0, 0, 0xc3],
+            symbols: vec![
+                Symbol {
+                    name: String::from("main"),
+                    value: Some(0),
+                    size: 6,
+                    global: true,
+                    function: true,
+                },
+                Symbol {
+                    name: String::from("helper"),
+                    value: Some(5),
+                    size: 1,
+                    global: false,
+                    function: true,
+                },
+                Symbol {
+                    name: String::from("abort"),
+                    value: None,
+                    size: 0,
+                    global: true,
+                    function: false,
+                },
+            ],
+            relocations: vec![Relocation {
+                offset: 1,
+                symbol: 2,
+                kind: RelocationKind::Call,
+                addend: -4,
+            }],
+            alignment: 0,
+        };
+        let bytes = write(&object);
+        let strings = String::from_utf8_lossy(&bytes);
+        for name in &["foo.c", "main", "helper", "abort", ".rela.text"] {
+            assert!(strings.contains(name), "{}", name);
+        }
+        // The text section directly follows the header
+        assert_eq!(bytes[64..70], object.text[..]);
+        // The relocation refers to abort, after null, file, helper and main
+        let rela = 72;
+        assert_eq!(bytes[rela..rela + 8], 1u64.to_le_bytes());
+        assert_eq!(bytes[rela + 8..rela + 16], (4u64 << 32 | 4).to_le_bytes());
+        assert_eq!(bytes[rela + 16..rela + 24], (-4i64).to_le_bytes());
+    }
+}
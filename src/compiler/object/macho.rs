@@ -0,0 +1,285 @@
+use super::{Object, RelocationKind, StringTable};
+
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+const CPU_SUBTYPE_X86_64_ALL: u32 = 3;
+const MH_OBJECT: u32 = 1;
+const MH_SUBSECTIONS_VIA_SYMBOLS: u32 = 0x2000;
+
+const LC_SYMTAB: u32 = 0x2;
+const LC_DYSYMTAB: u32 = 0xb;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_BUILD_VERSION: u32 = 0x32;
+
+const PLATFORM_MACOS: u32 = 1;
+/// macOS 10.14, encoded as xxxx.yy.zz nibbles
+const MIN_OS_VERSION: u32 = 0x000a_0e00;
+
+const VM_PROT_ALL: u32 = 0x7;
+const S_ATTR_PURE_INSTRUCTIONS: u32 = 0x8000_0000;
+const S_ATTR_SOME_INSTRUCTIONS: u32 = 0x400;
+
+const N_EXT: u8 = 0x1;
+const N_SECT: u8 = 0xe;
+/// The section number of the text section, counting from 1
+const TEXT: u8 = 1;
+
+const X86_64_RELOC_BRANCH: u32 = 2;
+
+const HEADER_SIZE: usize = 32;
+const SEGMENT_SIZE: u32 = 72;
+const SECTION_SIZE: u32 = 80;
+const BUILD_VERSION_SIZE: u32 = 24;
+const SYMTAB_SIZE: u32 = 24;
+const DYSYMTAB_SIZE: u32 = 80;
+
+/// A fixed size name field of a load command, padded with nulls.
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    let mut field = [0; 16];
+    field[..name.len()].copy_from_slice(name.as_bytes());
+    out.extend_from_slice(&field);
+}
+
+fn push_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Encode an object as an x86-64 Mach-O relocatable file. Mach-O has no
+/// symbol sizes or types, so those are left out, as is the source file name.
+pub fn write(object: &Object) -> Vec<u8> {
+    // Local symbols, then defined globals, then undefined symbols, which the
+    // dynamic symbol table describes as three ranges. The last two are
+    // sorted by name as the system assembler does.
+    let mut order: Vec<usize> = (0..object.symbols.len()).collect();
+    order.sort_by_key(|&i| {
+        let symbol = &object.symbols[i];
+        let group = match (symbol.global, symbol.value) {
+            (false, _) => 0,
+            (true, Some(_)) => 1,
+            (true, None) => 2,
+        };
+        let name = if symbol.global { &*symbol.name } else { "" };
+        (group, name)
+    });
+    let count = |global, defined| {
+        object
+            .symbols
+            .iter()
+            .filter(|s| s.global == global && s.value.is_some() == defined)
+            .count() as u32
+    };
+    let locals = count(false, true) + count(false, false);
+    let (defined, undefined) = (count(true, true), count(true, false));
+
+    let mut strings = StringTable::new();
+    let mut symtab = Vec::new();
+    let mut symbol_index = vec![0; object.symbols.len()];
+    for (index, &i) in order.iter().enumerate() {
+        let symbol = &object.symbols[i];
+        let external = if symbol.global { N_EXT } else { 0 };
+        let (kind, section) = match symbol.value {
+            Some(_) => (N_SECT, TEXT),
+            None => (0, 0),
+        };
+        push_u32s(&mut symtab, &[strings.add(&symbol.name)]);
+        symtab.push(kind | external);
+        symtab.push(section);
+        // n_desc
+        symtab.extend_from_slice(&0u16.to_le_bytes());
+        symtab.extend_from_slice(&symbol.value.unwrap_or(0).to_le_bytes());
+        symbol_index[i] = index as u32;
+    }
+    strings.0.resize(strings.0.len().next_multiple_of(8), 0);
+
+    // The addend is implicit in the field being patched, which is left 0
+    // since x86-64 branches are already relative to the end of the field
+    let mut relocations = Vec::new();
+    for relocation in &object.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Call => X86_64_RELOC_BRANCH,
+        };
+        // PC-relative, 4 bytes long and referring to a symbol
+        let info = symbol_index[relocation.symbol] | 1 << 24 | 2 << 25 | 1 << 27 | kind << 28;
+        push_u32s(&mut relocations, &[relocation.offset as u32, info]);
+    }
+
+    let commands_size =
+        SEGMENT_SIZE + SECTION_SIZE + BUILD_VERSION_SIZE + SYMTAB_SIZE + DYSYMTAB_SIZE;
+    let alignment = object.alignment.max(16);
+    let text_offset = (HEADER_SIZE + commands_size as usize).next_multiple_of(alignment as usize);
+    let text_size = object.text.len();
+    let relocations_offset = (text_offset + text_size).next_multiple_of(8);
+    let symbols_offset = relocations_offset + relocations.len();
+    let strings_offset = symbols_offset + symtab.len();
+
+    let mut out = Vec::with_capacity(strings_offset + strings.0.len());
+    push_u32s(
+        &mut out,
+        &[
+            MH_MAGIC_64,
+            CPU_TYPE_X86_64,
+            CPU_SUBTYPE_X86_64_ALL,
+            MH_OBJECT,
+            // ncmds, sizeofcmds
+            4,
+            commands_size,
+            MH_SUBSECTIONS_VIA_SYMBOLS,
+            // reserved
+            0,
+        ],
+    );
+
+    // One unnamed segment holding the text section
+    push_u32s(&mut out, &[LC_SEGMENT_64, SEGMENT_SIZE + SECTION_SIZE]);
+    push_name(&mut out, "");
+    // vmaddr, vmsize, fileoff, filesize
+    for value in [0, text_size, text_offset, text_size] {
+        out.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+    // maxprot, initprot, nsects, flags
+    push_u32s(&mut out, &[VM_PROT_ALL, VM_PROT_ALL, 1, 0]);
+    push_name(&mut out, "__text");
+    push_name(&mut out, "__TEXT");
+    // addr, size
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&(text_size as u64).to_le_bytes());
+    push_u32s(
+        &mut out,
+        &[
+            text_offset as u32,
+            alignment.trailing_zeros(),
+            relocations_offset as u32,
+            object.relocations.len() as u32,
+            S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS,
+            // reserved1, reserved2, reserved3
+            0,
+            0,
+            0,
+        ],
+    );
+
+    // Without a platform the linker warns that the object is for an unknown
+    // one. No tools or SDK version are recorded.
+    push_u32s(
+        &mut out,
+        &[
+            LC_BUILD_VERSION,
+            BUILD_VERSION_SIZE,
+            PLATFORM_MACOS,
+            MIN_OS_VERSION,
+            0,
+            0,
+        ],
+    );
+
+    push_u32s(
+        &mut out,
+        &[
+            LC_SYMTAB,
+            SYMTAB_SIZE,
+            symbols_offset as u32,
+            object.symbols.len() as u32,
+            strings_offset as u32,
+            strings.0.len() as u32,
+        ],
+    );
+
+    push_u32s(&mut out, &[LC_DYSYMTAB, DYSYMTAB_SIZE]);
+    push_u32s(
+        &mut out,
+        &[0, locals, locals, defined, locals + defined, undefined],
+    );
+    // No table of contents, modules, indirect symbols or dynamic relocations
+    push_u32s(&mut out, &[0; 12]);
+
+    out.resize(text_offset, 0);
+    out.extend_from_slice(&object.text);
+    out.resize(relocations_offset, 0);
+    out.extend_from_slice(&relocations);
+    out.extend_from_slice(&symtab);
+    out.extend_from_slice(&strings.0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Relocation, Symbol};
+    use super::*;
+    use std::convert::TryInto;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn file_header() {
+        let bytes = write(&Object::default());
+        assert_eq!(u32_at(&bytes, 0), MH_MAGIC_64);
+        assert_eq!(u32_at(&bytes, 4), CPU_TYPE_X86_64);
+        assert_eq!(u32_at(&bytes, 12), MH_OBJECT);
+        // Four load commands, the first a segment
+        assert_eq!(u32_at(&bytes, 16), 4);
+        assert_eq!(u32_at(&bytes, 32), LC_SEGMENT_64);
+    }
+
+    #[test]
+    fn symbols_and_relocations() {
+        let object = Object {
+            source_file: None,
+            text: vec![0xe8, 0, 0, 0, 0, 0xc3],
+            symbols: vec![
+                Symbol {
+                    name: String::from("_main"),
+                    value: Some(0),
+                    size: 6,
+                    global: true,
+                    function: true,
+                },
+                Symbol {
+                    name: String::from("_helper"),
+                    value: Some(5),
+                    size: 1,
+                    global: false,
+                    function: true,
+                },
+                Symbol {
+                    name: String::from("_abort"),
+                    value: None,
+                    size: 0,
+                    global: true,
+                    function: false,
+                },
+            ],
+            relocations: vec![Relocation {
+                offset: 1,
+                symbol: 2,
+                kind: RelocationKind::Call,
+                addend: -4,
+            }],
+            alignment: 0,
+        };
+        let bytes = write(&object);
+        let strings = String::from_utf8_lossy(&bytes);
+        for name in &["_main", "_helper", "_abort", "__text", "__TEXT"] {
+            assert!(strings.contains(name), "{}", name);
+        }
+        // The text section follows the load commands, aligned to 16 bytes
+        assert_eq!(bytes[320..326], object.text[..]);
+        // The relocation refers to _abort, after _helper and _main
+        let relocation = 328;
+        assert_eq!(u32_at(&bytes, relocation), 1);
+        assert_eq!(u32_at(&bytes, relocation + 4), 2 | 0x2d << 24);
+        // One local, one defined global and one undefined symbol
+        let dysymtab = HEADER_SIZE + 152 + 24 + 24;
+        assert_eq!(u32_at(&bytes, dysymtab), LC_DYSYMTAB);
+        assert_eq!(
+            (8..32)
+                .step_by(4)
+                .map(|i| u32_at(&bytes, dysymtab + i))
+                .collect::<Vec<_>>(),
+            [0, 1, 1, 1, 2, 1]
+        );
+    }
+}
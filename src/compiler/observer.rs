@@ -0,0 +1,50 @@
+//! Hooks for watching each stage's output as compilation goes, so that
+//! loggers, visualisers and lint passes can run without changing the
+//! pipeline.
+
+use std::fmt;
+use std::path::Path;
+
+use super::ast;
+use super::lexer::{Span, Token};
+
+/// Called with the output of each stage for each source as it's produced.
+/// Every method does nothing by default, so observers only implement the
+/// stages they're interested in.
+pub trait Observer {
+    fn tokens(&self, _source: &Path, _tokens: &[(Token, Span)]) {}
+    fn ast(&self, _source: &Path, _ast: &ast::Node) {}
+    /// LLVM IR, when it's one of the outputs
+    fn ir(&self, _source: &Path, _ir: &str) {}
+    /// The code generated for the source, which is QBE's IL with the QBE
+    /// backend
+    fn assembly(&self, _source: &Path, _assembly: &str) {}
+}
+
+/// The observers of a compilation, called in order.
+#[derive(Default)]
+pub struct Observers(pub Vec<Box<dyn Observer>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observer for Observers {
+    fn tokens(&self, source: &Path, tokens: &[(Token, Span)]) {
+        self.0.iter().for_each(|o| o.tokens(source, tokens));
+    }
+
+    fn ast(&self, source: &Path, ast: &ast::Node) {
+        self.0.iter().for_each(|o| o.ast(source, ast));
+    }
+
+    fn ir(&self, source: &Path, ir: &str) {
+        self.0.iter().for_each(|o| o.ir(source, ir));
+    }
+
+    fn assembly(&self, source: &Path, assembly: &str) {
+        self.0.iter().for_each(|o| o.assembly(source, assembly));
+    }
+}
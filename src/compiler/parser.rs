@@ -1,149 +1,632 @@
-use std::error::Error;
-use std::iter::Peekable;
+//! Building the syntax tree from tokens, by recursive descent.
+
+use std::cell::Cell;
+use std::iter::{self, Peekable};
+use std::thread;
 
 use super::ast;
+use super::config::Cancellation;
 use super::error::SyntaxError;
-use super::lexer::Token;
+use super::lexer::{self, Name, Span, Token};
+
+/// A token consumed by the parser, which may know its position in the source.
+pub trait SourceToken {
+    fn token(&self) -> &Token;
+    fn span(&self) -> Option<Span>;
+}
+
+impl SourceToken for Token {
+    fn token(&self) -> &Token {
+        self
+    }
+
+    fn span(&self) -> Option<Span> {
+        None
+    }
+}
+
+impl SourceToken for (Token, Span) {
+    fn token(&self) -> &Token {
+        &self.0
+    }
+
+    fn span(&self) -> Option<Span> {
+        Some(self.1)
+    }
+}
+
+fn next<'a, I, T>(tokens: &mut Peekable<I>) -> Option<&'a Token>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    tokens.next().map(SourceToken::token)
+}
+
+fn peek<'a, I, T>(tokens: &mut Peekable<I>) -> Option<&'a Token>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    tokens.peek().map(|t| t.token())
+}
 
-fn token_to_unop(t: &Token) -> Result<ast::UnOp, Box<dyn Error>> {
+fn token_to_unop(t: &Token) -> Result<ast::UnOp, SyntaxError> {
     match t {
         Token::Bang => Ok(ast::UnOp::LogicalNegate),
         Token::Minus => Ok(ast::UnOp::Negate),
         Token::Tilde => Ok(ast::UnOp::Complement),
-        _ => Err("Invalid unary operator".into()),
+        _ => Err(SyntaxError::InvalidFactor),
     }
 }
 
-fn token_to_binop(t: &Token) -> Result<ast::BinOp, Box<dyn Error>> {
+fn token_to_binop(t: &Token) -> Result<ast::BinOp, SyntaxError> {
     match t {
         Token::Plus => Ok(ast::BinOp::Add),
         Token::Minus => Ok(ast::BinOp::Subtract),
         Token::Asterisk => Ok(ast::BinOp::Multiply),
         Token::Slash => Ok(ast::BinOp::Divide),
-        _ => Err("Invalid binary operator".into()),
+        _ => Err(SyntaxError::InvalidExpression),
     }
 }
 
-fn parse_factor<'a, I>(tokens: &mut Peekable<I>) -> Result<ast::Factor, Box<dyn Error>>
+/// How deeply an expression's syntax tree can nest, which keeps the
+/// recursion here and in later stages from overflowing the stack. Each
+/// operand after the first in a chain like `1 + 2 + 3` is a level deeper,
+/// as is each statement nested in another and the expressions within it.
+const MAX_NESTING: usize = 1024;
+
+/// How many levels a statement nested in another counts as. Generating code
+/// for one takes about four times the stack of an operator in an expression,
+/// so only around 250 statements can nest.
+const STATEMENT_NESTING: usize = 4;
+
+fn parse_factor<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    depth: usize,
+) -> Result<ast::Factor, SyntaxError>
 where
-    I: Iterator<Item = &'a Token>,
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
 {
-    match tokens.next() {
+    if depth > MAX_NESTING {
+        return Err(SyntaxError::NestedTooDeeply);
+    }
+    match next(tokens) {
         Some(Token::IntLiteral(n)) => Ok(ast::Factor::IntLiteral(*n)),
+        // A character constant is an `int`
+        Some(Token::CharLiteral { value, .. }) => Ok(ast::Factor::IntLiteral(*value)),
         Some(t @ Token::Bang) | Some(t @ Token::Minus) | Some(t @ Token::Tilde) => Ok(
-            ast::Factor::UnOp(token_to_unop(t)?, parse_factor(tokens)?.into()),
+            ast::Factor::UnOp(token_to_unop(t)?, parse_factor(tokens, depth + 1)?.into()),
         ),
         Some(Token::OpenParen) => {
-            let expr = parse_expression(tokens)?;
-            match tokens.next() {
+            let expr = parse_expression(tokens, depth + 1)?;
+            match next(tokens) {
                 Some(Token::CloseParen) => Ok(ast::Factor::Expr(expr.into())),
-                _ => Err(SyntaxError::MissingCloseParen.into()),
+                _ => Err(SyntaxError::MissingCloseParen),
             }
         }
-        _ => Err(SyntaxError::InvalidFactor.into()),
+        _ => Err(SyntaxError::InvalidFactor),
     }
 }
 
-fn parse_term<'a, I>(tokens: &mut Peekable<I>) -> Result<ast::Term, Box<dyn Error>>
+fn parse_term<'a, I, T>(tokens: &mut Peekable<I>, depth: usize) -> Result<ast::Term, SyntaxError>
 where
-    I: Iterator<Item = &'a Token>,
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
 {
-    let mut factor = parse_factor(tokens)?;
-    loop {
-        let next = tokens.peek();
-        match next {
-            Some(t) => match t {
-                Token::Asterisk | Token::Slash => {
-                    let op = token_to_binop(tokens.next().unwrap())?;
-                    let next_factor = parse_factor(tokens)?;
-                    factor = ast::Factor::BinOp(op, factor.into(), next_factor.into());
-                }
-                _ => break,
-            },
-            None => break,
-        };
+    let mut depth = depth;
+    let mut factor = parse_factor(tokens, depth)?;
+    while let Some(Token::Asterisk | Token::Slash) = peek(tokens) {
+        let op = token_to_binop(next(tokens).unwrap())?;
+        depth += 1;
+        let next_factor = parse_factor(tokens, depth)?;
+        factor = ast::Factor::BinOp(op, factor.into(), next_factor.into());
     }
     Ok(ast::Term::Factor(factor.into()))
 }
 
-fn parse_expression<'a, I>(tokens: &mut Peekable<I>) -> Result<ast::Expr, Box<dyn Error>>
+fn parse_expression<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    depth: usize,
+) -> Result<ast::Expr, SyntaxError>
 where
-    I: Iterator<Item = &'a Token>,
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
 {
-    let mut term = parse_term(tokens)?;
-    loop {
-        let next = tokens.peek();
-        match next {
-            Some(t) => match t {
-                Token::Plus | Token::Minus => {
-                    let op = token_to_binop(tokens.next().unwrap())?;
-                    let next_term = parse_term(tokens)?;
-                    term = ast::Term::BinOp(op, term.into(), next_term.into());
-                }
-                _ => break,
-            },
-            None => break,
-        };
+    let mut depth = depth;
+    let mut term = parse_term(tokens, depth)?;
+    while let Some(Token::Plus | Token::Minus) = peek(tokens) {
+        let op = token_to_binop(next(tokens).unwrap())?;
+        depth += 1;
+        let next_term = parse_term(tokens, depth)?;
+        term = ast::Term::BinOp(op, term.into(), next_term.into());
     }
     Ok(ast::Expr::Term(term.into()))
 }
 
-fn parse_statement<'a, I>(tokens: &mut Peekable<I>) -> Result<ast::Node, Box<dyn Error>>
+/// Why parsing stopped before the end of the program.
+#[derive(Debug, PartialEq)]
+enum Stop {
+    Error(SyntaxError),
+    Cancelled,
+}
+
+impl From<SyntaxError> for Stop {
+    fn from(error: SyntaxError) -> Self {
+        Stop::Error(error)
+    }
+}
+
+/// Parse a statement, stopping if `cancellation` is used.
+fn parse_statement<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    depth: usize,
+    cancellation: Option<&Cancellation>,
+) -> Result<ast::Node, Stop>
 where
-    I: Iterator<Item = &'a Token>,
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
 {
-    match tokens.next() {
+    if cancellation.is_some_and(Cancellation::is_cancelled) {
+        return Err(Stop::Cancelled);
+    }
+    if depth > MAX_NESTING {
+        return Err(SyntaxError::NestedTooDeeply.into());
+    }
+    let span = tokens.peek().and_then(|t| t.span());
+    let statement = match next(tokens) {
         Some(Token::ReturnKw) => {
-            let expr = parse_expression(tokens)?;
-            match tokens.next() {
+            let expr = parse_expression(tokens, depth)?;
+            match next(tokens) {
+                Some(Token::Semicolon) => ast::Node::Statement(ast::Statement::Return, expr.into()),
+                _ => return Err(SyntaxError::MissingSemicolon.into()),
+            }
+        }
+        Some(Token::AsmKw) => {
+            // Basic asm is volatile whether it says so or not
+            let volatile = peek(tokens) == Some(&Token::VolatileKw);
+            if volatile {
+                next(tokens);
+            }
+            if next(tokens) != Some(&Token::OpenParen) {
+                return Err(SyntaxError::MissingOpenParen.into());
+            }
+            let text = match next(tokens) {
+                Some(Token::StringLiteral(text)) => text.clone(),
+                _ => return Err(SyntaxError::UnexpectedToken.into()),
+            };
+            if next(tokens) != Some(&Token::CloseParen) {
+                return Err(SyntaxError::MissingCloseParen.into());
+            }
+            match next(tokens) {
+                Some(Token::Semicolon) => ast::Node::InlineAsm { text, volatile },
+                _ => return Err(SyntaxError::MissingSemicolon.into()),
+            }
+        }
+        Some(Token::IfKw) => {
+            if next(tokens) != Some(&Token::OpenParen) {
+                return Err(SyntaxError::MissingOpenParen.into());
+            }
+            let condition = parse_expression(tokens, depth + 1)?;
+            if next(tokens) != Some(&Token::CloseParen) {
+                return Err(SyntaxError::MissingCloseParen.into());
+            }
+            let then = parse_statement(tokens, depth + STATEMENT_NESTING, cancellation)?;
+            // An `else` belongs to the innermost `if` without one
+            let otherwise = match peek(tokens) {
+                Some(Token::ElseKw) => {
+                    next(tokens);
+                    Some(parse_statement(tokens, depth + STATEMENT_NESTING, cancellation)?.into())
+                }
+                _ => None,
+            };
+            ast::Node::Statement(ast::Statement::If(then.into(), otherwise), condition.into())
+        }
+        Some(Token::DoKw) => {
+            let body = parse_statement(tokens, depth + STATEMENT_NESTING, cancellation)?;
+            if next(tokens) != Some(&Token::WhileKw) {
+                return Err(SyntaxError::MissingKeyword(String::from("while")).into());
+            }
+            if next(tokens) != Some(&Token::OpenParen) {
+                return Err(SyntaxError::MissingOpenParen.into());
+            }
+            let condition = parse_expression(tokens, depth + 1)?;
+            if next(tokens) != Some(&Token::CloseParen) {
+                return Err(SyntaxError::MissingCloseParen.into());
+            }
+            match next(tokens) {
                 Some(Token::Semicolon) => {
-                    Ok(ast::Node::Statement(ast::Statement::Return, expr.into()))
+                    ast::Node::Statement(ast::Statement::DoWhile(body.into()), condition.into())
                 }
-                _ => Err(SyntaxError::MissingSemicolon.into()),
+                _ => return Err(SyntaxError::MissingSemicolon.into()),
+            }
+        }
+        Some(Token::OpenBrace) => ast::Node::Block(parse_block(
+            tokens,
+            depth + STATEMENT_NESTING,
+            cancellation,
+        )?),
+        Some(t @ Token::BreakKw) | Some(t @ Token::ContinueKw) => {
+            let jump = if *t == Token::BreakKw {
+                ast::Jump::Break
+            } else {
+                ast::Jump::Continue
+            };
+            match next(tokens) {
+                Some(Token::Semicolon) => ast::Node::Jump(jump),
+                _ => return Err(SyntaxError::MissingSemicolon.into()),
             }
         }
-        _ => Err(SyntaxError::UnexpectedToken.into()),
+        _ => return Err(SyntaxError::UnexpectedToken.into()),
+    };
+    // Record where the statement came from when the tokens carry positions
+    Ok(match span {
+        Some(span) => ast::Node::Located(span, statement.into()),
+        None => statement,
+    })
+}
+
+fn parse_function<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    cancellation: Option<&Cancellation>,
+) -> Result<ast::Node, Stop>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    let (id, attributes) = parse_declaration(tokens)?;
+    let statements = parse_block(tokens, 0, cancellation)?;
+    Ok(ast::Node::Function(
+        id,
+        attributes,
+        ast::Node::Block(statements).into(),
+    ))
+}
+
+/// Parse the statements of a block, after its `{`, up to and including the
+/// `}` that closes it.
+fn parse_block<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    depth: usize,
+    cancellation: Option<&Cancellation>,
+) -> Result<Vec<ast::Node>, Stop>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    let mut statements = Vec::new();
+    while !matches!(peek(tokens), Some(Token::CloseBrace) | None) {
+        statements.push(parse_statement(tokens, depth, cancellation)?);
+    }
+    match next(tokens) {
+        Some(Token::CloseBrace) => Ok(statements),
+        _ => Err(SyntaxError::MissingCloseBrace.into()),
     }
 }
 
-fn parse_function<'a, I>(tokens: &mut Peekable<I>) -> Result<ast::Node, Box<dyn Error>>
+/// Parse a function's declaration up to the `{` that opens its body,
+/// returning its name and how it's declared.
+fn parse_declaration<'a, I, T>(
+    tokens: &mut Peekable<I>,
+) -> Result<(Name, ast::Attributes), SyntaxError>
 where
-    I: Iterator<Item = &'a Token>,
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
 {
-    match tokens.next() {
-        Some(Token::IntKw) => match tokens.next() {
-            Some(Token::Identifier(id)) => match tokens.next() {
-                Some(Token::OpenParen) => match tokens.next() {
-                    Some(Token::CloseParen) => match tokens.next() {
-                        Some(Token::OpenBrace) => {
-                            let s = parse_statement(tokens)?;
-                            match tokens.next() {
-                                Some(Token::CloseBrace) => {
-                                    Ok(ast::Node::Function(String::from(id), s.into()))
-                                }
-                                _ => Err(SyntaxError::MissingCloseBrace.into()),
-                            }
-                        }
-                        _ => Err(SyntaxError::MissingOpenBrace.into()),
-                    },
-                    _ => Err(SyntaxError::MissingCloseParen.into()),
-                },
-                _ => Err(SyntaxError::MissingOpenParen.into()),
-            },
-            _ => Err(SyntaxError::MissingIdentifier.into()),
-        },
-        _ => Err(SyntaxError::MissingKeyword(String::from("int")).into()),
+    // Specifiers can come before or after the return type
+    let mut attributes = ast::Attributes::default();
+    parse_specifiers(tokens, &mut attributes)?;
+    if next(tokens) != Some(&Token::IntKw) {
+        return Err(SyntaxError::MissingKeyword(String::from("int")));
+    }
+    parse_specifiers(tokens, &mut attributes)?;
+    let id = match next(tokens) {
+        Some(Token::Identifier(id)) => Name::clone(id),
+        _ => return Err(SyntaxError::MissingIdentifier),
+    };
+    if next(tokens) != Some(&Token::OpenParen) {
+        return Err(SyntaxError::MissingOpenParen);
     }
+    if parse_parameters(tokens, &mut attributes) != Some(&Token::CloseParen) {
+        return Err(SyntaxError::MissingCloseParen);
+    }
+    if next(tokens) != Some(&Token::OpenBrace) {
+        return Err(SyntaxError::MissingOpenBrace);
+    }
+    Ok((id, attributes))
 }
 
-pub fn parse(tokens: &Vec<Token>) -> Result<ast::Node, Box<dyn Error>> {
-    Ok(ast::Node::Program(
-        parse_function(&mut tokens.iter().peekable())?.into(),
-    ))
+/// Parse a parameter list, which can only be empty, returning the token after
+/// it. `(void)` declares that there are no parameters.
+fn parse_parameters<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    attributes: &mut ast::Attributes,
+) -> Option<&'a Token>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    if let Some(Token::VoidKw) = peek(tokens) {
+        next(tokens);
+        attributes.prototype = true;
+    }
+    next(tokens)
+}
+
+/// Parse the specifiers of a function: `inline`, and GNU `__extension__`
+/// and `__attribute__((...))`, which are accepted so that code written for
+/// GCC parses.
+fn parse_specifiers<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    attributes: &mut ast::Attributes,
+) -> Result<(), SyntaxError>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    loop {
+        match peek(tokens) {
+            Some(Token::InlineKw) => attributes.inline = true,
+            Some(Token::ExtensionKw) => (),
+            Some(Token::AttributeKw) => {
+                next(tokens);
+                parse_gnu_attributes(tokens, attributes)?;
+                continue;
+            }
+            _ => return Ok(()),
+        }
+        next(tokens);
+    }
+}
+
+/// Parse the `((...))` after `__attribute__`, a list of attributes separated
+/// by commas, each of which can be empty or have arguments in parentheses.
+/// Only `noreturn` and `unused` are recorded, and the rest are ignored.
+fn parse_gnu_attributes<'a, I, T>(
+    tokens: &mut Peekable<I>,
+    attributes: &mut ast::Attributes,
+) -> Result<(), SyntaxError>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    for _ in 0..2 {
+        if next(tokens) != Some(&Token::OpenParen) {
+            return Err(SyntaxError::MissingOpenParen);
+        }
+    }
+    loop {
+        match next(tokens) {
+            Some(Token::CloseParen) => break,
+            Some(Token::Comma) => continue,
+            // Any attribute can also be spelt with underscores around it
+            Some(Token::Identifier(name)) => {
+                let name = name.strip_prefix("__").unwrap_or(name);
+                match name.strip_suffix("__").unwrap_or(name) {
+                    "noreturn" => attributes.noreturn = true,
+                    "unused" => attributes.unused = true,
+                    _ => (),
+                }
+            }
+            Some(Token::OpenParen) => return Err(SyntaxError::UnexpectedToken),
+            None => return Err(SyntaxError::MissingCloseParen),
+            // Keywords such as `const` name attributes too
+            Some(_) => (),
+        }
+        if peek(tokens) == Some(&Token::OpenParen) {
+            skip_arguments(tokens)?;
+        }
+    }
+    if next(tokens) != Some(&Token::CloseParen) {
+        return Err(SyntaxError::MissingCloseParen);
+    }
+    Ok(())
+}
+
+/// Skip the parenthesized arguments of an attribute, which are ignored.
+fn skip_arguments<'a, I, T>(tokens: &mut Peekable<I>) -> Result<(), SyntaxError>
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    let mut depth = 0;
+    loop {
+        match next(tokens) {
+            Some(Token::OpenParen) => depth += 1,
+            Some(Token::CloseParen) if depth == 1 => return Ok(()),
+            Some(Token::CloseParen) => depth -= 1,
+            Some(_) => (),
+            None => return Err(SyntaxError::MissingCloseParen),
+        }
+    }
+}
+
+/// Roughly the most stack the parser uses for each level of nesting
+const STACK_PER_LEVEL: usize = 8 << 10;
+
+/// Run `f` on a thread with `stack` bytes of stack on top of the usual 1 MiB,
+/// or return `None` if it can't be started or panics.
+fn with_stack<T: Send + 'static>(
+    stack: usize,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    thread::Builder::new()
+        .stack_size((1 << 20) + stack)
+        .spawn(f)
+        .ok()?
+        .join()
+        .ok()
+}
+
+/// Tokenize and parse arbitrary bytes, such as from a fuzzer, returning the
+/// first error rather than panicking or overflowing the stack on any input.
+pub fn parse_lossy(input: &[u8]) -> Result<ast::Node, SyntaxError> {
+    let input = String::from_utf8_lossy(input).into_owned();
+    with_stack(MAX_NESTING * STACK_PER_LEVEL, move || {
+        let tokens = lexer::tokenize(&input).map_err(|errors| {
+            errors
+                .into_iter()
+                .next()
+                .map_or(SyntaxError::Unknown, |e| e.0)
+        })?;
+        parse(&tokens).map_err(|(e, _)| e)
+    })
+    .unwrap_or(Err(SyntaxError::Unknown))
+}
+
+/// Parse a program, or return the error with the index of the token that
+/// caused it, which is the number of tokens if the input ended too soon.
+pub fn parse<T: SourceToken>(tokens: &[T]) -> Result<ast::Node, (SyntaxError, usize)> {
+    parse_with(tokens, None).expect("only cancellation stops parsing early")
+}
+
+/// Parse a program as [`parse`] does, or return `None` if `cancellation` is
+/// used before it's done, which is checked before each statement.
+pub(crate) fn parse_cancellable<T: SourceToken>(
+    tokens: &[T],
+    cancellation: &Cancellation,
+) -> Option<Result<ast::Node, (SyntaxError, usize)>> {
+    parse_with(tokens, Some(cancellation))
+}
+
+fn parse_with<T: SourceToken>(
+    tokens: &[T],
+    cancellation: Option<&Cancellation>,
+) -> Option<Result<ast::Node, (SyntaxError, usize)>> {
+    // Count every token asked for, including attempts to read past the end
+    let taken = Cell::new(0);
+    let mut iter = tokens.iter();
+    let mut counted = iter::from_fn(|| {
+        taken.set(taken.get() + 1);
+        iter.next()
+    })
+    .peekable();
+    match parse_function(&mut counted, cancellation) {
+        Ok(function) => Some(Ok(ast::Node::Program(function.into()))),
+        // Every error is found just after taking the token that caused it,
+        // with no lookahead since
+        Err(Stop::Error(e)) => Some(Err((e, (taken.get() - 1).min(tokens.len())))),
+        Err(Stop::Cancelled) => None,
+    }
+}
+
+/// Parse as much of a program as possible, for tools such as editors that
+/// work on sources as they're written. Each statement that can't be parsed
+/// is replaced by an [`ast::Node::Error`], as is the whole function if its
+/// declaration can't be, and every error is returned with the index of its
+/// token as with [`parse`]. A program that parses gives the same tree as
+/// [`parse`] and no errors.
+pub fn parse_with_recovery<T: SourceToken>(tokens: &[T]) -> (ast::Node, Vec<(SyntaxError, usize)>) {
+    let taken = Cell::new(0);
+    let mut iter = tokens.iter();
+    let mut counted = iter::from_fn(|| {
+        taken.set(taken.get() + 1);
+        iter.next()
+    })
+    .peekable();
+    // The index of the token just taken, which caused an error
+    let at = || (taken.get() - 1).min(tokens.len());
+    let error = |span: Option<Span>| match span {
+        Some(span) => ast::Node::Located(span, ast::Node::Error.into()),
+        None => ast::Node::Error,
+    };
+    let mut errors = Vec::new();
+
+    let span = counted.peek().and_then(|t| t.span());
+    let (id, attributes) = match parse_declaration(&mut counted) {
+        Ok(declaration) => declaration,
+        Err(e) => {
+            errors.push((e, at()));
+            return (ast::Node::Program(error(span).into()), errors);
+        }
+    };
+    let mut statements = Vec::new();
+    let mut closed = false;
+    while !matches!(peek(&mut counted), Some(Token::CloseBrace) | None) {
+        let span = counted.peek().and_then(|t| t.span());
+        // The statement's first token, which has been peeked at
+        let start = taken.get() - 1;
+        match parse_statement(&mut counted, 0, None) {
+            Ok(statement) => statements.push(statement),
+            Err(Stop::Cancelled) => unreachable!("nothing can cancel recovery"),
+            Err(Stop::Error(e)) => {
+                let index = at();
+                errors.push((e, index));
+                statements.push(error(span));
+                // How many blocks the statement has opened and not closed,
+                // up to and including the token of the error
+                let mut depth =
+                    tokens[start..(index + 1).min(tokens.len())]
+                        .iter()
+                        .fold(0isize, |depth, t| match t.token() {
+                            Token::OpenBrace => depth + 1,
+                            Token::CloseBrace => depth - 1,
+                            _ => depth,
+                        });
+                // Skip to the end of the statement, unless the error was at
+                // the end of the body or of the statement already
+                match tokens.get(index).map(SourceToken::token) {
+                    None => {
+                        closed = true;
+                        break;
+                    }
+                    // The `}` closed the function body rather than a block
+                    _ if depth < 0 => {
+                        closed = true;
+                        break;
+                    }
+                    Some(Token::Semicolon) if depth == 0 => continue,
+                    Some(Token::CloseBrace) if depth == 0 && !continues(&mut counted) => continue,
+                    Some(_) => (),
+                }
+                // Skip past any blocks still open, to the `;` or `}` that
+                // ends the statement
+                while let Some(token) = peek(&mut counted) {
+                    if *token == Token::CloseBrace && depth == 0 {
+                        break;
+                    }
+                    next(&mut counted);
+                    match token {
+                        Token::OpenBrace => depth += 1,
+                        Token::CloseBrace => depth -= 1,
+                        _ => (),
+                    }
+                    let ended = match token {
+                        Token::Semicolon => true,
+                        Token::CloseBrace => !continues(&mut counted),
+                        _ => false,
+                    };
+                    if depth == 0 && ended {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    if !closed && next(&mut counted) != Some(&Token::CloseBrace) {
+        errors.push((SyntaxError::MissingCloseBrace, at()));
+    }
+    let function = ast::Node::Function(id, attributes, ast::Node::Block(statements).into());
+    (ast::Node::Program(function.into()), errors)
+}
+
+/// Whether a statement carries on after the `}` of one of its blocks, with
+/// an `else` branch or the condition of a `do` loop.
+fn continues<'a, I, T>(tokens: &mut Peekable<I>) -> bool
+where
+    I: Iterator<Item = &'a T>,
+    T: SourceToken + 'a,
+{
+    matches!(peek(tokens), Some(Token::ElseKw | Token::WhileKw))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
     use super::*;
     use ast::*;
     use Token::*;
@@ -151,7 +634,7 @@ mod tests {
     #[test]
     fn int_literal() {
         assert_eq!(
-            parse_expression(&mut vec![IntLiteral(1)].iter().peekable()).unwrap(),
+            parse_expression(&mut [IntLiteral(1)].iter().peekable(), 0).unwrap(),
             Expr::Term(Term::Factor(Factor::IntLiteral(1).into()).into())
         );
     }
@@ -159,7 +642,7 @@ mod tests {
     #[test]
     fn unary_operators() {
         assert_eq!(
-            parse_expression(&mut vec![Tilde, IntLiteral(0)].iter().peekable()).unwrap(),
+            parse_expression(&mut [Tilde, IntLiteral(0)].iter().peekable(), 0).unwrap(),
             Expr::Term(
                 Term::Factor(Factor::UnOp(UnOp::Complement, Factor::IntLiteral(0).into()).into())
                     .into()
@@ -170,8 +653,12 @@ mod tests {
     #[test]
     fn return_statement() {
         assert_eq!(
-            parse_statement(&mut vec![ReturnKw, IntLiteral(0), Semicolon].iter().peekable())
-                .unwrap(),
+            parse_statement(
+                &mut [ReturnKw, IntLiteral(0), Semicolon].iter().peekable(),
+                0,
+                None,
+            )
+            .unwrap(),
             Node::Statement(
                 Statement::Return,
                 Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into()
@@ -179,12 +666,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cancelled_parse() {
+        let tokens = lexer::tokenize("int main() { return 1; }").unwrap();
+        let cancellation = Cancellation::default();
+        assert!(parse_cancellable(&tokens, &cancellation).is_some());
+        cancellation.cancel();
+        assert!(parse_cancellable(&tokens, &cancellation).is_none());
+    }
+
     #[test]
     fn basic_function() {
-        let func_name = String::from("foo");
+        let func_name: Name = "foo".into();
         assert_eq!(
             parse_function(
-                &mut vec![
+                &mut [
                     IntKw,
                     Identifier(func_name.clone()),
                     OpenParen,
@@ -196,138 +692,342 @@ mod tests {
                     CloseBrace
                 ]
                 .iter()
-                .peekable()
+                .peekable(),
+                None
             )
             .unwrap(),
             Node::Function(
                 func_name.clone(),
-                Node::Statement(
+                Attributes::default(),
+                Node::Block(vec![Node::Statement(
                     Statement::Return,
                     Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into()
-                )
+                )])
                 .into()
             )
         );
     }
 
     #[test]
-    fn program_function_return_0() {
-        let func_name = String::from("foo");
+    fn asm_statement() {
         assert_eq!(
-            parse(&vec![
-                IntKw,
-                Identifier(func_name.clone()),
-                OpenParen,
-                CloseParen,
-                OpenBrace,
-                ReturnKw,
-                IntLiteral(0),
-                Semicolon,
-                CloseBrace
-            ])
+            parse_statement(
+                &mut [
+                    AsmKw,
+                    OpenParen,
+                    StringLiteral(String::from("nop")),
+                    CloseParen,
+                    Semicolon
+                ]
+                .iter()
+                .peekable(),
+                0,
+                None,
+            )
             .unwrap(),
-            Node::Program(
-                Node::Function(
-                    func_name.clone(),
-                    Node::Statement(
-                        Statement::Return,
-                        Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into()
-                    )
-                    .into()
-                )
-                .into()
+            Node::InlineAsm {
+                text: String::from("nop"),
+                volatile: false
+            }
+        );
+        assert_eq!(
+            parse_statement(
+                &mut [
+                    AsmKw,
+                    VolatileKw,
+                    OpenParen,
+                    StringLiteral(String::from("nop")),
+                    CloseParen,
+                    Semicolon
+                ]
+                .iter()
+                .peekable(),
+                0,
+                None,
             )
+            .unwrap(),
+            Node::InlineAsm {
+                text: String::from("nop"),
+                volatile: true
+            }
+        );
+        assert_eq!(
+            parse_statement(
+                &mut [
+                    AsmKw,
+                    RestrictKw,
+                    OpenParen,
+                    StringLiteral(String::from("nop")),
+                    CloseParen,
+                    Semicolon
+                ]
+                .iter()
+                .peekable(),
+                0,
+                None,
+            ),
+            Err(Stop::Error(SyntaxError::MissingOpenParen))
+        );
+        assert_eq!(
+            parse_statement(
+                &mut [AsmKw, OpenParen, IntLiteral(0), CloseParen, Semicolon]
+                    .iter()
+                    .peekable(),
+                0,
+                None,
+            ),
+            Err(Stop::Error(SyntaxError::UnexpectedToken))
         );
     }
 
     #[test]
-    fn program_function_return_complement_0() {
-        let func_name = String::from("foo");
+    fn if_statements() {
+        let parse_source =
+            |source| format!("{}", parse(&lexer::tokenize(source).unwrap()).unwrap());
+        let ast =
+            parse_source("int main() { if (1) return 2; else if (3) return 4; else return 5; }");
+        assert!(
+            ast.contains(
+                "IF Int<1> THEN RETURN Int<2> ELSE IF Int<3> THEN RETURN Int<4> ELSE RETURN Int<5>"
+            ),
+            "{}",
+            ast
+        );
+        let ast = parse_source("int main() { if (1) if (2) return 3; else return 4; return 5; }");
+        // The else belongs to the nearest if
+        assert!(
+            ast.contains("IF Int<1> THEN IF Int<2> THEN RETURN Int<3> ELSE RETURN Int<4>"),
+            "{}",
+            ast
+        );
+        let error = |source| parse(&lexer::tokenize(source).unwrap()).unwrap_err().0;
         assert_eq!(
-            parse(&vec![
-                IntKw,
-                Identifier(func_name.clone()),
-                OpenParen,
-                CloseParen,
-                OpenBrace,
-                ReturnKw,
-                Tilde,
-                IntLiteral(0),
-                Semicolon,
-                CloseBrace
-            ])
-            .unwrap(),
-            Node::Program(
-                Node::Function(
-                    func_name.clone(),
-                    Node::Statement(
-                        Statement::Return,
-                        Expr::Term(
-                            Term::Factor(
-                                Factor::UnOp(UnOp::Complement, Factor::IntLiteral(0).into()).into()
-                            )
-                            .into()
-                        )
-                        .into()
-                    )
-                    .into()
-                )
-                .into()
-            )
+            error("int main() { if 1 return 2; }"),
+            SyntaxError::MissingOpenParen
+        );
+        assert_eq!(
+            error("int main() { if (1 return 2; }"),
+            SyntaxError::MissingCloseParen
+        );
+        assert_eq!(
+            error("int main() { if (1) else return 2; }"),
+            SyntaxError::UnexpectedToken
+        );
+        assert_eq!(
+            error("int main() { else return 2; }"),
+            SyntaxError::UnexpectedToken
         );
     }
 
     #[test]
-    fn return_unary_on_unary_expr() {
-        let func_name = String::from("foo");
+    fn do_while_loops() {
+        let parse_source =
+            |source| format!("{}", parse(&lexer::tokenize(source).unwrap()).unwrap());
         assert_eq!(
-            parse(&vec![
-                IntKw,
-                Identifier(func_name.clone()),
-                OpenParen,
-                CloseParen,
-                OpenBrace,
-                ReturnKw,
-                Tilde,
-                OpenParen,
-                Bang,
-                IntLiteral(1),
-                CloseParen,
-                Semicolon,
-                CloseBrace
-            ])
+            parse_source("int main() { do { asm(\"nop\"); } while (1); return 0; }"),
+            parse_source("int main() { do asm(\"nop\"); while (1); return 0; }")
+        );
+        let ast = parse_source("int main() { do do return 1; while (2); while (3); }");
+        assert!(
+            ast.contains("DO DO RETURN Int<1> WHILE Int<2> WHILE Int<3>"),
+            "{}",
+            ast
+        );
+        let error = |source| parse(&lexer::tokenize(source).unwrap()).unwrap_err().0;
+        assert_eq!(
+            error("int main() { do return 1; while (0) }"),
+            SyntaxError::MissingSemicolon
+        );
+        assert_eq!(
+            error("int main() { do return 1; (0); }"),
+            SyntaxError::MissingKeyword(String::from("while"))
+        );
+        assert_eq!(
+            error("int main() { do { return 1; } while (0);"),
+            SyntaxError::MissingCloseBrace
+        );
+        let ast = parse_source("int main() { do { break; continue; } while (1); }");
+        assert!(
+            ast.contains("DO BREAK\n        CONTINUE WHILE Int<1>"),
+            "{}",
+            ast
+        );
+        assert_eq!(
+            error("int main() { do break while (1); }"),
+            SyntaxError::MissingSemicolon
+        );
+    }
+
+    #[test]
+    fn inline_functions() {
+        for source in &[
+            "inline int f() { return 0; }",
+            "int __inline__ f() { return 0; }",
+            "inline int inline f() { return 0; }",
+        ] {
+            let tokens = lexer::tokenize(source).unwrap();
+            let Ok(ast::Node::Program(function)) = parse(&tokens) else {
+                panic!("Expected a program from {}", source);
+            };
+            assert!(
+                matches!(
+                    &*function,
+                    Node::Function(_, Attributes { inline: true, .. }, _)
+                ),
+                "{}",
+                source
+            );
+            assert!(function.function_names().is_empty());
+        }
+        let tokens = lexer::tokenize("int f inline() { return 0; }").unwrap();
+        assert_eq!(parse(&tokens), Err((SyntaxError::MissingOpenParen, 2)));
+    }
+
+    #[test]
+    fn gnu_attributes() {
+        let attributes = |source| {
+            let tokens = lexer::tokenize(source).unwrap();
+            match parse(&tokens) {
+                Ok(ast::Node::Program(function)) => match *function {
+                    Node::Function(_, attributes, _) => Ok(attributes),
+                    other => panic!("Expected a function, got {:?}", other),
+                },
+                Ok(other) => panic!("Expected a program, got {:?}", other),
+                Err((e, _)) => Err(e),
+            }
+        };
+        let noreturn =
+            attributes("__extension__ __attribute__((__noreturn__)) int main() { return 0; }")
+                .unwrap();
+        assert!(noreturn.noreturn && !noreturn.unused);
+        let both = attributes(
+            "int __attribute__((, unused, aligned(1 << (4)), const,)) \
+             __attribute((noreturn)) inline main(void) { return 0; }",
+        )
+        .unwrap();
+        assert_eq!(
+            both,
+            Attributes {
+                inline: true,
+                prototype: true,
+                noreturn: true,
+                unused: true,
+            }
+        );
+        // Unknown attributes are ignored
+        assert_eq!(
+            attributes("int __attribute__((cold)) main() { return 0; }"),
+            Ok(Attributes::default())
+        );
+
+        for (source, error) in [
+            (
+                "int __attribute__(noreturn) main() { return 0; }",
+                SyntaxError::MissingOpenParen,
+            ),
+            (
+                "int __attribute__((noreturn) main() { return 0; }",
+                SyntaxError::MissingCloseParen,
+            ),
+            (
+                "int __attribute__((aligned(16) main() { return 0; }",
+                SyntaxError::MissingCloseParen,
+            ),
+            (
+                "int __attribute__(((noreturn))) main() { return 0; }",
+                SyntaxError::UnexpectedToken,
+            ),
+        ] {
+            assert_eq!(attributes(source), Err(error), "{}", source);
+        }
+    }
+
+    #[test]
+    fn void_parameter_list() {
+        let prototype = |source| {
+            let tokens = lexer::tokenize(source).unwrap();
+            match parse(&tokens) {
+                Ok(ast::Node::Program(function)) => match *function {
+                    Node::Function(_, attributes, _) => Ok(attributes.prototype),
+                    other => panic!("Expected a function, got {:?}", other),
+                },
+                Ok(other) => panic!("Expected a program, got {:?}", other),
+                Err((e, _)) => Err(e),
+            }
+        };
+        assert_eq!(prototype("int main(void) { return 0; }"), Ok(true));
+        assert_eq!(prototype("int main() { return 0; }"), Ok(false));
+        assert_eq!(
+            prototype("int main(void void) { return 0; }"),
+            Err(SyntaxError::MissingCloseParen)
+        );
+    }
+
+    #[test]
+    fn function_name_is_shared() {
+        let tokens = lexer::tokenize("int main() { return 0; }").unwrap();
+        let Ok(ast::Node::Program(function)) = parse(&tokens) else {
+            panic!("Expected a program");
+        };
+        match (&tokens[1].0, &*function) {
+            (Identifier(token), Node::Function(name, ..)) => {
+                assert!(std::sync::Arc::ptr_eq(token, name))
+            }
+            other => panic!("Expected a function name, got {:?}", other),
+        }
+    }
+
+    /// Compare the tree of each test program that parses with its dump in
+    /// `tests/testfiles/ast`, as `--emit ast` writes it. Set `RCC_BLESS` to
+    /// write the dumps instead, after checking the changes are intended.
+    #[test]
+    fn golden_ast_dumps() {
+        let golden_dir = Path::new("tests/testfiles/ast");
+        let bless = env::var_os("RCC_BLESS").is_some();
+        for entry in fs::read_dir("tests/testfiles/valid").unwrap() {
+            let path = entry.unwrap().path();
+            let source = fs::read_to_string(&path).unwrap();
+            // Not every test file is supported by the parser yet
+            let ast = match lexer::tokenize(&source).map(|tokens| parse(&tokens)) {
+                Ok(Ok(ast)) => format!("{:#?}\n", ast),
+                _ => continue,
+            };
+            let golden = golden_dir.join(path.with_extension("ast").file_name().unwrap());
+            if bless {
+                fs::write(&golden, ast).unwrap();
+                continue;
+            }
+            let expected = fs::read_to_string(&golden).unwrap_or_else(|_| {
+                panic!(
+                    "No AST dump for {}, run with RCC_BLESS=1 to write it",
+                    path.display()
+                )
+            });
+            assert_eq!(ast, expected, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn located_return_statement() {
+        let span = |line, column| Span { line, column };
+        assert_eq!(
+            parse_statement(
+                &mut [
+                    (ReturnKw, span(2, 5)),
+                    (IntLiteral(0), span(2, 12)),
+                    (Semicolon, span(2, 13))
+                ]
+                .iter()
+                .peekable(),
+                0,
+                None,
+            )
             .unwrap(),
-            Node::Program(
-                Node::Function(
-                    func_name.clone(),
-                    Node::Statement(
-                        Statement::Return,
-                        Expr::Term(
-                            Term::Factor(
-                                Factor::UnOp(
-                                    UnOp::Complement,
-                                    Factor::Expr(
-                                        Expr::Term(
-                                            Term::Factor(
-                                                Factor::UnOp(
-                                                    UnOp::LogicalNegate,
-                                                    Factor::IntLiteral(1).into()
-                                                )
-                                                .into()
-                                            )
-                                            .into()
-                                        )
-                                        .into()
-                                    )
-                                    .into()
-                                )
-                                .into()
-                            )
-                            .into()
-                        )
-                        .into()
-                    )
-                    .into()
+            Node::Located(
+                span(2, 5),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into()
                 )
                 .into()
             )
@@ -336,19 +1036,16 @@ mod tests {
 
     macro_rules! assert_raises_syntax_error {
         ($left:expr, $err:expr) => {
-            assert_eq!(
-                *$left.err().unwrap().downcast::<SyntaxError>().unwrap(),
-                $err
-            );
+            assert_eq!($left.unwrap_err().0, $err);
         };
     }
 
     #[test]
     fn function_missing_closing_brace() {
         assert_raises_syntax_error!(
-            parse(&vec![
+            parse(&[
                 IntKw,
-                Identifier(String::from("foo")),
+                Identifier("foo".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -363,9 +1060,9 @@ mod tests {
     #[test]
     fn function_missing_closing_paren() {
         assert_raises_syntax_error!(
-            parse(&vec![
+            parse(&[
                 IntKw,
-                Identifier(String::from("foo")),
+                Identifier("foo".into()),
                 OpenParen,
                 OpenBrace,
                 ReturnKw,
@@ -380,9 +1077,9 @@ mod tests {
     #[test]
     fn function_missing_closing_paren_and_brace() {
         assert_raises_syntax_error!(
-            parse(&vec![
+            parse(&[
                 IntKw,
-                Identifier(String::from("foo")),
+                Identifier("foo".into()),
                 OpenParen,
                 OpenBrace,
                 ReturnKw,
@@ -396,9 +1093,9 @@ mod tests {
     #[test]
     fn function_missing_return_value() {
         assert_raises_syntax_error!(
-            parse(&vec![
+            parse(&[
                 IntKw,
-                Identifier(String::from("foo")),
+                Identifier("foo".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -413,9 +1110,9 @@ mod tests {
     #[test]
     fn function_missing_semicolon() {
         assert_raises_syntax_error!(
-            parse(&vec![
+            parse(&[
                 IntKw,
-                Identifier(String::from("foo")),
+                Identifier("foo".into()),
                 OpenParen,
                 CloseParen,
                 OpenBrace,
@@ -426,4 +1123,170 @@ mod tests {
             SyntaxError::MissingSemicolon
         );
     }
+
+    #[test]
+    fn error_positions() {
+        let error_index = |rest: Vec<Token>| {
+            let mut tokens = vec![
+                IntKw,
+                Identifier("foo".into()),
+                OpenParen,
+                CloseParen,
+                OpenBrace,
+                ReturnKw,
+            ];
+            tokens.extend(rest);
+            parse(&tokens).unwrap_err().1
+        };
+        // The token that caused the error
+        assert_eq!(error_index(vec![IntLiteral(5), CloseBrace]), 7);
+        assert_eq!(error_index(vec![Semicolon, CloseBrace]), 6);
+        // Or the end of the input
+        assert_eq!(error_index(vec![IntLiteral(5), Semicolon]), 8);
+        assert_eq!(error_index(vec![IntLiteral(5), Plus]), 8);
+    }
+
+    #[test]
+    fn recovery() {
+        let recover = |source| {
+            let tokens = lexer::tokenize(source).unwrap();
+            let (ast, errors) = parse_with_recovery(&tokens);
+            let errors: Vec<_> = errors.into_iter().map(|(e, _)| e).collect();
+            (format!("{}", ast), errors)
+        };
+        // A program that parses is unchanged
+        let source = "int main() { asm(\"nop\"); return 2; }";
+        let tokens = lexer::tokenize(source).unwrap();
+        assert_eq!(
+            parse_with_recovery(&tokens),
+            (parse(&tokens).unwrap(), vec![])
+        );
+
+        // Each bad statement becomes an error node, and parsing carries on
+        let (ast, errors) = recover("int main() { asm(1); asm(\"nop\") x; return 1 + ; }");
+        assert_eq!(
+            errors,
+            [
+                SyntaxError::UnexpectedToken,
+                SyntaxError::MissingSemicolon,
+                SyntaxError::InvalidFactor,
+            ]
+        );
+        assert_eq!(ast.matches("ERROR").count(), 3, "{}", ast);
+        let (ast, errors) = recover("int main() { retrun 1; return 2; }");
+        assert_eq!(errors, [SyntaxError::UnexpectedToken]);
+        assert!(
+            ast.contains("ERROR") && ast.contains("RETURN Int<2>"),
+            "{}",
+            ast
+        );
+
+        // Errors in nested blocks skip past the block, and parsing carries on
+        let (ast, errors) = recover("int main() { if (1) { return 1 } return 2 +; return 3; }");
+        assert_eq!(
+            errors,
+            [SyntaxError::MissingSemicolon, SyntaxError::InvalidFactor]
+        );
+        assert_eq!(ast.matches("ERROR").count(), 2, "{}", ast);
+        assert!(ast.contains("RETURN Int<3>"), "{}", ast);
+        let (ast, errors) =
+            recover("int main() { do { if (1) { return 1 + ; } } while (1); return 2; }");
+        assert_eq!(errors, [SyntaxError::InvalidFactor]);
+        assert!(ast.contains("RETURN Int<2>"), "{}", ast);
+        let (ast, errors) =
+            recover("int main() { do { { asm(1); } return 1; } while (x); return 2; return 3 }");
+        assert_eq!(
+            errors,
+            [SyntaxError::UnexpectedToken, SyntaxError::MissingSemicolon]
+        );
+        assert!(ast.contains("RETURN Int<2>"), "{}", ast);
+
+        let (ast, errors) =
+            recover("int main() { if (1) { return 1 + ; } else { return 2; } return 3; }");
+        assert_eq!(errors, [SyntaxError::InvalidFactor]);
+        assert!(ast.contains("RETURN Int<3>"), "{}", ast);
+
+        // The end of the body isn't reported again
+        assert_eq!(
+            recover("int main() { return 1 }").1,
+            [SyntaxError::MissingSemicolon]
+        );
+        assert_eq!(
+            recover("int main() { return 1; ").1,
+            [SyntaxError::MissingCloseBrace]
+        );
+        assert_eq!(recover("int main() {").1, [SyntaxError::MissingCloseBrace]);
+
+        // A bad declaration leaves nothing to recover
+        let (ast, errors) = recover("int (void) { return 1; }");
+        assert_eq!(errors, [SyntaxError::MissingIdentifier]);
+        assert_eq!(ast, "ERROR");
+
+        // Error nodes are located when the tokens are
+        let tokens = lexer::tokenize("int main() {\n    return ;\n}").unwrap();
+        let (ast, errors) = parse_with_recovery(&tokens);
+        assert_eq!(errors, [(SyntaxError::InvalidFactor, 6)]);
+        let Node::Program(function) = ast else {
+            panic!("Expected a program");
+        };
+        let Node::Function(_, _, body) = *function else {
+            panic!("Expected a function");
+        };
+        assert_eq!(
+            *body,
+            Node::Block(vec![Node::Located(
+                Span { line: 2, column: 5 },
+                Node::Error.into()
+            )])
+        );
+    }
+
+    #[test]
+    fn nested_too_deeply() {
+        let program = |expr: String| format!("int main() {{ return {}; }}", expr);
+        let parens = program(format!("{}1{}", "(".repeat(2000), ")".repeat(2000)));
+        assert_eq!(
+            parse_lossy(parens.as_bytes()).unwrap_err(),
+            SyntaxError::NestedTooDeeply
+        );
+        let negations = program(format!("{}1", "-".repeat(2000)));
+        assert_eq!(
+            parse_lossy(negations.as_bytes()).unwrap_err(),
+            SyntaxError::NestedTooDeeply
+        );
+        let chain = program(vec!["1"; 2000].join(" + "));
+        assert_eq!(
+            parse_lossy(chain.as_bytes()).unwrap_err(),
+            SyntaxError::NestedTooDeeply
+        );
+        let shallow = program(vec!["1"; 500].join(" * "));
+        assert!(parse_lossy(shallow.as_bytes()).is_ok());
+
+        // Each nested statement counts as several levels
+        let function = |body: String| format!("int main() {{ {} }}", body);
+        let blocks = |depth| {
+            function(format!(
+                "{}return 1;{}",
+                "{".repeat(depth),
+                "}".repeat(depth)
+            ))
+        };
+        assert_eq!(
+            parse_lossy(blocks(300).as_bytes()).unwrap_err(),
+            SyntaxError::NestedTooDeeply
+        );
+        assert!(parse_lossy(blocks(250).as_bytes()).is_ok());
+        let ifs = function(format!("{}return 1;", "if (1) ".repeat(300)));
+        assert_eq!(
+            parse_lossy(ifs.as_bytes()).unwrap_err(),
+            SyntaxError::NestedTooDeeply
+        );
+    }
+
+    #[test]
+    fn lossy_input() {
+        assert!(parse_lossy(b"int main() { return 2; }").is_ok());
+        assert!(parse_lossy(b"int main() { return \xff; }").is_err());
+        assert!(parse_lossy(b"").is_err());
+    }
 }
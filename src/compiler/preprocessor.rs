@@ -0,0 +1,1090 @@
+//! rcc's own C preprocessor, used instead of the system one with
+//! `-fintegrated-cpp`.
+//!
+//! `#include`, `#define` and conditional compilation are supported so far,
+//! without the `#` and `##` operators. Headers are searched for in the `-I`
+//! directories, so system headers still need the system preprocessor.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::diagnostic::{Diagnostic, Warning};
+
+mod expression;
+
+/// The file and 1-based line that a line of preprocessed output came from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub file: Rc<Path>,
+    pub line: usize,
+}
+
+/// Preprocessed source text, with the origin of each of its lines so that
+/// diagnostics can point into the original files.
+#[derive(Debug, Default)]
+pub struct Preprocessed {
+    pub text: String,
+    origins: Vec<Origin>,
+    /// Warnings found, such as from `#warning`, for the driver to report
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Preprocessed {
+    /// Text that rcc didn't preprocess, whose lines are where they came from.
+    pub fn unmapped(text: impl Into<String>) -> Self {
+        Preprocessed {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Where the given 1-based line of `text` came from.
+    pub fn origin(&self, line: usize) -> Option<&Origin> {
+        self.origins.get(line.checked_sub(1)?)
+    }
+
+    /// The file and line that the given line of `text` came from, which for
+    /// unmapped text are `filename` and the same line.
+    pub fn place<'a>(&'a self, filename: &'a Path, line: usize) -> (&'a Path, usize) {
+        match self.origin(line) {
+            Some(origin) => (&origin.file, origin.line),
+            None => (filename, line),
+        }
+    }
+
+    fn push_line(&mut self, line: &str, origin: Origin) {
+        self.text.push_str(line);
+        self.text.push('\n');
+        self.origins.push(origin);
+    }
+}
+
+/// Preprocess the source text of `filename`, which may be `<stdin>`.
+pub fn preprocess(
+    contents: &str,
+    filename: &Path,
+    include_dirs: &[PathBuf],
+) -> Result<Preprocessed, Box<dyn Error>> {
+    let mut output = Preprocessed::default();
+    let macros = PREDEFINED
+        .iter()
+        .map(|&(name, replacement)| {
+            let definition = Macro {
+                params: None,
+                replacement: String::from(replacement),
+            };
+            (String::from(name), definition)
+        })
+        .collect();
+    let mut preprocessor = Preprocessor {
+        include_dirs,
+        macros,
+        once: HashSet::new(),
+        guards: HashMap::new(),
+        depth: 0,
+    };
+    preprocessor.process(contents, Rc::from(filename), &mut output)?;
+    Ok(output)
+}
+
+/// The macros defined by the implementation. `__FILE__` and `__LINE__` are
+/// replaced with the current position when they're expanded.
+const PREDEFINED: [(&str, &str); 4] = [
+    ("__FILE__", ""),
+    ("__LINE__", ""),
+    ("__STDC__", "1"),
+    ("__STDC_HOSTED__", "1"),
+];
+
+fn is_predefined(name: &str) -> bool {
+    PREDEFINED.iter().any(|&(predefined, _)| predefined == name)
+}
+
+/// A macro definition. Whitespace in the replacement is normalised so
+/// that redefinitions can be compared.
+#[derive(Debug, PartialEq)]
+struct Macro {
+    /// The parameter names of a function-like macro, ending with `...` if
+    /// it's variadic
+    params: Option<Vec<String>>,
+    replacement: String,
+}
+
+/// An `#if` group and the state of its current branch
+struct Conditional {
+    /// Whether the lines of the current branch are kept
+    active: bool,
+    /// Whether the group itself is in a kept part of the file
+    parent_active: bool,
+    /// Whether an earlier branch, or the current one, was kept
+    taken: bool,
+    seen_else: bool,
+    /// Where the group started, for reporting it unterminated
+    line: usize,
+}
+
+struct Preprocessor<'a> {
+    include_dirs: &'a [PathBuf],
+    macros: HashMap<String, Macro>,
+    /// Files that contain `#pragma once`
+    once: HashSet<PathBuf>,
+    /// Files wrapped in an include guard, with the guard's macro
+    guards: HashMap<PathBuf, String>,
+    /// How many includes deep the current file is
+    depth: usize,
+}
+
+/// The limit on nested includes, which stops a file that includes itself
+/// without a guard from recursing forever
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+impl Preprocessor<'_> {
+    fn process(
+        &mut self,
+        contents: &str,
+        file: Rc<Path>,
+        output: &mut Preprocessed,
+    ) -> Result<(), Box<dyn Error>> {
+        let source = strip_comments(&splice_lines(contents));
+        // Conditional groups can't span files, so each file has its own stack
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let origin = Origin {
+                file: file.clone(),
+                line: i + 1,
+            };
+            let locate = |e| format!("{}:{}: {}", file.display(), origin.line, e);
+            let active = conditionals.last().is_none_or(|c| c.active);
+            let directive = match line.trim_start().strip_prefix('#') {
+                Some(directive) => directive.trim(),
+                None if active => {
+                    let line = self.expand(line, &origin, &[]).map_err(locate)?;
+                    output.push_line(&line, origin);
+                    continue;
+                }
+                None => {
+                    output.push_line("", origin);
+                    continue;
+                }
+            };
+            let (name, rest) = split_directive(directive);
+            match name {
+                "if" | "ifdef" | "ifndef" => {
+                    // Conditions in skipped groups aren't evaluated at all
+                    let condition =
+                        active && self.condition(name, rest, &origin).map_err(locate)?;
+                    conditionals.push(Conditional {
+                        active: condition,
+                        parent_active: active,
+                        taken: condition,
+                        seen_else: false,
+                        line: origin.line,
+                    });
+                }
+                "elif" | "else" | "endif" => {
+                    let conditional = conditionals
+                        .last_mut()
+                        .ok_or_else(|| locate(format!("#{} without #if", name)))?;
+                    if name != "endif" && conditional.seen_else {
+                        return Err(locate(format!("#{} after #else", name)).into());
+                    }
+                    let open = conditional.parent_active && !conditional.taken;
+                    match name {
+                        "elif" => {
+                            conditional.active =
+                                open && self.condition(name, rest, &origin).map_err(locate)?;
+                        }
+                        "else" => {
+                            conditional.active = open;
+                            conditional.seen_else = true;
+                        }
+                        _ => {
+                            conditionals.pop();
+                        }
+                    }
+                    if let Some(conditional) = conditionals.last_mut() {
+                        conditional.taken |= conditional.active;
+                    }
+                }
+                // Other directives in skipped groups are ignored
+                _ if !active => (),
+                "include" => {
+                    let header = self.find_header(rest, &file).map_err(locate)?;
+                    let key = fs::canonicalize(&header).unwrap_or_else(|_| header.clone());
+                    if self.once.contains(&key) {
+                        continue;
+                    }
+                    if let Some(guard) = self.guards.get(&key) {
+                        if self.macros.contains_key(guard) {
+                            continue;
+                        }
+                    }
+                    if self.depth == MAX_INCLUDE_DEPTH {
+                        return Err(locate(format!(
+                            "#include nested more than {} levels deep, including {}",
+                            MAX_INCLUDE_DEPTH, rest
+                        ))
+                        .into());
+                    }
+                    let contents = fs::read_to_string(&header)
+                        .map_err(|e| locate(format!("{}: {}", rest, e)))?;
+                    if let Some(guard) = include_guard(&strip_comments(&splice_lines(&contents))) {
+                        self.guards.insert(key, String::from(guard));
+                    }
+                    self.depth += 1;
+                    let result = self.process(&contents, Rc::from(header.as_path()), output);
+                    self.depth -= 1;
+                    result?;
+                    continue;
+                }
+                "pragma" if rest == "once" => {
+                    let key = fs::canonicalize(&file).unwrap_or_else(|_| file.to_path_buf());
+                    self.once.insert(key);
+                }
+                // Pragmas rcc doesn't know are ignored, as other compilers do
+                "pragma" => (),
+                "define" => self.define(rest).map_err(locate)?,
+                "undef" => {
+                    let (name, rest) = macro_name(rest).map_err(locate)?;
+                    if !rest.trim().is_empty() {
+                        return Err(locate(format!("Extra tokens after #undef {}", name)).into());
+                    }
+                    if is_predefined(name) {
+                        return Err(locate(format!("Cannot undefine {}", name)).into());
+                    }
+                    self.macros.remove(name);
+                }
+                "warning" => output.warnings.push(Diagnostic {
+                    warning: Warning::Cpp,
+                    message: locate(format!("#warning {}", rest)),
+                }),
+                // The null directive does nothing
+                "" => (),
+                _ => return Err(locate(format!("Unsupported directive #{}", name)).into()),
+            }
+            // Keep a line in place of the directive so that line numbers in
+            // the output match the source wherever nothing was included
+            output.push_line("", origin);
+        }
+        match conditionals.last() {
+            Some(conditional) => Err(format!(
+                "{}:{}: Unterminated conditional directive",
+                file.display(),
+                conditional.line
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Evaluate the condition of an `#if`, `#elif`, `#ifdef` or `#ifndef`.
+    fn condition(&self, directive: &str, operand: &str, origin: &Origin) -> Result<bool, String> {
+        if directive == "ifdef" || directive == "ifndef" {
+            let (name, rest) = macro_name(operand)?;
+            if !rest.trim().is_empty() {
+                return Err(format!("Extra tokens after #{} {}", directive, name));
+            }
+            return Ok(self.macros.contains_key(name) == (directive == "ifdef"));
+        }
+        let expanded = self.expand(&self.replace_defined(operand)?, origin, &[])?;
+        Ok(expression::evaluate(&expanded)? != 0)
+    }
+
+    /// Replace each `defined NAME` or `defined(NAME)` with 1 or 0, before the
+    /// names can be expanded as macros.
+    fn replace_defined(&self, text: &str) -> Result<String, String> {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while !rest.is_empty() {
+            let (len, _) = token_len(rest);
+            if &rest[..len] != "defined" {
+                result.push_str(&rest[..len]);
+                rest = &rest[len..];
+                continue;
+            }
+            let operand = rest[len..].trim_start();
+            let (parenthesised, operand) = match operand.strip_prefix('(') {
+                Some(operand) => (true, operand.trim_start()),
+                None => (false, operand),
+            };
+            let (name, mut after) = macro_name(operand)
+                .map_err(|_| String::from("Expected a macro name after defined"))?;
+            if parenthesised {
+                after = after
+                    .trim_start()
+                    .strip_prefix(')')
+                    .ok_or("Missing ) after defined")?;
+            }
+            result.push_str(if self.macros.contains_key(name) {
+                "1"
+            } else {
+                "0"
+            });
+            rest = after;
+        }
+        Ok(result)
+    }
+
+    /// Handle `#define NAME replacement` or `#define NAME(params)
+    /// replacement`. A macro may only be redefined with the same parameters
+    /// and replacement.
+    fn define(&mut self, definition: &str) -> Result<(), String> {
+        let (name, rest) = macro_name(definition)?;
+        if is_predefined(name) {
+            return Err(format!("Cannot redefine {}", name));
+        }
+        // Only a parenthesis directly after the name makes a function-like
+        // macro; otherwise it's part of the replacement
+        let (params, replacement) = match rest.strip_prefix('(') {
+            Some(rest) => {
+                let close = rest
+                    .find(')')
+                    .ok_or_else(|| format!("Missing ) in parameters of macro {}", name))?;
+                (Some(parse_params(&rest[..close])?), &rest[close + 1..])
+            }
+            None => (None, rest),
+        };
+        let definition = Macro {
+            params,
+            replacement: replacement.split_whitespace().collect::<Vec<_>>().join(" "),
+        };
+        match self.macros.get(name) {
+            Some(existing) if *existing != definition => {
+                Err(format!("Macro {} redefined differently", name))
+            }
+            _ => {
+                self.macros.insert(String::from(name), definition);
+                Ok(())
+            }
+        }
+    }
+
+    /// Replace macros in `text` with their expansions. Each replacement is
+    /// rescanned along with the rest of the text, so a macro can expand to
+    /// the name of a function-like macro whose arguments follow it. A macro
+    /// isn't expanded again within its own replacement, nor if it's in
+    /// `disabled`, so that self-referential macros terminate. `origin` is
+    /// the line being expanded, for `__FILE__` and `__LINE__`.
+    fn expand(&self, text: &str, origin: &Origin, disabled: &[String]) -> Result<String, String> {
+        let mut input = String::from(text);
+        let mut result = String::with_capacity(text.len());
+        let mut pos = 0;
+        // The macros whose replacements are being scanned, each with the
+        // length of the input that follows its replacement
+        let mut active: Vec<(String, usize)> = Vec::new();
+        while pos < input.len() {
+            let remaining = input.len() - pos;
+            active.retain(|&(_, after)| after < remaining);
+            let (len, identifier) = token_len(&input[pos..]);
+            let name = &input[pos..pos + len];
+            let is_disabled =
+                disabled.iter().any(|m| m == name) || active.iter().any(|(m, _)| m == name);
+            let definition = match self.macros.get(name) {
+                Some(definition) if identifier && !is_disabled => definition,
+                _ => {
+                    result.push_str(name);
+                    pos += len;
+                    continue;
+                }
+            };
+            let name = String::from(name);
+            let mut end = pos + len;
+            let replacement = match &definition.params {
+                None if name == "__FILE__" => quote(&origin.file.display().to_string()),
+                None if name == "__LINE__" => origin.line.to_string(),
+                None => definition.replacement.clone(),
+                Some(params) => {
+                    let (args, args_len) = match call_arguments(&input[end..]) {
+                        Some(Ok(call)) => call,
+                        Some(Err(e)) => return Err(format!("{} in call to macro {}", e, name)),
+                        // A function-like macro name alone isn't expanded
+                        None => {
+                            result.push_str(&name);
+                            pos = end;
+                            continue;
+                        }
+                    };
+                    end += args_len;
+                    // Arguments are expanded before they're substituted
+                    let mut disabled = disabled.to_vec();
+                    disabled.extend(active.iter().map(|(m, _)| m.clone()));
+                    let args = args
+                        .iter()
+                        .map(|arg| self.expand(arg, origin, &disabled))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    substitute(&name, definition, params, &args)?
+                }
+            };
+            // Replacements that ended inside the arguments are finished with
+            let after = input.len() - end;
+            active.retain(|&(_, a)| a <= after);
+            active.push((name, after));
+            input.replace_range(pos..end, &replacement);
+        }
+        Ok(result)
+    }
+
+    /// Resolve the operand of `#include`. Quoted names are looked for next to
+    /// the including file first, then in the include directories.
+    fn find_header(&self, operand: &str, includer: &Path) -> Result<PathBuf, String> {
+        let (name, quoted) = if let Some(name) = delimited(operand, '"', '"') {
+            (name, true)
+        } else if let Some(name) = delimited(operand, '<', '>') {
+            (name, false)
+        } else {
+            return Err(String::from("Expected \"file\" or <file> after #include"));
+        };
+        let mut dirs = Vec::new();
+        if quoted {
+            // Files from stdin have no directory, so use the working directory
+            let dir = includer.parent().unwrap_or_else(|| Path::new(""));
+            dirs.push(dir);
+        }
+        dirs.extend(self.include_dirs.iter().map(PathBuf::as_path));
+        dirs.into_iter()
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| format!("Cannot find header {}", operand))
+    }
+}
+
+/// The macro guarding a header of the form
+///
+/// ```c
+/// #ifndef NAME
+/// #define NAME ...
+/// ...
+/// #endif
+/// ```
+///
+/// where nothing but blank lines is outside the `#ifndef` group. Once the
+/// macro is defined, including the header again would have no effect.
+fn include_guard(source: &str) -> Option<&str> {
+    let mut directives = source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.strip_prefix('#').map(|d| split_directive(d.trim())));
+    let guard = match directives.next()?? {
+        ("ifndef", name) if macro_name(name).ok()?.1.is_empty() => name,
+        _ => return None,
+    };
+    match directives.next()?? {
+        ("define", definition) if macro_name(definition).ok()?.0 == guard => (),
+        _ => return None,
+    }
+    // The group opened by #ifndef must only close on the last line
+    let mut depth = 1;
+    for directive in directives {
+        if depth == 0 {
+            return None;
+        }
+        match directive {
+            Some(("if", _)) | Some(("ifdef", _)) | Some(("ifndef", _)) => depth += 1,
+            Some(("endif", _)) => depth -= 1,
+            // An #else would be kept when the macro is defined
+            Some(("else", _)) | Some(("elif", _)) if depth == 1 => return None,
+            _ => (),
+        }
+    }
+    if depth == 0 {
+        Some(guard)
+    } else {
+        None
+    }
+}
+
+/// Split a directive into its name and the rest of the line.
+fn split_directive(directive: &str) -> (&str, &str) {
+    let end = directive
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(directive.len());
+    (&directive[..end], directive[end..].trim())
+}
+
+/// Parse the comma separated parameter list of a function-like macro.
+fn parse_params(list: &str) -> Result<Vec<String>, String> {
+    if list.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let params: Vec<String> = list.split(',').map(|p| String::from(p.trim())).collect();
+    for (i, param) in params.iter().enumerate() {
+        let valid = if param == "..." {
+            i == params.len() - 1
+        } else {
+            param.starts_with(is_identifier_start) && identifier_len(param) == param.len()
+        };
+        if !valid {
+            return Err(format!("Invalid macro parameter `{}`", param));
+        }
+        if params[..i].contains(param) {
+            return Err(format!("Duplicate macro parameter {}", param));
+        }
+    }
+    Ok(params)
+}
+
+/// Find the arguments of a function-like macro call at the start of `s`,
+/// returning them with the length of the call's text, or `None` if `s`
+/// doesn't start with a parenthesis.
+fn call_arguments(s: &str) -> Option<Result<(Vec<String>, usize), &'static str>> {
+    let open = s.len() - s.trim_start().len();
+    if !s[open..].starts_with('(') {
+        return None;
+    }
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = open + 1;
+    let mut pos = start;
+    while pos < s.len() {
+        let (len, _) = token_len(&s[pos..]);
+        match &s[pos..pos + len] {
+            "(" => depth += 1,
+            ")" if depth > 0 => depth -= 1,
+            ")" => {
+                args.push(String::from(s[start..pos].trim()));
+                return Some(Ok((args, pos + 1)));
+            }
+            "," if depth == 0 => {
+                args.push(String::from(s[start..pos].trim()));
+                start = pos + 1;
+            }
+            _ => (),
+        }
+        pos += len;
+    }
+    Some(Err("Missing )"))
+}
+
+/// Replace the parameters in a function-like macro's replacement with its
+/// arguments.
+fn substitute(
+    name: &str,
+    definition: &Macro,
+    params: &[String],
+    args: &[String],
+) -> Result<String, String> {
+    let variadic = params.last().is_some_and(|p| p == "...");
+    let named = if variadic {
+        &params[..params.len() - 1]
+    } else {
+        params
+    };
+    // An empty argument list is a single empty argument
+    let args = if args.len() == 1 && args[0].is_empty() && named.is_empty() {
+        &args[..0]
+    } else {
+        args
+    };
+    if args.len() < named.len() || (!variadic && args.len() > named.len()) {
+        return Err(format!(
+            "Macro {} expects {} arguments, got {}",
+            name,
+            named.len(),
+            args.len()
+        ));
+    }
+    let rest = args[named.len()..].join(", ");
+
+    let mut result = String::new();
+    let mut text = definition.replacement.as_str();
+    while !text.is_empty() {
+        let (len, _) = token_len(text);
+        let token = &text[..len];
+        match named.iter().position(|p| p == token) {
+            Some(i) => result.push_str(&args[i]),
+            None if variadic && token == "__VA_ARGS__" => result.push_str(&rest),
+            None => result.push_str(token),
+        }
+        text = &text[len..];
+    }
+    Ok(result)
+}
+
+/// The length of the token at the start of `s`, and whether it's an
+/// identifier. Anything that isn't an identifier, number or literal is
+/// treated as a one character token.
+fn token_len(s: &str) -> (usize, bool) {
+    let c = match s.chars().next() {
+        Some(c) => c,
+        None => return (0, false),
+    };
+    if c == '"' || c == '\'' {
+        (literal_len(s, c), false)
+    } else if c.is_ascii_digit() {
+        // Numbers such as 0x1f contain letters that aren't names
+        let len = s
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(s.len());
+        (len, false)
+    } else if is_identifier_start(c) {
+        (identifier_len(s), true)
+    } else {
+        (c.len_utf8(), false)
+    }
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// The length of the identifier at the start of `s`.
+fn identifier_len(s: &str) -> usize {
+    s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len())
+}
+
+/// Split the macro name from the start of a directive's operand.
+fn macro_name(s: &str) -> Result<(&str, &str), String> {
+    if !s.starts_with(is_identifier_start) {
+        return Err(String::from("Expected a macro name"));
+    }
+    let len = identifier_len(s);
+    Ok((&s[..len], &s[len..]))
+}
+
+/// The length of the string or character literal at the start of `s`,
+/// which is delimited by `quote`. An unterminated literal runs to the end.
+fn literal_len(s: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + 1;
+        }
+    }
+    s.len()
+}
+
+/// A string literal with the value `s`.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The text between `open` and `close`, if `s` is exactly that.
+fn delimited(s: &str, open: char, close: char) -> Option<&str> {
+    let inner = s.strip_prefix(open)?.strip_suffix(close)?;
+    if inner.is_empty() || inner.contains(close) {
+        None
+    } else {
+        Some(inner)
+    }
+}
+
+/// Join lines ending in a backslash with the next. The removed line breaks
+/// are added after the joined line, so later lines keep their numbers.
+fn splice_lines(contents: &str) -> String {
+    let mut result = String::with_capacity(contents.len());
+    let mut pending = 0;
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&'\n') {
+            chars.next();
+            pending += 1;
+        } else if c == '\n' {
+            result.extend(std::iter::repeat_n('\n', pending + 1));
+            pending = 0;
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Replace each comment with a space, keeping the line breaks inside block
+/// comments so that line numbers are unchanged.
+fn strip_comments(contents: &str) -> String {
+    let mut result = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+                result.push(' ');
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        result.push('\n');
+                    }
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                result.push(' ');
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(file: &Path, line: usize) -> Origin {
+        Origin {
+            file: Rc::from(file),
+            line,
+        }
+    }
+
+    #[test]
+    fn comments_keep_line_numbers() {
+        let source = "int main() /* a\ncomment */ {\n  return 2; // two\n}\n";
+        assert_eq!(
+            strip_comments(source),
+            "int main() \n  {\n  return 2;  \n}\n"
+        );
+        assert_eq!(splice_lines("a \\\nb\nc\n"), "a b\n\nc\n");
+    }
+
+    #[test]
+    fn include_quoted_and_angled() {
+        let dir = tempfile::tempdir().unwrap();
+        let include = dir.path().join("include");
+        fs::create_dir(&include).unwrap();
+        fs::write(dir.path().join("local.h"), "int local;\n").unwrap();
+        fs::write(include.join("lib.h"), "int lib;\n#include \"nested.h\"\n").unwrap();
+        fs::write(include.join("nested.h"), "int nested;\n").unwrap();
+        let main = dir.path().join("main.c");
+        let source = "#include \"local.h\"\n# include <lib.h>\nint main;\n";
+
+        let output = preprocess(source, &main, std::slice::from_ref(&include)).unwrap();
+        assert_eq!(
+            output.text,
+            "int local;\nint lib;\nint nested;\nint main;\n"
+        );
+        assert_eq!(
+            output.origin(1),
+            Some(&origin(&dir.path().join("local.h"), 1))
+        );
+        assert_eq!(
+            output.origin(3),
+            Some(&origin(&include.join("nested.h"), 1))
+        );
+        assert_eq!(output.origin(4), Some(&origin(&main, 3)));
+        assert_eq!(output.origin(5), None);
+
+        // Angled includes don't look next to the source
+        let error = preprocess("#include <local.h>\n", &main, &[include])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with(":1: Cannot find header <local.h>"),
+            "{}",
+            error
+        );
+    }
+
+    fn expand(source: &str) -> Result<String, String> {
+        preprocess(source, Path::new("test.c"), &[])
+            .map(|output| output.text)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn object_like_macros() {
+        assert_eq!(
+            expand("#define TWO 2\n#define FOUR TWO * TWO\nreturn FOUR;\n").unwrap(),
+            "\n\nreturn 2 * 2;\n"
+        );
+        // Names are only replaced as whole identifiers, outside literals
+        assert_eq!(
+            expand("#define x 1\nx xx 0x1 \"x\" 'x' _x x\n").unwrap(),
+            "\n1 xx 0x1 \"x\" 'x' _x 1\n"
+        );
+        // Definitions only apply after they appear, and until #undef
+        assert_eq!(
+            expand("N\n#define N 1\nN\n#undef N\nN\n").unwrap(),
+            "N\n\n1\n\nN\n"
+        );
+        // A macro isn't expanded inside its own replacement
+        assert_eq!(
+            expand("#define A B + A\n#define B A\nA B\n").unwrap(),
+            "\n\nA + A B + A\n"
+        );
+    }
+
+    #[test]
+    fn function_like_macros() {
+        assert_eq!(
+            expand("#define MIN(a,b) ((a)<(b)?(a):(b))\nMIN(x, f(1, 2))\n").unwrap(),
+            "\n((x)<(f(1, 2))?(x):(f(1, 2)))\n"
+        );
+        // Only a name followed by a parenthesis is a call
+        assert_eq!(
+            expand("#define F() 1\n#define G (2)\nF F ( ) G\n").unwrap(),
+            "\n\nF 1 (2)\n"
+        );
+        // Arguments are expanded, and so is the result along with what
+        // follows it
+        assert_eq!(
+            expand("#define ID(x) x\n#define N 3\n#define CALL ID\nID(ID(N)) CALL(N)\n").unwrap(),
+            "\n\n\n3 3\n"
+        );
+        assert_eq!(
+            expand("#define LOG(fmt, ...) log(fmt, __VA_ARGS__)\nLOG(\"%d,%d\", 1, 2)\n").unwrap(),
+            "\nlog(\"%d,%d\", 1, 2)\n"
+        );
+    }
+
+    #[test]
+    fn recursive_function_like_macros() {
+        // f isn't expanded within its own expansion, even as an argument
+        assert_eq!(
+            expand("#define f(x) x + f(x)\nf(f(1))\n").unwrap(),
+            "\n1 + f(1) + f(1 + f(1))\n"
+        );
+        assert_eq!(expand("#define g(x) g(x)\ng(2)\n").unwrap(), "\ng(2)\n");
+    }
+
+    #[test]
+    fn bad_function_like_macros() {
+        assert_eq!(
+            expand("#define F(a, b) a\nF(1)\n").unwrap_err(),
+            "test.c:2: Macro F expects 2 arguments, got 1"
+        );
+        assert_eq!(
+            expand("#define F(a) a\nF((1, 2)\n").unwrap_err(),
+            "test.c:2: Missing ) in call to macro F"
+        );
+        assert_eq!(
+            expand("#define F(a, a) a\n").unwrap_err(),
+            "test.c:1: Duplicate macro parameter a"
+        );
+        assert_eq!(
+            expand("#define F(x\n").unwrap_err(),
+            "test.c:1: Missing ) in parameters of macro F"
+        );
+        assert_eq!(
+            expand("#define F(a) a\n#define F (a) a\n").unwrap_err(),
+            "test.c:2: Macro F redefined differently"
+        );
+    }
+
+    #[test]
+    fn conditional_groups() {
+        let source = "#define A 2
+#ifdef A
+a
+#else
+not a
+#endif
+#ifndef B
+not b
+#endif
+#if A * 2 == 3
+three
+#elif defined(A) && !defined B
+four
+#elif 1
+later
+#else
+none
+#endif
+";
+        assert_eq!(
+            expand(source).unwrap(),
+            "\n\na\n\n\n\n\nnot b\n\n\n\n\nfour\n\n\n\n\n\n"
+        );
+    }
+
+    #[test]
+    fn nested_conditional_groups() {
+        // Nothing in a skipped group is processed, not even its conditions
+        let source = "#if 0
+#if 1 / 0
+#include \"missing.h\"
+#else
+#error skipped
+#endif
+#elif 1
+#if 0
+#else
+kept
+#endif
+#endif
+";
+        assert_eq!(expand(source).unwrap(), "\n\n\n\n\n\n\n\n\nkept\n\n\n");
+    }
+
+    #[test]
+    fn unbalanced_conditionals() {
+        assert_eq!(
+            expand("#if 1\n#else\n#elif 1\n#endif\n").unwrap_err(),
+            "test.c:3: #elif after #else"
+        );
+        assert_eq!(
+            expand("x\n#endif\n").unwrap_err(),
+            "test.c:2: #endif without #if"
+        );
+        assert_eq!(
+            expand("#ifdef X\n#if 1\n#endif\n").unwrap_err(),
+            "test.c:1: Unterminated conditional directive"
+        );
+        assert_eq!(
+            expand("#if defined(X\n#endif\n").unwrap_err(),
+            "test.c:1: Missing ) after defined"
+        );
+    }
+
+    #[test]
+    fn predefined_macros() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("where.h");
+        fs::write(&header, "\n__FILE__ __LINE__\n").unwrap();
+        let main = dir.path().join("main.c");
+        let source = "#include \"where.h\"\n#define HERE __LINE__\nHERE __STDC__\n\
+            #if __LINE__ == 4 && defined __FILE__\n__FILE__\n#endif\n";
+        let output = preprocess(source, &main, &[]).unwrap();
+        assert_eq!(
+            output.text,
+            format!(
+                "\n{} 2\n\n3 1\n\n{}\n\n",
+                quote(&header.display().to_string()),
+                quote(&main.display().to_string())
+            )
+        );
+        assert_eq!(quote("C:\\a \"b\""), "\"C:\\\\a \\\"b\\\"\"");
+        assert_eq!(
+            expand("#undef __LINE__\n").unwrap_err(),
+            "test.c:1: Cannot undefine __LINE__"
+        );
+        assert_eq!(
+            expand("#define __STDC__ 0\n").unwrap_err(),
+            "test.c:1: Cannot redefine __STDC__"
+        );
+    }
+
+    #[test]
+    fn headers_included_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("once.h"), "#pragma once\nonce\n").unwrap();
+        fs::write(
+            dir.path().join("guarded.h"),
+            "// Guarded\n#ifndef GUARDED_H\n#define GUARDED_H\nguarded\n#endif\n",
+        )
+        .unwrap();
+        let source = "#include \"once.h\"\n#include \"guarded.h\"\n\
+            #include \"once.h\"\n#include \"guarded.h\"\n#pragma weak x\n";
+        let text = preprocess(source, &dir.path().join("main.c"), &[])
+            .unwrap()
+            .text;
+        assert_eq!(text.matches("once").count(), 1);
+        assert_eq!(text.matches("guarded").count(), 1);
+    }
+
+    #[test]
+    fn include_guard_detection() {
+        assert_eq!(
+            include_guard("\n#ifndef A\n#define A\n#if B\n#endif\nx\n#endif\n\n"),
+            Some("A")
+        );
+        // Something outside the group, or a different macro
+        assert_eq!(include_guard("#ifndef A\n#define A\n#endif\nx\n"), None);
+        assert_eq!(include_guard("x\n#ifndef A\n#define A\n#endif\n"), None);
+        assert_eq!(include_guard("#ifndef A\n#define B\n#endif\n"), None);
+        assert_eq!(include_guard("#ifndef A\n#define A\n#else\n#endif\n"), None);
+        assert_eq!(
+            include_guard("#ifndef A\n#define A\n#endif\n#if 1\n#endif\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn runaway_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = dir.path().join("loop.h");
+        fs::write(&header, "#include \"loop.h\"\n").unwrap();
+        let error = preprocess("#include \"loop.h\"\n", &dir.path().join("main.c"), &[])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!(
+                "{}:1: #include nested more than 200 levels deep, including \"loop.h\"",
+                header.display()
+            )
+        );
+    }
+
+    #[test]
+    fn macro_redefinition() {
+        assert_eq!(
+            expand("#define N 1 +  2\n#define N 1 + 2\nN\n").unwrap(),
+            "\n\n1 + 2\n"
+        );
+        assert_eq!(
+            expand("#define N 1\n#define N 2\n").unwrap_err(),
+            "test.c:2: Macro N redefined differently"
+        );
+        assert_eq!(
+            expand("#define N 1\n#undef N\n#define N 2\nN\n").unwrap(),
+            "\n\n\n2\n"
+        );
+    }
+
+    #[test]
+    fn warning_directive() {
+        let output = preprocess(
+            "#warning not finished\n#if 0\n#warning skipped\n#endif\n",
+            Path::new("test.c"),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(output.text, "\n\n\n\n");
+        assert_eq!(
+            output.warnings,
+            [Diagnostic {
+                warning: Warning::Cpp,
+                message: String::from("test.c:1: #warning not finished")
+            }]
+        );
+    }
+
+    #[test]
+    fn malformed_directives() {
+        let path = Path::new("test.c");
+        assert_eq!(
+            preprocess("\n#include stdio.h\n", path, &[])
+                .unwrap_err()
+                .to_string(),
+            "test.c:2: Expected \"file\" or <file> after #include"
+        );
+        assert_eq!(
+            preprocess("#ident \"x\"\n", path, &[])
+                .unwrap_err()
+                .to_string(),
+            "test.c:1: Unsupported directive #ident"
+        );
+        assert_eq!(
+            preprocess("#define 3 x\n", path, &[])
+                .unwrap_err()
+                .to_string(),
+            "test.c:1: Expected a macro name"
+        );
+        assert_eq!(
+            preprocess("#undef X Y\n", path, &[])
+                .unwrap_err()
+                .to_string(),
+            "test.c:1: Extra tokens after #undef X"
+        );
+        assert_eq!(
+            preprocess("#\nint x;\n", path, &[]).unwrap().text,
+            "\nint x;\n"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+//! Evaluation of the integer constant expressions in `#if` and `#elif`,
+//! after `defined` and macros have been replaced.
+
+/// Evaluate a controlling expression. Any identifiers left after macro
+/// expansion count as 0, and arithmetic is done in 64 bits.
+pub fn evaluate(text: &str) -> Result<i64, String> {
+    let tokens = tokenize(text)?;
+    if tokens.is_empty() {
+        return Err(String::from("Expected an expression"));
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = parser.conditional()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(token) => Err(format!("Unexpected {} in expression", token)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Operator(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Operator(op) => write!(f, "`{}`", op),
+        }
+    }
+}
+
+/// Operators, with longer ones first so that they match in preference
+const OPERATORS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "?", ":", "+", "-", "*", "/", "%",
+    "<", ">", "&", "|", "^", "~", "!",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..len])?));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(0));
+            len
+        } else if c == '\'' {
+            let (value, len) = parse_char(rest)?;
+            tokens.push(Token::Number(value));
+            len
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| format!("Unexpected `{}` in expression", c))?;
+            tokens.push(Token::Operator(op));
+            op.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parse an integer literal, ignoring any `u` and `l` suffixes.
+fn parse_number(literal: &str) -> Result<i64, String> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let parsed = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse()
+    };
+    // Values that only fit unsigned wrap, as if every value were intmax_t
+    parsed
+        .map(|n| n as i64)
+        .map_err(|_| format!("Invalid integer constant {}", literal))
+}
+
+/// Parse a character constant such as `'a'` or `'\n'`, returning its value
+/// and length.
+fn parse_char(s: &str) -> Result<(i64, usize), String> {
+    let mut chars = s.char_indices().skip(1);
+    let value = match chars.next() {
+        Some((_, '\\')) => match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '0')) => '\0',
+            Some((_, c)) if c == '\\' || c == '\'' || c == '"' => c,
+            _ => return Err(String::from("Unsupported escape in character constant")),
+        },
+        Some((_, c)) if c != '\'' => c,
+        _ => return Err(String::from("Empty character constant")),
+    };
+    match chars.next() {
+        Some((i, '\'')) => Ok((value as i64, i + 1)),
+        _ => Err(String::from("Unterminated character constant")),
+    }
+}
+
+/// A precedence climbing parser that evaluates as it goes.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+/// Binary operators from lowest to highest precedence
+const BINARY_PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Parser {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Operator(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_operator() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("Expected `{}` in expression", op))
+        }
+    }
+
+    fn conditional(&mut self) -> Result<i64, String> {
+        let condition = self.binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let if_true = self.conditional()?;
+        self.expect(":")?;
+        let if_false = self.conditional()?;
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == BINARY_PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_operator() {
+            if !BINARY_PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = apply(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Operator("(")) => {
+                let value = self.conditional()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Operator("+")) => self.unary(),
+            Some(Token::Operator("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Operator("~")) => Ok(!self.unary()?),
+            Some(Token::Operator("!")) => Ok((self.unary()? == 0) as i64),
+            Some(token) => Err(format!("Unexpected {} in expression", token)),
+            None => Err(String::from("Unexpected end of expression")),
+        }
+    }
+}
+
+fn apply(op: &str, a: i64, b: i64) -> Result<i64, String> {
+    Ok(match op {
+        "||" => (a != 0 || b != 0) as i64,
+        "&&" => (a != 0 && b != 0) as i64,
+        "|" => a | b,
+        "^" => a ^ b,
+        "&" => a & b,
+        "==" => (a == b) as i64,
+        "!=" => (a != b) as i64,
+        "<" => (a < b) as i64,
+        ">" => (a > b) as i64,
+        "<=" => (a <= b) as i64,
+        ">=" => (a >= b) as i64,
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => return Err(String::from("Division by zero in expression")),
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        _ => unreachable!("not a binary operator: {}", op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(3));
+        assert_eq!(evaluate("1 << 2 + 1"), Ok(8));
+        assert_eq!(evaluate("1 | 2 ^ 3 & 4"), Ok(3));
+        assert_eq!(evaluate("0 || 2 && 3"), Ok(1));
+        assert_eq!(evaluate("1 ? 0 ? 5 : 6 : 7"), Ok(6));
+        assert_eq!(evaluate("-~!0 == 2 && 3 >= 3"), Ok(1));
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(evaluate("0x1F + 010 + 2UL"), Ok(41));
+        assert_eq!(evaluate("'a' == 97 && '\\n' == 10"), Ok(1));
+        // Identifiers that aren't macros are zero
+        assert_eq!(evaluate("UNDEFINED + 1"), Ok(1));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert_eq!(evaluate(""), Err(String::from("Expected an expression")));
+        assert_eq!(
+            evaluate("1 / (2 - 2)"),
+            Err(String::from("Division by zero in expression"))
+        );
+        assert_eq!(
+            evaluate("(1 + 2"),
+            Err(String::from("Expected `)` in expression"))
+        );
+        assert_eq!(
+            evaluate("1 2"),
+            Err(String::from("Unexpected 2 in expression"))
+        );
+        assert_eq!(
+            evaluate("1 = 2"),
+            Err(String::from("Unexpected `=` in expression"))
+        );
+        assert_eq!(
+            evaluate("09"),
+            Err(String::from("Invalid integer constant 09"))
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use super::ast;
+use super::backend::{self, Backend};
+use super::config::Cancellation;
+#[cfg(feature = "driver")]
+use super::target::{Arch, Os, Target};
+
+/// Lowers the AST to QBE intermediate language, for comparison with rcc's
+/// own code generators or for compiling with `qbe`.
+///
+/// Every intermediate value is assigned to a fresh temporary; QBE takes care
+/// of register allocation. The IL itself is target independent; the target
+/// only matters when running `qbe`.
+#[derive(Default)]
+pub struct Qbe {
+    pub cancellation: Cancellation,
+}
+
+impl Qbe {
+    /// The name `qbe -t` uses for the target
+    #[cfg(feature = "driver")]
+    pub fn qbe_target(target: &Target) -> &'static str {
+        match (target.arch, target.os) {
+            (Arch::X86_64, Os::MacOs) => "amd64_apple",
+            (Arch::X86_64, _) => "amd64_sysv",
+            (Arch::RiscV64, _) => "rv64",
+        }
+    }
+}
+
+impl Backend for Qbe {
+    fn generate(&self, ast: &ast::Node) -> Result<String, &'static str> {
+        let mut function = Function {
+            cancellation: self.cancellation.clone(),
+            ..Function::default()
+        };
+        ast.lower(&mut function)?;
+        // QBE expects the file to end with a newline
+        Ok(function.code.join("\n") + "\n")
+    }
+}
+
+/// The instructions emitted so far and the next free temporary and label
+#[derive(Default)]
+struct Function {
+    code: Vec<String>,
+    temporaries: usize,
+    labels: usize,
+    /// Whether the current block has ended with a jump
+    terminated: bool,
+    /// The labels `continue` and `break` jump to in each loop being
+    /// lowered, innermost last
+    loops: Vec<(String, String)>,
+    cancellation: Cancellation,
+}
+
+impl Function {
+    /// Emit an instruction computing a word, returning the temporary holding it
+    fn assign(&mut self, instruction: String) -> String {
+        self.start_reachable();
+        self.temporaries += 1;
+        let temp = format!("%t{}", self.temporaries);
+        self.code.push(format!("\t{} =w {}", temp, instruction));
+        temp
+    }
+
+    /// Emit a jump, which ends the current block.
+    fn jump(&mut self, instruction: String) {
+        self.start_reachable();
+        self.code.push(format!("\t{}", instruction));
+        self.terminated = true;
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("@l{}", self.labels)
+    }
+
+    /// Start the block `label`, which the current one falls through to if it
+    /// hasn't ended.
+    fn start_block(&mut self, label: &str) {
+        self.code.push(String::from(label));
+        self.terminated = false;
+    }
+
+    /// Start a block for code following a jump, which QBE requires even
+    /// though it's never run.
+    fn start_reachable(&mut self) {
+        if self.terminated {
+            let label = self.label();
+            self.start_block(&label);
+        }
+    }
+}
+
+trait Lower {
+    /// Emit the IL for this node, returning the operand holding its value
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str>;
+}
+
+impl Lower for ast::Node {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Node::Program(node) => {
+                node.lower(f)?;
+            }
+            ast::Node::Function(id, _, node) => {
+                let _span = tracing::debug_span!("function", name = &**id).entered();
+                // QBE adds any platform symbol prefix itself
+                f.code.push(format!("export function w ${}() {{", id));
+                f.start_block("@start");
+                node.lower(f)?;
+                if !f.terminated {
+                    ast::Node::implicit_return().lower(f)?;
+                }
+                f.code.push(String::from("}"));
+            }
+            ast::Node::Statement(st, expr) => match st {
+                ast::Statement::Return => {
+                    let value = expr.lower(f)?;
+                    f.jump(format!("ret {}", value));
+                }
+                ast::Statement::If(then, otherwise) => {
+                    let condition = expr.lower(f)?;
+                    let (then_label, end) = (f.label(), f.label());
+                    let other = match otherwise {
+                        Some(_) => f.label(),
+                        None => end.clone(),
+                    };
+                    f.jump(format!("jnz {}, {}, {}", condition, then_label, other));
+                    f.start_block(&then_label);
+                    then.lower(f)?;
+                    if let Some(otherwise) = otherwise {
+                        if !f.terminated {
+                            f.jump(format!("jmp {}", end));
+                        }
+                        f.start_block(&other);
+                        otherwise.lower(f)?;
+                    }
+                    f.start_block(&end);
+                }
+                ast::Statement::DoWhile(body) => {
+                    let (start, next, end) = (f.label(), f.label(), f.label());
+                    f.start_block(&start);
+                    f.loops.push((next.clone(), end.clone()));
+                    let lowered = body.lower(f);
+                    f.loops.pop();
+                    lowered?;
+                    f.start_block(&next);
+                    let condition = expr.lower(f)?;
+                    f.jump(format!("jnz {}, {}, {}", condition, start, end));
+                    f.start_block(&end);
+                }
+            },
+            ast::Node::Block(statements) => {
+                for statement in statements {
+                    backend::check_cancelled(&f.cancellation)?;
+                    statement.lower(f)?;
+                }
+            }
+            ast::Node::Jump(jump) => {
+                let (next, end) = f.loops.last().expect(ast::JUMP_OUTSIDE_LOOP);
+                let target = match jump {
+                    ast::Jump::Break => end.clone(),
+                    ast::Jump::Continue => next.clone(),
+                };
+                f.jump(format!("jmp {}", target));
+            }
+            ast::Node::InlineAsm { .. } => {
+                return Err("Inline assembly isn't supported by the QBE backend");
+            }
+            ast::Node::Located(_, node) => {
+                node.lower(f)?;
+            }
+            ast::Node::Error => return Err("Can't generate code for a program with syntax errors"),
+        };
+        Ok(String::new())
+    }
+}
+
+impl Lower for ast::Expr {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Expr::Term(t) => t.lower(f),
+        }
+    }
+}
+
+impl Lower for ast::Term {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            ast::Term::Factor(fac) => fac.lower(f),
+            ast::Term::BinOp(op, t1, t2) => lower_binary_op(op, &**t1, &**t2, f),
+        }
+    }
+}
+
+impl Lower for ast::Factor {
+    fn lower(&self, f: &mut Function) -> Result<String, &'static str> {
+        match self {
+            // Constants can be used directly as operands
+            ast::Factor::IntLiteral(n) => Ok(n.to_string()),
+            ast::Factor::UnOp(op, fac) => {
+                let value = fac.lower(f)?;
+                let instruction = match op {
+                    ast::UnOp::Negate => format!("neg {}", value),
+                    ast::UnOp::Complement => format!("xor {}, -1", value),
+                    ast::UnOp::LogicalNegate => format!("ceqw {}, 0", value),
+                };
+                Ok(f.assign(instruction))
+            }
+            ast::Factor::BinOp(op, f1, f2) => lower_binary_op(op, &**f1, &**f2, f),
+            ast::Factor::Expr(e) => e.lower(f),
+        }
+    }
+}
+
+fn lower_binary_op(
+    op: &ast::BinOp,
+    a: &impl Lower,
+    b: &impl Lower,
+    f: &mut Function,
+) -> Result<String, &'static str> {
+    let a = a.lower(f)?;
+    let b = b.lower(f)?;
+    let instruction = match op {
+        ast::BinOp::Add => "add",
+        ast::BinOp::Subtract => "sub",
+        ast::BinOp::Multiply => "mul",
+        ast::BinOp::Divide => "div",
+    };
+    Ok(f.assign(format!("{} {}, {}", instruction, a, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::*;
+
+    fn generate(ast: &Node) -> Result<String, &'static str> {
+        Qbe::default().generate(ast)
+    }
+
+    fn function(expr: Expr) -> Node {
+        Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(Statement::Return, expr.into()).into(),
+            )
+            .into(),
+        )
+    }
+
+    fn int(n: i32) -> Box<Factor> {
+        Factor::IntLiteral(n).into()
+    }
+
+    #[test]
+    fn if_statement() {
+        // if (1) return 2; else {} and then the implicit return 0
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::If(
+                        Node::Statement(
+                            Statement::Return,
+                            Expr::Term(Term::Factor(int(2)).into()).into(),
+                        )
+                        .into(),
+                        Some(Node::Block(vec![]).into()),
+                    ),
+                    Expr::Term(Term::Factor(int(1)).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "export function w $main() {
+@start
+\tjnz 1, @l1, @l3
+@l1
+\tret 2
+@l3
+@l2
+\tret 0
+}
+"
+        );
+    }
+
+    #[test]
+    fn return_constant() {
+        let ast = function(Expr::Term(Term::Factor(int(2)).into()));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "export function w $main() {
+@start
+\tret 2
+}
+"
+        );
+    }
+
+    #[test]
+    fn unary_operators() {
+        let ast = function(Expr::Term(
+            Term::Factor(
+                Factor::UnOp(
+                    UnOp::LogicalNegate,
+                    Factor::UnOp(UnOp::Complement, Factor::UnOp(UnOp::Negate, int(3)).into())
+                        .into(),
+                )
+                .into(),
+            )
+            .into(),
+        ));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "export function w $main() {
+@start
+\t%t1 =w neg 3
+\t%t2 =w xor %t1, -1
+\t%t3 =w ceqw %t2, 0
+\tret %t3
+}
+"
+        );
+    }
+
+    #[test]
+    fn binary_operators() {
+        // 1 - 6 / (2 * 3)
+        let ast = function(Expr::Term(
+            Term::BinOp(
+                BinOp::Subtract,
+                Term::Factor(int(1)).into(),
+                Term::Factor(
+                    Factor::BinOp(
+                        BinOp::Divide,
+                        int(6),
+                        Factor::BinOp(BinOp::Multiply, int(2), int(3)).into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        ));
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "export function w $main() {
+@start
+\t%t1 =w mul 2, 3
+\t%t2 =w div 6, %t1
+\t%t3 =w sub 1, %t2
+\tret %t3
+}
+"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "driver")]
+    fn qbe_targets() {
+        let name = |triple| Qbe::qbe_target(&Target::parse(triple).unwrap());
+        assert_eq!(name("x86_64-unknown-linux-gnu"), "amd64_sysv");
+        assert_eq!(name("x86_64-apple-darwin"), "amd64_apple");
+        assert_eq!(name("riscv64-unknown-linux-gnu"), "rv64");
+    }
+}
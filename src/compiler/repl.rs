@@ -0,0 +1,128 @@
+//! An interactive loop that compiles, or with `--interpret` interprets, each
+//! expression or `return` statement it reads and prints the value.
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+
+use super::config::Config;
+use super::driver::{compile, interpret_file};
+use super::error::{CompilerError, IoContext};
+use super::toolchain;
+
+/// The function each input is compiled into
+const VALUE_FUNCTION: &str = "rcc_repl_value";
+
+/// A C program that prints the value of `VALUE_FUNCTION`, linked with each
+/// input. Exit statuses are too narrow to pass the value back.
+const PRINTER: &str = "#include <stdio.h>
+
+int rcc_repl_value(void);
+
+int main(void) {
+    printf(\"%d\\n\", rcc_repl_value());
+    return 0;
+}
+";
+
+/// Read lines from `input` until it ends or `:quit` is entered, writing a
+/// prompt and then each value to `output`. Errors in the code that's read
+/// are reported to stderr without ending the loop.
+pub fn run(
+    mut config: Config,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<(), CompilerError> {
+    let dir = tempfile::tempdir().context("create a temporary directory in", &env::temp_dir())?;
+    let source = dir.path().join("input.c");
+    let exe = dir.path().join("input");
+    config.filenames = vec![source.clone()];
+    config.output = Some(exe.clone());
+    // The interpreter needs nothing to print values with
+    if !config.interpret {
+        let printer = dir.path().join("printer.c");
+        let printer_object = dir.path().join("printer.o");
+        fs::write(&printer, PRINTER).context("write", &printer)?;
+        toolchain::compile_c(&config, &printer, &printer_object)
+            .map_err(|e| CompilerError::Link(e.to_string()))?;
+        config
+            .linker_args
+            .push(printer_object.to_string_lossy().into_owned());
+    }
+
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        if line.trim() == ":quit" {
+            break;
+        }
+        if let Some(code) = wrap(&line) {
+            fs::write(&source, code).context("write", &source)?;
+            if config.interpret {
+                match interpret_file(&source, VALUE_FUNCTION, &config) {
+                    Ok(value) => writeln!(output, "{}", value)?,
+                    Err(e) => eprintln!("{}", e),
+                }
+            } else {
+                match compile(&config) {
+                    Ok(_) => {
+                        let result = Command::new(&exe).output().context("run", &exe)?;
+                        match result.status.signal() {
+                            // Such as SIGFPE from dividing by zero
+                            Some(signal) => eprintln!("Evaluation failed with signal {}", signal),
+                            None => output.write_all(&result.stdout)?,
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Make a line of input into a function returning its value, or `None` if
+/// it's blank.
+fn wrap(line: &str) -> Option<String> {
+    let code = line.trim().trim_end_matches(';').trim_end();
+    if code.is_empty() {
+        return None;
+    }
+    let is_return = code
+        .strip_prefix("return")
+        .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'));
+    let statement = if is_return {
+        format!("{};", code)
+    } else {
+        format!("return {};", code)
+    };
+    Some(format!("int {}() {{ {} }}\n", VALUE_FUNCTION, statement))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_input() {
+        assert_eq!(
+            wrap("1 + 2").unwrap(),
+            "int rcc_repl_value() { return 1 + 2; }\n"
+        );
+        assert_eq!(
+            wrap("  return -3;  ").unwrap(),
+            "int rcc_repl_value() { return -3; }\n"
+        );
+        assert_eq!(
+            wrap("returned").unwrap(),
+            "int rcc_repl_value() { return returned; }\n"
+        );
+        assert_eq!(wrap(" ; "), None);
+    }
+}
@@ -0,0 +1,470 @@
+use super::assembly;
+use super::ast;
+use super::backend::{self, Backend, Labels};
+use super::config::Cancellation;
+use super::source_map;
+use super::target::Target;
+
+/// 64-bit RISC-V (RV64IM) code generator using the LP64 calling convention.
+///
+/// Expressions are evaluated into `a0`, which is also the return register.
+pub struct RiscV64 {
+    pub target: Target,
+    /// The name of the source file being compiled, if known
+    pub source_file: Option<String>,
+    /// Align the start of each function to this many bytes
+    pub align_functions: Option<u32>,
+    /// Mark the code generated for each located node, for a source map
+    pub source_map: bool,
+    pub cancellation: Cancellation,
+}
+
+impl Backend for RiscV64 {
+    fn generate(&self, ast: &ast::Node) -> Result<String, &'static str> {
+        let mut code = Vec::new();
+        if let (Some(file), true) = (&self.source_file, self.target.is_elf()) {
+            code.push(format!("  .file \"{}\"", file.escape_default()));
+        }
+        code.push(ast.generate_assembly(&mut Context::new(self))?);
+        // Assemblers expect the file to end with a newline
+        Ok(code.join("\n") + "\n")
+    }
+}
+
+/// The state of generating one program.
+struct Context<'a> {
+    backend: &'a RiscV64,
+    labels: Labels,
+}
+
+impl<'a> Context<'a> {
+    fn new(backend: &'a RiscV64) -> Self {
+        Context {
+            backend,
+            labels: Labels::new(".L"),
+        }
+    }
+
+    /// Generate a new label local to the object file.
+    fn label(&mut self) -> String {
+        self.labels.label()
+    }
+}
+
+trait Assembly {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str>;
+}
+
+impl Assembly for ast::Node {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str> {
+        let mut code = Vec::new();
+        match self {
+            ast::Node::Program(node) => {
+                code.push(String::from("  .section .text"));
+                code.push(node.generate_assembly(ctx)?);
+                if ctx.backend.target.is_elf() {
+                    code.push(String::from("  .section .note.GNU-stack,\"\",@progbits"));
+                }
+            }
+            ast::Node::Function(id, _, node) => {
+                let _span = tracing::debug_span!("function", name = &**id).entered();
+                let symbol = ctx.backend.target.symbol(id);
+                code.extend(assembly::align_function(ctx.backend.align_functions));
+                code.push(format!(".globl {}", symbol));
+                if ctx.backend.target.is_elf() {
+                    code.push(format!("  .type {}, @function", symbol));
+                }
+                code.push(format!("{}:", symbol));
+                code.push(node.generate_assembly(ctx)?);
+                if !node.always_returns() {
+                    code.push(ast::Node::implicit_return().generate_assembly(ctx)?);
+                }
+                if ctx.backend.target.is_elf() {
+                    code.push(format!("  .size {}, .-{}", symbol, symbol));
+                }
+            }
+            ast::Node::Statement(st, expr) => match st {
+                ast::Statement::Return => {
+                    code.push(expr.generate_assembly(ctx)?);
+                    code.push(String::from("  ret"));
+                }
+                ast::Statement::If(then, otherwise) => {
+                    // Branch past the first branch when the condition is 0
+                    let other = ctx.label();
+                    code.push(expr.generate_assembly(ctx)?);
+                    code.push(format!("  beqz\ta0, {}", other));
+                    code.push(then.generate_assembly(ctx)?);
+                    if let Some(otherwise) = otherwise {
+                        // Skip the else branch, unless the first one returned
+                        let end = (!then.always_returns()).then(|| ctx.label());
+                        if let Some(end) = &end {
+                            code.push(format!("  j\t{}", end));
+                        }
+                        code.push(format!("{}:", other));
+                        code.push(otherwise.generate_assembly(ctx)?);
+                        code.extend(end.map(|end| format!("{}:", end)));
+                    } else {
+                        code.push(format!("{}:", other));
+                    }
+                }
+                ast::Statement::DoWhile(body) => {
+                    // The body comes first, so only the loop back is a branch
+                    let start = ctx.label();
+                    code.push(format!("{}:", start));
+                    ctx.labels.enter_loop();
+                    let body = body.generate_assembly(ctx);
+                    let labels = ctx.labels.exit_loop();
+                    code.push(body?);
+                    code.extend(labels.next.map(|next| format!("{}:", next)));
+                    code.push(expr.generate_assembly(ctx)?);
+                    code.push(format!("  bnez\ta0, {}", start));
+                    code.extend(labels.end.map(|end| format!("{}:", end)));
+                }
+            },
+            ast::Node::Block(statements) => {
+                for statement in statements {
+                    backend::check_cancelled(&ctx.backend.cancellation)?;
+                    code.push(statement.generate_assembly(ctx)?);
+                }
+            }
+            ast::Node::Jump(jump) => code.push(format!("  j\t{}", ctx.labels.jump(*jump))),
+            ast::Node::InlineAsm { text, .. } => code.extend(assembly::inline_asm(text)),
+            ast::Node::Located(span, node) => {
+                if ctx.backend.source_map {
+                    code.push(source_map::begin(*span));
+                }
+                code.push(node.generate_assembly(ctx)?);
+                if ctx.backend.source_map {
+                    code.push(source_map::end());
+                }
+            }
+            ast::Node::Error => return Err("Can't generate code for a program with syntax errors"),
+        };
+        Ok(code.join("\n"))
+    }
+}
+
+impl Assembly for ast::Expr {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str> {
+        match self {
+            ast::Expr::Term(t) => t.generate_assembly(ctx),
+        }
+    }
+}
+
+impl Assembly for ast::Term {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str> {
+        match self {
+            ast::Term::Factor(f) => f.generate_assembly(ctx),
+            ast::Term::BinOp(op, t1, t2) => generate_binary_op(op, &**t1, &**t2, ctx),
+        }
+    }
+}
+
+impl Assembly for ast::Factor {
+    fn generate_assembly(&self, ctx: &mut Context) -> Result<String, &'static str> {
+        match self {
+            // Load the integer into a0
+            ast::Factor::IntLiteral(n) => Ok(format!("  li\ta0, {}", n)),
+            ast::Factor::UnOp(op, f) => generate_unary_op(op, f, ctx),
+            ast::Factor::BinOp(op, f1, f2) => generate_binary_op(op, &**f1, &**f2, ctx),
+            ast::Factor::Expr(e) => e.generate_assembly(ctx),
+        }
+    }
+}
+
+fn generate_unary_op(
+    op: &ast::UnOp,
+    factor: &ast::Factor,
+    ctx: &mut Context,
+) -> Result<String, &'static str> {
+    let mut code = vec![factor.generate_assembly(ctx)?];
+    match op {
+        ast::UnOp::Negate => code.push(String::from("  negw\ta0, a0")),
+        ast::UnOp::Complement => code.push(String::from("  not\ta0, a0")),
+        // Set a0 to 1 if it is zero, otherwise 0
+        ast::UnOp::LogicalNegate => code.push(String::from("  seqz\ta0, a0")),
+    }
+    Ok(code.join("\n"))
+}
+
+fn generate_binary_op(
+    op: &ast::BinOp,
+    a: &impl Assembly,
+    b: &impl Assembly,
+    ctx: &mut Context,
+) -> Result<String, &'static str> {
+    let mut code = vec![
+        // Evaluate a
+        a.generate_assembly(ctx)?,
+        // Push a0 on to the stack, keeping sp 16-byte aligned
+        String::from("  addi\tsp, sp, -16"),
+        String::from("  sd\ta0, 0(sp)"),
+        // Evaluate b
+        b.generate_assembly(ctx)?,
+        // Pop a's result from the stack to t0
+        String::from("  ld\tt0, 0(sp)"),
+        String::from("  addi\tsp, sp, 16"),
+    ];
+    // The "w" forms operate on the low 32 bits and sign extend the result
+    match op {
+        ast::BinOp::Add => code.push(String::from("  addw\ta0, t0, a0")),
+        ast::BinOp::Subtract => code.push(String::from("  subw\ta0, t0, a0")),
+        ast::BinOp::Multiply => code.push(String::from("  mulw\ta0, t0, a0")),
+        ast::BinOp::Divide => code.push(String::from("  divw\ta0, t0, a0")),
+    };
+    Ok(code.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::*;
+
+    fn generate(ast: &Node) -> Result<String, &'static str> {
+        RiscV64 {
+            target: Target::parse("riscv64-unknown-linux-gnu").unwrap(),
+            source_file: None,
+            align_functions: None,
+            source_map: false,
+            cancellation: Cancellation::default(),
+        }
+        .generate(ast)
+    }
+
+    #[test]
+    fn basic_function() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(Term::Factor(Factor::IntLiteral(0).into()).into()).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  li\ta0, 0
+  ret
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn if_statement() {
+        let literal = |n| Expr::Term(Term::Factor(Factor::IntLiteral(n).into()).into());
+        // if (1) asm("nop"); else return 2;
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::If(
+                        Node::InlineAsm {
+                            text: String::from("nop"),
+                            volatile: false,
+                        }
+                        .into(),
+                        Some(Node::Statement(Statement::Return, literal(2).into()).into()),
+                    ),
+                    literal(1).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        // Falling off the end returns 0
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl main
+  .type main, @function
+main:
+  li\ta0, 1
+  beqz\ta0, .L1
+  nop
+  j\t.L2
+.L1:
+  li\ta0, 2
+  ret
+.L2:
+  li\ta0, 0
+  ret
+  .size main, .-main
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn do_while_loop() {
+        let literal = |n| Expr::Term(Term::Factor(Factor::IntLiteral(n).into()).into());
+        // do asm("nop"); while (0);
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::DoWhile(
+                        Node::InlineAsm {
+                            text: String::from("nop"),
+                            volatile: false,
+                        }
+                        .into(),
+                    ),
+                    literal(0).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl main
+  .type main, @function
+main:
+.L1:
+  nop
+  li\ta0, 0
+  bnez\ta0, .L1
+  li\ta0, 0
+  ret
+  .size main, .-main
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let literal = |n| Expr::Term(Term::Factor(Factor::IntLiteral(n).into()).into());
+        // do { do continue; while (1); break; } while (2);
+        let inner = Node::Statement(
+            Statement::DoWhile(Node::Jump(Jump::Continue).into()),
+            literal(1).into(),
+        );
+        let ast = Node::Program(
+            Node::Function(
+                "main".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::DoWhile(Node::Block(vec![inner, Node::Jump(Jump::Break)]).into()),
+                    literal(2).into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        // Each jumps to the labels of its own loop
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl main
+  .type main, @function
+main:
+.L1:
+.L2:
+  j\t.L3
+.L3:
+  li\ta0, 1
+  bnez\ta0, .L2
+  j\t.L4
+  li\ta0, 2
+  bnez\ta0, .L1
+.L4:
+  li\ta0, 0
+  ret
+  .size main, .-main
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn function_return_logical_negate_1() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(
+                        Term::Factor(
+                            Factor::UnOp(UnOp::LogicalNegate, Factor::IntLiteral(1).into()).into(),
+                        )
+                        .into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  li\ta0, 1
+  seqz\ta0, a0
+  ret
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+
+    #[test]
+    fn function_return_subtract() {
+        let ast = Node::Program(
+            Node::Function(
+                "foo".into(),
+                Attributes::default(),
+                Node::Statement(
+                    Statement::Return,
+                    Expr::Term(
+                        Term::BinOp(
+                            BinOp::Subtract,
+                            Term::Factor(Factor::IntLiteral(2).into()).into(),
+                            Term::Factor(Factor::IntLiteral(1).into()).into(),
+                        )
+                        .into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            )
+            .into(),
+        );
+        assert_eq!(
+            generate(&ast).unwrap(),
+            "  .section .text
+.globl foo
+  .type foo, @function
+foo:
+  li\ta0, 2
+  addi\tsp, sp, -16
+  sd\ta0, 0(sp)
+  li\ta0, 1
+  ld\tt0, 0(sp)
+  addi\tsp, sp, 16
+  subw\ta0, t0, a0
+  ret
+  .size foo, .-foo
+  .section .note.GNU-stack,\"\",@progbits
+"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Checking that a parsed program keeps the constraints the grammar can't
+//! express, the validation stage of compilation.
+
+use super::ast;
+use super::error::SemanticError;
+use super::lexer::Span;
+
+/// Every constraint `ast` breaks, in source order, with where it is if the
+/// tree records that.
+pub fn check(ast: &ast::Node) -> Vec<(SemanticError, Option<Span>)> {
+    let mut errors = Vec::new();
+    check_node(ast, None, 0, &mut errors);
+    errors
+}
+
+/// Check `node`, which is within `loops` loops.
+fn check_node(
+    node: &ast::Node,
+    span: Option<Span>,
+    loops: usize,
+    errors: &mut Vec<(SemanticError, Option<Span>)>,
+) {
+    match node {
+        ast::Node::Program(node) | ast::Node::Function(_, _, node) => {
+            check_node(node, span, loops, errors)
+        }
+        ast::Node::Located(span, node) => check_node(node, Some(*span), loops, errors),
+        ast::Node::Block(statements) => {
+            for statement in statements {
+                check_node(statement, span, loops, errors);
+            }
+        }
+        ast::Node::Statement(ast::Statement::If(then, otherwise), _) => {
+            check_node(then, span, loops, errors);
+            if let Some(otherwise) = otherwise {
+                check_node(otherwise, span, loops, errors);
+            }
+        }
+        ast::Node::Statement(ast::Statement::DoWhile(body), _) => {
+            check_node(body, span, loops + 1, errors)
+        }
+        ast::Node::Jump(jump) if loops == 0 => {
+            errors.push((SemanticError::JumpOutsideLoop(*jump), span))
+        }
+        ast::Node::Statement(ast::Statement::Return, _)
+        | ast::Node::Jump(_)
+        | ast::Node::InlineAsm { .. }
+        | ast::Node::Error => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{lexer, parser};
+    use ast::Jump;
+
+    fn errors(source: &str) -> Vec<(SemanticError, Option<Span>)> {
+        check(&parser::parse(&lexer::tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn jumps_outside_loops() {
+        let span = |line, column| Some(Span { line, column });
+        // The error is at the jump's keyword
+        assert_eq!(
+            errors("int main() { break; }"),
+            [(SemanticError::JumpOutsideLoop(Jump::Break), span(1, 14))]
+        );
+        assert_eq!(
+            errors("int main() { { if (1) continue; } }"),
+            [(SemanticError::JumpOutsideLoop(Jump::Continue), span(1, 23))]
+        );
+        // A jump after a loop isn't in it, and every jump is reported
+        assert_eq!(
+            errors("int main() {\n  do { } while (1);\n  break;\n  continue;\n}"),
+            [
+                (SemanticError::JumpOutsideLoop(Jump::Break), span(3, 3)),
+                (SemanticError::JumpOutsideLoop(Jump::Continue), span(4, 3))
+            ]
+        );
+        assert!(errors("int main() { do if (1) { break; } while (1); }").is_empty());
+        assert!(
+            errors("int main() { do { do continue; while (0); break; } while (1); }").is_empty()
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Which statement of the source each part of the generated assembly came
+//! from, for tools that show the instructions a line of C compiles to.
+//!
+//! The code generators mark where the code for each located node begins and
+//! ends with lines of their own, which are taken out of the assembly here and
+//! turned into line ranges.
+
+use std::fmt;
+
+use super::lexer::Span;
+
+/// Starts a marker line, which no line of generated assembly starts with.
+const MARKER: char = '\0';
+
+/// The line marking the start of the code for the node at `span`.
+pub(super) fn begin(span: Span) -> String {
+    format!("{}{}:{}", MARKER, span.line, span.column)
+}
+
+/// The line marking the end of the code for the innermost node begun.
+pub(super) fn end() -> String {
+    MARKER.to_string()
+}
+
+/// The lines of assembly generated for a part of the source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    /// The first and last lines of the assembly, counting from 1
+    pub start: usize,
+    pub end: usize,
+    /// Where the node they were generated for is in the source
+    pub span: Span,
+}
+
+/// Every part of an assembly file that came from a located node, in order
+/// of where they start. Nested nodes give ranges within their parents'.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub mappings: Vec<Mapping>,
+}
+
+/// The map as JSON, in the form the sidecar `.asm.json` files have:
+///
+/// ```text
+/// {"mappings": [{"start": 8, "end": 12, "line": 2, "column": 5}]}
+/// ```
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{\"mappings\": [")?;
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(
+                f,
+                "{{\"start\": {}, \"end\": {}, \"line\": {}, \"column\": {}}}",
+                mapping.start, mapping.end, mapping.span.line, mapping.span.column
+            )?;
+        }
+        write!(f, "]}}")
+    }
+}
+
+/// Take the markers out of `code`, returning the assembly without them and
+/// the ranges they marked. Nodes that generated no code aren't mapped.
+pub(super) fn extract(code: &str) -> (String, SourceMap) {
+    let mut assembly = String::with_capacity(code.len());
+    let mut map = SourceMap::default();
+    let mut open = Vec::new();
+    let mut lines = 0;
+    for line in code.split_inclusive('\n') {
+        let Some(marker) = line.trim_end_matches('\n').strip_prefix(MARKER) else {
+            assembly.push_str(line);
+            lines += 1;
+            continue;
+        };
+        let span = marker.split_once(':').and_then(|(line, column)| {
+            Some(Span {
+                line: line.parse().ok()?,
+                column: column.parse().ok()?,
+            })
+        });
+        match span {
+            Some(span) => open.push((lines + 1, span)),
+            None => match open.pop() {
+                Some((start, span)) if start <= lines => map.mappings.push(Mapping {
+                    start,
+                    end: lines,
+                    span,
+                }),
+                _ => (),
+            },
+        }
+    }
+    map.mappings.sort_by_key(|mapping| mapping.start);
+    (assembly, map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: usize, column: usize) -> Span {
+        Span { line, column }
+    }
+
+    #[test]
+    fn markers_become_ranges() {
+        let code = [
+            String::from("main:"),
+            begin(span(2, 5)),
+            String::from("  movl\t$1, %eax"),
+            begin(span(2, 12)),
+            String::from("  negl\t%eax"),
+            end(),
+            String::from("  ret"),
+            end(),
+            // Nothing generated
+            begin(span(3, 5)),
+            end(),
+            String::from(""),
+        ]
+        .join("\n");
+        let (assembly, map) = extract(&code);
+        assert_eq!(assembly, "main:\n  movl\t$1, %eax\n  negl\t%eax\n  ret\n");
+        assert_eq!(
+            map.mappings,
+            [
+                Mapping {
+                    start: 2,
+                    end: 4,
+                    span: span(2, 5)
+                },
+                Mapping {
+                    start: 3,
+                    end: 3,
+                    span: span(2, 12)
+                },
+            ]
+        );
+        assert_eq!(
+            map.to_string(),
+            "{\"mappings\": [{\"start\": 2, \"end\": 4, \"line\": 2, \"column\": 5}, \
+             {\"start\": 3, \"end\": 3, \"line\": 2, \"column\": 12}]}"
+        );
+        assert_eq!(SourceMap::default().to_string(), "{\"mappings\": []}");
+    }
+
+    #[test]
+    fn assembly_without_markers_is_unchanged() {
+        let code = "main:\n  ret\n";
+        assert_eq!(extract(code), (String::from(code), SourceMap::default()));
+    }
+}
@@ -0,0 +1,80 @@
+//! Counting how much memory the compiler allocates, for `--time-passes`.
+//! Nothing is counted unless a binary installs [`Allocator`] as its global
+//! allocator, as `rcc` does.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping count of the bytes allocated at once.
+pub struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+fn allocated(size: usize) {
+    COUNTING.store(true, Ordering::Relaxed);
+    let total = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(total, Ordering::Relaxed);
+}
+
+/// Start measuring a new peak, returning what's allocated now, or `None` if
+/// allocations aren't being counted.
+pub fn start() -> Option<usize> {
+    if !COUNTING.load(Ordering::Relaxed) {
+        return None;
+    }
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    Some(allocated)
+}
+
+/// The most allocated at once since [`start`] returned `base`, beyond `base`.
+pub fn peak_since(base: usize) -> usize {
+    PEAK.load(Ordering::Relaxed).saturating_sub(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_peak_allocation() {
+        let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+        unsafe {
+            let first = Allocator.alloc(layout);
+            let base = start().expect("Allocations counted");
+            let second = Allocator.alloc(layout);
+            Allocator.dealloc(second, layout);
+            // Other tests don't use this allocator, so only these count
+            assert_eq!(peak_since(base), 1 << 20);
+            let grown = Allocator.realloc(first, layout, 3 << 20);
+            assert_eq!(peak_since(base), 2 << 20);
+            Allocator.dealloc(grown, Layout::from_size_align(3 << 20, 8).unwrap());
+        }
+    }
+}
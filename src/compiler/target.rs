@@ -0,0 +1,287 @@
+use std::fmt;
+
+/// The instruction set to generate code for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    X86_64,
+    RiscV64,
+}
+
+/// The operating system, which determines the object format and symbol naming.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Os {
+    Linux,
+    MacOs,
+}
+
+/// The C library environment the program is linked against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Abi {
+    Gnu,
+    Musl,
+    None,
+}
+
+/// A target triple, from which all target-specific code generation and
+/// toolchain decisions are made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Target {
+    pub arch: Arch,
+    pub os: Os,
+    pub abi: Abi,
+}
+
+/// Every target rcc can compile for.
+pub const SUPPORTED: [Target; 5] = [
+    Target {
+        arch: Arch::X86_64,
+        os: Os::Linux,
+        abi: Abi::Gnu,
+    },
+    Target {
+        arch: Arch::X86_64,
+        os: Os::Linux,
+        abi: Abi::Musl,
+    },
+    Target {
+        arch: Arch::X86_64,
+        os: Os::MacOs,
+        abi: Abi::None,
+    },
+    Target {
+        arch: Arch::RiscV64,
+        os: Os::Linux,
+        abi: Abi::Gnu,
+    },
+    Target {
+        arch: Arch::RiscV64,
+        os: Os::Linux,
+        abi: Abi::Musl,
+    },
+];
+
+impl Target {
+    /// The target rcc is running on, or `None` if rcc can't compile for it.
+    pub fn host() -> Option<Self> {
+        let arch = if cfg!(target_arch = "x86_64") {
+            Arch::X86_64
+        } else if cfg!(target_arch = "riscv64") {
+            Arch::RiscV64
+        } else {
+            return None;
+        };
+        let target = if cfg!(target_os = "macos") {
+            Target {
+                arch,
+                os: Os::MacOs,
+                abi: Abi::None,
+            }
+        } else if cfg!(target_os = "linux") {
+            Target {
+                arch,
+                os: Os::Linux,
+                abi: if cfg!(target_env = "musl") {
+                    Abi::Musl
+                } else {
+                    Abi::Gnu
+                },
+            }
+        } else {
+            return None;
+        };
+        Some(target).filter(|target| SUPPORTED.contains(target))
+    }
+
+    /// Parse a target triple such as `x86_64-unknown-linux-gnu` or
+    /// `x86_64-apple-darwin`. The vendor component is optional.
+    pub fn parse(triple: &str) -> Result<Self, &'static str> {
+        let mut parts = triple.split('-');
+        let arch = match parts.next() {
+            Some("x86_64") | Some("amd64") => Arch::X86_64,
+            Some("riscv64") | Some("riscv64gc") => Arch::RiscV64,
+            _ => return Err("Unknown target architecture"),
+        };
+        let rest: Vec<&str> = parts.collect();
+        let os = if rest.contains(&"linux") {
+            Os::Linux
+        } else if rest
+            .iter()
+            .any(|p| p.starts_with("darwin") || *p == "macos")
+        {
+            Os::MacOs
+        } else {
+            return Err("Unknown target operating system");
+        };
+        let abi = match (os, rest.last()) {
+            (Os::Linux, Some(&"musl")) => Abi::Musl,
+            (Os::Linux, _) => Abi::Gnu,
+            (Os::MacOs, _) => Abi::None,
+        };
+        if arch == Arch::RiscV64 && os == Os::MacOs {
+            return Err("RISC-V is not supported on macOS");
+        }
+        Ok(Target { arch, os, abi })
+    }
+
+    /// The assembly-level name of a C symbol.
+    pub fn symbol(&self, name: &str) -> String {
+        match self.os {
+            // Mach-O prefixes C symbols with an underscore
+            Os::MacOs => format!("_{}", name),
+            Os::Linux => String::from(name),
+        }
+    }
+
+    pub fn is_elf(&self) -> bool {
+        self.os == Os::Linux
+    }
+
+    /// The name of a toolchain program for this target: the plain name for
+    /// the host, or with the usual cross-compiler prefix otherwise.
+    pub fn tool(&self, name: &str) -> String {
+        if Self::host() == Some(*self) {
+            String::from(name)
+        } else {
+            match (self.os, self.abi) {
+                (Os::Linux, Abi::Musl) => format!("{}-linux-musl-{}", self.arch, name),
+                (Os::Linux, _) => format!("{}-linux-gnu-{}", self.arch, name),
+                (Os::MacOs, _) => format!("{}-apple-darwin-{}", self.arch, name),
+            }
+        }
+    }
+
+    /// The C compiler drivers to look for, in order of preference, to
+    /// preprocess with and to link with when the startup files for linking
+    /// directly can't be found.
+    pub fn compilers(&self) -> [String; 3] {
+        [self.tool("gcc"), self.tool("clang"), self.tool("cc")]
+    }
+}
+
+/// The host, or x86-64 Linux on a host rcc can't compile for, where the
+/// command line and [`ConfigBuilder`](super::config::ConfigBuilder) require
+/// a target instead.
+impl Default for Target {
+    fn default() -> Self {
+        Self::host().unwrap_or(SUPPORTED[0])
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X86_64 => write!(f, "x86_64"),
+            Self::RiscV64 => write!(f, "riscv64"),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.os, self.abi) {
+            (Os::Linux, Abi::Musl) => write!(f, "{}-unknown-linux-musl", self.arch),
+            (Os::Linux, _) => write!(f, "{}-unknown-linux-gnu", self.arch),
+            (Os::MacOs, _) => write!(f, "{}-apple-darwin", self.arch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_triples() {
+        assert_eq!(
+            Target::parse("x86_64-unknown-linux-gnu").unwrap(),
+            Target {
+                arch: Arch::X86_64,
+                os: Os::Linux,
+                abi: Abi::Gnu
+            }
+        );
+        assert_eq!(
+            Target::parse("x86_64-linux-musl").unwrap(),
+            Target {
+                arch: Arch::X86_64,
+                os: Os::Linux,
+                abi: Abi::Musl
+            }
+        );
+        assert_eq!(
+            Target::parse("x86_64-apple-darwin20").unwrap(),
+            Target {
+                arch: Arch::X86_64,
+                os: Os::MacOs,
+                abi: Abi::None
+            }
+        );
+        assert_eq!(
+            Target::parse("riscv64gc-unknown-linux-gnu").unwrap(),
+            Target {
+                arch: Arch::RiscV64,
+                os: Os::Linux,
+                abi: Abi::Gnu
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_triples() {
+        assert_eq!(
+            Target::parse("mips-unknown-linux-gnu").unwrap_err(),
+            "Unknown target architecture"
+        );
+        assert_eq!(
+            Target::parse("x86_64-pc-windows-msvc").unwrap_err(),
+            "Unknown target operating system"
+        );
+        assert_eq!(
+            Target::parse("riscv64-apple-darwin").unwrap_err(),
+            "RISC-V is not supported on macOS"
+        );
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for triple in &[
+            "x86_64-unknown-linux-gnu",
+            "x86_64-unknown-linux-musl",
+            "x86_64-apple-darwin",
+            "riscv64-unknown-linux-gnu",
+        ] {
+            assert_eq!(Target::parse(triple).unwrap().to_string(), *triple);
+        }
+    }
+
+    #[test]
+    fn supported_targets_parse() {
+        for target in &SUPPORTED {
+            assert_eq!(Target::parse(&target.to_string()).unwrap(), *target);
+        }
+        if let Some(host) = Target::host() {
+            assert!(SUPPORTED.contains(&host));
+        }
+    }
+
+    #[test]
+    fn symbol_names() {
+        let linux = Target::parse("x86_64-linux-gnu").unwrap();
+        let macos = Target::parse("x86_64-apple-darwin").unwrap();
+        assert_eq!(linux.symbol("main"), "main");
+        assert_eq!(macos.symbol("main"), "_main");
+    }
+
+    #[test]
+    fn cross_compiler_names() {
+        let riscv = Target::parse("riscv64-unknown-linux-gnu").unwrap();
+        if Target::host() != Some(riscv) {
+            assert_eq!(riscv.compilers()[0], "riscv64-linux-gnu-gcc");
+            assert_eq!(riscv.tool("as"), "riscv64-linux-gnu-as");
+        }
+        if let Some(host) = Target::host() {
+            assert_eq!(host.compilers(), ["gcc", "clang", "cc"]);
+            assert_eq!(host.tool("ld"), "ld");
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! `rcc test`, which compiles a corpus of test programs laid out as in Nora
+//! Sandler's test suite: `stage_N/valid` programs must compile and exit with
+//! the expected status, and `stage_N/invalid` programs must be rejected.
+//!
+//! A program's expectation comes from a comment anywhere in it:
+//!
+//! - `// expect-exit: <status>` for the status a valid program exits with,
+//!   which is otherwise found by compiling it with the system compiler
+//! - `// expect-error: <SyntaxError variant>` for the error an invalid
+//!   program is rejected with, which is otherwise any error
+//! - `// expect-skip: <reason>` for a program that isn't supported yet
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::config::{Config, Verbosity};
+use super::driver::{compile, interpret};
+use super::error::{CompilerError, IoContext};
+use super::toolchain;
+
+/// What a test program is expected to do.
+#[derive(Debug, PartialEq)]
+pub enum Expectation {
+    Exit(i32),
+    Error(String),
+    Skip(String),
+}
+
+/// The expectation annotated in a test program's source, if any.
+pub fn expectation(source: &str) -> Option<Expectation> {
+    source.lines().find_map(|line| {
+        let (_, comment) = line.split_once("//")?;
+        let (kind, value) = comment.split_once(':')?;
+        let value = value.trim();
+        match kind.trim() {
+            "expect-exit" => value.parse().ok().map(Expectation::Exit),
+            "expect-error" => Some(Expectation::Error(String::from(value))),
+            "expect-skip" => Some(Expectation::Skip(String::from(value))),
+            _ => None,
+        }
+    })
+}
+
+/// The results for one stage of a corpus.
+#[derive(Debug, Default, PartialEq)]
+pub struct StageSummary {
+    pub name: String,
+    pub valid_passed: usize,
+    pub valid: usize,
+    pub invalid_passed: usize,
+    pub invalid: usize,
+    pub skipped: usize,
+}
+
+/// The results of a run, with a description of each failure.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub stages: Vec<StageSummary>,
+    pub failures: Vec<String>,
+}
+
+impl Summary {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Write each failure, then a table of the results for each stage.
+    pub fn write(&self, mut output: impl Write) -> io::Result<()> {
+        for failure in &self.failures {
+            writeln!(output, "FAIL {}", failure)?;
+        }
+        if !self.failures.is_empty() {
+            writeln!(output)?;
+        }
+        let width = self
+            .stages
+            .iter()
+            .map(|stage| stage.name.len())
+            .chain(Some("total".len()))
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            output,
+            "{:width$}  {:>9}  {:>9}  {:>7}",
+            "stage",
+            "valid",
+            "invalid",
+            "skipped",
+            width = width
+        )?;
+        let mut total = StageSummary {
+            name: String::from("total"),
+            ..Default::default()
+        };
+        for stage in &self.stages {
+            write_row(&mut output, stage, width)?;
+            total.valid_passed += stage.valid_passed;
+            total.valid += stage.valid;
+            total.invalid_passed += stage.invalid_passed;
+            total.invalid += stage.invalid;
+            total.skipped += stage.skipped;
+        }
+        write_row(&mut output, &total, width)
+    }
+}
+
+fn write_row(output: &mut impl Write, stage: &StageSummary, width: usize) -> io::Result<()> {
+    writeln!(
+        output,
+        "{:width$}  {:>9}  {:>9}  {:>7}",
+        stage.name,
+        format!("{}/{}", stage.valid_passed, stage.valid),
+        format!("{}/{}", stage.invalid_passed, stage.invalid),
+        stage.skipped,
+        width = width
+    )
+}
+
+/// The stages in a corpus directory: the directory itself if it has `valid`
+/// or `invalid` programs, or else its `stage_N` directories in order.
+fn stages(dir: &Path) -> Result<Vec<PathBuf>, CompilerError> {
+    if dir.join("valid").is_dir() || dir.join("invalid").is_dir() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    let mut stages = Vec::new();
+    for entry in fs::read_dir(dir).context("read", dir)? {
+        let path = entry.context("read", dir)?.path();
+        let number = path
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix("stage_")?.parse::<u32>().ok());
+        if let (Some(number), true) = (number, path.is_dir()) {
+            stages.push((number, path));
+        }
+    }
+    if stages.is_empty() {
+        return Err(CompilerError::Usage(format!(
+            "{} has no stage_N, valid or invalid directories",
+            dir.display()
+        )));
+    }
+    stages.sort();
+    Ok(stages.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The C sources in `dir`, sorted, or none if it doesn't exist.
+fn sources(dir: &Path) -> Result<Vec<PathBuf>, CompilerError> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut sources = Vec::new();
+    for entry in fs::read_dir(dir).context("read", dir)? {
+        let path = entry.context("read", dir)?.path();
+        if path.extension().is_some_and(|ext| ext == "c") {
+            sources.push(path);
+        }
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+/// The configuration to compile one test program with, keeping the options
+/// that affect code generation.
+fn program_config(config: &Config, source: &Path, exe: &Path) -> Config {
+    Config {
+        filenames: vec![source.to_path_buf()],
+        output: Some(exe.to_path_buf()),
+        target: config.target,
+        preprocessor: config.preprocessor,
+        include_dirs: config.include_dirs.clone(),
+        debug_info: config.debug_info,
+        stack_protector: config.stack_protector,
+        align_functions: config.align_functions,
+        check_div: config.check_div,
+        opt_level: config.opt_level,
+        backend: config.backend,
+        integrated_as: config.integrated_as,
+        cc: config.cc.clone(),
+        assembler: config.assembler.clone(),
+        linker_args: config.linker_args.clone(),
+        static_link: config.static_link,
+        strip: config.strip,
+        link_for_size: config.link_for_size,
+        interpret: config.interpret,
+        verbosity: Verbosity::Quiet,
+        warnings_as_errors: config.warnings_as_errors.clone(),
+        cancellation: config.cancellation.clone(),
+        ..Default::default()
+    }
+}
+
+/// The exit status of running `exe`, or 128 plus the signal that killed it.
+fn exit_status(exe: &Path) -> Result<i32, CompilerError> {
+    let status = Command::new(exe).status().context("run", exe)?;
+    Ok(match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    })
+}
+
+/// Check a valid program compiles and exits as expected, returning why not.
+fn check_valid(config: &Config, source: &Path, expected: Option<i32>) -> Result<(), String> {
+    let dir = tempfile::tempdir()
+        .context("create a temporary directory in", &env::temp_dir())
+        .map_err(|e| e.to_string())?;
+    let exe = dir.path().join("test");
+    let expected = match expected {
+        Some(status) => status,
+        None => {
+            let reference = dir.path().join("reference");
+            toolchain::compile_program(config, source, &reference)
+                .map_err(|e| format!("the system compiler failed: {}", e))?;
+            exit_status(&reference).map_err(|e| e.to_string())?
+        }
+    };
+    let config = program_config(config, source, &exe);
+    let actual = if config.interpret {
+        interpret(&config).map(|value| value & 0xff)
+    } else {
+        compile(&config).and_then(|_| exit_status(&exe))
+    }
+    .map_err(|e| format!("failed to compile: {}", e))?;
+    if actual != expected {
+        return Err(format!("exited with {}, expected {}", actual, expected));
+    }
+    Ok(())
+}
+
+/// Check an invalid program is rejected, with the expected error if there is
+/// one, returning why not.
+fn check_invalid(config: &Config, source: &Path, expected: Option<&str>) -> Result<(), String> {
+    let dir = tempfile::tempdir()
+        .context("create a temporary directory in", &env::temp_dir())
+        .map_err(|e| e.to_string())?;
+    let config = program_config(config, source, &dir.path().join("test"));
+    let error = match compile(&config) {
+        Ok(_) => return Err(String::from("compiled, but should have been rejected")),
+        Err(CompilerError::Report(mut report)) if !report.errors.is_empty() => {
+            report.errors.remove(0)
+        }
+        Err(error) => error,
+    };
+    match (&error, expected) {
+        (CompilerError::Usage(_) | CompilerError::Io(_) | CompilerError::Cancelled, _) => {
+            Err(error.to_string())
+        }
+        (CompilerError::Lex(e, _) | CompilerError::Parse(e, _), Some(expected)) => {
+            let actual = format!("{:?}", e);
+            let variant = actual.split('(').next().unwrap_or_default();
+            if variant == expected {
+                Ok(())
+            } else {
+                Err(format!("rejected with {}, expected {}", variant, expected))
+            }
+        }
+        (_, Some(expected)) => Err(format!(
+            "rejected with \"{}\", expected {}",
+            error, expected
+        )),
+        (_, None) => Ok(()),
+    }
+}
+
+/// Run every test program in the corpora named by `config.filenames`,
+/// compiling each with the options in `config`.
+pub fn run(config: &Config) -> Result<Summary, CompilerError> {
+    let mut summary = Summary::default();
+    for root in &config.filenames {
+        for stage_dir in stages(root)? {
+            let name = stage_dir.file_name().map_or_else(
+                || stage_dir.display().to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            );
+            let mut stage = StageSummary {
+                name: name.clone(),
+                ..Default::default()
+            };
+            for valid in [true, false] {
+                let kind = if valid { "valid" } else { "invalid" };
+                for source in sources(&stage_dir.join(kind))? {
+                    let text = fs::read_to_string(&source).context("read", &source)?;
+                    let result = match expectation(&text) {
+                        Some(Expectation::Skip(_)) => {
+                            stage.skipped += 1;
+                            continue;
+                        }
+                        Some(Expectation::Exit(status)) if valid => {
+                            check_valid(config, &source, Some(status))
+                        }
+                        Some(Expectation::Error(error)) if !valid => {
+                            check_invalid(config, &source, Some(&error))
+                        }
+                        None if valid => check_valid(config, &source, None),
+                        None => check_invalid(config, &source, None),
+                        Some(expectation) => Err(format!(
+                            "{:?} doesn't apply to {} programs",
+                            expectation, kind
+                        )),
+                    };
+                    let passed = match result {
+                        Ok(()) => 1,
+                        Err(reason) => {
+                            let file = source.file_name().unwrap_or_default().to_string_lossy();
+                            summary
+                                .failures
+                                .push(format!("{}/{}/{}: {}", name, kind, file, reason));
+                            0
+                        }
+                    };
+                    if valid {
+                        stage.valid += 1;
+                        stage.valid_passed += passed;
+                    } else {
+                        stage.invalid += 1;
+                        stage.invalid_passed += passed;
+                    }
+                }
+            }
+            summary.stages.push(stage);
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::config::WarningsAsErrors;
+
+    #[test]
+    fn expectations() {
+        assert_eq!(
+            expectation("// expect-exit: 14\nint main() { return 14; }"),
+            Some(Expectation::Exit(14))
+        );
+        assert_eq!(
+            expectation("int main() {\n  return 0 // expect-error: MissingSemicolon\n}"),
+            Some(Expectation::Error(String::from("MissingSemicolon")))
+        );
+        assert_eq!(
+            expectation("// expect-skip: no loops yet"),
+            Some(Expectation::Skip(String::from("no loops yet")))
+        );
+        assert_eq!(expectation("// a comment: not an expectation"), None);
+        assert_eq!(expectation("int main() { return 0; }"), None);
+    }
+
+    #[test]
+    fn program_options() {
+        let config = Config {
+            align_functions: Some(16),
+            warnings_as_errors: WarningsAsErrors::All,
+            ..Default::default()
+        };
+        let program = program_config(&config, Path::new("a.c"), Path::new("a"));
+        assert_eq!(program.align_functions, Some(16));
+        assert_eq!(program.warnings_as_errors, WarningsAsErrors::All);
+    }
+
+    #[test]
+    fn stage_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for stage in ["stage_10", "stage_2", "stage_1", "other"] {
+            fs::create_dir_all(dir.path().join(stage).join("valid")).unwrap();
+        }
+        let names: Vec<_> = stages(dir.path())
+            .unwrap()
+            .iter()
+            .map(|stage| stage.file_name().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["stage_1", "stage_2", "stage_10"]);
+        // A single stage
+        let stage = dir.path().join("stage_1");
+        assert_eq!(stages(&stage).unwrap(), [stage]);
+        assert!(stages(&dir.path().join("other").join("valid")).is_err());
+    }
+
+    #[test]
+    fn summary_table() {
+        let summary = Summary {
+            stages: vec![
+                StageSummary {
+                    name: String::from("stage_1"),
+                    valid_passed: 6,
+                    valid: 6,
+                    invalid_passed: 4,
+                    invalid: 4,
+                    skipped: 0,
+                },
+                StageSummary {
+                    name: String::from("stage_2"),
+                    valid_passed: 2,
+                    valid: 3,
+                    invalid_passed: 1,
+                    invalid: 1,
+                    skipped: 1,
+                },
+            ],
+            failures: vec![String::from(
+                "stage_2/valid/neg.c: exited with 1, expected 255",
+            )],
+        };
+        let mut output = Vec::new();
+        summary.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "FAIL stage_2/valid/neg.c: exited with 1, expected 255
+
+stage        valid    invalid  skipped
+stage_1        6/6        4/4        0
+stage_2        2/3        1/1        1
+total          8/9        5/5        1
+"
+        );
+        assert!(!summary.passed());
+    }
+}
@@ -0,0 +1,521 @@
+use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use super::config::{self, Config, Verbosity, WarningsAsErrors};
+use super::target::{Abi, Arch, Os, Target};
+
+/// A command as it could be typed into a shell, with arguments quoted where
+/// they need it.
+pub fn command_line(command: &Command) -> String {
+    let mut words = vec![command.get_program()];
+    words.extend(command.get_args());
+    words
+        .iter()
+        .map(|word| {
+            let word = word.to_string_lossy();
+            let plain = !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            if plain {
+                word.into_owned()
+            } else {
+                format!("'{}'", word.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+const NO_COMPILER: &str = "No system compiler found; install gcc or clang, or pass --cc";
+
+/// Whether `program` names an executable file, either as a path or in one
+/// of the directories in `path`.
+fn find_in(program: &str, path: Option<&OsStr>) -> bool {
+    let executable = |file: &Path| {
+        fs::metadata(file)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        return executable(Path::new(program));
+    }
+    path.is_some_and(|path| env::split_paths(path).any(|dir| executable(&dir.join(program))))
+}
+
+/// Whether `program` can be run from `PATH`.
+fn find_program(program: &str) -> bool {
+    find_in(program, env::var_os("PATH").as_deref())
+}
+
+/// The C compiler driver chosen with --cc, or else the first of the target's
+/// compilers that's installed.
+fn cc(config: &Config) -> Option<String> {
+    let cc = match &config.cc {
+        Some(cc) => Some(cc.clone()),
+        None => config
+            .target
+            .compilers()
+            .iter()
+            .find(|cc| find_program(cc))
+            .cloned(),
+    };
+    tracing::trace!(?cc, "system compiler");
+    cc
+}
+
+/// Run a toolchain program, failing if its exit status does. Anything it
+/// prints to stderr is forwarded with a `linker:` prefix, and kept as context
+/// in the error if it fails. In a dry run the command is only printed.
+fn run(config: &Config, command: &mut Command) -> Result<(), Box<dyn Error>> {
+    if config.dry_run {
+        eprintln!("{}", command_line(command));
+        return Ok(());
+    }
+    if config.verbosity == Verbosity::Verbose {
+        eprintln!("rcc: running {}", command_line(command));
+    }
+    tracing::debug!(command = %command_line(command), "running");
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command.output().map_err(|e| match e.kind() {
+        ErrorKind::NotFound => format!("{} not found", program),
+        _ => format!("Failed to run {}: {}", program, e),
+    })?;
+    tracing::debug!(
+        %program,
+        status = %output.status,
+        stderr = %String::from_utf8_lossy(&output.stderr),
+        "finished"
+    );
+    let diagnostics: String = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(|line| format!("linker: {}\n", line))
+        .collect();
+    if !output.status.success() {
+        return Err(format!("{}{} failed ({})", diagnostics, program, output.status).into());
+    }
+    // Warnings don't stop the build
+    if !diagnostics.is_empty() && config.verbosity != Verbosity::Quiet {
+        eprint!("{}", diagnostics);
+    }
+    Ok(())
+}
+
+/// Run the C preprocessor over a source file, returning the preprocessed
+/// text without line markers. `contents` is only used when reading from
+/// stdin; otherwise the file is passed by name so that `#include "..."`
+/// resolves relative to it.
+pub fn preprocess(
+    config: &Config,
+    filename: &Path,
+    contents: &str,
+) -> Result<String, Box<dyn Error>> {
+    let cc = cc(config).ok_or(
+        "No system compiler found to preprocess with; install gcc or clang, pass --cc, \
+         or use --no-preprocess",
+    )?;
+    let mut command = Command::new(&cc);
+    command.args(["-E", "-P"]);
+    for dir in &config.include_dirs {
+        command.arg("-I").arg(dir);
+    }
+    // rcc's warnings have the same names as the system compiler's
+    match &config.warnings_as_errors {
+        WarningsAsErrors::None => (),
+        WarningsAsErrors::All => {
+            command.arg("-Werror");
+        }
+        WarningsAsErrors::Only(warnings) => {
+            command.args(warnings.iter().map(|w| format!("-Werror={}", w.name())));
+        }
+    }
+    let stdin = config::is_stdin(filename);
+    if stdin {
+        command.args(["-x", "c", "-"]).stdin(Stdio::piped());
+    } else {
+        command.arg(filename).stdin(Stdio::null());
+    }
+    if config.verbosity == Verbosity::Verbose {
+        eprintln!("rcc: running {}", command_line(&command));
+    }
+    tracing::debug!(command = %command_line(&command), "running");
+    let mut child = match command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            return Err(format!(
+                "{} not found; use --no-preprocess to compile without preprocessing",
+                cc
+            )
+            .into())
+        }
+        Err(e) => return Err(format!("Failed to run {}: {}", cc, e).into()),
+    };
+    // Write from another thread so a full output pipe can't block the input
+    let writer = child.stdin.take().map(|mut pipe| {
+        let contents = contents.to_owned();
+        thread::spawn(move || pipe.write_all(contents.as_bytes()))
+    });
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .map_err(|_| "Failed to write to the preprocessor")??;
+    }
+    let diagnostics = String::from_utf8_lossy(&output.stderr);
+    tracing::debug!(program = %cc, status = %output.status, stderr = %diagnostics, "finished");
+    if !output.status.success() {
+        return Err(diagnostics.into_owned().into());
+    }
+    // Warnings such as redefined macros don't stop compilation
+    if !diagnostics.is_empty() && config.verbosity != Verbosity::Quiet {
+        eprint!("{}", diagnostics);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Assemble `assembly` into the relocatable object file `object`.
+pub fn assemble(config: &Config, assembly: &Path, object: &Path) -> Result<(), Box<dyn Error>> {
+    let as_program = config.target.tool("as");
+    let mut command = match &config.assembler {
+        Some(assembler) => Command::new(assembler),
+        None if find_program(&as_program) => Command::new(as_program),
+        // Compiler drivers assemble too, and clang can without binutils
+        None => {
+            let mut command = Command::new(cc(config).ok_or(NO_COMPILER)?);
+            command.arg("-c");
+            command
+        }
+    };
+    run(config, command.arg(assembly).arg("-o").arg(object))
+}
+
+/// Compile a C source file into the object file `object` with the C
+/// compiler driver.
+pub fn compile_c(config: &Config, source: &Path, object: &Path) -> Result<(), Box<dyn Error>> {
+    let cc = cc(config).ok_or(NO_COMPILER)?;
+    run(
+        config,
+        Command::new(cc).arg("-c").arg(source).arg("-o").arg(object),
+    )
+}
+
+/// Compile and link a C program into the executable `exe` with the C compiler
+/// driver, such as to compare its behaviour with rcc's.
+pub fn compile_program(config: &Config, source: &Path, exe: &Path) -> Result<(), Box<dyn Error>> {
+    let cc = cc(config).ok_or(NO_COMPILER)?;
+    run(config, Command::new(cc).arg(source).arg("-o").arg(exe))
+}
+
+/// Link object files into an executable. When the C runtime's startup files
+/// and the linker can be found the linker is run directly; otherwise, or when
+/// the user has chosen a C compiler, the compiler driver is left to find them.
+pub fn link(config: &Config, objects: &[PathBuf], exe: &Path) -> Result<(), Box<dyn Error>> {
+    let target = &config.target;
+    let ld = target.tool("ld");
+    let startup = match StartupFiles::find(target, config.static_link) {
+        Some(startup) if config.cc.is_none() && find_program(&ld) => startup,
+        _ => {
+            let cc = cc(config).ok_or(NO_COMPILER)?;
+            let mut command = Command::new(cc);
+            if config.static_link {
+                command.arg("-static");
+            }
+            if config.strip {
+                command.arg("-s");
+            }
+            if config.link_for_size {
+                command.arg(format!("-Wl,{}", size_args(target).join(",")));
+            }
+            return run(
+                config,
+                command
+                    .args(objects)
+                    .args(&config.linker_args)
+                    .arg("-o")
+                    .arg(exe),
+            );
+        }
+    };
+
+    let mut command = Command::new(ld);
+    command.arg("-o").arg(exe);
+    if config.static_link {
+        command.arg("-static");
+    } else {
+        command.args(["-dynamic-linker", startup.dynamic_linker]);
+    }
+    if config.strip {
+        command.arg("-s");
+    }
+    if config.link_for_size {
+        command.args(size_args(target));
+    }
+    command.arg(&startup.crt1).arg(&startup.crti);
+    if let Some((begin, _)) = &startup.crtbegin_end {
+        command.arg(begin);
+    }
+    command
+        .args(objects)
+        .args(ld_args(&config.linker_args))
+        .arg(format!("-L{}", startup.lib_dir.display()));
+    match &startup.crtbegin_end {
+        // The static C library calls into the compiler's support libraries,
+        // which are next to crtbegin
+        Some((begin, _)) if config.static_link => {
+            if let Some(gcc_dir) = begin.parent() {
+                command.arg(format!("-L{}", gcc_dir.display()));
+            }
+            command.args(["--start-group", "-lc", "-lgcc", "-lgcc_eh", "--end-group"]);
+        }
+        _ => {
+            command.arg("-lc");
+        }
+    }
+    if let Some((_, end)) = &startup.crtbegin_end {
+        command.arg(end);
+    }
+    command.arg(&startup.crtn);
+    run(config, &mut command)
+}
+
+/// The linker arguments that make an executable smaller: dropping sections
+/// and shared libraries nothing uses.
+fn size_args(target: &Target) -> &'static [&'static str] {
+    match target.os {
+        Os::Linux => &["--gc-sections", "--as-needed", "-O1"],
+        Os::MacOs => &["-dead_strip", "-dead_strip_dylibs"],
+    }
+}
+
+/// Translate arguments meant for a compiler driver's link step into
+/// arguments for the linker itself.
+fn ld_args(args: &[String]) -> Vec<&str> {
+    args.iter()
+        .flat_map(|arg| match arg.strip_prefix("-Wl,") {
+            Some(list) => list.split(',').collect(),
+            None => vec![arg.as_str()],
+        })
+        .collect()
+}
+
+/// The C runtime objects and libraries needed to link an executable
+#[derive(Debug)]
+struct StartupFiles {
+    /// The directory containing libc and the crt objects
+    lib_dir: PathBuf,
+    crt1: PathBuf,
+    crti: PathBuf,
+    crtn: PathBuf,
+    /// The compiler's constructor support, which plain C programs can do
+    /// without
+    crtbegin_end: Option<(PathBuf, PathBuf)>,
+    dynamic_linker: &'static str,
+}
+
+impl StartupFiles {
+    /// The startup files for a dynamically linked executable, or one linked
+    /// statically, which needs GCC's support libraries.
+    fn find(target: &Target, static_link: bool) -> Option<Self> {
+        if target.os != Os::Linux {
+            return None;
+        }
+        let lib_dir = library_dirs(target)
+            .into_iter()
+            .find(|dir| dir.join("crt1.o").is_file())?;
+        let crtn = lib_dir.join("crtn.o");
+        let crti = lib_dir.join("crti.o");
+        if !crti.is_file() || !crtn.is_file() {
+            return None;
+        }
+        let crtbegin_end = find_crtbegin_end(target, static_link);
+        if static_link && crtbegin_end.is_none() {
+            return None;
+        }
+        Some(StartupFiles {
+            crt1: lib_dir.join("crt1.o"),
+            crti,
+            crtn,
+            crtbegin_end,
+            lib_dir,
+            dynamic_linker: dynamic_linker(target),
+        })
+    }
+}
+
+fn multiarch(target: &Target) -> String {
+    match target.abi {
+        Abi::Musl => format!("{}-linux-musl", target.arch),
+        _ => format!("{}-linux-gnu", target.arch),
+    }
+}
+
+/// The program interpreter for dynamically linked executables.
+fn dynamic_linker(target: &Target) -> &'static str {
+    match (target.arch, target.abi) {
+        (Arch::X86_64, Abi::Musl) => "/lib/ld-musl-x86_64.so.1",
+        (Arch::X86_64, _) => "/lib64/ld-linux-x86-64.so.2",
+        (Arch::RiscV64, Abi::Musl) => "/lib/ld-musl-riscv64.so.1",
+        (Arch::RiscV64, _) => "/lib/ld-linux-riscv64-lp64d.so.1",
+    }
+}
+
+/// Where the C library may be installed, most specific first.
+fn library_dirs(target: &Target) -> Vec<PathBuf> {
+    let triple = multiarch(target);
+    let mut dirs = vec![
+        PathBuf::from(format!("/usr/lib/{}", triple)),
+        PathBuf::from(format!("/usr/{}/lib", triple)),
+    ];
+    if target.abi == Abi::Musl {
+        dirs.push(PathBuf::from("/usr/lib/musl/lib"));
+    } else if Target::host() == Some(*target) {
+        dirs.extend(
+            ["/usr/lib64", "/lib64", "/usr/lib"]
+                .iter()
+                .map(PathBuf::from),
+        );
+    }
+    dirs
+}
+
+/// Find crtbegin.o and crtend.o in the newest installed GCC for the target,
+/// or crtbeginT.o in place of crtbegin.o for a static executable.
+fn find_crtbegin_end(target: &Target, static_link: bool) -> Option<(PathBuf, PathBuf)> {
+    let begin = if static_link {
+        "crtbeginT.o"
+    } else {
+        "crtbegin.o"
+    };
+    let triple = multiarch(target);
+    let mut versions = Vec::new();
+    for root in &["/usr/lib/gcc", "/usr/lib/gcc-cross"] {
+        if let Ok(entries) = fs::read_dir(Path::new(root).join(&triple)) {
+            versions.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+        }
+    }
+    // Sort version directories numerically so that 10 comes after 9
+    versions.sort_by_key(|dir| {
+        dir.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| {
+                name.split('.')
+                    .map(|part| part.parse::<u32>().unwrap_or(0))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    });
+    versions.into_iter().rev().find_map(|dir| {
+        let (begin, end) = (dir.join(begin), dir.join("crtend.o"));
+        if begin.is_file() && end.is_file() {
+            Some((begin, end))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamic_linkers() {
+        let linker = |triple| dynamic_linker(&Target::parse(triple).unwrap());
+        assert_eq!(linker("x86_64-linux-gnu"), "/lib64/ld-linux-x86-64.so.2");
+        assert_eq!(linker("riscv64-linux-musl"), "/lib/ld-musl-riscv64.so.1");
+    }
+
+    #[test]
+    fn quoted_command_lines() {
+        let mut command = Command::new("ld");
+        command.args(["-o", "my prog", "-L/usr/lib", "it's", ""]);
+        assert_eq!(
+            command_line(&command),
+            "ld -o 'my prog' -L/usr/lib 'it'\\''s' ''"
+        );
+    }
+
+    #[test]
+    fn find_programs() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str, mode| {
+            let path = dir.path().join(name);
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        file("tool", 0o755);
+        file("data", 0o644);
+        let path = env::join_paths([Path::new("/nonexistent"), dir.path()]).unwrap();
+        let path = Some(path.as_os_str());
+
+        assert!(find_in("tool", path));
+        assert!(!find_in("data", path));
+        assert!(!find_in("missing", path));
+        assert!(!find_in("tool", None));
+        let absolute = dir.path().join("tool");
+        assert!(find_in(absolute.to_str().unwrap(), None));
+    }
+
+    #[test]
+    fn exit_status_decides_success() {
+        let config = Config {
+            verbosity: Verbosity::Quiet,
+            ..Default::default()
+        };
+        let sh = |script| run(&config, Command::new("sh").args(["-c", script]));
+        assert!(sh("echo 'warning: benign' >&2").is_ok());
+        assert_eq!(
+            sh("exit 3").unwrap_err().to_string(),
+            "sh failed (exit status: 3)"
+        );
+        assert_eq!(
+            sh("echo 'undefined reference to foo' >&2; exit 1")
+                .unwrap_err()
+                .to_string(),
+            "linker: undefined reference to foo\nsh failed (exit status: 1)"
+        );
+    }
+
+    #[test]
+    fn chosen_compiler_is_used() {
+        let config = Config {
+            cc: Some(String::from("my-cc")),
+            ..Default::default()
+        };
+        assert_eq!(cc(&config).as_deref(), Some("my-cc"));
+    }
+
+    #[test]
+    fn linker_arguments() {
+        let args = [String::from("-lm"), String::from("-Wl,--gc-sections,-O1")];
+        assert_eq!(ld_args(&args), ["-lm", "--gc-sections", "-O1"]);
+    }
+
+    #[test]
+    fn no_startup_files_for_macos() {
+        let target = Target::parse("x86_64-apple-darwin").unwrap();
+        assert!(StartupFiles::find(&target, false).is_none());
+    }
+
+    #[test]
+    fn library_dirs_for_cross_targets() {
+        let target = Target::parse("riscv64-linux-gnu").unwrap();
+        assert_eq!(
+            library_dirs(&target)[..2],
+            [
+                PathBuf::from("/usr/lib/riscv64-linux-gnu"),
+                PathBuf::from("/usr/riscv64-linux-gnu/lib")
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! A C interface for embedding the compiler, built into the `cdylib` with
+//! the `ffi` feature. Each function takes NUL-terminated source text and
+//! fills in an [`RccResult`] whose JSON describes the output or the errors.
+//! `include/rcc.h` declares the interface for C.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use crate::compiler::error::CompilerError;
+use crate::compiler::lexer::{Span, Token};
+use crate::Options;
+
+/// The result of a call, which the caller frees with [`rcc_result_free`].
+#[repr(C)]
+pub struct RccResult {
+    /// 0 on success, or the exit status `rcc` would give for the error
+    pub status: c_int,
+    /// The output as `{"tokens": [...]}`, `{"ast": "..."}` or
+    /// `{"assembly": "..."}`, or the errors as `{"errors": [...]}`
+    pub json: *mut c_char,
+}
+
+/// Tokenize `source`, giving each token with its position.
+///
+/// # Safety
+///
+/// `source` must be a NUL-terminated string and `result` must point to an
+/// `RccResult` to fill in.
+#[no_mangle]
+pub unsafe extern "C" fn rcc_tokenize(source: *const c_char, result: *mut RccResult) -> c_int {
+    call(source, result, tokenize_json)
+}
+
+/// Parse `source`, giving the syntax tree in rcc's `--emit ast` format.
+///
+/// # Safety
+///
+/// As for [`rcc_tokenize`].
+#[no_mangle]
+pub unsafe extern "C" fn rcc_parse(source: *const c_char, result: *mut RccResult) -> c_int {
+    call(source, result, parse_json)
+}
+
+/// Compile `source` to assembly for the host.
+///
+/// # Safety
+///
+/// As for [`rcc_tokenize`].
+#[no_mangle]
+pub unsafe extern "C" fn rcc_compile(source: *const c_char, result: *mut RccResult) -> c_int {
+    call(source, result, compile_json)
+}
+
+/// Free the JSON of a result filled in by any of the other functions.
+///
+/// # Safety
+///
+/// `result` must have been filled in by this library, and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rcc_result_free(result: *mut RccResult) {
+    if let Some(result) = result.as_mut() {
+        if !result.json.is_null() {
+            drop(CString::from_raw(result.json));
+            result.json = ptr::null_mut();
+        }
+    }
+}
+
+/// The tokens of `source` as `{"tokens": [...]}`, or its errors as
+/// `{"errors": [...]}`.
+pub(crate) fn tokenize_json(source: &str) -> Result<String, String> {
+    let tokens = crate::tokenize(source).map_err(|errors| {
+        let errors = errors
+            .into_iter()
+            .map(|(e, span)| syntax_error_json(&e.to_string(), e.code(), span))
+            .collect::<Vec<_>>();
+        format!("{{\"errors\": [{}]}}", errors.join(", "))
+    })?;
+    let tokens: Vec<String> = tokens.iter().map(token_json).collect();
+    Ok(format!("{{\"tokens\": [{}]}}", tokens.join(", ")))
+}
+
+/// The syntax tree of `source` as `{"ast": "..."}`, or its errors.
+pub(crate) fn parse_json(source: &str) -> Result<String, String> {
+    let tokens = crate::tokenize(source).map_err(|errors| {
+        let error = CompilerError::report(
+            errors
+                .into_iter()
+                .map(|(e, span)| error_at(e, span, source, false))
+                .collect(),
+            0,
+        );
+        errors_json(&error)
+    })?;
+    let ast = crate::parse(&tokens).map_err(|(e, index)| {
+        let span = match tokens.get(index) {
+            Some((_, span)) => *span,
+            None => crate::compiler::lexer::span_at(source, source.trim_end().len()),
+        };
+        errors_json(&error_at(e, span, source, true))
+    })?;
+    Ok(format!(
+        "{{\"ast\": {}}}",
+        string_json(&format!("{:#?}", ast))
+    ))
+}
+
+/// The assembly for `source` as `{"assembly": "..."}`, or its errors.
+pub(crate) fn compile_json(source: &str) -> Result<String, String> {
+    let assembly = assembly(source)?;
+    Ok(format!("{{\"assembly\": {}}}", string_json(&assembly)))
+}
+
+/// The assembly for `source` for the host, or the JSON of its errors.
+pub(crate) fn assembly(source: &str) -> Result<String, String> {
+    crate::compile_to_assembly(source, &Options::default()).map_err(|e| errors_json(&e))
+}
+
+/// Run `f` on the source text and store its JSON in `result`, returning the
+/// status. Errors are also JSON, with the status of a source error.
+unsafe fn call(
+    source: *const c_char,
+    result: *mut RccResult,
+    f: impl FnOnce(&str) -> Result<String, String>,
+) -> c_int {
+    let result = match result.as_mut() {
+        Some(result) => result,
+        None => return 1,
+    };
+    let (status, json) = if source.is_null() {
+        (1, message_json("No source provided"))
+    } else {
+        match CStr::from_ptr(source).to_str() {
+            Ok(source) => match f(source) {
+                Ok(json) => (0, json),
+                Err(json) => (2, json),
+            },
+            Err(_) => (1, message_json("Source is not valid UTF-8")),
+        }
+    };
+    // JSON strings escape NUL, so there's never one inside
+    result.status = status;
+    result.json = CString::new(json).unwrap_or_default().into_raw();
+    status
+}
+
+fn error_at(e: crate::SyntaxError, span: Span, source: &str, parsing: bool) -> CompilerError {
+    let text = source.lines().nth(span.line - 1).unwrap_or_default();
+    let location = crate::compiler::diagnostic::Location::new(
+        std::path::Path::new("<input>"),
+        source,
+        span.line,
+        span.column,
+        crate::compiler::diagnostic::token_len(text, span.column),
+    );
+    if parsing {
+        CompilerError::Parse(e, location)
+    } else {
+        CompilerError::Lex(e, location)
+    }
+}
+
+fn errors_json(error: &CompilerError) -> String {
+    let errors = match error {
+        CompilerError::Report(report) => report.errors.iter().map(error_json).collect(),
+        error => vec![error_json(error)],
+    };
+    format!("{{\"errors\": [{}]}}", errors.join(", "))
+}
+
+fn error_json(error: &CompilerError) -> String {
+    match error {
+        CompilerError::Lex(e, location) | CompilerError::Parse(e, location) => {
+            let span = Span {
+                line: location.line,
+                column: location.column,
+            };
+            syntax_error_json(&e.to_string(), e.code(), span)
+        }
+        error => format!("{{\"message\": {}}}", string_json(&error.to_string())),
+    }
+}
+
+fn syntax_error_json(message: &str, code: &str, span: Span) -> String {
+    format!(
+        "{{\"message\": {}, \"code\": \"{}\", \"line\": {}, \"column\": {}}}",
+        string_json(message),
+        code,
+        span.line,
+        span.column
+    )
+}
+
+fn message_json(message: &str) -> String {
+    format!(
+        "{{\"errors\": [{{\"message\": {}}}]}}",
+        string_json(message)
+    )
+}
+
+fn token_json((token, span): &(Token, Span)) -> String {
+    format!(
+        "{{\"token\": {}, \"line\": {}, \"column\": {}}}",
+        string_json(&format!("{:?}", token)),
+        span.line,
+        span.column
+    )
+}
+
+/// `s` as a JSON string literal.
+fn string_json(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(
+        f: unsafe extern "C" fn(*const c_char, *mut RccResult) -> c_int,
+        source: &str,
+    ) -> (c_int, String) {
+        let source = CString::new(source).unwrap();
+        let mut result = RccResult {
+            status: -1,
+            json: ptr::null_mut(),
+        };
+        unsafe {
+            let status = f(source.as_ptr(), &mut result);
+            assert_eq!(status, result.status);
+            let json = CStr::from_ptr(result.json).to_str().unwrap().to_owned();
+            rcc_result_free(&mut result);
+            assert!(result.json.is_null());
+            (status, json)
+        }
+    }
+
+    #[test]
+    fn tokenize() {
+        assert_eq!(
+            json(rcc_tokenize, "int main"),
+            (
+                0,
+                String::from(
+                    "{\"tokens\": [{\"token\": \"IntKw\", \"line\": 1, \"column\": 1}, \
+                     {\"token\": \"Identifier(\\\"main\\\")\", \"line\": 1, \"column\": 5}]}"
+                )
+            )
+        );
+        assert_eq!(
+            json(rcc_tokenize, "int $x"),
+            (
+                2,
+                String::from(
+                    "{\"errors\": [{\"message\": \"Syntax Error [E0108]: Invalid identifier: $x\", \
+                     \"code\": \"E0108\", \"line\": 1, \"column\": 5}]}"
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let (status, ast) = json(rcc_parse, "int main() { return 1; }");
+        assert_eq!(status, 0);
+        assert!(ast.starts_with("{\"ast\": \"Program(\\n"), "{}", ast);
+        let (status, errors) = json(rcc_parse, "int main() { return 1 }");
+        assert_eq!(status, 2);
+        assert!(
+            errors.contains("\"code\": \"E0101\", \"line\": 1, \"column\": 23"),
+            "{}",
+            errors
+        );
+    }
+
+    #[test]
+    fn compile() {
+        let (status, assembly) = json(rcc_compile, "int main() { return 1; }");
+        assert_eq!(status, 0);
+        assert!(assembly.starts_with("{\"assembly\": \""), "{}", assembly);
+        assert!(assembly.contains("main"), "{}", assembly);
+    }
+
+    #[test]
+    fn null_arguments() {
+        let mut result = RccResult {
+            status: -1,
+            json: ptr::null_mut(),
+        };
+        unsafe {
+            assert_eq!(rcc_compile(ptr::null(), ptr::null_mut()), 1);
+            assert_eq!(rcc_compile(ptr::null(), &mut result), 1);
+            rcc_result_free(&mut result);
+            rcc_result_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(string_json("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    }
+}
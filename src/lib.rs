@@ -1 +1,24 @@
+//! A compiler for a small subset of C.
+//!
+//! [`compile_to_assembly`] compiles source text in one step. Each stage is
+//! also available on its own, to tokenize with [`tokenize`], parse the tokens
+//! into an [`ast`] with [`parse`], and [`generate`] assembly from that.
+//!
+//! Compiling files with the system toolchain, as the `rcc` binary does, needs
+//! the default `driver` feature. Without it the library builds for targets
+//! such as `wasm32-unknown-unknown`, where the `wasm` feature adds bindings
+//! for JavaScript.
+
 pub mod compiler;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use compiler::config::Options;
+pub use compiler::error::{CompilerError, SyntaxError};
+pub use compiler::lexer::{semantic_tokens, tokenize, tokenize_lossy};
+pub use compiler::parser::{parse, parse_lossy, parse_with_recovery};
+pub use compiler::{ast, compile_to_assembly, generate, generate_with_map};
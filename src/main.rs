@@ -1,19 +1,86 @@
 use std::env;
+use std::fs;
+use std::io;
 use std::process;
 
-mod compiler;
-use compiler::config::Config;
+use rcc::compiler::{self, config, config::Subcommand, error::CompilerError};
+
+// Counts allocations for --time-passes
+#[global_allocator]
+static ALLOCATOR: compiler::stats::Allocator = compiler::stats::Allocator;
+
+fn usage_error(message: &str) -> ! {
+    let error = CompilerError::Usage(String::from(message));
+    eprintln!("Problem parsing arguments: {}", error);
+    process::exit(error.exit_code());
+}
 
 fn main() {
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
-        eprintln!("Problem parsing arguments: {}", err);
-        process::exit(1);
-    });
+    let args = config::with_environment(env::args().collect(), |name| env::var(name).ok());
+    let args = config::expand_response_files(args, |path| fs::read_to_string(path))
+        .unwrap_or_else(|err| usage_error(&err));
+    if let Some(filter) = config::log_filter(args.iter().cloned(), env::var("RCC_LOG").ok()) {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::new(filter))
+            .with_writer(io::stderr)
+            .init();
+    }
 
-    if let Err(e) = compiler::compile(&config) {
-        eprintln!("Compilation error: {}", e);
-        process::exit(1);
+    if let Some(info) = config::requested_info(args.iter().cloned()) {
+        println!("{}", info.trim_end());
+        return;
     }
 
-    println!("Compilation complete.");
+    let (config, subcommand) = config::parse_command_line(args.into_iter()).unwrap_or_else(|err| {
+        // clap's message shows the usage and where to find more help
+        eprint!("{}", err);
+        process::exit(CompilerError::Usage(err.to_string()).exit_code());
+    });
+
+    let result = match subcommand {
+        Some(Subcommand::Run(program_args)) => match compiler::run(config, &program_args) {
+            Ok(status) => process::exit(status),
+            Err(e) => Err(e),
+        },
+        Some(Subcommand::Repl) => compiler::repl::run(config, io::stdin().lock(), io::stdout()),
+        Some(Subcommand::Test) => compiler::test_runner::run(&config).and_then(|summary| {
+            summary.write(io::stdout()).map_err(CompilerError::Io)?;
+            if !summary.passed() {
+                process::exit(1);
+            }
+            Ok(())
+        }),
+        Some(Subcommand::Fmt { check }) => compiler::format::run(&config, check).map(|changed| {
+            if check && !changed.is_empty() {
+                for filename in &changed {
+                    println!("{}", filename.display());
+                }
+                process::exit(1);
+            }
+        }),
+        None if config.watch => compiler::watch(&config),
+        None if config.interpret => match compiler::interpret(&config) {
+            Ok(value) => process::exit(value),
+            Err(e) => Err(e),
+        },
+        None => compiler::compile(&config).map(|_| ()),
+    };
+    if let Err(e) = result {
+        match &e {
+            CompilerError::Runtime(_) => eprintln!("Runtime error: {}", e),
+            CompilerError::Report(report) => {
+                for error in &report.errors {
+                    eprintln!("Compilation error: {}", error);
+                }
+                if report.omitted > 0 {
+                    eprintln!(
+                        "{} more errors not shown; see --error-limit",
+                        report.omitted
+                    );
+                }
+            }
+            _ => eprintln!("Compilation error: {}", e),
+        }
+        process::exit(e.exit_code());
+    }
 }
@@ -0,0 +1,171 @@
+//! A Python extension module named `rcc`, built into the `cdylib` with the
+//! `python` feature, as by `maturin develop`. It gives the same JSON as the C
+//! interface in [`crate::ffi`], decoded into Python objects.
+//!
+//! ```python
+//! >>> import rcc
+//! >>> rcc.compile_to_assembly("int main() { return 2; }")
+//! ```
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+use crate::ffi;
+
+create_exception!(
+    rcc,
+    RccError,
+    PyException,
+    "A compilation failed, with the exit status rcc would give as `status` and \
+     each of the errors as a dict with a message, and a code, line and column \
+     for syntax errors, as `errors`."
+);
+
+/// The tokens of `source`, each a dict with the token, line and column.
+#[pyfunction]
+fn tokenize<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyAny>> {
+    call(py, ffi::tokenize_json(source))?.get_item("tokens")
+}
+
+/// The syntax tree of `source` as JSON text, `{"ast": "..."}` with the tree
+/// as `rcc --emit ast` writes it.
+#[pyfunction]
+fn parse_to_json(py: Python<'_>, source: &str) -> PyResult<String> {
+    match ffi::parse_json(source) {
+        Ok(json) => Ok(json),
+        Err(json) => Err(error(py, &json)),
+    }
+}
+
+/// The assembly for `source`, for the host.
+#[pyfunction]
+fn compile_to_assembly<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyAny>> {
+    call(py, ffi::compile_json(source))?.get_item("assembly")
+}
+
+#[pymodule]
+#[pyo3(name = "rcc")]
+fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("RccError", m.py().get_type::<RccError>())?;
+    m.add_function(wrap_pyfunction!(tokenize, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_to_json, m)?)?;
+    m.add_function(wrap_pyfunction!(compile_to_assembly, m)?)?;
+    Ok(())
+}
+
+/// The decoded JSON of a result, or an `RccError` for its errors.
+fn call(py: Python<'_>, result: Result<String, String>) -> PyResult<Bound<'_, PyAny>> {
+    match result {
+        Ok(json) => loads(py, &json),
+        Err(json) => Err(error(py, &json)),
+    }
+}
+
+/// An `RccError` for the JSON errors of a failed call, which has the status
+/// of a source error as in [`ffi`].
+fn error(py: Python<'_>, json: &str) -> PyErr {
+    rcc_error(py, json).unwrap_or_else(|err| err)
+}
+
+fn rcc_error(py: Python<'_>, json: &str) -> PyResult<PyErr> {
+    let errors = loads(py, json)?.get_item("errors")?;
+    let messages = errors
+        .try_iter()?
+        .map(|e| e?.get_item("message")?.extract::<String>())
+        .collect::<PyResult<Vec<_>>>()?;
+    let err = RccError::new_err(messages.join("\n"));
+    err.value(py).setattr("status", 2)?;
+    err.value(py).setattr("errors", errors)?;
+    Ok(err)
+}
+
+fn loads<'py>(py: Python<'py>, json: &str) -> PyResult<Bound<'py, PyAny>> {
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyList;
+
+    fn with_module(f: impl FnOnce(Python<'_>, &Bound<'_, PyModule>)) {
+        Python::initialize();
+        Python::attach(|py| {
+            let m = PyModule::new(py, "rcc").unwrap();
+            init(&m).unwrap();
+            f(py, &m)
+        })
+    }
+
+    #[test]
+    fn tokenize() {
+        with_module(|_, m| {
+            let tokens = m.call_method1("tokenize", ("int main",)).unwrap();
+            let tokens = tokens.cast::<PyList>().unwrap();
+            assert_eq!(tokens.len(), 2);
+            let token = tokens.get_item(1).unwrap();
+            assert_eq!(
+                token
+                    .get_item("token")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "Identifier(\"main\")"
+            );
+            assert_eq!(
+                token
+                    .get_item("column")
+                    .unwrap()
+                    .extract::<usize>()
+                    .unwrap(),
+                5
+            );
+        })
+    }
+
+    #[test]
+    fn parse_to_json() {
+        with_module(|_, m| {
+            let ast = m
+                .call_method1("parse_to_json", ("int main() { return 1; }",))
+                .unwrap();
+            assert!(ast
+                .extract::<String>()
+                .unwrap()
+                .starts_with("{\"ast\": \"Program(\\n"));
+        })
+    }
+
+    #[test]
+    fn compile_to_assembly() {
+        with_module(|_, m| {
+            let assembly = m
+                .call_method1("compile_to_assembly", ("int main() { return 1; }",))
+                .unwrap();
+            assert!(assembly.extract::<String>().unwrap().contains("main"));
+        })
+    }
+
+    #[test]
+    fn errors() {
+        with_module(|py, m| {
+            let err = m
+                .call_method1("compile_to_assembly", ("int main() { return 1 }",))
+                .unwrap_err();
+            assert!(err.is_instance_of::<RccError>(py));
+            let value = err.value(py);
+            assert_eq!(
+                value.getattr("status").unwrap().extract::<i32>().unwrap(),
+                2
+            );
+            let errors = value.getattr("errors").unwrap();
+            let error = errors.get_item(0).unwrap();
+            assert_eq!(
+                error.get_item("code").unwrap().extract::<String>().unwrap(),
+                "E0101"
+            );
+            assert!(err.to_string().contains("E0101"), "{}", err);
+        })
+    }
+}
@@ -0,0 +1,54 @@
+//! JavaScript bindings built with the `wasm` feature, for running the front
+//! end and code generation in a browser, as by
+//! `wasm-pack build -- --no-default-features --features wasm`. Each function
+//! throws an `Error` whose message is the JSON of the errors,
+//! `{"errors": [...]}`, as the C interface in [`crate::ffi`] gives them.
+
+use wasm_bindgen::prelude::*;
+
+use crate::ffi;
+
+/// The tokens of `source` as JSON, `{"tokens": [...]}` with the token, line
+/// and column of each.
+#[wasm_bindgen]
+pub fn tokenize(source: &str) -> Result<String, JsError> {
+    ffi::tokenize_json(source).map_err(|json| JsError::new(&json))
+}
+
+/// The syntax tree of `source` as JSON, `{"ast": "..."}` with the tree as
+/// `rcc --emit ast` writes it.
+#[wasm_bindgen]
+pub fn parse(source: &str) -> Result<String, JsError> {
+    ffi::parse_json(source).map_err(|json| JsError::new(&json))
+}
+
+/// The assembly for `source`, for x86-64 Linux.
+#[wasm_bindgen(js_name = compileToAssembly)]
+pub fn compile_to_assembly(source: &str) -> Result<String, JsError> {
+    ffi::assembly(source).map_err(|json| JsError::new(&json))
+}
+
+// Errors need a JavaScript engine, so only success is tested natively
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn tokenize() {
+        assert_eq!(
+            super::tokenize("int main").unwrap(),
+            "{\"tokens\": [{\"token\": \"IntKw\", \"line\": 1, \"column\": 1}, \
+             {\"token\": \"Identifier(\\\"main\\\")\", \"line\": 1, \"column\": 5}]}"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let ast = super::parse("int main() { return 1; }").unwrap();
+        assert!(ast.starts_with("{\"ast\": \"Program(\\n"), "{}", ast);
+    }
+
+    #[test]
+    fn compile_to_assembly() {
+        let assembly = super::compile_to_assembly("int main() { return 1; }").unwrap();
+        assert!(assembly.contains("main:"), "{}", assembly);
+    }
+}
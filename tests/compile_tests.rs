@@ -1,12 +1,23 @@
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
 
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use tempfile::NamedTempFile;
 
 extern crate rcc;
-use rcc::compiler::{self, config::Config, error::SyntaxError};
+use rcc::compiler::{
+    self,
+    config::{Config, Emit, Preprocessor, StackProtector},
+    error::{CompilerError, SyntaxError},
+    target::Target,
+    test_runner::{self, expectation, Expectation},
+};
+
+mod generator;
 
 const VALID_TEST_DIR: &str = "tests/testfiles/valid";
 const INVALID_TEST_DIR: &str = "tests/testfiles/invalid";
@@ -15,109 +26,1496 @@ fn execute(file: &Path) -> io::Result<Output> {
     Command::new(file.to_str().expect("Failed to convert path to string")).output()
 }
 
-fn compile_and_execute_gcc(file: &PathBuf) -> io::Result<Output> {
-    let temp_file = NamedTempFile::new()?;
+fn compile_and_execute_gcc(file: &Path) -> io::Result<Output> {
+    // Close the file handle so the executable isn't busy when it's run
+    let temp_path = NamedTempFile::new()?.into_temp_path();
     Command::new("gcc")
         .arg(file.to_str().expect("Failed to convert path to string"))
-        .args(&["-o", &temp_file.path().to_str().expect("Bad path")])
+        .args(["-o", temp_path.to_str().expect("Bad path")])
         .output()?;
-    let output = execute(temp_file.path());
-    fs::remove_file(temp_file)?;
+    let output = execute(&temp_path);
+    temp_path.close()?;
     output
 }
 
-macro_rules! file_compilation_tests {
-    ($($name:ident: $test_file:expr,)*) => {
-        $(
-            #[test]
-            fn $name() {
-                let mut path = PathBuf::from(VALID_TEST_DIR);
-                path.push($test_file);
-                let config = Config{ filename: path.clone() };
+/// Whether `program` is installed, such as a compiler to compare against.
+fn installed(program: &str) -> bool {
+    Command::new(program).arg("--version").output().is_ok()
+}
 
-                compiler::compile(&config).expect("Compilation failed");
+/// Skip the rest of a test, saying why, when there's no C compiler driver for
+/// rcc to preprocess and link with, as on minimal containers.
+macro_rules! require_system_compiler {
+    () => {
+        if !["gcc", "clang", "cc"].iter().any(|cc| installed(cc)) {
+            eprintln!("Skipping: no system compiler found; install gcc or clang");
+            return;
+        }
+    };
+}
 
-                let mut exe_path = PathBuf::from(&path);
-                exe_path.set_extension("");
-                let actual = execute(&exe_path).expect("Failed to execute rcc exe");
-                fs::remove_file(exe_path.to_str().expect("Bad path")).expect("Failed to remove exe file");
+/// The exit status of the test program at `path` when compiled by gcc, or
+/// if gcc isn't installed, from its `expect-exit` annotation.
+fn expected_status(path: &Path) -> i32 {
+    if installed("gcc") {
+        let output = compile_and_execute_gcc(path).expect("Failed to execute gcc exe");
+        return output.status.code().expect("gcc exe killed by a signal");
+    }
+    let source = fs::read_to_string(path).expect("Failed to read test file");
+    match expectation(&source) {
+        Some(Expectation::Exit(status)) => status,
+        _ => panic!(
+            "gcc not found, and {} has no expect-exit annotation",
+            path.display()
+        ),
+    }
+}
 
-                let expected = compile_and_execute_gcc(&path).expect("Failed to execute gcc exe");
+/// Whether the test program at `path` is annotated `expect-skip`, as using
+/// something the compiler doesn't support yet.
+fn skipped(path: &Path) -> bool {
+    let source = fs::read_to_string(path).expect("Failed to read test file");
+    matches!(expectation(&source), Some(Expectation::Skip(_)))
+}
 
-                assert_eq!(actual, expected);
-            }
-        )*
-    }
-}
-
-file_compilation_tests! {
-    abundant_spaces: "abundant_spaces.c",
-    add: "add.c",
-    associativity_div: "associativity_div.c",
-    associativity: "associativity.c",
-    bitwise_zero: "bitwise_zero.c",
-    bitwise: "bitwise.c",
-    div_neg: "div_neg.c",
-    div: "div.c",
-    many_newlines: "many_newlines.c",
-    minimal_whitespace: "minimal_whitespace.c",
-    mult: "mult.c",
-    multi_digit: "multi_digit.c",
-    neg: "neg.c",
-    nested_ops_2: "nested_ops_2.c",
-    nested_ops: "nested_ops.c",
-    not_0: "not_0.c",
-    not_5: "not_5.c",
-    parens: "parens.c",
-    precedence: "precedence.c",
-    return_0: "return_0.c",
-    return_2: "return_2.c",
-    sub_neg: "sub_neg.c",
-    sub: "sub.c",
-    unop_add: "unop_add.c",
-    unop_parens: "unop_parens.c",
-}
-
-macro_rules! assert_raises_syntax_error {
-    ($left:expr, $err:expr) => {
+/// What running a program should do. Only its exit status is checked,
+/// unless a test also declares what it should print.
+#[derive(Debug)]
+struct Expected {
+    status: i32,
+    stdout: Option<String>,
+}
+
+impl Expected {
+    fn status(status: i32) -> Self {
+        Expected {
+            status,
+            stdout: None,
+        }
+    }
+
+    /// As the test program at `path` exits when compiled by gcc, or if gcc
+    /// isn't installed, as its `expect-exit` annotation says.
+    fn test_file(path: &Path) -> Self {
+        Expected::status(expected_status(path))
+    }
+
+    fn stdout(self, stdout: &str) -> Self {
+        Expected {
+            stdout: Some(String::from(stdout)),
+            ..self
+        }
+    }
+}
+
+/// Check a program's output is as expected, describing it by `context`.
+fn assert_output(actual: &Output, expected: &Expected, context: &str) {
+    assert_eq!(
+        actual.status.code(),
+        Some(expected.status),
+        "{}: {}",
+        context,
+        actual.status
+    );
+    if let Some(stdout) = &expected.stdout {
         assert_eq!(
-            *$left.err().unwrap().downcast::<SyntaxError>().unwrap(),
-            $err
+            String::from_utf8_lossy(&actual.stdout),
+            *stdout,
+            "{}",
+            context
         );
+    }
+}
+
+/// Run the executable `exe` and check it behaves as expected.
+fn assert_runs(exe: &Path, expected: &Expected, context: &str) {
+    let actual = execute(exe).expect("Failed to execute rcc exe");
+    assert_output(&actual, expected, context);
+}
+
+#[test]
+fn stack_protector_all() {
+    require_system_compiler!();
+    let mut path = PathBuf::from(VALID_TEST_DIR);
+    path.push("nested_ops.c");
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("nested_ops.c");
+    fs::copy(&path, &source).expect("Failed to copy test file");
+    let config = Config {
+        filenames: vec![source],
+        stack_protector: StackProtector::All,
+        ..Default::default()
     };
+
+    let output = compiler::compile(&config).expect("Compilation failed");
+
+    let exe_path = output.executable.expect("No executable linked");
+    assert_runs(&exe_path, &Expected::test_file(&path), "nested_ops.c");
 }
 
-macro_rules! file_error_tests {
-    ($($name:ident: ($test_file:expr, $err:expr),)*) => {
-        $(
-            #[test]
-            fn $name() {
-                let mut path = PathBuf::from(INVALID_TEST_DIR);
-                path.push($test_file);
-                let config = Config{ filename: path.clone() };
+#[test]
+fn integrated_assembler_matches_gcc() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    for entry in fs::read_dir(VALID_TEST_DIR).expect("Failed to read test dir") {
+        let path = entry.expect("Failed to read test file").path();
+        if skipped(&path) {
+            continue;
+        }
+        let source = dir.path().join(path.file_name().unwrap());
+        fs::copy(&path, &source).expect("Failed to copy test file");
+        let exe = source.with_extension("");
+        let config = Config {
+            filenames: vec![source],
+            output: Some(exe.clone()),
+            stack_protector: StackProtector::All,
+            integrated_as: true,
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Integrated assembler failed");
+
+        let context = path.display().to_string();
+        assert_runs(&exe, &Expected::test_file(&path), &context);
+    }
+}
+
+#[test]
+fn integrated_assembler_writes_macho() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 2 * 3;\n}\n").expect("Failed to write source");
+    let object = dir.path().join("prog.o");
+    let config = Config {
+        filenames: vec![source],
+        output: Some(object.clone()),
+        target: Target::parse("x86_64-apple-darwin").unwrap(),
+        preprocessor: Preprocessor::Off,
+        emit: vec![Emit::Object],
+        integrated_as: true,
+        ..Default::default()
+    };
+    compiler::compile(&config).expect("Compilation failed");
+    let bytes = fs::read(&object).expect("Failed to read object");
+    assert_eq!(bytes[..4], [0xcf, 0xfa, 0xed, 0xfe]);
+    assert!(String::from_utf8_lossy(&bytes).contains("_main"));
+}
+
+#[test]
+fn llvm_ir_matches_gcc() {
+    require_system_compiler!();
+    // lli is optional; there's nothing to compare against without it
+    if !installed("lli") {
+        eprintln!("Skipping: lli not found; install LLVM");
+        return;
+    }
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    for entry in fs::read_dir(VALID_TEST_DIR).expect("Failed to read test dir") {
+        let path = entry.expect("Failed to read test file").path();
+        if skipped(&path) {
+            continue;
+        }
+        let config = Config {
+            filenames: vec![path.clone()],
+            emit: vec![Emit::LlvmIr],
+            out_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Compilation failed");
+
+        let ir_path = dir
+            .path()
+            .join(path.with_extension("ll").file_name().unwrap());
+        let actual = Command::new("lli")
+            .arg(&ir_path)
+            .output()
+            .expect("Failed to run lli");
+
+        let context = path.display().to_string();
+        assert_output(&actual, &Expected::test_file(&path), &context);
+    }
+}
+
+#[test]
+fn interpreter_matches_gcc() {
+    require_system_compiler!();
+    for entry in fs::read_dir(VALID_TEST_DIR).expect("Failed to read test dir") {
+        let path = entry.expect("Failed to read test file").path();
+        let config = Config {
+            filenames: vec![path.clone()],
+            interpret: true,
+            ..Default::default()
+        };
+        // Not every test file is supported by the compiler yet
+        let value = match compiler::interpret(&config) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+
+        assert_eq!(value & 0xff, expected_status(&path), "{}", path.display());
+    }
+}
+
+/// Compile random programs and check each exits with the value `main`
+/// returns, agreeing with the interpreter and with gcc if it's installed.
+/// `RCC_SEED` picks a different set of programs.
+#[test]
+fn random_programs() {
+    require_system_compiler!();
+    let seed = std::env::var("RCC_SEED").map_or(0, |seed| seed.parse().expect("Invalid RCC_SEED"));
+    let mut rng = StdRng::seed_from_u64(seed);
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("random.c");
+    let exe = dir.path().join("random");
+    let has_gcc = installed("gcc");
+    for _ in 0..20 {
+        let (program, value) = generator::program(&mut rng);
+        fs::write(&source, &program).expect("Failed to write source");
+        let config = Config::builder()
+            .input(&source)
+            .output(&exe)
+            .build()
+            .expect("Invalid config");
+        let context = format!("seed {}:\n{}", seed, program);
+
+        compiler::compile(&config).expect(&context);
+        assert_runs(&exe, &Expected::status(value & 0xff), &context);
+
+        let interpreted = compiler::interpret(&config).expect(&context);
+        assert_eq!(interpreted, value, "{}", context);
+
+        if has_gcc {
+            let gcc = compile_and_execute_gcc(&source).expect("Failed to execute gcc exe");
+            assert_output(&gcc, &Expected::status(value & 0xff), &context);
+        }
+    }
+}
+
+#[test]
+fn link_multiple_files() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let main = dir.path().join("main.c");
+    let foo = dir.path().join("foo.c");
+    let exe = dir.path().join("prog");
+    fs::write(&main, "int main() { return 3; }").expect("Failed to write main.c");
+    fs::write(&foo, "int foo() { return 4; }").expect("Failed to write foo.c");
+    let config = Config::builder()
+        .input(&main)
+        .input(&foo)
+        .output(&exe)
+        .build()
+        .expect("Invalid config");
+
+    compiler::compile(&config).expect("Compilation failed");
+
+    assert_runs(&exe, &Expected::status(3), "prog");
+    // Only the executable is left behind
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+
+    let config = Config::builder()
+        .input(&main)
+        .input(&main)
+        .output(&exe)
+        .build()
+        .expect("Invalid config");
+    let err = compiler::compile(&config).unwrap_err().to_string();
+    assert!(err.starts_with("Duplicate symbol `main`"), "{}", err);
+}
+
+#[test]
+fn directory_and_wildcard_inputs() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let src = dir.path().join("src");
+    fs::create_dir(&src).expect("Failed to create src dir");
+    fs::write(src.join("main.c"), "int main() { return 3; }").expect("Failed to write main.c");
+    fs::write(src.join("foo.c"), "int foo() { return 4; }").expect("Failed to write foo.c");
+    let exe = dir.path().join("prog");
+    let rcc = |inputs: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("--no-cache")
+            .args(inputs)
+            .arg("-o")
+            .arg(&exe)
+            .output()
+            .expect("Failed to run rcc")
+    };
+
+    assert!(rcc(&["--dir", src.to_str().unwrap()]).status.success());
+    assert_runs(&exe, &Expected::status(3), "--dir");
+    fs::remove_file(&exe).expect("Failed to remove exe");
+    // As if quoted, so the shell doesn't expand it
+    let pattern = src.join("*.c");
+    assert!(rcc(&[pattern.to_str().unwrap()]).status.success());
+    assert_runs(&exe, &Expected::status(3), "*.c");
+
+    // Errors after parsing say which file they're in
+    fs::write(src.join("asm.c"), "int bar() { asm(\"hlt 1\"); return 0; }")
+        .expect("Failed to write asm.c");
+    let output = rcc(&["-fintegrated-as", "--dir", src.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("{}: ", src.join("asm.c").display())),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn emit_multiple_outputs() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let config = Config {
+        filenames: vec![source.clone()],
+        emit: vec![Emit::Ast, Emit::Assembly, Emit::Object, Emit::Executable],
+        ..Default::default()
+    };
+
+    let output = compiler::compile(&config).expect("Compilation failed");
+
+    let ast = fs::read_to_string(dir.path().join("prog.ast")).expect("No AST written");
+    assert!(ast.starts_with("Program("), "{}", ast);
+    assert_eq!(output.assembly, [dir.path().join("prog.s")]);
+    assert!(output.assembly[0].is_file());
+    assert!(dir.path().join("prog.o").is_file());
+    assert!(output.diagnostics.is_empty());
+    assert!(output.timings.codegen.time > Duration::ZERO);
+    assert!(output.timings.link.time > Duration::ZERO);
+    let exe = output.executable.expect("No executable linked");
+    assert_eq!(exe, dir.path().join("prog"));
+    assert_runs(&exe, &Expected::status(3), "prog");
+    assert!(!dir.path().join("prog.tokens").exists());
+}
+
+#[test]
+fn emit_tokens_to_stdout() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 3;\n}").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--emit", "tokens", "-o", "-", source.to_str().unwrap()])
+        .output()
+        .expect("Failed to run rcc");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("1:1: IntKw\n"), "{}", stdout);
+    assert!(stdout.contains("2:13: Semicolon\n"), "{}", stdout);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    // -o names a single output
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--emit", "tokens,ast", "-o", "-", source.to_str().unwrap()])
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn emit_semantic_tokens() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "// two\nint main() { return 2; }").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--emit", "semantic-tokens", "--no-preprocess"])
+        .arg(&source)
+        .output()
+        .expect("Failed to run rcc");
+
+    assert!(output.status.success());
+    let tokens = fs::read_to_string(dir.path().join("prog.semtok")).expect("No tokens written");
+    let lines: Vec<&str> = tokens.lines().collect();
+    assert_eq!(lines.len(), 10, "{}", tokens);
+    assert_eq!(
+        lines[..3],
+        ["1:1: comment 6", "2:1: keyword 3", "2:5: identifier 4"]
+    );
+    assert_eq!(lines[7], "2:21: literal 1");
+}
+
+#[test]
+fn fmt_subcommand() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main(){return 2*(1+0x3) ;} // six\n").expect("Failed to write source");
+    let fmt = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("fmt")
+            .arg("--no-cache")
+            .args(args)
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc")
+    };
+
+    let output = fmt(&["--check"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", source.display())
+    );
+
+    assert!(fmt(&[]).status.success());
+    assert_eq!(
+        fs::read_to_string(&source).unwrap(),
+        "int main() {\n    return 2 * (1 + 0x3);\n} // six\n"
+    );
+    let output = fmt(&["--check"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn time_passes() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--time-passes", "--no-preprocess", "-S"])
+        .arg(&source)
+        .output()
+        .expect("Failed to run rcc");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stderr.lines().collect();
+    let stages: Vec<&str> = lines
+        .iter()
+        .filter_map(|l| l.split_whitespace().next())
+        .collect();
+    assert_eq!(
+        stages,
+        [
+            "stage",
+            "preprocess",
+            "lex",
+            "parse",
+            "codegen",
+            "assemble",
+            "link",
+            "total"
+        ],
+        "{}",
+        stderr
+    );
+    // rcc counts allocations, so every stage has a peak
+    assert!(lines[2].ends_with(" KiB"), "{}", stderr);
+    assert!(lines[7].ends_with(" KiB"), "{}", stderr);
+}
+
+#[test]
+fn inline_asm_statements() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(
+        &source,
+        "int main() {\n    asm(\"nop\");\n    __asm__ __volatile__(\"nop\\n\\tnop\");\n    return 4;\n}\n",
+    )
+    .expect("Failed to write source");
+    let exe = dir.path().join("prog");
+    for integrated_as in [false, true] {
+        let config = Config {
+            filenames: vec![source.clone()],
+            output: Some(exe.clone()),
+            integrated_as,
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Compilation failed");
+        assert_runs(&exe, &Expected::status(4), "inline asm");
+    }
+}
+
+#[test]
+fn checked_division() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let cases = [
+        (
+            "int main() {\n    return 2 / (1 - 1);\n}\n",
+            "division by zero",
+        ),
+        (
+            "int main() {\n    return (-2147483647 - 1) / -1;\n}\n",
+            "division overflow",
+        ),
+    ];
+    for (i, (program, message)) in cases.iter().enumerate() {
+        let source = dir.path().join(format!("prog{}.c", i));
+        fs::write(&source, program).expect("Failed to write source");
+        let exe = dir.path().join(format!("prog{}", i));
+        let config = Config {
+            filenames: vec![source],
+            output: Some(exe.clone()),
+            check_div: true,
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Compilation failed");
+        let output = execute(&exe).expect("Failed to execute rcc exe");
+        assert_eq!(output.status.code(), None, "{}", message);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            format!("rcc: {}\n", message)
+        );
+    }
+}
+
+/// Whether the C library can be linked statically, which some
+/// distributions package separately.
+fn static_libc_installed() -> bool {
+    Command::new("cc")
+        .arg("-print-file-name=libc.a")
+        .output()
+        .map(|output| Path::new(String::from_utf8_lossy(&output.stdout).trim()).is_absolute())
+        .unwrap_or(false)
+}
+
+/// Whether the ELF executable at `path` has a PT_INTERP program header,
+/// naming the dynamic linker that loads it.
+fn has_interpreter(path: &Path) -> bool {
+    const PT_INTERP: u32 = 3;
+    let bytes = fs::read(path).expect("Failed to read executable");
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let mut phoff = [0; 8];
+    phoff.copy_from_slice(&bytes[32..40]);
+    let phoff = u64::from_le_bytes(phoff) as usize;
+    let (size, count) = (u16_at(54) as usize, u16_at(56) as usize);
+    (0..count).any(|i| {
+        let header = phoff + i * size;
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&bytes[header..header + 4]);
+        u32::from_le_bytes(kind) == PT_INTERP
+    })
+}
+
+#[test]
+fn static_linking() {
+    require_system_compiler!();
+    if !static_libc_installed() {
+        eprintln!("Skipping: no static C library found");
+        return;
+    }
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 7;\n}\n").expect("Failed to write source");
+    let exe = dir.path().join("prog");
+    // Both linking with ld directly and through the compiler driver
+    for cc in [None, Some(String::from("cc"))] {
+        let mut config = Config {
+            filenames: vec![source.clone()],
+            output: Some(exe.clone()),
+            cc: cc.clone(),
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Compilation failed");
+        assert!(has_interpreter(&exe), "{:?}", cc);
+
+        config.static_link = true;
+        compiler::compile(&config).expect("Static compilation failed");
+        assert!(!has_interpreter(&exe), "{:?}", cc);
+        assert_runs(&exe, &Expected::status(7), "static prog");
+    }
+}
+
+#[test]
+fn strip_and_link_for_size() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 8;\n}\n").expect("Failed to write source");
+    let exe = dir.path().join("prog");
+    let size = |strip, link_for_size| {
+        let config = Config {
+            filenames: vec![source.clone()],
+            output: Some(exe.clone()),
+            strip,
+            link_for_size,
+            ..Default::default()
+        };
+        compiler::compile(&config).expect("Compilation failed");
+        assert_runs(&exe, &Expected::status(8), "prog");
+        fs::metadata(&exe).expect("No executable").len()
+    };
+    let unstripped = size(false, false);
+    assert!(size(true, false) < unstripped);
+    assert!(size(true, true) < unstripped);
+}
+
+#[test]
+fn source_directory_untouched() {
+    require_system_compiler!();
+    let src = tempfile::tempdir().expect("Failed to create temp dir");
+    let out = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = src.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let config = Config::builder()
+        .input(source)
+        .output(out.path().join("prog"))
+        .build()
+        .expect("Invalid config");
+
+    compiler::compile(&config).expect("Compilation failed");
+
+    assert_eq!(fs::read_dir(src.path()).unwrap().count(), 1);
+    assert_eq!(fs::read_dir(out.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn keep_intermediates() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let config = Config {
+        filenames: vec![source],
+        keep_intermediates: true,
+        ..Default::default()
+    };
+
+    compiler::compile(&config).expect("Compilation failed");
+
+    for name in &["prog", "prog.s", "prog.o"] {
+        assert!(dir.path().join(name).is_file(), "{} is missing", name);
+    }
+}
+
+#[test]
+fn out_dir() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let out_dir = dir.path().join("build/debug");
+    let config = Config {
+        filenames: vec![source],
+        keep_intermediates: true,
+        out_dir: Some(out_dir.clone()),
+        ..Default::default()
+    };
+
+    compiler::compile(&config).expect("Compilation failed");
+
+    for name in &["prog", "prog.s", "prog.o"] {
+        assert!(out_dir.join(name).is_file(), "{} is missing", name);
+        assert!(
+            !dir.path().join(name).exists(),
+            "{} is in the source tree",
+            name
+        );
+    }
+    assert_runs(&out_dir.join("prog"), &Expected::status(3), "prog");
+}
+
+#[test]
+fn assembly_source_map() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 2 * 3;\n}\n").expect("Failed to write source");
+    let status = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--no-preprocess", "--emit", "asm,asm-map"])
+        .arg(&source)
+        .status()
+        .expect("Failed to run rcc");
+    assert!(status.success());
+
+    let assembly = fs::read_to_string(dir.path().join("prog.s")).expect("No assembly");
+    let map = fs::read_to_string(dir.path().join("prog.asm.json")).expect("No map");
+    assert!(!assembly.contains('\0'));
+    let lines: Vec<&str> = assembly.lines().collect();
+    let start = lines.iter().position(|line| line.contains("movl")).unwrap() + 1;
+    let end = lines.iter().position(|line| line.contains("ret")).unwrap() + 1;
+    assert_eq!(
+        map.trim_end(),
+        format!(
+            "{{\"mappings\": [{{\"start\": {}, \"end\": {}, \"line\": 2, \"column\": 5}}]}}",
+            start, end
+        )
+    );
+}
 
-                assert_raises_syntax_error!(
-                    compiler::compile(&config),
-                    $err
-                );
+#[test]
+fn compile_from_stdin() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let exe = dir.path().join("prog");
+    let mut rcc = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["-", "-o"])
+        .arg(&exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to run rcc");
+    rcc.stdin
+        .take()
+        .unwrap()
+        .write_all(b"int main() { return 5; }")
+        .expect("Failed to write source");
+    assert!(rcc.wait().expect("Failed to wait for rcc").success());
+
+    assert_runs(&exe, &Expected::status(5), "prog");
+}
+
+#[test]
+fn preprocess_includes_and_macros() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    let exe = dir.path().join("prog");
+    fs::write(dir.path().join("value.h"), "#define VALUE 4\n").expect("Failed to write header");
+    fs::write(
+        &source,
+        "#include \"value.h\"\n/* The answer */\nint main() { return VALUE + 1; }\n",
+    )
+    .expect("Failed to write source");
+    let config = Config {
+        filenames: vec![source.clone()],
+        output: Some(exe.clone()),
+        ..Default::default()
+    };
+
+    compiler::compile(&config).expect("Compilation failed");
+    assert_runs(&exe, &Expected::status(5), "prog");
+
+    let config = Config {
+        preprocessor: Preprocessor::Off,
+        ..config
+    };
+    assert!(compiler::compile(&config).is_err());
+}
+
+#[test]
+fn integrated_preprocessor_includes() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let include = dir.path().join("include");
+    fs::create_dir(&include).expect("Failed to create include dir");
+    fs::write(include.join("body.h"), "return 6; // six\n").expect("Failed to write header");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n#include <body.h>\n}\n").expect("Failed to write source");
+    let exe = dir.path().join("prog");
+    let config = Config {
+        filenames: vec![source],
+        output: Some(exe.clone()),
+        preprocessor: Preprocessor::Integrated,
+        include_dirs: vec![include],
+        ..Default::default()
+    };
+
+    compiler::compile(&config).expect("Compilation failed");
+    assert_runs(&exe, &Expected::status(6), "prog");
+}
+
+#[test]
+fn run_subcommand() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 7; }").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .args([
+            "run",
+            "--no-cache",
+            source.to_str().unwrap(),
+            "--",
+            "--help",
+        ])
+        .output()
+        .expect("Failed to run rcc");
+
+    assert_output(&output, &Expected::status(7).stdout(""), "rcc run");
+    assert!(output.stderr.is_empty());
+    // Nothing is left next to the source
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn repl_prints_values() {
+    require_system_compiler!();
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("repl")
+        .arg("--no-cache")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to run rcc");
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(b"2 * 300\nreturn -5;\n1 +\n\n~0\n:quit\n42\n")
+        .expect("Failed to write input");
+    let output = repl.wait_with_output().expect("Failed to wait for rcc");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let values: Vec<&str> = stdout
+        .split("> ")
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .collect();
+    assert_eq!(values, ["600", "-5", "-1"]);
+    // The error doesn't stop the loop
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Syntax Error"));
+}
+
+#[test]
+fn interpret_flag() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 6 * 7; }").expect("Failed to write source");
+    let rcc = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .args(args)
+            .arg("--no-cache")
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc")
+    };
+    assert_eq!(rcc(&["--interpret"]).status.code(), Some(42));
+    assert_eq!(rcc(&["run", "--interpret"]).status.code(), Some(42));
+    // Nothing was compiled
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    fs::write(&source, "int main() { return 1 / 0; }").expect("Failed to write source");
+    let output = rcc(&["--interpret"]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Division by zero"));
+}
+
+#[test]
+fn response_files() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("my prog.c");
+    fs::write(&source, "int main() { return 2 + 3; }").expect("Failed to write source");
+    let args = dir.path().join("args.txt");
+    let contents = format!("--interpret\n--no-preprocess\n\"{}\"\n", source.display());
+    fs::write(&args, contents).expect("Failed to write response file");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .arg(format!("@{}", args.display()))
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(5));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .arg(format!("@{}", dir.path().join("missing.txt").display()))
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot read response file"));
+}
+
+#[test]
+fn watch_recompiles_on_change() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    let exe = dir.path().join("prog");
+    fs::write(&source, "int main() { return 1; }").expect("Failed to write source");
+    let mut rcc = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["--watch", "-q", source.to_str().unwrap(), "-o"])
+        .arg(&exe)
+        .spawn()
+        .expect("Failed to run rcc");
+
+    // Wait for the executable to return the expected status
+    let wait_for = |status| {
+        for _ in 0..100 {
+            if execute(&exe).ok().and_then(|output| output.status.code()) == Some(status) {
+                return true;
             }
-        )*
-    }
-}
-
-file_error_tests! {
-    malformed_paren: ("malformed_paren.c", SyntaxError::MissingSemicolon),
-    missing_closing_brace: ("missing_closing_brace.c", SyntaxError::MissingCloseBrace),
-    missing_const: ("missing_const.c", SyntaxError::InvalidFactor),
-    missing_first_op: ("missing_first_op.c", SyntaxError::InvalidFactor),
-    missing_paren: ("missing_paren.c", SyntaxError::MissingCloseParen),
-    missing_return_space: ("missing_return_space.c", SyntaxError::UnexpectedToken),
-    missing_return_val: ("missing_return_val.c", SyntaxError::InvalidFactor),
-    missing_second_op: ("missing_second_op.c", SyntaxError::InvalidFactor),
-    missing_semicolon: ("missing_semicolon.c", SyntaxError::MissingSemicolon),
-    missing_semicolon_2: ("missing_semicolon_2.c", SyntaxError::MissingSemicolon),
-    nested_missing_const: ("nested_missing_const.c", SyntaxError::InvalidFactor),
-    no_semicolon: ("no_semicolon.c", SyntaxError::MissingSemicolon),
-    wrong_return_case: ("wrong_return_case.c", SyntaxError::UnexpectedToken),
-    wrong_unary_order: ("wrong_unary_order.c", SyntaxError::InvalidFactor),
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+    let first = wait_for(1);
+    fs::write(&source, "int main() { return 2; }").expect("Failed to write source");
+    let second = wait_for(2);
+    rcc.kill().expect("Failed to stop rcc");
+    rcc.wait().expect("Failed to wait for rcc");
+
+    assert!(first, "The initial compilation didn't happen");
+    assert!(second, "The change wasn't recompiled");
+}
+
+#[test]
+fn dry_run_prints_commands() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .args(["-###", source.to_str().unwrap()])
+        .output()
+        .expect("Failed to run rcc");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let commands: Vec<&str> = stderr.lines().collect();
+    assert_eq!(commands.len(), 2, "{}", stderr);
+    assert!(commands[0].starts_with("as "), "{}", stderr);
+    assert!(commands[1].contains(" -o "), "{}", stderr);
+    // Nothing was built
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn verbose_output() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let run = |flag: &str| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("--no-cache")
+            .args([flag, source.to_str().unwrap()])
+            .output()
+            .expect("Failed to run rcc")
+    };
+
+    let output = run("-v");
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for stage in &[
+        "preprocess",
+        "lex",
+        "parse",
+        "codegen",
+        "assemble",
+        "link",
+        "running",
+        "bytes",
+    ] {
+        assert!(stderr.contains(stage), "{} missing from {}", stage, stderr);
+    }
+
+    // Quiet by default
+    let output = run("-q");
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+    let output = run("-g");
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+}
+
+#[test]
+fn debug_logging() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let run = |flag: &str, rcc_log: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("--no-cache")
+            .args([flag, source.to_str().unwrap()])
+            .env("RCC_LOG", rcc_log)
+            .output()
+            .expect("Failed to run rcc");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = run("-vv", "");
+    for expected in &[
+        "DEBUG",
+        "stage{name=\"link\"}",
+        "running command=",
+        "status=",
+    ] {
+        assert!(
+            stderr.contains(expected),
+            "{} missing from {}",
+            expected,
+            stderr
+        );
+    }
+    // RCC_LOG logs without -v's report
+    let stderr = run("-g", "rcc::compiler::toolchain=debug");
+    assert!(stderr.contains("running command="), "{}", stderr);
+    assert!(!stderr.contains("INFO") && !stderr.contains("rcc: link"));
+}
+
+#[test]
+fn cached_objects_are_reused() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    let cache_dir = dir.path().join("cache");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let compile = |flags: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("-v")
+            .arg("--cache-dir")
+            .arg(&cache_dir)
+            .args(flags)
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc");
+        assert!(output.status.success());
+        assert_eq!(
+            execute(&dir.path().join("prog")).unwrap().status.code(),
+            Some(3)
+        );
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = compile(&[]);
+    assert!(stderr.contains("codegen"), "{}", stderr);
+    assert!(cache_dir.is_dir());
+    let stderr = compile(&[]);
+    assert!(stderr.contains("using cached object"), "{}", stderr);
+    assert!(!stderr.contains("codegen"), "{}", stderr);
+    assert!(!stderr.contains("assemble"), "{}", stderr);
+
+    // Different options or sources miss the cache
+    let stderr = compile(&["-g"]);
+    assert!(stderr.contains("codegen"), "{}", stderr);
+    let stderr = compile(&["--no-cache"]);
+    assert!(stderr.contains("codegen"), "{}", stderr);
+    fs::write(&source, "int main() { return 3 + 0; }").expect("Failed to write source");
+    let stderr = compile(&[]);
+    assert!(stderr.contains("codegen"), "{}", stderr);
+}
+
+#[test]
+fn missing_toolchain() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let compile = |flags: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .env("PATH", dir.path())
+            .arg("--no-cache")
+            .args(flags)
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc");
+        assert!(!output.status.success());
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = compile(&[]);
+    assert!(
+        stderr.contains("No system compiler found to preprocess with"),
+        "{}",
+        stderr
+    );
+    let stderr = compile(&["--no-preprocess"]);
+    assert!(
+        stderr.contains("No system compiler found; install gcc or clang, or pass --cc"),
+        "{}",
+        stderr
+    );
+    let stderr = compile(&["--no-preprocess", "--assembler", "no-such-as"]);
+    assert!(stderr.contains("no-such-as not found"), "{}", stderr);
+}
+
+#[test]
+fn errors_show_source_line() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    return 1 + ;\n}\n").expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .arg(&source)
+        .output()
+        .expect("Failed to run rcc");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let expected = format!(
+        "{}:2:16: Syntax Error [E0111]: Invalid factor\n 2 |     return 1 + ;\n   |                ^\n",
+        source.display()
+    );
+    assert!(stderr.ends_with(&expected), "{}", stderr);
+}
+
+#[test]
+fn errors_in_every_file_reported() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let first = dir.path().join("first.c");
+    let second = dir.path().join("second.c");
+    fs::write(&first, "int $a() { return $b; }").expect("Failed to write source");
+    fs::write(&second, "int main() { return 1 }").expect("Failed to write source");
+    let rcc = |flags: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("--no-cache")
+            .args(flags)
+            .arg(&first)
+            .arg(&second)
+            .output()
+            .expect("Failed to run rcc")
+    };
+
+    let output = rcc(&[]);
+    // The first error decides the exit status
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("Compilation error: ").count(),
+        3,
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Invalid identifier: $a"), "{}", stderr);
+    assert!(stderr.contains("Invalid identifier: $b"), "{}", stderr);
+    assert!(stderr.contains("Expected semicolon"), "{}", stderr);
+    assert!(!dir.path().join("first").exists());
+
+    let output = rcc(&["--error-limit", "1"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("Compilation error: ").count(),
+        1,
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.ends_with("2 more errors not shown; see --error-limit\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn break_outside_loop() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() {\n    if (1)\n        break;\n}\n")
+        .expect("Failed to write source");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .arg(&source)
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let expected = format!(
+        "{}:3:9: Semantic Error [E0200]: break statement not within a loop\n 3 |         break;\n   |         ^~~~~\n",
+        source.display()
+    );
+    assert!(stderr.contains(&expected), "{}", stderr);
+}
+
+/// Deeply nested statements are rejected before generating code or
+/// interpreting them could overflow the stack of a debug build.
+#[test]
+fn deeply_nested_statements() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    let output = dir.path().join("prog.s");
+    let rcc = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .args(args)
+            .args(["--no-cache", "--no-preprocess"])
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc")
+    };
+    let nested = |depth: usize| {
+        [
+            format!("{}return 3;{}", "{".repeat(depth), "}".repeat(depth)),
+            format!("{}return 3;", "if (1) ".repeat(depth)),
+            format!(
+                "{}return 3;{}",
+                "do ".repeat(depth),
+                " while (0);".repeat(depth)
+            ),
+        ]
+    };
+    for body in nested(1000) {
+        fs::write(&source, format!("int main() {{\n{}\n}}\n", body))
+            .expect("Failed to write source");
+        for args in [
+            &["-S", "-o", output.to_str().unwrap()][..],
+            &["--interpret"],
+        ] {
+            let result = rcc(args);
+            assert_eq!(result.status.code(), Some(3), "{:?}", args);
+            let stderr = String::from_utf8_lossy(&result.stderr);
+            assert!(stderr.contains("[E0113]"), "{}", stderr);
+        }
+    }
+    for body in nested(250) {
+        fs::write(&source, format!("int main() {{\n{}\n}}\n", body))
+            .expect("Failed to write source");
+        let result = rcc(&["-S", "-o", output.to_str().unwrap()]);
+        assert!(
+            result.status.success(),
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        assert_eq!(rcc(&["--interpret"]).status.code(), Some(3));
+    }
+}
+
+#[test]
+fn exit_codes() {
+    require_system_compiler!();
+    let rcc = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .arg("--no-cache")
+            .args(args)
+            .output()
+            .expect("Failed to run rcc")
+    };
+    let mut invalid = PathBuf::from(INVALID_TEST_DIR);
+    invalid.push("missing_semicolon.c");
+    let output = rcc(&["--parse", invalid.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(3));
+    // Diagnostics go to stderr
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Syntax Error"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Syntax Error"));
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("lex_error.c");
+    fs::write(&source, "int main() { return 1 @ 2; }").expect("Failed to write source");
+    assert_eq!(rcc(&[source.to_str().unwrap()]).status.code(), Some(2));
+
+    assert_eq!(rcc(&["--bogus", "foo.c"]).status.code(), Some(1));
+    assert_eq!(rcc(&["does_not_exist.c"]).status.code(), Some(1));
+}
+
+#[test]
+fn test_corpus() {
+    require_system_compiler!();
+    let config = Config::builder()
+        .input("tests/testfiles")
+        .build()
+        .expect("Invalid config");
+    let summary = test_runner::run(&config).expect("Failed to run the test corpus");
+    let mut table = Vec::new();
+    summary.write(&mut table).expect("Failed to write summary");
+    assert!(summary.passed(), "{}", String::from_utf8_lossy(&table));
+}
+
+#[test]
+fn test_subcommand() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let write = |path: &str, source: &str| {
+        let path = dir.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).expect("Failed to create stage dir");
+        fs::write(path, source).expect("Failed to write source");
+    };
+    write("stage_1/valid/three.c", "int main() { return 3; }");
+    write(
+        "stage_1/valid/wrong.c",
+        "// expect-exit: 4\nint main() { return 5; }",
+    );
+    write(
+        "stage_1/invalid/semicolon.c",
+        "// expect-error: MissingSemicolon\nint main() { return 2 }",
+    );
+    write(
+        "stage_2/valid/later.c",
+        "// expect-skip: not yet\nint main() { int a; }",
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("test")
+        .arg("--no-cache")
+        .arg(dir.path())
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout,
+        "FAIL stage_1/valid/wrong.c: exited with 5, expected 4
+
+stage        valid    invalid  skipped
+stage_1        1/2        1/1        0
+stage_2        0/0        0/0        1
+total          1/2        1/1        1
+"
+    );
+}
+
+#[test]
+fn warnings_as_errors() {
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("warn.c");
+    fs::write(&source, "#warning unfinished\nint main() { return 3; }\n")
+        .expect("Failed to write source");
+    let rcc = |flags: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rcc"))
+            .args(["-fintegrated-cpp", "--no-cache", "--validate"])
+            .args(flags)
+            .arg(&source)
+            .output()
+            .expect("Failed to run rcc")
+    };
+
+    let output = rcc(&[]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("warning: ") && stderr.ends_with("#warning unfinished [-Wcpp]\n"),
+        "{}",
+        stderr
+    );
+
+    for flags in [&["-Werror"][..], &["-Werror=cpp"]].iter() {
+        let output = rcc(flags);
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.ends_with("#warning unfinished [-Werror=cpp]\n"),
+            "{}",
+            stderr
+        );
+    }
+    assert!(rcc(&["-Werror", "-Wno-error"]).status.success());
+}
+
+#[test]
+fn io_errors_name_the_file() {
+    require_system_compiler!();
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let missing = dir.path().join("missing.c");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .arg("--no-cache")
+        .arg(&missing)
+        .output()
+        .expect("Failed to run rcc");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("cannot read {}: ", missing.display())),
+        "{}",
+        stderr
+    );
+
+    let source = dir.path().join("ok.c");
+    fs::write(&source, "int main() { return 0; }").expect("Failed to write source");
+    let unwritable = dir.path().join("no_such_dir").join("ok.s");
+    let output = Command::new(env!("CARGO_BIN_EXE_rcc"))
+        .args(["--no-cache", "-S", "-o"])
+        .arg(&unwritable)
+        .arg(&source)
+        .output()
+        .expect("Failed to run rcc");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!(
+            "cannot write output to {}: ",
+            unwritable.display()
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn compile_source_text_to_assembly() {
+    let options = rcc::Options::default();
+    let assembly =
+        rcc::compile_to_assembly("int main() { return 2; }", &options).expect("Failed to compile");
+    assert!(assembly.contains("main"), "{}", assembly);
+
+    let options = rcc::Options {
+        preprocess: true,
+        ..Default::default()
+    };
+    let assembly = rcc::compile_to_assembly("#define N 2\nint main() { return N; }", &options)
+        .expect("Failed to compile");
+    assert!(assembly.contains("main"), "{}", assembly);
+
+    match rcc::compile_to_assembly("int main() { return 2 }", &options) {
+        Err(CompilerError::Parse(SyntaxError::MissingSemicolon, location)) => {
+            assert_eq!(location.file, Path::new("<input>"));
+            assert_eq!((location.line, location.column), (1, 23));
+        }
+        result => panic!("Expected a missing semicolon, got {:?}", result),
+    }
+}
+
+#[test]
+fn pipeline_stages() {
+    use rcc::ast::{Node, Statement};
+    use rcc::compiler::lexer::Token;
+
+    let tokens = rcc::tokenize("int main() { return 7; }").expect("Failed to tokenize");
+    assert_eq!(tokens.len(), 9);
+    assert_eq!(tokens[0].0, Token::IntKw);
+    assert_eq!((tokens[1].1.line, tokens[1].1.column), (1, 5));
+
+    let ast = rcc::parse(&tokens).expect("Failed to parse");
+    assert_eq!(ast.function_names(), ["main"]);
+    let Node::Program(function) = &ast else {
+        panic!("Expected a program, got {:?}", ast)
+    };
+    assert!(format!("{}", function).contains(&format!("{}", Statement::Return)));
+
+    let assembly = rcc::generate(&ast, &rcc::Options::default()).expect("Failed to generate");
+    assert_eq!(
+        assembly,
+        rcc::compile_to_assembly("int main() { return 7; }", &rcc::Options::default()).unwrap()
+    );
+
+    let errors = rcc::tokenize("int $main() {}").unwrap_err();
+    assert_eq!(
+        errors[0].0,
+        SyntaxError::InvalidIdentifier(String::from("$main"))
+    );
+    assert_eq!(
+        rcc::parse(&tokens[..3]).unwrap_err(),
+        (SyntaxError::MissingCloseParen, 3)
+    );
+}
+
+#[test]
+fn observers_see_each_stage() {
+    require_system_compiler!();
+    use rcc::compiler::ast::Node;
+    use rcc::compiler::lexer::{Span, Token};
+    use rcc::compiler::observer::{Observer, Observers};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn tokens(&self, _: &Path, tokens: &[(Token, Span)]) {
+            self.0.borrow_mut().push(format!("{} tokens", tokens.len()));
+        }
+
+        fn ast(&self, _: &Path, ast: &Node) {
+            self.0.borrow_mut().push(ast.function_names().join(","));
+        }
+
+        fn assembly(&self, source: &Path, assembly: &str) {
+            assert!(assembly.contains("main"), "{}", assembly);
+            self.0
+                .borrow_mut()
+                .push(source.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+
+    let dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let source = dir.path().join("prog.c");
+    fs::write(&source, "int main() { return 3; }").expect("Failed to write source");
+    let recorder = Recorder::default();
+    let seen = recorder.0.clone();
+    let config = Config {
+        filenames: vec![source],
+        emit: vec![Emit::Assembly],
+        observers: Observers(vec![Box::new(recorder)]),
+        ..Default::default()
+    };
+
+    compiler::compile(&config).expect("Compilation failed");
+    assert_eq!(*seen.borrow(), ["9 tokens", "main", "prog.c"]);
 }
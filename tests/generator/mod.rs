@@ -0,0 +1,106 @@
+//! Random valid programs in the subset of C rcc supports, each with the
+//! value `main` returns, for differential testing of the backends.
+
+use rand::Rng;
+
+/// A constant expression and its value.
+enum Expr {
+    Literal(i32),
+    Unary(char, Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn random<R: Rng>(rng: &mut R, depth: u32) -> Self {
+        if depth == 0 || rng.gen_range(0, 4) == 0 {
+            // Mostly small numbers, so that products rarely overflow
+            return Expr::Literal(match rng.gen_range(0, 8) {
+                0 => rng.gen_range(0, i32::MAX),
+                _ => rng.gen_range(0, 20),
+            });
+        }
+        if rng.gen_range(0, 3) == 0 {
+            let op = ['-', '~', '!'][rng.gen_range(0, 3)];
+            Expr::Unary(op, Box::new(Self::random(rng, depth - 1)))
+        } else {
+            let op = ['+', '-', '*', '/'][rng.gen_range(0, 4)];
+            Expr::Binary(
+                op,
+                Box::new(Self::random(rng, depth - 1)),
+                Box::new(Self::random(rng, depth - 1)),
+            )
+        }
+    }
+
+    /// The value in C, or `None` if evaluating it is undefined behaviour.
+    fn value(&self) -> Option<i32> {
+        match self {
+            Expr::Literal(n) => Some(*n),
+            Expr::Unary(op, e) => {
+                let e = e.value()?;
+                match op {
+                    '-' => e.checked_neg(),
+                    '~' => Some(!e),
+                    _ => Some((e == 0) as i32),
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.value()?, right.value()?);
+                match op {
+                    '+' => left.checked_add(right),
+                    '-' => left.checked_sub(right),
+                    '*' => left.checked_mul(right),
+                    _ => left.checked_div(right),
+                }
+            }
+        }
+    }
+
+    fn precedence(&self) -> u32 {
+        match self {
+            Expr::Binary('+', ..) | Expr::Binary('-', ..) => 1,
+            Expr::Binary(..) => 2,
+            _ => 3,
+        }
+    }
+
+    /// The expression as source, with only the parentheses precedence and
+    /// left associativity need.
+    fn source(&self) -> String {
+        let parenthesise = |e: &Expr, needed: bool| {
+            if needed {
+                format!("({})", e.source())
+            } else {
+                e.source()
+            }
+        };
+        match self {
+            Expr::Literal(n) => n.to_string(),
+            // Parentheses around other unary operators keep `-(-1)` from
+            // becoming a decrement
+            Expr::Unary(op, e) => {
+                let needed = !matches!(**e, Expr::Literal(_));
+                format!("{}{}", op, parenthesise(e, needed))
+            }
+            Expr::Binary(op, left, right) => format!(
+                "{} {} {}",
+                parenthesise(left, left.precedence() < self.precedence()),
+                op,
+                parenthesise(right, right.precedence() <= self.precedence())
+            ),
+        }
+    }
+}
+
+/// A random program with the value its `main` returns.
+pub fn program<R: Rng>(rng: &mut R) -> (String, i32) {
+    loop {
+        let expr = Expr::random(rng, 5);
+        if let Some(value) = expr.value() {
+            return (
+                format!("int main() {{\n    return {};\n}}\n", expr.source()),
+                value,
+            );
+        }
+    }
+}
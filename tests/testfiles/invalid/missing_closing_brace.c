@@ -1,2 +1,3 @@
+// expect-error: MissingCloseBrace
 int main() {
     return 0;
\ No newline at end of file
@@ -1,3 +1,4 @@
+// expect-error: MissingCloseParen
 int main( {
     return 0;
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+// expect-error: UnexpectedToken
 int main()
 {
     return0;
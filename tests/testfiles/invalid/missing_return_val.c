@@ -1,3 +1,4 @@
+// expect-error: InvalidFactor
 int main() {
     return;
 }
\ No newline at end of file
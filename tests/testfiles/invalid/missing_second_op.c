@@ -1,3 +1,4 @@
+// expect-error: InvalidFactor
 int main()
 {
     return 1 + ;
@@ -1,3 +1,4 @@
+// expect-error: MissingSemicolon
 int main()
 {
     return 2 * 2
@@ -1,3 +1,4 @@
+// expect-error: UnexpectedToken
 int main()
 {
     RETURN 0;
@@ -1,3 +1,4 @@
+// expect-exit: 252
 int main()
 {
     return 1 - 2 - 3;
@@ -1,3 +1,4 @@
+// expect-exit: 0
 int main() {
     return !12;
 }
\ No newline at end of file
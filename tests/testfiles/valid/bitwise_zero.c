@@ -1,3 +1,4 @@
+// expect-exit: 255
 int main() {
     return ~0;
 }
\ No newline at end of file
@@ -0,0 +1,23 @@
+// expect-exit: 6
+int main(void) {
+    do {
+        do {
+            break;
+            return 1;
+        } while (1);
+        do {
+            continue;
+            return 2;
+        } while (0);
+        if (1)
+            break;
+        return 3;
+    } while (1);
+    do
+        if (0)
+            continue;
+        else
+            return 6;
+    while (0);
+    return 7;
+}
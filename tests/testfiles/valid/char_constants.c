@@ -0,0 +1,4 @@
+// expect-exit: 90
+int main() {
+    return '\x41' + '\101' - 'A' + '\n' - '\t' + '\\' - '\'' + '\"' - '\?' + '\0' - '\x0';
+}
@@ -0,0 +1,4 @@
+// expect-exit: 129
+int main() {
+    return -'\377' + '\x80' + 256;
+}
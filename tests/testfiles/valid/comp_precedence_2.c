@@ -1,3 +1,4 @@
+// expect-skip: relational and logical operators aren't supported yet
 int main() {
     return 2 == 2 || 0;
 }
\ No newline at end of file
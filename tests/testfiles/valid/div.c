@@ -1,3 +1,4 @@
+// expect-exit: 2
 int main()
 {
     return 4 / 2;
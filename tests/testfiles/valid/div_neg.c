@@ -1,3 +1,4 @@
+// expect-exit: 254
 int main()
 {
     return (-12) / 5;
@@ -0,0 +1,11 @@
+// expect-exit: 3
+int main(void) {
+    do {
+        if (0)
+            return 1;
+        asm("nop");
+    } while (0);
+    do
+        return 3;
+    while (1);
+}
@@ -0,0 +1,4 @@
+// expect-exit: 7
+__extension__ int __attribute__((unused, __cold__)) main(void) {
+    return 7;
+}
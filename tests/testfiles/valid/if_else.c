@@ -0,0 +1,13 @@
+// expect-exit: 4
+int main(void) {
+    if (1 - 1)
+        return 1;
+    else if (0)
+        return 2;
+    if (2)
+        if (0)
+            return 3;
+        else
+            return 4;
+    return 5;
+}
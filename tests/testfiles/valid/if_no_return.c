@@ -0,0 +1,5 @@
+// expect-exit: 0
+int main(void) {
+    if (3 * 0)
+        return 1;
+}
@@ -1,3 +1,4 @@
+// expect-exit: 0
 
 int
 main
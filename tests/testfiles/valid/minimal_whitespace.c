@@ -1 +1,2 @@
+// expect-exit: 0
 int main(){return 0;}
\ No newline at end of file
@@ -1,3 +1,4 @@
+// expect-exit: 6
 int main()
 {
     return 2 * 3;
@@ -1,3 +1,4 @@
+// expect-exit: 100
 int main()
 {
     return 100;
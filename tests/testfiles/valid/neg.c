@@ -1,3 +1,4 @@
+// expect-exit: 251
 int main() {
     return -5;
 }
\ No newline at end of file
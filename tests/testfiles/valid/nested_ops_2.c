@@ -1,3 +1,4 @@
+// expect-exit: 1
 int main()
 {
     return -~0;
@@ -1,3 +1,4 @@
+// expect-exit: 14
 int main()
 {
     return 2 + 3 * 4;
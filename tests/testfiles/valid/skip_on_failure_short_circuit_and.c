@@ -1,3 +1,4 @@
+// expect-skip: variables aren't supported yet
 int main() {
     int a = 0;
     int b = 0;
@@ -1,3 +1,4 @@
+// expect-skip: variables aren't supported yet
 int main() {
     int a = 1;
     int b = 0;
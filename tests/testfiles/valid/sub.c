@@ -1,3 +1,4 @@
+// expect-exit: 255
 int main()
 {
     return 1 - 2;
@@ -1,3 +1,4 @@
+// expect-exit: 3
 int main()
 {
     return 2 - -1;
@@ -1,3 +1,4 @@
+// expect-exit: 253
 int main()
 {
     return ~(1 + 1);
@@ -0,0 +1,4 @@
+// expect-exit: 6
+int main(void) {
+    return 6;
+}